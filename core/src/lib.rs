@@ -36,6 +36,29 @@
 //! Since a mutex only requires 2 bits, it can share space with other data.
 //! For example, one could create an `ArcMutex` type that combines the atomic
 //! reference count and the two mutex bits in the same atomic word.
+//!
+//! # Custom park backends
+//!
+//! By default parking suspends the current OS thread. Runtimes which schedule
+//! their own execution contexts, such as green threads or fibers, can install
+//! a `ParkBackend` with `set_park_backend` so that parking only suspends the
+//! current context. Each context is represented by a `ParkContext` owned by
+//! the runtime. Everything built on top of the parking lot, including the
+//! `parking_lot` locks, then works unchanged within the runtime.
+//!
+//! # Watchdog
+//!
+//! With the `watchdog` feature enabled, a `Watchdog` can be installed with
+//! `set_watchdog` to report threads which stay parked on a key for longer than
+//! a threshold. Locks built on top of the parking lot can use the same hook to
+//! report locks which were held for too long.
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature enabled, every call to `park` runs inside a
+//! `park` span and every unpark operation emits an event describing its
+//! `UnparkResult`, so that contention shows up alongside the rest of an
+//! application's traces. All of them use the `TRACE` level.
 
 #![warn(missing_docs)]
 #![warn(rust_2018_idioms)]
@@ -56,16 +79,33 @@
     feature(thread_local)
 )]
 
+#[cfg(feature = "lock_levels")]
+mod lock_level;
+#[cfg(feature = "lock_order_detection")]
+mod lock_order;
 mod parking_lot;
 mod spinwait;
 mod thread_parker;
+mod trace;
 mod util;
+#[cfg(feature = "watchdog")]
+mod watchdog;
 mod word_lock;
 
 pub use self::parking_lot::deadlock;
-pub use self::parking_lot::{park, unpark_all, unpark_filter, unpark_one, unpark_requeue};
 pub use self::parking_lot::{
-    FilterOp, ParkResult, ParkToken, RequeueOp, UnparkResult, UnparkToken,
+    all_parked_threads, park, park_async, parked_threads, unpark_all, unpark_filter, unpark_one,
+    unpark_requeue,
+};
+pub use self::parking_lot::{
+    FilterOp, ParkContext, ParkFuture, ParkResult, ParkToken, ParkedThread, RequeueOp,
+    UnparkResult, UnparkToken,
 };
 pub use self::parking_lot::{DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
 pub use self::spinwait::SpinWait;
+pub use self::thread_parker::{set_park_backend, ParkBackend};
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use self::trace::guard as trace_guard;
+#[cfg(feature = "watchdog")]
+pub use self::watchdog::{set_watchdog, watchdog, LockKind, Watchdog};
@@ -0,0 +1,133 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Lock hierarchy checking.
+//
+// Resources can be assigned a numeric level, and a thread may only acquire a
+// leveled resource if its level is strictly greater than that of every
+// leveled resource it already holds. Unlike lock order detection this doesn't
+// need to observe both orders of acquisition: the first acquisition which
+// goes against the hierarchy is caught.
+//
+// The levels of the resources held by a thread are kept in the same list of
+// resources as the one used by deadlock detection, in its `ThreadData`.
+
+use crate::word_lock::WordLock;
+use backtrace::Backtrace;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Representation of a lock hierarchy violation.
+///
+/// This is reported when a thread attempts to acquire `acquired_resource`
+/// while holding `held_resource`, and the level of `acquired_resource` is not
+/// strictly greater than the level of `held_resource`.
+pub struct LockLevelViolation {
+    held_resource: usize,
+    held_level: u32,
+    acquired_resource: usize,
+    acquired_level: u32,
+    thread_id: usize,
+    backtrace: Backtrace,
+}
+
+impl LockLevelViolation {
+    /// The key of the highest-level resource which was held by the thread
+    pub fn held_resource(&self) -> usize {
+        self.held_resource
+    }
+
+    /// The level of the resource which was held by the thread
+    pub fn held_level(&self) -> u32 {
+        self.held_level
+    }
+
+    /// The key of the resource which was acquired out of order
+    pub fn acquired_resource(&self) -> usize {
+        self.acquired_resource
+    }
+
+    /// The level of the resource which was acquired out of order
+    pub fn acquired_level(&self) -> u32 {
+        self.acquired_level
+    }
+
+    /// The system thread id of the thread which acquired the resource
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
+    /// The backtrace of the acquisition which caused the violation
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+struct ViolationsCell(UnsafeCell<Option<Vec<LockLevelViolation>>>);
+
+// The violations are only accessed while holding VIOLATIONS_LOCK.
+unsafe impl Sync for ViolationsCell {}
+
+static VIOLATIONS_LOCK: WordLock = WordLock::new();
+static VIOLATIONS: ViolationsCell = ViolationsCell(UnsafeCell::new(None));
+
+static PANIC: AtomicBool = AtomicBool::new(true);
+
+// Runs the given closure with exclusive access to the reported violations.
+fn with_violations<T>(f: impl FnOnce(&mut Vec<LockLevelViolation>) -> T) -> T {
+    struct Unlock;
+    impl Drop for Unlock {
+        fn drop(&mut self) {
+            // SAFETY: We hold the lock here, as required
+            unsafe { VIOLATIONS_LOCK.unlock() };
+        }
+    }
+
+    VIOLATIONS_LOCK.lock();
+    let _unlock = Unlock;
+    // SAFETY: We hold VIOLATIONS_LOCK.
+    let violations = unsafe { &mut *VIOLATIONS.0.get() };
+    f(violations.get_or_insert_with(Vec::new))
+}
+
+// Called with the highest-level resource held by the current thread, other
+// than the one being acquired.
+pub fn check_resource_level(key: usize, level: u32, held_key: usize, held_level: u32) {
+    if level > held_level {
+        return;
+    }
+
+    if PANIC.load(Ordering::Relaxed) {
+        panic!(
+            "lock level violation: acquiring a lock with level {} while holding a lock with level {}",
+            level, held_level
+        );
+    }
+    let violation = LockLevelViolation {
+        held_resource: held_key,
+        held_level,
+        acquired_resource: key,
+        acquired_level: level,
+        thread_id: thread_id::get(),
+        backtrace: Backtrace::new_unresolved(),
+    };
+    with_violations(|violations| violations.push(violation));
+}
+
+pub fn set_panic(panic: bool) {
+    PANIC.store(panic, Ordering::Relaxed);
+}
+
+pub fn check_lock_levels() -> Vec<LockLevelViolation> {
+    let mut violations = with_violations(|violations| violations.split_off(0));
+
+    // Resolve the symbols outside the lock since this is expensive.
+    for violation in &mut violations {
+        violation.backtrace.resolve();
+    }
+    violations
+}
@@ -0,0 +1,232 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Lock order checking, in the style of the Linux kernel's lockdep.
+//
+// Every time a thread acquires a resource while already holding other
+// resources, an edge `held -> acquired` is recorded in a global graph, along
+// with the backtrace of the acquisition which first established it. Before a
+// new edge is added, the graph is searched for a path in the opposite
+// direction: if one exists then two threads could deadlock by acquiring these
+// resources in different orders, even if they never actually do so.
+
+use crate::parking_lot::HeldResource;
+use crate::word_lock::WordLock;
+use backtrace::Backtrace;
+use std::cell::UnsafeCell;
+use std::collections::{HashMap, HashSet};
+
+/// Representation of a lock order inversion.
+///
+/// This is reported when a thread acquires `acquired_resource` while holding
+/// `held_resource`, after another acquisition has already established that
+/// `acquired_resource` is locked before `held_resource`.
+pub struct LockOrderViolation {
+    held_resource: usize,
+    acquired_resource: usize,
+    thread_id: usize,
+    backtrace: Backtrace,
+    previous_backtrace: Backtrace,
+}
+
+impl LockOrderViolation {
+    /// The key of the resource which was held by the thread
+    pub fn held_resource(&self) -> usize {
+        self.held_resource
+    }
+
+    /// The key of the resource which was acquired in the wrong order
+    pub fn acquired_resource(&self) -> usize {
+        self.acquired_resource
+    }
+
+    /// The system thread id of the thread which acquired the resource
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
+    /// The backtrace of the acquisition which caused the inversion
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    /// The backtrace of the earlier acquisition of the held resource which
+    /// completed the opposite order
+    pub fn previous_backtrace(&self) -> &Backtrace {
+        &self.previous_backtrace
+    }
+}
+
+struct LockOrderGraph {
+    // For each resource, the resources which have been acquired while holding
+    // it, along with the backtrace of the first such acquisition.
+    edges: HashMap<usize, HashMap<usize, Backtrace>>,
+
+    // The reverse of `edges`, used to remove a resource when it is forgotten.
+    preds: HashMap<usize, HashSet<usize>>,
+
+    // Inversions which have already been reported, so that each one is only
+    // reported the first time it is observed.
+    reported: HashSet<(usize, usize)>,
+
+    // Inversions which haven't been returned by `check_lock_order` yet.
+    violations: Vec<LockOrderViolation>,
+}
+
+impl LockOrderGraph {
+    fn new() -> LockOrderGraph {
+        LockOrderGraph {
+            edges: HashMap::new(),
+            preds: HashMap::new(),
+            reported: HashSet::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    // Looks for a path from `from` to `to`, returning the backtrace of the
+    // last edge on that path, which is the acquisition of `to`.
+    fn find_path(&self, from: usize, to: usize) -> Option<&Backtrace> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node) {
+                continue;
+            }
+            if let Some(next) = self.edges.get(&node) {
+                if let Some(backtrace) = next.get(&to) {
+                    return Some(backtrace);
+                }
+                stack.extend(next.keys());
+            }
+        }
+        None
+    }
+
+    // Removes all edges to and from `key`.
+    fn forget(&mut self, key: usize) {
+        if let Some(next) = self.edges.remove(&key) {
+            for node in next.keys() {
+                if let Some(prev) = self.preds.get_mut(node) {
+                    prev.remove(&key);
+                    if prev.is_empty() {
+                        self.preds.remove(node);
+                    }
+                }
+            }
+        }
+        if let Some(prev) = self.preds.remove(&key) {
+            for node in prev {
+                if let Some(next) = self.edges.get_mut(&node) {
+                    next.remove(&key);
+                    if next.is_empty() {
+                        self.edges.remove(&node);
+                    }
+                }
+            }
+        }
+        self.reported.retain(|&(a, b)| a != key && b != key);
+    }
+}
+
+struct GraphCell(UnsafeCell<Option<LockOrderGraph>>);
+
+// The graph is only accessed while holding GRAPH_LOCK.
+unsafe impl Sync for GraphCell {}
+
+static GRAPH_LOCK: WordLock = WordLock::new();
+static GRAPH: GraphCell = GraphCell(UnsafeCell::new(None));
+
+// Runs the given closure with exclusive access to the global graph.
+fn with_graph<T>(f: impl FnOnce(&mut LockOrderGraph) -> T) -> T {
+    struct Unlock;
+    impl Drop for Unlock {
+        fn drop(&mut self) {
+            // SAFETY: We hold the lock here, as required
+            unsafe { GRAPH_LOCK.unlock() };
+        }
+    }
+
+    GRAPH_LOCK.lock();
+    let _unlock = Unlock;
+    // SAFETY: We hold GRAPH_LOCK.
+    let graph = unsafe { &mut *GRAPH.0.get() };
+    f(graph.get_or_insert_with(LockOrderGraph::new))
+}
+
+pub fn forget_resource(key: usize) {
+    with_graph(|graph| graph.forget(key));
+}
+
+// Records the order of `key` relative to all resources held by the current
+// thread, reporting any inversions. Linked resources are treated as part of
+// the resource acquired just before them, so they are never added to the
+// graph and each inversion is only reported once.
+pub(crate) fn check_order(held: &[HeldResource], key: usize) {
+    if !held
+        .iter()
+        .any(|resource| !resource.linked && resource.key != key)
+    {
+        return;
+    }
+
+    let mut backtrace = None;
+    with_graph(|graph| {
+        for resource in held {
+            let held_key = resource.key;
+            if resource.linked || held_key == key {
+                continue;
+            }
+            let known = graph.edges.get(&held_key).and_then(|next| next.get(&key));
+            if known.is_some() {
+                continue;
+            }
+
+            // Don't add edges which close a cycle, so that the graph stays
+            // acyclic and each inversion is reported only once.
+            let previous = graph.find_path(key, held_key).cloned();
+            let current = backtrace
+                .get_or_insert_with(Backtrace::new_unresolved)
+                .clone();
+            match previous {
+                Some(previous_backtrace) => {
+                    if graph.reported.insert((held_key, key)) {
+                        graph.violations.push(LockOrderViolation {
+                            held_resource: held_key,
+                            acquired_resource: key,
+                            thread_id: thread_id::get(),
+                            backtrace: current,
+                            previous_backtrace,
+                        });
+                    }
+                }
+                None => {
+                    graph
+                        .edges
+                        .entry(held_key)
+                        .or_insert_with(HashMap::new)
+                        .insert(key, current);
+                    graph
+                        .preds
+                        .entry(key)
+                        .or_insert_with(HashSet::new)
+                        .insert(held_key);
+                }
+            }
+        }
+    });
+}
+
+pub fn check_lock_order() -> Vec<LockOrderViolation> {
+    let mut violations = with_graph(|graph| graph.violations.split_off(0));
+
+    // Resolve the symbols outside the lock since this is expensive.
+    for violation in &mut violations {
+        violation.backtrace.resolve();
+        violation.previous_backtrace.resolve();
+    }
+    violations
+}
@@ -5,31 +5,155 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use crate::thread_parker::{ThreadParker, ThreadParkerT, UnparkHandleT};
+use crate::thread_parker::{
+    current_context, BackendParker, ThreadParker, ThreadParkerT, UnparkHandleT,
+};
+use crate::trace;
 use crate::util::UncheckedOptionExt;
+#[cfg(feature = "watchdog")]
+use crate::watchdog;
 use crate::word_lock::WordLock;
 use core::{
     cell::{Cell, UnsafeCell},
+    future::Future,
+    pin::Pin,
     ptr,
-    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
 };
 use smallvec::SmallVec;
 use std::time::{Duration, Instant};
 
 static NUM_THREADS: AtomicUsize = AtomicUsize::new(0);
 
+// Source of the identifiers reported in `ParkedThread::thread_id`.
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
 /// Holds the pointer to the currently active `HashTable`.
 ///
 /// # Safety
 ///
 /// Except for the initial value of null, it must always point to a valid `HashTable` instance.
-/// Any `HashTable` this global static has ever pointed to must never be freed.
+/// A `HashTable` this global static has pointed to is only freed once it has been replaced and
+/// `EPOCH` has since advanced twice.
 static HASHTABLE: AtomicPtr<HashTable> = AtomicPtr::new(ptr::null_mut());
 
+/// Linked list (through `HashTable::next_retired`) of tables which have been replaced but which
+/// may still be accessed by other threads.
+static RETIRED: AtomicPtr<HashTable> = AtomicPtr::new(ptr::null_mut());
+
 // Even with 3x more buckets than threads, the memory overhead per thread is
 // still only a few hundred bytes per thread.
 const LOAD_FACTOR: usize = 3;
 
+// The table is only shrunk once it has this many times more buckets than it
+// needs. This avoids repeatedly resizing the table when the number of threads
+// fluctuates.
+const SHRINK_FACTOR: usize = 4;
+
+// Replaced tables are freed using epoch-based reclamation. Every thread
+// records the epoch in which it started accessing the hash table in its own
+// `Reader`, so that accesses don't write to any shared memory. The epoch is
+// only advanced once every thread accessing a table has seen the current
+// epoch, so a table replaced during an epoch can't be accessed by any thread
+// once the epoch has advanced twice.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+// Number of threads accessing a table after their `Reader` was destroyed,
+// which only happens while thread-local destructors run. The epoch can't be
+// advanced while this is non-zero.
+static UNREGISTERED_READERS: AtomicUsize = AtomicUsize::new(0);
+
+// Set while a thread is trying to free replaced tables.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+// Protects the list of registered readers.
+static READERS_LOCK: WordLock = WordLock::new();
+static READERS: ReaderList = ReaderList(UnsafeCell::new(None));
+
+struct ReaderList(UnsafeCell<Option<Vec<*const Reader>>>);
+
+// The list is only accessed while holding READERS_LOCK.
+unsafe impl Sync for ReaderList {}
+
+// Per-thread record of accesses to the hash table. Each one lives in its own
+// cache line so that threads don't contend with each other.
+#[repr(align(64))]
+struct Reader {
+    // While the thread is accessing a table, the epoch in which it started
+    // doing so shifted left by one, with the lowest bit set. Zero otherwise.
+    state: AtomicUsize,
+
+    // Number of nested accesses, which is only used by the owning thread.
+    depth: Cell<usize>,
+}
+
+impl Reader {
+    #[inline]
+    fn enter(&self) {
+        let depth = self.depth.get();
+        self.depth.set(depth + 1);
+        if depth == 0 {
+            // This must be ordered before loading HASHTABLE.
+            let epoch = EPOCH.load(Ordering::SeqCst);
+            self.state.swap((epoch << 1) | 1, Ordering::SeqCst);
+        }
+    }
+
+    #[inline]
+    fn leave(&self) -> bool {
+        let depth = self.depth.get() - 1;
+        self.depth.set(depth);
+        if depth == 0 {
+            self.state.store(0, Ordering::Release);
+        }
+        depth == 0
+    }
+}
+
+// Owns the `Reader` of a thread and keeps it registered while the thread is
+// alive.
+struct ReaderHandle(Box<Reader>);
+
+impl ReaderHandle {
+    fn new() -> ReaderHandle {
+        let reader = Box::new(Reader {
+            state: AtomicUsize::new(0),
+            depth: Cell::new(0),
+        });
+        READERS_LOCK.lock();
+        // SAFETY: We hold READERS_LOCK.
+        let readers = unsafe { &mut *READERS.0.get() };
+        readers
+            .get_or_insert_with(Vec::new)
+            .push(&*reader as *const Reader);
+        // SAFETY: We hold the lock here, as required
+        unsafe { READERS_LOCK.unlock() };
+        ReaderHandle(reader)
+    }
+}
+
+impl Drop for ReaderHandle {
+    fn drop(&mut self) {
+        READERS_LOCK.lock();
+        // SAFETY: We hold READERS_LOCK.
+        if let Some(readers) = unsafe { &mut *READERS.0.get() } {
+            let reader = &*self.0 as *const Reader;
+            if let Some(p) = readers.iter().position(|&r| r == reader) {
+                readers.swap_remove(p);
+            }
+            // Give back the memory after a spike in the number of threads.
+            if readers.len() * 4 < readers.capacity() {
+                readers.shrink_to_fit();
+            }
+        }
+        // SAFETY: We hold the lock here, as required
+        unsafe { READERS_LOCK.unlock() };
+    }
+}
+
+thread_local!(static READER: ReaderHandle = ReaderHandle::new());
+
 struct HashTable {
     // Hash buckets for the table
     entries: Box<[Bucket]>,
@@ -37,14 +161,17 @@ struct HashTable {
     // Number of bits used for the hash function
     hash_bits: u32,
 
-    // Previous table. This is only kept to keep leak detectors happy.
-    _prev: *const HashTable,
+    // Next table in the RETIRED list
+    next_retired: *mut HashTable,
+
+    // Value of EPOCH after the table was replaced
+    retired_epoch: usize,
 }
 
 impl HashTable {
     #[inline]
-    fn new(num_threads: usize, prev: *const HashTable) -> Box<HashTable> {
-        let new_size = (num_threads * LOAD_FACTOR).next_power_of_two();
+    fn new(num_threads: usize) -> Box<HashTable> {
+        let new_size = HashTable::size_for(num_threads);
         let hash_bits = 0usize.leading_zeros() - new_size.leading_zeros() - 1;
 
         let now = Instant::now();
@@ -57,9 +184,27 @@ impl HashTable {
         Box::new(HashTable {
             entries: entries.into_boxed_slice(),
             hash_bits,
-            _prev: prev,
+            next_retired: ptr::null_mut(),
+            retired_epoch: 0,
         })
     }
+
+    // Number of buckets in a table created for the given number of threads.
+    // This never goes below the size of the initial table.
+    #[inline]
+    fn size_for(num_threads: usize) -> usize {
+        (num_threads.max(LOAD_FACTOR) * LOAD_FACTOR).next_power_of_two()
+    }
+
+    // Returns whether this table should be replaced with one of a different
+    // size for the given number of threads.
+    #[inline]
+    fn needs_resize(&self, num_threads: usize) -> bool {
+        let len = self.entries.len();
+        len < LOAD_FACTOR * num_threads
+            || (len >= SHRINK_FACTOR * LOAD_FACTOR * num_threads
+                && HashTable::size_for(num_threads) < len)
+    }
 }
 
 #[repr(align(64))]
@@ -143,18 +288,131 @@ struct ThreadData {
     // Is the thread parked with a timeout?
     parked_with_timeout: Cell<bool>,
 
+    // Identifier reported to `parked_threads`
+    id: usize,
+
+    // Set if this ThreadData belongs to an asynchronous task parked with
+    // `park_async` instead of a thread.
+    task: Option<TaskData>,
+
+    // Set if this ThreadData belongs to an execution context managed by a
+    // custom `ParkBackend`, in which case `parker` is unused.
+    backend: Option<BackendParker>,
+
+    // Resources currently held by this thread. Used by deadlock detection and
+    // lock order and level checking.
+    #[cfg(any(
+        feature = "deadlock_detection",
+        feature = "lock_order_detection",
+        feature = "lock_levels"
+    ))]
+    resources: UnsafeCell<Vec<HeldResource>>,
+
+    // Set while this thread is updating the lock order graph, so that any
+    // locking done by the detector itself (e.g. in the allocator) is ignored.
+    #[cfg(feature = "lock_order_detection")]
+    checking_order: Cell<bool>,
+
     // Extra data for deadlock detection
     #[cfg(feature = "deadlock_detection")]
     deadlock_data: deadlock::DeadlockData,
 }
 
+// A resource held by a thread.
+#[cfg(any(
+    feature = "deadlock_detection",
+    feature = "lock_order_detection",
+    feature = "lock_levels"
+))]
+#[derive(Clone, Copy)]
+pub(crate) struct HeldResource {
+    pub(crate) key: usize,
+
+    // Level of the resource, if it was recorded by `acquire_resource_level`.
+    #[cfg(feature = "lock_levels")]
+    pub(crate) level: Option<u32>,
+
+    // Set for resources registered with `acquire_linked_resource`, which are
+    // ignored by lock order checking.
+    #[cfg(feature = "lock_order_detection")]
+    pub(crate) linked: bool,
+}
+
+struct TaskData {
+    // Waker registered by the last poll of the ParkFuture. This is protected
+    // by the bucket lock.
+    waker: Cell<Option<Waker>>,
+
+    // Whether the task is still in a queue. This is cleared while holding the
+    // bucket lock by whichever thread removes the task from the queue.
+    queued: Cell<bool>,
+}
+
 impl ThreadData {
     fn new() -> ThreadData {
         // Keep track of the total number of live ThreadData objects and resize
         // the hash table accordingly.
-        let num_threads = NUM_THREADS.fetch_add(1, Ordering::Relaxed) + 1;
-        grow_hashtable(num_threads);
+        NUM_THREADS.fetch_add(1, Ordering::Relaxed);
+        resize_hashtable();
+
+        ThreadData {
+            parker: ThreadParker::new(),
+            key: AtomicUsize::new(0),
+            next_in_queue: Cell::new(ptr::null()),
+            unpark_token: Cell::new(DEFAULT_UNPARK_TOKEN),
+            park_token: Cell::new(DEFAULT_PARK_TOKEN),
+            parked_with_timeout: Cell::new(false),
+            id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            task: None,
+            backend: None,
+            #[cfg(any(
+                feature = "deadlock_detection",
+                feature = "lock_order_detection",
+                feature = "lock_levels"
+            ))]
+            resources: UnsafeCell::new(Vec::new()),
+            #[cfg(feature = "lock_order_detection")]
+            checking_order: Cell::new(false),
+            #[cfg(feature = "deadlock_detection")]
+            deadlock_data: deadlock::DeadlockData::new(),
+        }
+    }
+
+    // Creates a ThreadData for an asynchronous task. Unlike threads, tasks are
+    // not counted in NUM_THREADS and therefore don't cause the hash table to
+    // grow.
+    fn new_task() -> ThreadData {
+        ThreadData {
+            parker: ThreadParker::new(),
+            key: AtomicUsize::new(0),
+            next_in_queue: Cell::new(ptr::null()),
+            unpark_token: Cell::new(DEFAULT_UNPARK_TOKEN),
+            park_token: Cell::new(DEFAULT_PARK_TOKEN),
+            parked_with_timeout: Cell::new(false),
+            id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            task: Some(TaskData {
+                waker: Cell::new(None),
+                queued: Cell::new(false),
+            }),
+            backend: None,
+            #[cfg(any(
+                feature = "deadlock_detection",
+                feature = "lock_order_detection",
+                feature = "lock_levels"
+            ))]
+            resources: UnsafeCell::new(Vec::new()),
+            #[cfg(feature = "lock_order_detection")]
+            checking_order: Cell::new(false),
+            #[cfg(feature = "deadlock_detection")]
+            deadlock_data: deadlock::DeadlockData::new(),
+        }
+    }
 
+    // Creates a ThreadData for an execution context managed by a custom
+    // `ParkBackend`. Several such contexts may share an OS thread, so they
+    // can't use the thread-local ThreadData. Like tasks they are not counted
+    // in NUM_THREADS, since a runtime may create a very large number of them.
+    fn new_backend(parker: BackendParker) -> ThreadData {
         ThreadData {
             parker: ThreadParker::new(),
             key: AtomicUsize::new(0),
@@ -162,15 +420,109 @@ impl ThreadData {
             unpark_token: Cell::new(DEFAULT_UNPARK_TOKEN),
             park_token: Cell::new(DEFAULT_PARK_TOKEN),
             parked_with_timeout: Cell::new(false),
+            id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            task: None,
+            backend: Some(parker),
+            #[cfg(any(
+                feature = "deadlock_detection",
+                feature = "lock_order_detection",
+                feature = "lock_levels"
+            ))]
+            resources: UnsafeCell::new(Vec::new()),
+            #[cfg(feature = "lock_order_detection")]
+            checking_order: Cell::new(false),
             #[cfg(feature = "deadlock_detection")]
             deadlock_data: deadlock::DeadlockData::new(),
         }
     }
+
+    #[inline]
+    unsafe fn prepare_park(&self) {
+        match self.backend {
+            None => self.parker.prepare_park(),
+            Some(ref parker) => parker.prepare_park(),
+        }
+    }
+
+    #[inline]
+    unsafe fn timed_out(&self) -> bool {
+        match self.backend {
+            None => self.parker.timed_out(),
+            Some(ref parker) => parker.timed_out(),
+        }
+    }
+
+    #[inline]
+    unsafe fn park(&self) {
+        match self.backend {
+            None => {
+                self.parker.park();
+                // call deadlock detection on_unpark hook
+                deadlock::on_unpark(self);
+            }
+            Some(ref parker) => {
+                parker.park();
+                deadlock::on_unpark(self);
+            }
+        }
+    }
+
+    #[inline]
+    unsafe fn park_until(&self, timeout: Instant) -> bool {
+        match self.backend {
+            None => self.parker.park_until(timeout),
+            Some(ref parker) => parker.park_until(timeout),
+        }
+    }
+
+    // Prepares the thread or task for being woken up. This must be called
+    // while holding the bucket lock, after the ThreadData has been removed
+    // from the queue. The returned handle should be used to perform the
+    // actual wake up once the bucket lock has been released.
+    #[inline]
+    unsafe fn unpark_lock(&self) -> WakeHandle {
+        if let Some(ref parker) = self.backend {
+            return WakeHandle::Task(parker.unpark_lock());
+        }
+        match self.task {
+            None => WakeHandle::Thread(self.parker.unpark_lock()),
+            Some(ref task) => {
+                task.queued.set(false);
+                WakeHandle::Task(task.waker.take())
+            }
+        }
+    }
+}
+
+// Handle for waking up a thread or task which has been removed from a queue.
+enum WakeHandle {
+    Thread(<ThreadParker as ThreadParkerT>::UnparkHandle),
+    Task(Option<Waker>),
+}
+
+impl WakeHandle {
+    #[inline]
+    unsafe fn unpark(self) {
+        match self {
+            WakeHandle::Thread(handle) => handle.unpark(),
+            WakeHandle::Task(waker) => {
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+    }
 }
 
-// Invokes the given closure with a reference to the current thread `ThreadData`.
+// Invokes the given closure with a reference to the current thread `ThreadData`,
+// or to the `ThreadData` of the current execution context if it is managed by a
+// custom backend.
 #[inline(always)]
 fn with_thread_data<T>(f: impl FnOnce(&ThreadData) -> T) -> T {
+    if let Some(context) = current_context() {
+        return f(&context.thread_data);
+    }
+
     // Unlike word_lock::ThreadData, parking_lot::ThreadData is always expensive
     // to construct. Try to use a thread-local version if possible. Otherwise just
     // create a ThreadData on the stack
@@ -185,39 +537,101 @@ fn with_thread_data<T>(f: impl FnOnce(&ThreadData) -> T) -> T {
 
 impl Drop for ThreadData {
     fn drop(&mut self) {
-        NUM_THREADS.fetch_sub(1, Ordering::Relaxed);
+        if self.task.is_none() && self.backend.is_none() {
+            // Shrink the hash table if many threads have exited
+            NUM_THREADS.fetch_sub(1, Ordering::Relaxed);
+            resize_hashtable();
+        }
+    }
+}
+
+/// The parking lot state of an execution context managed by a `ParkBackend`.
+///
+/// A backend creates one `ParkContext` for each of its execution contexts, and
+/// returns it from `ParkBackend::current` whenever that context is running.
+/// It links the context into a queue while it is parked, keeps track of the
+/// locks it holds for deadlock detection, and provides the identifier
+/// reported in `ParkedThread::thread_id`.
+pub struct ParkContext {
+    thread_data: ThreadData,
+}
+
+// SAFETY: The ThreadData is only used by the execution context it belongs to,
+// which only runs on one thread at a time, and by threads which unpark it while
+// holding the bucket lock, just like the ThreadData of a thread.
+unsafe impl Send for ParkContext {}
+unsafe impl Sync for ParkContext {}
+
+impl ParkContext {
+    /// Creates the state of a new execution context, which is resumed by
+    /// waking the given waker when it is unparked.
+    #[inline]
+    pub fn new(waker: Waker) -> ParkContext {
+        ParkContext {
+            thread_data: ThreadData::new_backend(BackendParker::new(waker)),
+        }
+    }
+}
+
+/// Marks the start of an access to the hash table. This must be called before loading
+/// `HASHTABLE`, and `leave_hashtable` must be called once the thread no longer uses any reference
+/// into the table, including after unlocking any buckets. Calls may be nested.
+#[inline]
+fn enter_hashtable() {
+    if READER.try_with(|reader| reader.0.enter()).is_err() {
+        UNREGISTERED_READERS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks the end of an access to the hash table started by `enter_hashtable`.
+#[inline]
+fn leave_hashtable() {
+    match READER.try_with(|reader| reader.0.leave()) {
+        Ok(false) => return,
+        Ok(true) => {}
+        Err(_) => {
+            UNREGISTERED_READERS.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    // Once a thread is done with the hash table, it frees any tables which
+    // can no longer be accessed.
+    if !RETIRED.load(Ordering::Relaxed).is_null() {
+        free_retired_hashtables();
     }
 }
 
 /// Returns a reference to the latest hash table, creating one if it doesn't exist yet.
-/// The reference is valid forever. However, the `HashTable` it references might become stale
-/// at any point. Meaning it still exists, but it is not the instance in active use.
+/// The reference is only valid until `leave_hashtable` is called. The `HashTable` it references
+/// might become stale at any point. Meaning it still exists, but it is not the instance in active
+/// use.
+///
+/// This must only be called between `enter_hashtable` and `leave_hashtable`.
 #[inline]
 fn get_hashtable() -> &'static HashTable {
-    let table = HASHTABLE.load(Ordering::Acquire);
+    let table = HASHTABLE.load(Ordering::SeqCst);
 
     // If there is no table, create one
     if table.is_null() {
         create_hashtable()
     } else {
-        // SAFETY: when not null, `HASHTABLE` always points to a `HashTable` that is never freed.
+        // SAFETY: when not null, `HASHTABLE` always points to a `HashTable` that isn't freed
+        // while we are between `enter_hashtable` and `leave_hashtable`.
         unsafe { &*table }
     }
 }
 
-/// Returns a reference to the latest hash table, creating one if it doesn't exist yet.
-/// The reference is valid forever. However, the `HashTable` it references might become stale
-/// at any point. Meaning it still exists, but it is not the instance in active use.
+/// Creates the initial hash table if it doesn't exist yet, and returns the latest hash table.
 #[cold]
 fn create_hashtable() -> &'static HashTable {
-    let new_table = Box::into_raw(HashTable::new(LOAD_FACTOR, ptr::null()));
+    let new_table = Box::into_raw(HashTable::new(LOAD_FACTOR));
 
     // If this fails then it means some other thread created the hash table first.
     let table = match HASHTABLE.compare_exchange(
         ptr::null_mut(),
         new_table,
-        Ordering::AcqRel,
-        Ordering::Acquire,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
     ) {
         Ok(_) => new_table,
         Err(old_table) => {
@@ -229,21 +643,26 @@ fn create_hashtable() -> &'static HashTable {
             old_table
         }
     };
-    // SAFETY: The `HashTable` behind `table` is never freed. It is either the table pointer we
-    // created here, or it is one loaded from `HASHTABLE`.
+    // SAFETY: The `HashTable` behind `table` is either the table pointer we created here, or it
+    // is one loaded from `HASHTABLE`. Either way it isn't freed before `leave_hashtable`.
     unsafe { &*table }
 }
 
-// Grow the hash table so that it is big enough for the given number of threads.
-// This isn't performance-critical since it is only done when a ThreadData is
-// created, which only happens once per thread.
-fn grow_hashtable(num_threads: usize) {
+// Resize the hash table if it is too small or much too big for the current
+// number of threads. This isn't performance-critical since it is only done
+// when a ThreadData is created or destroyed, which only happens once per
+// thread.
+fn resize_hashtable() {
+    enter_hashtable();
+
     // Lock all buckets in the existing table and get a reference to it
     let old_table = loop {
         let table = get_hashtable();
 
         // Check if we need to resize the existing table
-        if table.entries.len() >= LOAD_FACTOR * num_threads {
+        let num_threads = NUM_THREADS.load(Ordering::Relaxed);
+        if !table.needs_resize(num_threads) {
+            leave_hashtable();
             return;
         }
 
@@ -253,7 +672,7 @@ fn grow_hashtable(num_threads: usize) {
         }
 
         // Now check if our table is still the latest one. Another thread could
-        // have grown the hash table between us reading HASHTABLE and locking
+        // have resized the hash table between us reading HASHTABLE and locking
         // the buckets.
         if HASHTABLE.load(Ordering::Relaxed) == table as *const _ as *mut _ {
             break table;
@@ -266,8 +685,9 @@ fn grow_hashtable(num_threads: usize) {
         }
     };
 
-    // Create the new table
-    let mut new_table = HashTable::new(num_threads, old_table);
+    // Create the new table. The number of threads may have changed while we
+    // were locking the buckets, so check it again.
+    let mut new_table = HashTable::new(NUM_THREADS.load(Ordering::Relaxed));
 
     // Move the entries from the old table to the new one
     for bucket in &old_table.entries[..] {
@@ -278,15 +698,109 @@ fn grow_hashtable(num_threads: usize) {
     }
 
     // Publish the new table. No races are possible at this point because
-    // any other thread trying to grow the hash table is blocked on the bucket
-    // locks in the old table.
-    HASHTABLE.store(Box::into_raw(new_table), Ordering::Release);
+    // any other thread trying to resize the hash table is blocked on the
+    // bucket locks in the old table.
+    HASHTABLE.store(Box::into_raw(new_table), Ordering::SeqCst);
 
     // Unlock all buckets in the old table
     for bucket in &old_table.entries[..] {
         // SAFETY: We hold the lock here, as required
         unsafe { bucket.mutex.unlock() };
     }
+    leave_hashtable();
+
+    // Other threads may still be looking at the old table, so it can't be
+    // freed right away.
+    let old_table = old_table as *const HashTable as *mut HashTable;
+    // SAFETY: The old table is no longer reachable from HASHTABLE, so it is
+    // only pushed onto the RETIRED list once.
+    unsafe {
+        (*old_table).retired_epoch = EPOCH.load(Ordering::SeqCst);
+        retire_hashtables(old_table, old_table);
+    }
+    free_retired_hashtables();
+}
+
+/// Pushes a list of replaced tables, linked through `next_retired`, onto the `RETIRED` list.
+///
+/// # Safety
+///
+/// The tables must have been replaced in `HASHTABLE` and must not already be in the list.
+unsafe fn retire_hashtables(first: *mut HashTable, last: *mut HashTable) {
+    let mut head = RETIRED.load(Ordering::Relaxed);
+    loop {
+        (*last).next_retired = head;
+        match RETIRED.compare_exchange_weak(head, first, Ordering::SeqCst, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(x) => head = x,
+        }
+    }
+}
+
+/// Advances `EPOCH` if every thread accessing a hash table has seen its current value, and
+/// returns the resulting epoch.
+///
+/// This must only be called by the thread which set `RECLAIMING`.
+fn try_advance_epoch() -> usize {
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    if UNREGISTERED_READERS.load(Ordering::SeqCst) != 0 {
+        return epoch;
+    }
+
+    READERS_LOCK.lock();
+    // SAFETY: We hold READERS_LOCK, and readers are only freed after being
+    // removed from the list.
+    let readers = unsafe { &*READERS.0.get() };
+    let up_to_date = readers.iter().flatten().all(|&reader| {
+        let state = unsafe { (*reader).state.load(Ordering::SeqCst) };
+        state & 1 == 0 || state >> 1 == epoch
+    });
+    // SAFETY: We hold the lock here, as required
+    unsafe { READERS_LOCK.unlock() };
+
+    if !up_to_date {
+        return epoch;
+    }
+    EPOCH.store(epoch.wrapping_add(1), Ordering::SeqCst);
+    epoch.wrapping_add(1)
+}
+
+/// Frees the tables in the `RETIRED` list which no thread can still be accessing.
+#[cold]
+fn free_retired_hashtables() {
+    // Only one thread at a time tries to free tables, the others move on.
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    let epoch = try_advance_epoch();
+    let mut current = RETIRED.swap(ptr::null_mut(), Ordering::SeqCst);
+    let mut kept = ptr::null_mut::<HashTable>();
+    let mut kept_last = ptr::null_mut::<HashTable>();
+    while !current.is_null() {
+        // SAFETY: We own the list until the kept tables are put back.
+        unsafe {
+            let next = (*current).next_retired;
+            if epoch.wrapping_sub((*current).retired_epoch) >= 2 {
+                // SAFETY: The table was created with `Box::into_raw` and no
+                // other thread can access it any more.
+                drop(Box::from_raw(current));
+            } else {
+                (*current).next_retired = kept;
+                if kept.is_null() {
+                    kept_last = current;
+                }
+                kept = current;
+            }
+            current = next;
+        }
+    }
+    if !kept.is_null() {
+        // SAFETY: The tables were taken from the list above.
+        unsafe { retire_hashtables(kept, kept_last) };
+    }
+
+    RECLAIMING.store(false, Ordering::Release);
 }
 
 /// Iterate through all `ThreadData` objects in the bucket and insert them into the given table
@@ -332,6 +846,7 @@ fn hash(key: usize, bits: u32) -> usize {
 /// The returned bucket must be unlocked again in order to not cause deadlocks.
 #[inline]
 fn lock_bucket(key: usize) -> &'static Bucket {
+    enter_hashtable();
     loop {
         let hashtable = get_hashtable();
 
@@ -359,8 +874,9 @@ fn lock_bucket(key: usize) -> &'static Bucket {
 #[inline]
 fn lock_bucket_checked(key: &AtomicUsize) -> (usize, &'static Bucket) {
     loop {
-        let hashtable = get_hashtable();
         let current_key = key.load(Ordering::Relaxed);
+        enter_hashtable();
+        let hashtable = get_hashtable();
 
         let hash = hash(current_key, hashtable.hash_bits);
         let bucket = &hashtable.entries[hash];
@@ -379,7 +895,7 @@ fn lock_bucket_checked(key: &AtomicUsize) -> (usize, &'static Bucket) {
 
         // Unlock the bucket and try again
         // SAFETY: We hold the lock here, as required
-        unsafe { bucket.mutex.unlock() };
+        unsafe { unlock_bucket(bucket) };
     }
 }
 
@@ -390,6 +906,7 @@ fn lock_bucket_checked(key: &AtomicUsize) -> (usize, &'static Bucket) {
 /// careful to only unlock it once in this case, always use `unlock_bucket_pair`.
 #[inline]
 fn lock_bucket_pair(key1: usize, key2: usize) -> (&'static Bucket, &'static Bucket) {
+    enter_hashtable();
     loop {
         let hashtable = get_hashtable();
 
@@ -429,7 +946,18 @@ fn lock_bucket_pair(key1: usize, key2: usize) -> (&'static Bucket, &'static Buck
     }
 }
 
-/// Unlock a pair of buckets
+/// Unlock a bucket locked by `lock_bucket` or `lock_bucket_checked`.
+///
+/// # Safety
+///
+/// The bucket must be locked
+#[inline]
+unsafe fn unlock_bucket(bucket: &Bucket) {
+    bucket.mutex.unlock();
+    leave_hashtable();
+}
+
+/// Unlock a pair of buckets locked by `lock_bucket_pair`.
 ///
 /// # Safety
 ///
@@ -440,6 +968,54 @@ unsafe fn unlock_bucket_pair(bucket1: &Bucket, bucket2: &Bucket) {
     if !ptr::eq(bucket1, bucket2) {
         bucket2.mutex.unlock();
     }
+    leave_hashtable();
+}
+
+/// Removes the given `ThreadData` from the queue of a bucket and returns
+/// whether it was the last thread in the queue with the given key.
+///
+/// # Safety
+///
+/// The bucket must be locked and `thread_data` must currently be in its queue.
+unsafe fn remove_from_queue(bucket: &Bucket, key: usize, thread_data: *const ThreadData) -> bool {
+    let mut link = &bucket.queue_head;
+    let mut current = bucket.queue_head.get();
+    let mut previous = ptr::null();
+    let mut was_last_thread = true;
+    while !current.is_null() {
+        if current == thread_data {
+            let next = (*current).next_in_queue.get();
+            link.set(next);
+            if bucket.queue_tail.get() == current {
+                bucket.queue_tail.set(previous);
+            } else {
+                // Scan the rest of the queue to see if there are any other
+                // entries with the given key.
+                let mut scan = next;
+                while !scan.is_null() {
+                    if (*scan).key.load(Ordering::Relaxed) == key {
+                        was_last_thread = false;
+                        break;
+                    }
+                    scan = (*scan).next_in_queue.get();
+                }
+            }
+            break;
+        } else {
+            if (*current).key.load(Ordering::Relaxed) == key {
+                was_last_thread = false;
+            }
+            link = &(*current).next_in_queue;
+            previous = current;
+            current = link.get();
+        }
+    }
+
+    // There should be no way for the thread to have been removed from the
+    // queue by someone else.
+    debug_assert!(!current.is_null());
+
+    was_last_thread
 }
 
 /// Result of a park operation.
@@ -535,6 +1111,45 @@ pub const DEFAULT_UNPARK_TOKEN: UnparkToken = UnparkToken(0);
 /// A default park token to use.
 pub const DEFAULT_PARK_TOKEN: ParkToken = ParkToken(0);
 
+/// A snapshot of a thread parked in a queue, as returned by `parked_threads`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[allow(clippy::manual_non_exhaustive)]
+pub struct ParkedThread {
+    /// The key that the thread is parked on.
+    pub key: usize,
+
+    /// The `ParkToken` that the thread parked with.
+    pub park_token: ParkToken,
+
+    /// Whether the thread parked with a timeout.
+    pub parked_with_timeout: bool,
+
+    /// An identifier for the parked thread which is unique for the lifetime of
+    /// the process. It stays the same for every park operation performed by an
+    /// OS thread or by a context managed by a `ParkBackend`.
+    ///
+    /// The parking lot has no way of telling which asynchronous task is
+    /// parking, so the identifier of a task parked with `park_async` is not
+    /// stable: each `ParkFuture` gets a new one, even within the same task.
+    pub thread_id: usize,
+
+    /// Private field so new fields can be added without breakage.
+    _sealed: (),
+}
+
+impl ParkedThread {
+    #[inline]
+    unsafe fn new(thread_data: *const ThreadData) -> ParkedThread {
+        ParkedThread {
+            key: (*thread_data).key.load(Ordering::Relaxed),
+            park_token: (*thread_data).park_token.get(),
+            parked_with_timeout: (*thread_data).parked_with_timeout.get(),
+            thread_id: (*thread_data).id,
+            _sealed: (),
+        }
+    }
+}
+
 /// Parks the current thread in the queue associated with the given key.
 ///
 /// The `validate` function is called while the queue is locked and can abort
@@ -572,113 +1187,372 @@ pub unsafe fn park(
     park_token: ParkToken,
     timeout: Option<Instant>,
 ) -> ParkResult {
-    // Grab our thread data, this also ensures that the hash table exists
-    with_thread_data(|thread_data| {
-        // Lock the bucket for the given key
-        let bucket = lock_bucket(key);
+    let span = trace::ParkSpan::enter(key, park_token, timeout);
 
-        // If the validation function fails, just return
-        if !validate() {
-            // SAFETY: We hold the lock here, as required
-            bucket.mutex.unlock();
-            return ParkResult::Invalid;
-        }
-
-        // Append our thread data to the queue and unlock the bucket
-        thread_data.parked_with_timeout.set(timeout.is_some());
-        thread_data.next_in_queue.set(ptr::null());
-        thread_data.key.store(key, Ordering::Relaxed);
-        thread_data.park_token.set(park_token);
-        thread_data.parker.prepare_park();
-        if !bucket.queue_head.get().is_null() {
-            (*bucket.queue_tail.get()).next_in_queue.set(thread_data);
-        } else {
-            bucket.queue_head.set(thread_data);
+    // Grab our thread data, this also ensures that the hash table exists
+    let result = with_thread_data(|thread_data| {
+        if let Some(ref parker) = thread_data.backend {
+            parker.prepare_waker();
         }
-        bucket.queue_tail.set(thread_data);
-        // SAFETY: We hold the lock here, as required
-        bucket.mutex.unlock();
+        park_internal(
+            thread_data,
+            key,
+            validate,
+            before_sleep,
+            timed_out,
+            park_token,
+            timeout,
+        )
+    });
+    span.finish(result)
+}
 
-        // Invoke the pre-sleep callback
-        before_sleep();
+#[inline]
+unsafe fn park_internal(
+    thread_data: &ThreadData,
+    key: usize,
+    validate: impl FnOnce() -> bool,
+    before_sleep: impl FnOnce(),
+    timed_out: impl FnOnce(usize, bool),
+    park_token: ParkToken,
+    timeout: Option<Instant>,
+) -> ParkResult {
+    // Ask the watchdog how long we may sleep before being reported, while we
+    // are not in any queue yet.
+    #[cfg(feature = "watchdog")]
+    let park_threshold = watchdog::park_threshold();
 
-        // Park our thread and determine whether we were woken up by an unpark
-        // or by our timeout. Note that this isn't precise: we can still be
-        // unparked since we are still in the queue.
-        let unparked = match timeout {
-            Some(timeout) => thread_data.parker.park_until(timeout),
-            None => {
-                thread_data.parker.park();
-                // call deadlock detection on_unpark hook
-                deadlock::on_unpark(thread_data);
-                true
-            }
-        };
+    // Lock the bucket for the given key
+    let bucket = lock_bucket(key);
 
-        // If we were unparked, return now
-        if unparked {
-            return ParkResult::Unparked(thread_data.unpark_token.get());
-        }
+    // If the validation function fails, just return
+    if !validate() {
+        // SAFETY: We hold the lock here, as required
+        unlock_bucket(bucket);
+        return ParkResult::Invalid;
+    }
 
-        // Lock our bucket again. Note that the hashtable may have been rehashed in
-        // the meantime. Our key may also have changed if we were requeued.
-        let (key, bucket) = lock_bucket_checked(&thread_data.key);
+    // Append our thread data to the queue and unlock the bucket
+    thread_data.parked_with_timeout.set(timeout.is_some());
+    thread_data.next_in_queue.set(ptr::null());
+    thread_data.key.store(key, Ordering::Relaxed);
+    thread_data.park_token.set(park_token);
+    thread_data.prepare_park();
+    if !bucket.queue_head.get().is_null() {
+        (*bucket.queue_tail.get()).next_in_queue.set(thread_data);
+    } else {
+        bucket.queue_head.set(thread_data);
+    }
+    bucket.queue_tail.set(thread_data);
+    // SAFETY: We hold the lock here, as required
+    unlock_bucket(bucket);
 
-        // Now we need to check again if we were unparked or timed out. Unlike the
-        // last check this is precise because we hold the bucket lock.
-        if !thread_data.parker.timed_out() {
-            // SAFETY: We hold the lock here, as required
-            bucket.mutex.unlock();
+    // Invoke the pre-sleep callback
+    before_sleep();
+
+    // If a watchdog wants to hear about long waits then first sleep until its
+    // threshold, and report ourselves if we are still parked by then.
+    #[cfg(feature = "watchdog")]
+    {
+        if park_watched(thread_data, park_threshold, timeout) {
             return ParkResult::Unparked(thread_data.unpark_token.get());
         }
+    }
 
-        // We timed out, so we now need to remove our thread from the queue
-        let mut link = &bucket.queue_head;
-        let mut current = bucket.queue_head.get();
-        let mut previous = ptr::null();
-        let mut was_last_thread = true;
-        while !current.is_null() {
-            if current == thread_data {
-                let next = (*current).next_in_queue.get();
-                link.set(next);
-                if bucket.queue_tail.get() == current {
-                    bucket.queue_tail.set(previous);
-                } else {
-                    // Scan the rest of the queue to see if there are any other
-                    // entries with the given key.
-                    let mut scan = next;
-                    while !scan.is_null() {
-                        if (*scan).key.load(Ordering::Relaxed) == key {
-                            was_last_thread = false;
-                            break;
-                        }
-                        scan = (*scan).next_in_queue.get();
-                    }
-                }
+    // Park our thread and determine whether we were woken up by an unpark
+    // or by our timeout. Note that this isn't precise: we can still be
+    // unparked since we are still in the queue.
+    let unparked = match timeout {
+        Some(timeout) => thread_data.park_until(timeout),
+        None => {
+            thread_data.park();
+            true
+        }
+    };
+
+    // If we were unparked, return now
+    if unparked {
+        return ParkResult::Unparked(thread_data.unpark_token.get());
+    }
+
+    // Lock our bucket again. Note that the hashtable may have been rehashed in
+    // the meantime. Our key may also have changed if we were requeued.
+    let (key, bucket) = lock_bucket_checked(&thread_data.key);
+
+    // Now we need to check again if we were unparked or timed out. Unlike the
+    // last check this is precise because we hold the bucket lock.
+    if !thread_data.timed_out() {
+        // SAFETY: We hold the lock here, as required
+        unlock_bucket(bucket);
+        return ParkResult::Unparked(thread_data.unpark_token.get());
+    }
+
+    // We timed out, so we now need to remove our thread from the queue.
+    // Then invoke the callback to indicate that we timed out, and whether
+    // we were the last thread on the queue.
+    let was_last_thread = remove_from_queue(bucket, key, thread_data);
+    timed_out(key, was_last_thread);
+
+    // Unlock the bucket, we are done
+    // SAFETY: We hold the lock here, as required
+    unlock_bucket(bucket);
+    ParkResult::TimedOut
+}
+
+// Parks the thread until the park threshold of the watchdog has passed, and
+// reports it to the watchdog if it is still parked by then. Returns whether
+// the thread was unparked. Otherwise the thread is ready to be parked again.
+#[cfg(feature = "watchdog")]
+#[inline]
+unsafe fn park_watched(
+    thread_data: &ThreadData,
+    threshold: Option<Duration>,
+    timeout: Option<Instant>,
+) -> bool {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => return false,
+    };
+    let start = Instant::now();
+    let deadline = match start.checked_add(threshold) {
+        Some(deadline) => deadline,
+        None => return false,
+    };
+    if timeout.map_or(false, |timeout| timeout <= deadline) {
+        return false;
+    }
+    if thread_data.park_until(deadline) {
+        return true;
+    }
+
+    // Check precisely whether we were unparked while holding the bucket lock.
+    // If not then nobody can unpark us until we release it, so we can safely
+    // prepare the parker for another sleep. Our key may have changed if we
+    // were requeued.
+    let (key, bucket) = lock_bucket_checked(&thread_data.key);
+    if !thread_data.timed_out() {
+        // SAFETY: We hold the lock here, as required
+        unlock_bucket(bucket);
+        return true;
+    }
+    thread_data.prepare_park();
+    // SAFETY: We hold the lock here, as required
+    unlock_bucket(bucket);
+
+    // We are still in the queue, so the report is made by another thread.
+    watchdog::report_parked(key, start.elapsed());
+    false
+}
+
+/// Parks the current asynchronous task in the queue associated with the given
+/// key.
+///
+/// This is the asynchronous counterpart of `park`: instead of blocking the
+/// current thread, it returns a `ParkFuture` which resolves once the task has
+/// been unparked. Tasks parked this way share the queues used by `park`, so
+/// `unpark_one`, `unpark_all`, `unpark_requeue` and `unpark_filter` will wake
+/// up both threads and tasks in queue order.
+///
+/// The `validate` function is called immediately while the queue is locked
+/// and can abort the operation by returning false, in which case the returned
+/// future resolves to `ParkResult::Invalid`. If `validate` returns true then
+/// the task is appended to the queue and the queue is unlocked, after which
+/// the `before_sleep` function is called. The task holds its place in the
+/// queue from this point on, even before the future is first polled.
+///
+/// The `timed_out` function is called while the queue is locked if the
+/// timeout is reached, with the same arguments as in `park`. Note that the
+/// timeout is only checked when the future is polled: it is the
+/// responsibility of the caller to arrange for the task to be polled again
+/// once the timeout has passed. The `timed_out` function is also called if the
+/// future is cancelled or dropped while it is still in the queue.
+///
+/// # Safety
+///
+/// You should only call this function with an address that you control, since
+/// you could otherwise interfere with the operation of other synchronization
+/// primitives.
+///
+/// The `validate` and `timed_out` functions are called while the queue is
+/// locked and must not panic or call into any function in `parking_lot`.
+///
+/// The `before_sleep` function is called outside the queue lock and is allowed
+/// to call `unpark_one`, `unpark_all`, `unpark_requeue` or `unpark_filter`, but
+/// it is not allowed to call `park` or panic.
+#[inline]
+pub unsafe fn park_async<T: FnOnce(usize, bool)>(
+    key: usize,
+    validate: impl FnOnce() -> bool,
+    before_sleep: impl FnOnce(),
+    timed_out: T,
+    park_token: ParkToken,
+    timeout: Option<Instant>,
+) -> ParkFuture<T> {
+    // Allocate the queue node for the task before locking the bucket
+    let node = Box::new(ThreadData::new_task());
 
-                // Callback to indicate that we timed out, and whether we were the
-                // last thread on the queue.
+    // Lock the bucket for the given key
+    let bucket = lock_bucket(key);
+
+    // If the validation function fails, just return
+    if !validate() {
+        // SAFETY: We hold the lock here, as required
+        unlock_bucket(bucket);
+        return ParkFuture {
+            node: None,
+            timed_out: None,
+            timeout,
+            result: Some(ParkResult::Invalid),
+        };
+    }
+
+    // Append the task to the queue and unlock the bucket
+    node.parked_with_timeout.set(timeout.is_some());
+    node.key.store(key, Ordering::Relaxed);
+    node.park_token.set(park_token);
+    node.task.as_ref().unchecked_unwrap().queued.set(true);
+    let node_ptr: *const ThreadData = &*node;
+    if !bucket.queue_head.get().is_null() {
+        (*bucket.queue_tail.get()).next_in_queue.set(node_ptr);
+    } else {
+        bucket.queue_head.set(node_ptr);
+    }
+    bucket.queue_tail.set(node_ptr);
+    // SAFETY: We hold the lock here, as required
+    unlock_bucket(bucket);
+
+    // Invoke the pre-sleep callback
+    before_sleep();
+
+    ParkFuture {
+        node: Some(node),
+        timed_out: Some(timed_out),
+        timeout,
+        result: None,
+    }
+}
+
+/// Future returned by `park_async`.
+///
+/// The future resolves to the result of the park operation once the task has
+/// been unparked or the timeout has expired. Dropping the future before it
+/// completes removes the task from the queue.
+#[must_use = "futures do nothing unless polled"]
+pub struct ParkFuture<T: FnOnce(usize, bool)> {
+    // Queue node for this task. This is None once the task has been removed
+    // from the queue and the result has been observed.
+    node: Option<Box<ThreadData>>,
+    timed_out: Option<T>,
+    timeout: Option<Instant>,
+    result: Option<ParkResult>,
+}
+
+// The queue node is only accessed by other threads while holding the bucket
+// lock, so the future can be freely moved between threads.
+unsafe impl<T: FnOnce(usize, bool) + Send> Send for ParkFuture<T> {}
+
+// The timed_out callback is never pinned.
+impl<T: FnOnce(usize, bool)> Unpin for ParkFuture<T> {}
+
+impl<T: FnOnce(usize, bool)> ParkFuture<T> {
+    /// Removes the task from the queue if it is still parked.
+    ///
+    /// If the task was still in the queue then the `timed_out` callback is
+    /// invoked and `None` is returned. If the task was already unparked but
+    /// the future hasn't observed it yet then the `UnparkToken` it was
+    /// unparked with is returned, which allows the caller to pass on anything
+    /// that was handed off to the task. In all cases the future will resolve
+    /// to `ParkResult::Invalid` if it is polled afterwards.
+    pub fn cancel(&mut self) -> Option<UnparkToken> {
+        let node = self.node.take()?;
+        self.result = Some(ParkResult::Invalid);
+        unsafe {
+            let (key, bucket) = lock_bucket_checked(&node.key);
+            let task = node.task.as_ref().unchecked_unwrap();
+            if !task.queued.get() {
+                // SAFETY: We hold the lock here, as required
+                unlock_bucket(bucket);
+                return Some(node.unpark_token.get());
+            }
+
+            let was_last_thread = remove_from_queue(bucket, key, &*node);
+            task.queued.set(false);
+            let waker = task.waker.take();
+            if let Some(timed_out) = self.timed_out.take() {
                 timed_out(key, was_last_thread);
-                break;
-            } else {
-                if (*current).key.load(Ordering::Relaxed) == key {
-                    was_last_thread = false;
-                }
-                link = &(*current).next_in_queue;
-                previous = current;
-                current = link.get();
             }
+            // SAFETY: We hold the lock here, as required
+            unlock_bucket(bucket);
+            drop(waker);
+            None
         }
+    }
+}
 
-        // There should be no way for our thread to have been removed from the queue
-        // if we timed out.
-        debug_assert!(!current.is_null());
+impl<T: FnOnce(usize, bool)> Future for ParkFuture<T> {
+    type Output = ParkResult;
 
-        // Unlock the bucket, we are done
-        // SAFETY: We hold the lock here, as required
-        bucket.mutex.unlock();
-        ParkResult::TimedOut
-    })
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<ParkResult> {
+        let this = self.get_mut();
+        let node = match this.node {
+            Some(ref node) => node,
+            None => {
+                return Poll::Ready(
+                    this.result
+                        .take()
+                        .expect("ParkFuture polled after completion"),
+                )
+            }
+        };
+
+        // Clone the waker before locking the bucket since this may call
+        // arbitrary code.
+        let new_waker = cx.waker().clone();
+        unsafe {
+            // Lock our bucket. Our key may have changed if we were requeued.
+            let (key, bucket) = lock_bucket_checked(&node.key);
+            let task = node.task.as_ref().unchecked_unwrap();
+
+            // If we are no longer in the queue then we were unparked
+            if !task.queued.get() {
+                // SAFETY: We hold the lock here, as required
+                unlock_bucket(bucket);
+                let token = node.unpark_token.get();
+                this.node = None;
+                return Poll::Ready(ParkResult::Unparked(token));
+            }
+
+            // Check whether the timeout has expired
+            if let Some(timeout) = this.timeout {
+                if Instant::now() >= timeout {
+                    let was_last_thread = remove_from_queue(bucket, key, &**node);
+                    task.queued.set(false);
+                    let old_waker = task.waker.take();
+                    this.timed_out.take().unchecked_unwrap()(key, was_last_thread);
+                    // SAFETY: We hold the lock here, as required
+                    unlock_bucket(bucket);
+                    drop(old_waker);
+                    this.node = None;
+                    return Poll::Ready(ParkResult::TimedOut);
+                }
+            }
+
+            // Register the waker of the current task, and drop the old one
+            // after releasing the lock.
+            let old_waker = task.waker.replace(Some(new_waker));
+            // SAFETY: We hold the lock here, as required
+            unlock_bucket(bucket);
+            drop(old_waker);
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: FnOnce(usize, bool)> Drop for ParkFuture<T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.cancel();
+    }
 }
 
 /// Unparks one thread from the queue associated with the given key.
@@ -746,11 +1620,12 @@ pub unsafe fn unpark_one(
             // times out. Then we unlock the queue since we don't want to keep
             // the queue locked while we perform a system call. Finally we wake
             // up the parked thread.
-            let handle = (*current).parker.unpark_lock();
+            let handle = (*current).unpark_lock();
             // SAFETY: We hold the lock here, as required
-            bucket.mutex.unlock();
+            unlock_bucket(bucket);
             handle.unpark();
 
+            trace::unpark("unpark_one", key, result);
             return result;
         } else {
             link = &(*current).next_in_queue;
@@ -762,7 +1637,8 @@ pub unsafe fn unpark_one(
     // No threads with a matching key were found in the bucket
     callback(result);
     // SAFETY: We hold the lock here, as required
-    bucket.mutex.unlock();
+    unlock_bucket(bucket);
+    trace::unpark("unpark_one", key, result);
     result
 }
 
@@ -802,7 +1678,7 @@ pub unsafe fn unpark_all(key: usize, unpark_token: UnparkToken) -> usize {
             // Don't wake up threads while holding the queue lock. See comment
             // in unpark_one. For now just record which threads we need to wake
             // up.
-            threads.push((*current).parker.unpark_lock());
+            threads.push((*current).unpark_lock());
             current = next;
         } else {
             link = &(*current).next_in_queue;
@@ -813,7 +1689,7 @@ pub unsafe fn unpark_all(key: usize, unpark_token: UnparkToken) -> usize {
 
     // Unlock the bucket
     // SAFETY: We hold the lock here, as required
-    bucket.mutex.unlock();
+    unlock_bucket(bucket);
 
     // Now that we are outside the lock, wake up all the threads that we removed
     // from the queue.
@@ -822,6 +1698,14 @@ pub unsafe fn unpark_all(key: usize, unpark_token: UnparkToken) -> usize {
         handle.unpark();
     }
 
+    trace::unpark(
+        "unpark_all",
+        key,
+        UnparkResult {
+            unparked_threads: num_threads,
+            ..UnparkResult::default()
+        },
+    );
     num_threads
 }
 
@@ -868,6 +1752,7 @@ pub unsafe fn unpark_requeue(
     if op == RequeueOp::Abort {
         // SAFETY: Both buckets are locked, as required.
         unlock_bucket_pair(bucket_from, bucket_to);
+        trace::requeue(key_from, key_to, op, result);
         return result;
     }
 
@@ -946,7 +1831,7 @@ pub unsafe fn unpark_requeue(
     // See comment in unpark_one for why we mess with the locking
     if let Some(wakeup_thread) = wakeup_thread {
         (*wakeup_thread).unpark_token.set(token);
-        let handle = (*wakeup_thread).parker.unpark_lock();
+        let handle = (*wakeup_thread).unpark_lock();
         // SAFETY: Both buckets are locked, as required.
         unlock_bucket_pair(bucket_from, bucket_to);
         handle.unpark();
@@ -955,6 +1840,7 @@ pub unsafe fn unpark_requeue(
         unlock_bucket_pair(bucket_from, bucket_to);
     }
 
+    trace::requeue(key_from, key_to, op, result);
     result
 }
 
@@ -1045,11 +1931,11 @@ pub unsafe fn unpark_filter(
     // them for unparking.
     for t in threads.iter_mut() {
         (*t.0).unpark_token.set(token);
-        t.1 = Some((*t.0).parker.unpark_lock());
+        t.1 = Some((*t.0).unpark_lock());
     }
 
     // SAFETY: We hold the lock here, as required
-    bucket.mutex.unlock();
+    unlock_bucket(bucket);
 
     // Now that we are outside the lock, wake up all the threads that we removed
     // from the queue.
@@ -1057,33 +1943,147 @@ pub unsafe fn unpark_filter(
         handle.unchecked_unwrap().unpark();
     }
 
+    trace::unpark("unpark_filter", key, result);
     result
 }
 
+/// Returns a snapshot of the threads parked in the queue associated with the
+/// given key.
+///
+/// The threads are listed in queue order, which is the order in which
+/// `unpark_one` would wake them up. Since threads may be parked and unparked
+/// concurrently, the result may already be out of date by the time this
+/// function returns, so it should only be used for diagnostics.
+pub fn parked_threads(key: usize) -> Vec<ParkedThread> {
+    let mut threads = Vec::new();
+    let bucket = lock_bucket(key);
+    unsafe {
+        let mut current = bucket.queue_head.get();
+        while !current.is_null() {
+            if (*current).key.load(Ordering::Relaxed) == key {
+                threads.push(ParkedThread::new(current));
+            }
+            current = (*current).next_in_queue.get();
+        }
+
+        // SAFETY: We hold the lock here, as required
+        unlock_bucket(bucket);
+    }
+    threads
+}
+
+/// Returns a snapshot of all threads parked in the parking lot, for any key.
+///
+/// The whole hash table is locked while the snapshot is taken, so the result
+/// is consistent across keys. Threads parked on the same key are listed in
+/// queue order, but no particular order is guaranteed between keys.
+///
+/// This is much more expensive than `parked_threads` and blocks all parking
+/// and unparking while it runs, so it should be used sparingly.
+pub fn all_parked_threads() -> Vec<ParkedThread> {
+    let mut threads = Vec::with_capacity(NUM_THREADS.load(Ordering::Relaxed));
+    enter_hashtable();
+    let mut table = get_hashtable();
+    unsafe {
+        loop {
+            // Lock all buckets in the table
+            for b in &table.entries[..] {
+                b.mutex.lock();
+            }
+
+            // Now check if our table is still the latest one. Another thread
+            // could have resized the hash table between us getting and locking
+            // the hash table.
+            let new_table = get_hashtable();
+            if ptr::eq(new_table, table) {
+                break;
+            }
+
+            // Unlock buckets and try again
+            for b in &table.entries[..] {
+                // SAFETY: We hold the lock here, as required
+                b.mutex.unlock();
+            }
+
+            table = new_table;
+        }
+
+        for b in &table.entries[..] {
+            let mut current = b.queue_head.get();
+            while !current.is_null() {
+                threads.push(ParkedThread::new(current));
+                current = (*current).next_in_queue.get();
+            }
+        }
+
+        for b in &table.entries[..] {
+            // SAFETY: We hold the lock here, as required
+            b.mutex.unlock();
+        }
+    }
+    leave_hashtable();
+    threads
+}
+
 /// \[Experimental\] Deadlock detection
 ///
-/// Enabled via the `deadlock_detection` feature flag.
+/// Enabled via the `deadlock_detection` feature flag. Lock order checking is
+/// enabled separately via the `lock_order_detection` feature flag.
 pub mod deadlock {
     #[cfg(feature = "deadlock_detection")]
     use super::deadlock_impl;
+    #[cfg(any(
+        feature = "deadlock_detection",
+        feature = "lock_order_detection",
+        feature = "lock_levels"
+    ))]
+    use super::{with_thread_data, HeldResource};
+
+    #[cfg(feature = "lock_levels")]
+    use crate::lock_level;
+    #[cfg(feature = "lock_order_detection")]
+    use crate::lock_order;
 
     #[cfg(feature = "deadlock_detection")]
     pub(super) use super::deadlock_impl::DeadlockData;
 
+    #[cfg(feature = "lock_levels")]
+    pub use crate::lock_level::LockLevelViolation;
+    #[cfg(feature = "lock_order_detection")]
+    pub use crate::lock_order::LockOrderViolation;
+
     /// Acquire a resource identified by key in the deadlock detector
-    /// Noop if deadlock_detection feature isn't enabled.
+    /// Noop if neither the deadlock_detection nor the lock_order_detection
+    /// feature is enabled.
     ///
     /// # Safety
     ///
     /// Call after the resource is acquired
     #[inline]
     pub unsafe fn acquire_resource(_key: usize) {
-        #[cfg(feature = "deadlock_detection")]
-        deadlock_impl::acquire_resource(_key);
+        #[cfg(any(feature = "deadlock_detection", feature = "lock_order_detection"))]
+        push_resource(_key, false);
+    }
+
+    /// Acquire a resource identified by key in the deadlock detector, which is
+    /// always acquired and released together with the resource acquired just
+    /// before it, such as the second resource registered by a reader-writer
+    /// lock. Lock order checking treats it as part of that resource.
+    /// Noop if neither the deadlock_detection nor the lock_order_detection
+    /// feature is enabled.
+    ///
+    /// # Safety
+    ///
+    /// Call after the resource is acquired
+    #[inline]
+    pub unsafe fn acquire_linked_resource(_key: usize) {
+        #[cfg(any(feature = "deadlock_detection", feature = "lock_order_detection"))]
+        push_resource(_key, true);
     }
 
     /// Release a resource identified by key in the deadlock detector.
-    /// Noop if deadlock_detection feature isn't enabled.
+    /// Noop if neither the deadlock_detection nor the lock_order_detection
+    /// feature is enabled.
     ///
     /// # Panics
     ///
@@ -1094,8 +2094,149 @@ pub mod deadlock {
     /// Call before the resource is released
     #[inline]
     pub unsafe fn release_resource(_key: usize) {
-        #[cfg(feature = "deadlock_detection")]
-        deadlock_impl::release_resource(_key);
+        #[cfg(any(
+            feature = "deadlock_detection",
+            feature = "lock_order_detection",
+            feature = "lock_levels"
+        ))]
+        remove_resource(_key);
+    }
+
+    /// Forget the lock order recorded for a resource identified by key, so
+    /// that its key can be reused by another resource.
+    /// Noop if the lock_order_detection feature isn't enabled.
+    ///
+    /// Call when the resource is destroyed.
+    #[inline]
+    pub fn forget_resource(_key: usize) {
+        #[cfg(feature = "lock_order_detection")]
+        {
+            // Ignore locks dropped by the lock order detector itself.
+            if !with_thread_data(|thread_data| thread_data.checking_order.get()) {
+                lock_order::forget_resource(_key);
+            }
+        }
+    }
+
+    /// Checks that the current thread may acquire a resource with the given
+    /// level, which must be strictly greater than the level of every leveled
+    /// resource it already holds.
+    /// Noop if the lock_levels feature isn't enabled.
+    ///
+    /// Only blocking acquisitions should be checked. A failed attempt to
+    /// acquire a resource without blocking can't deadlock, which allows
+    /// resources to be acquired out of order with back-off, as done by
+    /// `lock_api::lock_all`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the level is out of order, unless this was disabled with
+    /// `set_lock_level_panic`, in which case the violation is reported by
+    /// `check_lock_levels` instead.
+    ///
+    /// Call before attempting to acquire the resource, so that a panic
+    /// doesn't leave it locked.
+    #[inline]
+    pub fn check_resource_level(_key: usize, _level: u32) {
+        #[cfg(feature = "lock_levels")]
+        {
+            let highest = with_thread_data(|thread_data| {
+                // SAFETY: The resources are only accessed by their own thread.
+                let resources = unsafe { &*thread_data.resources.get() };
+                resources
+                    .iter()
+                    .filter(|resource| resource.key != _key)
+                    .filter_map(|resource| Some((resource.key, resource.level?)))
+                    .max_by_key(|&(_, level)| level)
+            });
+            if let Some((held_key, held_level)) = highest {
+                lock_level::check_resource_level(_key, _level, held_key, held_level);
+            }
+        }
+    }
+
+    /// Records the level of a resource identified by key which was acquired
+    /// by the current thread, whether or not it was checked with
+    /// `check_resource_level`. It is forgotten by `release_resource`.
+    /// Noop if the lock_levels feature isn't enabled.
+    ///
+    /// # Safety
+    ///
+    /// Call after the resource is acquired, and after `acquire_resource`
+    #[inline]
+    pub unsafe fn acquire_resource_level(_key: usize, _level: u32) {
+        #[cfg(feature = "lock_levels")]
+        with_thread_data(|thread_data| {
+            let resources = &mut *thread_data.resources.get();
+
+            // The resource was already recorded by `acquire_resource` if
+            // deadlock detection or lock order checking is enabled.
+            match resources
+                .iter()
+                .rposition(|resource| resource.key == _key && resource.level.is_none())
+            {
+                Some(p) => resources[p].level = Some(_level),
+                None => resources.push(HeldResource {
+                    key: _key,
+                    level: Some(_level),
+                    #[cfg(feature = "lock_order_detection")]
+                    linked: false,
+                }),
+            }
+        });
+    }
+
+    // Records a resource as held by the current thread, after checking its
+    // order relative to the resources which are already held.
+    #[cfg(any(feature = "deadlock_detection", feature = "lock_order_detection"))]
+    unsafe fn push_resource(key: usize, _linked: bool) {
+        with_thread_data(|thread_data| {
+            #[cfg(feature = "lock_order_detection")]
+            {
+                if thread_data.checking_order.get() {
+                    return;
+                }
+                if !_linked {
+                    thread_data.checking_order.set(true);
+                    lock_order::check_order(&*thread_data.resources.get(), key);
+                    thread_data.checking_order.set(false);
+                }
+            }
+            (*thread_data.resources.get()).push(HeldResource {
+                key,
+                #[cfg(feature = "lock_levels")]
+                level: None,
+                #[cfg(feature = "lock_order_detection")]
+                linked: _linked,
+            });
+        });
+    }
+
+    // Forgets a resource held by the current thread, along with its level.
+    #[cfg(any(
+        feature = "deadlock_detection",
+        feature = "lock_order_detection",
+        feature = "lock_levels"
+    ))]
+    unsafe fn remove_resource(key: usize) {
+        with_thread_data(|thread_data| {
+            #[cfg(feature = "lock_order_detection")]
+            {
+                if thread_data.checking_order.get() {
+                    return;
+                }
+            }
+            let resources = &mut (*thread_data.resources.get());
+
+            // There is only one situation where we can fail to find the
+            // resource, other than an unleveled resource without deadlock
+            // detection: we are currently running TLS destructors and our
+            // ThreadData has already been freed. There isn't much we can do
+            // about it at this point, so just ignore it.
+            if let Some(p) = resources.iter().rposition(|resource| resource.key == key) {
+                resources.swap_remove(p);
+            }
+        });
     }
 
     /// Returns all deadlocks detected *since* the last call.
@@ -1106,6 +2247,38 @@ pub mod deadlock {
         deadlock_impl::check_deadlock()
     }
 
+    /// Returns all lock order inversions detected *since* the last call.
+    ///
+    /// An inversion is reported the first time a thread acquires two
+    /// resources in the opposite order to the one previously observed,
+    /// whether or not this actually results in a deadlock. Resources are
+    /// identified by their key (usually the address of the lock), so locks
+    /// must call `forget_resource` when they are destroyed to avoid spurious
+    /// reports once their memory is reused by another lock.
+    #[cfg(feature = "lock_order_detection")]
+    #[inline]
+    pub fn check_lock_order() -> Vec<LockOrderViolation> {
+        lock_order::check_lock_order()
+    }
+
+    /// Returns all lock level violations detected *since* the last call.
+    ///
+    /// Violations are only recorded here after panicking has been disabled
+    /// with `set_lock_level_panic`.
+    #[cfg(feature = "lock_levels")]
+    #[inline]
+    pub fn check_lock_levels() -> Vec<LockLevelViolation> {
+        lock_level::check_lock_levels()
+    }
+
+    /// Sets whether acquiring a resource out of level order panics, which is
+    /// the default, or is only recorded for `check_lock_levels`.
+    #[cfg(feature = "lock_levels")]
+    #[inline]
+    pub fn set_lock_level_panic(panic: bool) {
+        lock_level::set_panic(panic)
+    }
+
     #[inline]
     pub(super) unsafe fn on_unpark(_td: &super::ThreadData) {
         #[cfg(feature = "deadlock_detection")]
@@ -1115,8 +2288,11 @@ pub mod deadlock {
 
 #[cfg(feature = "deadlock_detection")]
 mod deadlock_impl {
-    use super::{get_hashtable, lock_bucket, with_thread_data, ThreadData, NUM_THREADS};
-    use crate::thread_parker::{ThreadParkerT, UnparkHandleT};
+    use super::{
+        enter_hashtable, get_hashtable, leave_hashtable, lock_bucket, unlock_bucket, ThreadData,
+        NUM_THREADS,
+    };
+    use crate::thread_parker::ThreadParkerT;
     use crate::word_lock::WordLock;
     use backtrace::Backtrace;
     use petgraph;
@@ -1146,9 +2322,6 @@ mod deadlock_impl {
     }
 
     pub struct DeadlockData {
-        // Currently owned resources (keys)
-        resources: UnsafeCell<Vec<usize>>,
-
         // Set when there's a pending callstack request
         deadlocked: Cell<bool>,
 
@@ -1162,7 +2335,6 @@ mod deadlock_impl {
     impl DeadlockData {
         pub fn new() -> Self {
             DeadlockData {
-                resources: UnsafeCell::new(Vec::new()),
                 deadlocked: Cell::new(false),
                 backtrace_sender: UnsafeCell::new(None),
                 thread_id: thread_id::get(),
@@ -1183,32 +2355,20 @@ mod deadlock_impl {
             drop(sender);
 
             // park until the end of the time
-            td.parker.prepare_park();
-            td.parker.park();
+            match td.backend {
+                None => {
+                    td.parker.prepare_park();
+                    td.parker.park();
+                }
+                Some(ref parker) => {
+                    parker.prepare_park();
+                    parker.park();
+                }
+            }
             unreachable!("unparked deadlocked thread!");
         }
     }
 
-    pub unsafe fn acquire_resource(key: usize) {
-        with_thread_data(|thread_data| {
-            (*thread_data.deadlock_data.resources.get()).push(key);
-        });
-    }
-
-    pub unsafe fn release_resource(key: usize) {
-        with_thread_data(|thread_data| {
-            let resources = &mut (*thread_data.deadlock_data.resources.get());
-
-            // There is only one situation where we can fail to find the
-            // resource: we are currently running TLS destructors and our
-            // ThreadData has already been freed. There isn't much we can do
-            // about it at this point, so just ignore it.
-            if let Some(p) = resources.iter().rposition(|x| *x == key) {
-                resources.swap_remove(p);
-            }
-        });
-    }
-
     pub fn check_deadlock() -> Vec<Vec<DeadlockedThread>> {
         unsafe {
             // fast pass
@@ -1225,6 +2385,7 @@ mod deadlock_impl {
     // then checks for the presence of cycles (deadlocks).
     // This variant isn't precise as it doesn't lock the entire table before checking
     unsafe fn check_wait_graph_fast() -> bool {
+        enter_hashtable();
         let table = get_hashtable();
         let thread_count = NUM_THREADS.load(Ordering::Relaxed);
         let mut graph = DiGraphMap::<usize, ()>::with_capacity(thread_count * 2, thread_count * 2);
@@ -1234,11 +2395,12 @@ mod deadlock_impl {
             let mut current = b.queue_head.get();
             while !current.is_null() {
                 if !(*current).parked_with_timeout.get()
+                    && (*current).task.is_none()
                     && !(*current).deadlock_data.deadlocked.get()
                 {
                     // .resources are waiting for their owner
-                    for &resource in &(*(*current).deadlock_data.resources.get()) {
-                        graph.add_edge(resource, current as usize, ());
+                    for resource in &(*(*current).resources.get()) {
+                        graph.add_edge(resource.key, current as usize, ());
                     }
                     // owner waits for resource .key
                     graph.add_edge(current as usize, (*current).key.load(Ordering::Relaxed), ());
@@ -1248,6 +2410,7 @@ mod deadlock_impl {
             // SAFETY: We hold the lock here, as required
             b.mutex.unlock();
         }
+        leave_hashtable();
 
         petgraph::algo::is_cyclic_directed(&graph)
     }
@@ -1267,6 +2430,7 @@ mod deadlock_impl {
         static DEADLOCK_DETECTION_LOCK: WordLock = WordLock::new();
         DEADLOCK_DETECTION_LOCK.lock();
 
+        enter_hashtable();
         let mut table = get_hashtable();
         loop {
             // Lock all buckets in the old table
@@ -1275,7 +2439,7 @@ mod deadlock_impl {
             }
 
             // Now check if our table is still the latest one. Another thread could
-            // have grown the hash table between us getting and locking the hash table.
+            // have resized the hash table between us getting and locking the hash table.
             let new_table = get_hashtable();
             if new_table as *const _ == table as *const _ {
                 break;
@@ -1298,11 +2462,12 @@ mod deadlock_impl {
             let mut current = b.queue_head.get();
             while !current.is_null() {
                 if !(*current).parked_with_timeout.get()
+                    && (*current).task.is_none()
                     && !(*current).deadlock_data.deadlocked.get()
                 {
                     // .resources are waiting for their owner
-                    for &resource in &(*(*current).deadlock_data.resources.get()) {
-                        graph.add_edge(Resource(resource), Thread(current), ());
+                    for resource in &(*(*current).resources.get()) {
+                        graph.add_edge(Resource(resource.key), Thread(current), ());
                     }
                     // owner waits for resource .key
                     graph.add_edge(
@@ -1319,6 +2484,7 @@ mod deadlock_impl {
             // SAFETY: We hold the lock here, as required
             b.mutex.unlock();
         }
+        leave_hashtable();
 
         // find cycles
         let cycles = graph_cycles(&graph);
@@ -1328,12 +2494,13 @@ mod deadlock_impl {
         for cycle in cycles {
             let (sender, receiver) = mpsc::channel();
             for td in cycle {
-                let bucket = lock_bucket((*td).key.load(Ordering::Relaxed));
+                let key = (*td).key.load(Ordering::Relaxed);
+                let bucket = lock_bucket(key);
                 (*td).deadlock_data.deadlocked.set(true);
                 *(*td).deadlock_data.backtrace_sender.get() = Some(sender.clone());
-                let handle = (*td).parker.unpark_lock();
+                let handle = (*td).unpark_lock();
                 // SAFETY: We hold the lock here, as required
-                bucket.mutex.unlock();
+                unlock_bucket(bucket);
                 // unpark the deadlocked thread!
                 // on unpark it'll notice the deadlocked flag and report back
                 handle.unpark();
@@ -1396,15 +2563,23 @@ mod deadlock_impl {
 
 #[cfg(test)]
 mod tests {
-    use super::{ThreadData, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
+    use super::{
+        ParkContext, ParkResult, ParkToken, ThreadData, UnparkToken, DEFAULT_PARK_TOKEN,
+        DEFAULT_UNPARK_TOKEN,
+    };
+    use crate::ParkBackend;
     use std::{
+        future::Future,
+        mem,
+        pin::Pin,
         ptr,
         sync::{
-            atomic::{AtomicIsize, AtomicPtr, AtomicUsize, Ordering},
-            Arc,
+            atomic::{AtomicBool, AtomicIsize, AtomicPtr, AtomicUsize, Ordering},
+            Arc, Barrier,
         },
-        thread,
-        time::Duration,
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+        thread::{self, Thread},
+        time::{Duration, Instant},
     };
 
     /// Calls a closure for every `ThreadData` currently parked on a given key
@@ -1421,7 +2596,608 @@ mod tests {
         }
 
         // SAFETY: We hold the lock here, as required
-        unsafe { bucket.mutex.unlock() };
+        unsafe { super::unlock_bucket(bucket) };
+    }
+
+    /// Returns a `Waker` which counts the number of times it was woken up.
+    fn counting_waker(count: Arc<AtomicUsize>) -> Waker {
+        unsafe fn clone(data: *const ()) -> RawWaker {
+            let count = Arc::from_raw(data as *const AtomicUsize);
+            let cloned = count.clone();
+            mem::forget(count);
+            RawWaker::new(Arc::into_raw(cloned) as *const (), &VTABLE)
+        }
+        unsafe fn wake(data: *const ()) {
+            wake_by_ref(data);
+            drop_waker(data);
+        }
+        unsafe fn wake_by_ref(data: *const ()) {
+            (*(data as *const AtomicUsize)).fetch_add(1, Ordering::SeqCst);
+        }
+        unsafe fn drop_waker(data: *const ()) {
+            drop(Arc::from_raw(data as *const AtomicUsize));
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+        let raw = RawWaker::new(Arc::into_raw(count) as *const (), &VTABLE);
+        unsafe { Waker::from_raw(raw) }
+    }
+
+    fn count_parked(key: usize) -> usize {
+        let mut count = 0;
+        for_each(key, |_| count += 1);
+        count
+    }
+
+    /// Returns a `Waker` which unparks the given thread.
+    fn thread_waker(thread: Thread) -> Waker {
+        unsafe fn clone(data: *const ()) -> RawWaker {
+            let thread = Arc::from_raw(data as *const Thread);
+            let cloned = thread.clone();
+            mem::forget(thread);
+            RawWaker::new(Arc::into_raw(cloned) as *const (), &VTABLE)
+        }
+        unsafe fn wake(data: *const ()) {
+            wake_by_ref(data);
+            drop_waker(data);
+        }
+        unsafe fn wake_by_ref(data: *const ()) {
+            (*(data as *const Thread)).unpark();
+        }
+        unsafe fn drop_waker(data: *const ()) {
+            drop(Arc::from_raw(data as *const Thread));
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+        let raw = RawWaker::new(Arc::into_raw(Arc::new(thread)) as *const (), &VTABLE);
+        unsafe { Waker::from_raw(raw) }
+    }
+
+    /// Park backend which only manages threads named `park-backend`, each of
+    /// which is an execution context, and counts how many times they were
+    /// parked.
+    struct TestBackend {
+        parks: AtomicUsize,
+    }
+
+    unsafe impl ParkBackend for TestBackend {
+        fn current(&self) -> Option<Arc<ParkContext>> {
+            thread_local! {
+                static CONTEXT: Arc<ParkContext> =
+                    Arc::new(ParkContext::new(thread_waker(thread::current())));
+            }
+            if thread::current().name() == Some("park-backend") {
+                CONTEXT.try_with(|context| context.clone()).ok()
+            } else {
+                None
+            }
+        }
+
+        fn park(&self, timeout: Option<Instant>) {
+            self.parks.fetch_add(1, Ordering::SeqCst);
+            match timeout {
+                Some(timeout) => {
+                    let now = Instant::now();
+                    if timeout > now {
+                        thread::park_timeout(timeout - now);
+                    }
+                }
+                None => thread::park(),
+            }
+        }
+    }
+
+    static TEST_BACKEND: TestBackend = TestBackend {
+        parks: AtomicUsize::new(0),
+    };
+
+    fn spawn_with_test_backend<T: Send + 'static>(
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> thread::JoinHandle<T> {
+        if let Err(backend) = crate::set_park_backend(&TEST_BACKEND) {
+            assert!(ptr::eq(
+                backend as *const _ as *const u8,
+                &TEST_BACKEND as *const _ as *const u8
+            ));
+        }
+        thread::Builder::new()
+            .name("park-backend".to_string())
+            .spawn(f)
+            .unwrap()
+    }
+
+    #[test]
+    fn park_backend_unpark() {
+        static KEY: u8 = 0;
+        let key = &KEY as *const _ as usize;
+        let parks = TEST_BACKEND.parks.load(Ordering::SeqCst);
+
+        let t = spawn_with_test_backend(move || unsafe {
+            super::park(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None)
+        });
+        while count_parked(key) == 0 {
+            thread::yield_now();
+        }
+        let result = unsafe { super::unpark_one(key, |_| UnparkToken(7)) };
+        assert_eq!(result.unparked_threads, 1);
+        assert_eq!(t.join().unwrap(), ParkResult::Unparked(UnparkToken(7)));
+        assert!(TEST_BACKEND.parks.load(Ordering::SeqCst) > parks);
+    }
+
+    // Returns the number of buckets in the current hash table.
+    fn hashtable_len() -> usize {
+        super::enter_hashtable();
+        let len = super::get_hashtable().entries.len();
+        super::leave_hashtable();
+        len
+    }
+
+    #[test]
+    fn hashtable_shrink() {
+        const NUM_THREADS: usize = 300;
+
+        let barrier = Arc::new(Barrier::new(NUM_THREADS + 1));
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    super::with_thread_data(|_| ());
+                    barrier.wait();
+                    barrier.wait();
+                })
+            })
+            .collect();
+        barrier.wait();
+        assert!(hashtable_len() >= super::LOAD_FACTOR * NUM_THREADS);
+        barrier.wait();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Other tests may still be running threads, but far fewer than this.
+        assert!(hashtable_len() < super::LOAD_FACTOR * NUM_THREADS);
+
+        // Replaced tables are freed by later accesses to the hash table once
+        // no thread can still be accessing them, even if other threads keep
+        // accessing it all the time.
+        static KEY: u8 = 0;
+        let key = &KEY as *const _ as usize;
+        let done = Arc::new(AtomicBool::new(false));
+        let load: Vec<_> = (0..2)
+            .map(|_| {
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        unsafe { super::unpark_one(key, |_| DEFAULT_UNPARK_TOKEN) };
+                    }
+                })
+            })
+            .collect();
+        let start = Instant::now();
+        loop {
+            unsafe { super::unpark_one(key, |_| DEFAULT_UNPARK_TOKEN) };
+            if super::RETIRED.load(Ordering::SeqCst).is_null() {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::yield_now();
+        }
+        done.store(true, Ordering::Relaxed);
+        for t in load {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn park_backend_context() {
+        static KEY: u8 = 0;
+        let key = &KEY as *const _ as usize;
+
+        let t = spawn_with_test_backend(move || {
+            let context = TEST_BACKEND.current().unwrap();
+            let thread_data = super::with_thread_data(|thread_data| thread_data as *const _);
+            assert!(ptr::eq(thread_data, &context.thread_data));
+            for _ in 0..2 {
+                unsafe {
+                    super::park(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None);
+                }
+            }
+        });
+
+        // The context keeps its identifier across parks.
+        let mut ids = vec![];
+        for _ in 0..2 {
+            while count_parked(key) == 0 {
+                thread::yield_now();
+            }
+            ids.push(super::parked_threads(key)[0].thread_id);
+            unsafe { super::unpark_one(key, |_| DEFAULT_UNPARK_TOKEN) };
+        }
+        t.join().unwrap();
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn park_backend_timeout() {
+        static KEY: u8 = 0;
+        let key = &KEY as *const _ as usize;
+
+        let t = spawn_with_test_backend(move || {
+            let timeout = Instant::now() + Duration::from_millis(50);
+            let result = unsafe {
+                super::park(
+                    key,
+                    || true,
+                    || {},
+                    |_, _| {},
+                    DEFAULT_PARK_TOKEN,
+                    Some(timeout),
+                )
+            };
+            assert!(Instant::now() >= timeout);
+            result
+        });
+        assert_eq!(t.join().unwrap(), ParkResult::TimedOut);
+        assert_eq!(count_parked(key), 0);
+    }
+
+    #[test]
+    fn parked_threads_snapshot() {
+        static KEY: u8 = 0;
+        let key = &KEY as *const _ as usize;
+
+        let mut threads = vec![];
+        for i in 0..3 {
+            threads.push(thread::spawn(move || {
+                let timeout = if i == 2 {
+                    Some(Instant::now() + Duration::from_secs(60))
+                } else {
+                    None
+                };
+                unsafe { super::park(key, || true, || {}, |_, _| {}, ParkToken(i), timeout) }
+            }));
+            while count_parked(key) != i + 1 {
+                thread::yield_now();
+            }
+        }
+
+        let parked = super::parked_threads(key);
+        assert!(parked.iter().all(|t| t.key == key));
+        assert_eq!(
+            parked.iter().map(|t| t.park_token).collect::<Vec<_>>(),
+            [ParkToken(0), ParkToken(1), ParkToken(2)]
+        );
+        assert_eq!(
+            parked
+                .iter()
+                .map(|t| t.parked_with_timeout)
+                .collect::<Vec<_>>(),
+            [false, false, true]
+        );
+        assert_ne!(parked[0].thread_id, parked[1].thread_id);
+        assert_ne!(parked[1].thread_id, parked[2].thread_id);
+
+        let all = super::all_parked_threads();
+        assert!(parked.iter().all(|t| all.contains(t)));
+
+        unsafe { super::unpark_all(key, DEFAULT_UNPARK_TOKEN) };
+        for t in threads {
+            assert_eq!(
+                t.join().unwrap(),
+                ParkResult::Unparked(DEFAULT_UNPARK_TOKEN)
+            );
+        }
+        assert!(super::parked_threads(key).is_empty());
+    }
+
+    #[test]
+    fn park_async_unpark() {
+        let key = &park_async_unpark as *const _ as usize;
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let waker = counting_waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut future =
+            unsafe { super::park_async(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None) };
+        assert_eq!(count_parked(key), 1);
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        assert_eq!(wakes.load(Ordering::SeqCst), 0);
+
+        let result = unsafe { super::unpark_one(key, |_| UnparkToken(42)) };
+        assert_eq!(result.unparked_threads, 1);
+        assert_eq!(wakes.load(Ordering::SeqCst), 1);
+        assert_eq!(count_parked(key), 0);
+        assert_eq!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(ParkResult::Unparked(UnparkToken(42)))
+        );
+    }
+
+    #[test]
+    fn park_async_invalid() {
+        let key = &park_async_invalid as *const _ as usize;
+        let waker = counting_waker(Arc::new(AtomicUsize::new(0)));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut future =
+            unsafe { super::park_async(key, || false, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None) };
+        assert_eq!(count_parked(key), 0);
+        assert_eq!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(ParkResult::Invalid)
+        );
+    }
+
+    #[test]
+    fn park_async_timeout() {
+        let key = &park_async_timeout as *const _ as usize;
+        let waker = counting_waker(Arc::new(AtomicUsize::new(0)));
+        let mut cx = Context::from_waker(&waker);
+        let timed_out = Arc::new(AtomicUsize::new(0));
+
+        let timed_out2 = timed_out.clone();
+        let mut future = unsafe {
+            super::park_async(
+                key,
+                || true,
+                || {},
+                move |k, was_last_thread| {
+                    assert_eq!(k, key);
+                    assert!(was_last_thread);
+                    timed_out2.fetch_add(1, Ordering::SeqCst);
+                },
+                DEFAULT_PARK_TOKEN,
+                Some(Instant::now() + Duration::from_millis(10)),
+            )
+        };
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(ParkResult::TimedOut)
+        );
+        assert_eq!(timed_out.load(Ordering::SeqCst), 1);
+        assert_eq!(count_parked(key), 0);
+    }
+
+    #[test]
+    fn park_async_cancel() {
+        let key = &park_async_cancel as *const _ as usize;
+        let timed_out = Arc::new(AtomicUsize::new(0));
+
+        // Dropping a parked future removes it from the queue
+        let timed_out2 = timed_out.clone();
+        let future = unsafe {
+            super::park_async(
+                key,
+                || true,
+                || {},
+                move |_, _| {
+                    timed_out2.fetch_add(1, Ordering::SeqCst);
+                },
+                DEFAULT_PARK_TOKEN,
+                None,
+            )
+        };
+        assert_eq!(count_parked(key), 1);
+        drop(future);
+        assert_eq!(count_parked(key), 0);
+        assert_eq!(timed_out.load(Ordering::SeqCst), 1);
+
+        // Cancelling an unparked future returns the token it was unparked with
+        let mut future =
+            unsafe { super::park_async(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None) };
+        unsafe { super::unpark_all(key, UnparkToken(7)) };
+        assert_eq!(future.cancel(), Some(UnparkToken(7)));
+        assert_eq!(future.cancel(), None);
+    }
+
+    #[test]
+    fn park_async_from_other_thread() {
+        static KEY: AtomicUsize = AtomicUsize::new(0);
+        let key = &KEY as *const _ as usize;
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let waker = counting_waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut future =
+            unsafe { super::park_async(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None) };
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+
+        // Threads and tasks share the same queue, in FIFO order
+        let thread = thread::spawn(move || unsafe {
+            super::park(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None)
+        });
+        while count_parked(key) != 2 {
+            thread::yield_now();
+        }
+        unsafe { super::unpark_one(key, |_| DEFAULT_UNPARK_TOKEN) };
+        while wakes.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        assert!(Pin::new(&mut future).poll(&mut cx).is_ready());
+        unsafe { super::unpark_one(key, |_| DEFAULT_UNPARK_TOKEN) };
+        assert!(thread.join().unwrap().is_unparked());
+    }
+
+    #[cfg(feature = "watchdog")]
+    #[test]
+    fn watchdog_reports_long_park() {
+        use crate::{LockKind, Watchdog};
+        use std::sync::Mutex;
+
+        // Minimal lock built on the parking lot.
+        struct TestLock(AtomicBool);
+        impl TestLock {
+            fn lock(&self) {
+                let key = self as *const _ as usize;
+                while self.0.swap(true, Ordering::Acquire) {
+                    let validate = || self.0.load(Ordering::Relaxed);
+                    unsafe {
+                        super::park(key, validate, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None);
+                    }
+                }
+            }
+            fn unlock(&self) {
+                self.0.store(false, Ordering::Release);
+                unsafe { super::unpark_one(self as *const _ as usize, |_| DEFAULT_UNPARK_TOKEN) };
+            }
+        }
+        static LOCK: TestLock = TestLock(AtomicBool::new(false));
+        static LOCKING_KEY: u8 = 0;
+
+        struct TestWatchdog(Mutex<Vec<(usize, LockKind, Duration)>>);
+        impl Watchdog for TestWatchdog {
+            fn park_threshold(&self) -> Option<Duration> {
+                Some(Duration::from_millis(20))
+            }
+            fn report(&self, key: usize, kind: LockKind, elapsed: Duration) {
+                let locking = key == &LOCKING_KEY as *const _ as usize;
+                if locking {
+                    LOCK.lock();
+                }
+                self.0.lock().unwrap().push((key, kind, elapsed));
+                if locking {
+                    LOCK.unlock();
+                }
+            }
+        }
+        static WATCHDOG: TestWatchdog = TestWatchdog(Mutex::new(Vec::new()));
+        assert!(crate::set_watchdog(&WATCHDOG).is_ok());
+        assert!(crate::set_watchdog(&WATCHDOG).is_err());
+
+        // Reports of parked threads are delivered by another thread, so wait
+        // for the expected number of them.
+        let reports = |key, count| {
+            let start = Instant::now();
+            loop {
+                let reports = WATCHDOG
+                    .0
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|&&(k, _, _)| k == key)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if reports.len() >= count || start.elapsed() > Duration::from_secs(10) {
+                    return reports;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        // A thread which is unparked after the threshold is reported once
+        static KEY: AtomicUsize = AtomicUsize::new(0);
+        let key = &KEY as *const _ as usize;
+        let thread = thread::spawn(move || unsafe {
+            super::park(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None)
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(count_parked(key), 1);
+        unsafe { super::unpark_one(key, |_| UnparkToken(3)) };
+        assert_eq!(thread.join().unwrap(), ParkResult::Unparked(UnparkToken(3)));
+        let parks = reports(key, 1);
+        assert_eq!(parks.len(), 1);
+        assert_eq!(parks[0].1, LockKind::Parked);
+        assert!(parks[0].2 >= Duration::from_millis(20));
+
+        // A thread still times out after being reported
+        let key = &watchdog_reports_long_park as *const _ as usize;
+        let timeout = Instant::now() + Duration::from_millis(60);
+        let result = unsafe {
+            super::park(
+                key,
+                || true,
+                || {},
+                |_, _| {},
+                DEFAULT_PARK_TOKEN,
+                Some(timeout),
+            )
+        };
+        assert_eq!(result, ParkResult::TimedOut);
+        assert_eq!(count_parked(key), 0);
+        assert_eq!(reports(key, 1).len(), 1);
+
+        // The watchdog may lock a contended lock while reporting a parked
+        // thread, without disturbing the queue that thread is in.
+        let key = &LOCKING_KEY as *const _ as usize;
+        LOCK.lock();
+        let thread = thread::spawn(move || unsafe {
+            super::park(key, || true, || {}, |_, _| {}, DEFAULT_PARK_TOKEN, None)
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(reports(key, 0).is_empty());
+        LOCK.unlock();
+        assert_eq!(reports(key, 1).len(), 1);
+        assert_eq!(count_parked(key), 1);
+        unsafe { super::unpark_one(key, |_| UnparkToken(4)) };
+        assert_eq!(thread.join().unwrap(), ParkResult::Unparked(UnparkToken(4)));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_park_and_unpark() {
+        use std::fmt::{self, Write};
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        struct Fields(String);
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+
+        // Subscriber which records every span, recorded value and event.
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+        impl Recorder {
+            fn push(&self, name: &str, record: impl FnOnce(&mut Fields)) {
+                let mut fields = Fields(name.to_string());
+                record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+            }
+        }
+        impl Subscriber for Recorder {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                self.push(span.metadata().name(), |f| span.record(f));
+                Id::from_u64(1)
+            }
+            fn record(&self, _span: &Id, values: &Record<'_>) {
+                self.push("record", |f| values.record(f));
+            }
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                self.push("event", |f| event.record(f));
+            }
+            fn enter(&self, _span: &Id) {}
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let recorder = Recorder::default();
+        let key = &tracing_park_and_unpark as *const _ as usize;
+        tracing::subscriber::with_default(recorder.clone(), || unsafe {
+            let timeout = Instant::now() + Duration::from_millis(10);
+            super::park(key, || true, || {}, |_, _| {}, ParkToken(5), Some(timeout));
+            super::unpark_one(key, |_| DEFAULT_UNPARK_TOKEN);
+        });
+
+        let lines = recorder.0.lock().unwrap();
+        assert_eq!(
+            *lines,
+            [
+                format!("park key={} park_token=5 timed=true", key),
+                "record result=TimedOut".to_string(),
+                format!(
+                    "event message=unpark op=\"unpark_one\" key={} unparked_threads=0 \
+                     requeued_threads=0 have_more_threads=false be_fair=false",
+                    key
+                ),
+            ]
+        );
     }
 
     macro_rules! test {
@@ -0,0 +1,231 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::parking_lot::ParkContext;
+use core::{
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    task::Waker,
+};
+use std::{sync::Arc, time::Instant};
+
+/// A custom mechanism for suspending and resuming execution contexts.
+///
+/// By default the parking lot suspends OS threads. A runtime which multiplexes
+/// its own execution contexts (green threads, fibers, coroutines or simulated
+/// threads in a test harness) over OS threads can install a backend with
+/// `set_park_backend` so that a context which parks only suspends itself,
+/// rather than the OS thread it is running on. All synchronization primitives
+/// built on top of the parking lot then work unchanged within the runtime.
+///
+/// Each execution context has its own `ParkContext`, which is returned by
+/// `current` while the context is running. Wake-ups are delivered through the
+/// `Waker` the `ParkContext` was created with. The semantics are the same as
+/// `std::thread::park`: if the waker is woken while the context is running
+/// then the next call to `park` must return immediately, and `park` is allowed
+/// to return spuriously.
+///
+/// The resources held for deadlock detection, lock order checking and lock
+/// levels are tracked per `ParkContext`, so these work for contexts managed by
+/// a backend just like they do for threads.
+///
+/// # Safety
+///
+/// `current` must always return the same `ParkContext` for a given execution
+/// context, and must never return a `ParkContext` which belongs to a different
+/// context. The parking lot links the `ParkContext` into its queues while the
+/// context is parked, so sharing one between contexts which run concurrently
+/// corrupts these queues.
+pub unsafe trait ParkBackend: Sync {
+    /// Returns the `ParkContext` of the current execution context, or `None`
+    /// if the caller is not running inside a context managed by this backend,
+    /// in which case the OS thread is used instead.
+    ///
+    /// This is called every time the current context is about to park, before
+    /// any queue is locked, as well as whenever a lock is acquired or released
+    /// with deadlock detection enabled. Any locking done by this function
+    /// itself is attributed to the OS thread.
+    fn current(&self) -> Option<Arc<ParkContext>>;
+
+    /// Suspends the current execution context until the waker of its
+    /// `ParkContext` is woken, or until the timeout is reached.
+    fn park(&self, timeout: Option<Instant>);
+
+    /// Yields the current execution context, which is used by `SpinWait` once
+    /// it stops spinning on the CPU.
+    ///
+    /// The default implementation yields the OS thread.
+    fn yield_now(&self) {
+        super::imp::thread_yield();
+    }
+}
+
+// The installed backend. This points to a leaked box since trait object
+// references are too large to be stored in an atomic.
+static BACKEND: AtomicPtr<&'static dyn ParkBackend> = AtomicPtr::new(ptr::null_mut());
+
+/// Installs a custom backend which is used to suspend and resume execution
+/// contexts when they are parked.
+///
+/// Only one backend can be installed for the lifetime of the process. If a
+/// backend was already installed then the given one is returned as an error.
+///
+/// A context which is parked keeps using the mechanism it was parked with
+/// until it is woken up, so it is safe to install a backend while other
+/// threads are parked. However the backend should normally be installed
+/// before the runtime starts running any contexts.
+pub fn set_park_backend(backend: &'static dyn ParkBackend) -> Result<(), &'static dyn ParkBackend> {
+    let new = Box::into_raw(Box::new(backend));
+    match BACKEND.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            // SAFETY: The box was never published.
+            drop(unsafe { Box::from_raw(new) });
+            Err(backend)
+        }
+    }
+}
+
+// Returns the installed backend, if any.
+#[inline]
+fn backend() -> Option<&'static dyn ParkBackend> {
+    let backend = BACKEND.load(Ordering::Acquire);
+    if backend.is_null() {
+        None
+    } else {
+        // SAFETY: The box is leaked once it is published.
+        Some(unsafe { *backend })
+    }
+}
+
+// Yields the current execution context, using the backend if there is one.
+#[inline]
+pub fn thread_yield() {
+    match backend() {
+        Some(backend) => backend.yield_now(),
+        None => super::imp::thread_yield(),
+    }
+}
+
+// Returns the `ParkContext` of the current execution context if it is managed
+// by the installed backend.
+#[inline]
+pub fn current_context() -> Option<Arc<ParkContext>> {
+    thread_local! {
+        // Whether this thread is currently calling `ParkBackend::current`.
+        // `const` initializers need Rust 1.59, which is newer than our MSRV.
+        #[allow(clippy::missing_const_for_thread_local)]
+        static IN_CURRENT: Cell<bool> = Cell::new(false);
+    }
+
+    // Clears the flag even if the backend panics.
+    struct Leave<'a>(&'a Cell<bool>);
+    impl Drop for Leave<'_> {
+        fn drop(&mut self) {
+            self.0.set(false);
+        }
+    }
+
+    let backend = backend()?;
+    IN_CURRENT
+        .try_with(|in_current| {
+            // Locks taken by the backend while looking up the context belong
+            // to the OS thread, rather than recursing forever.
+            if in_current.replace(true) {
+                return None;
+            }
+            let _leave = Leave(in_current);
+            backend.current()
+        })
+        .ok()
+        .and_then(|context| context)
+}
+
+// Parker for an execution context managed by a `ParkBackend`. This provides
+// the same operations as `ThreadParkerT`.
+pub struct BackendParker {
+    parked: AtomicBool,
+
+    // Waker which the context was created with.
+    waker: Waker,
+
+    // Clone of `waker` for the current park. This is taken by the thread which
+    // unparks the context while holding the bucket lock, so that it remains
+    // valid even if the context wakes up spuriously and exits in the meantime.
+    pending: Cell<Option<Waker>>,
+}
+
+impl BackendParker {
+    #[inline]
+    pub fn new(waker: Waker) -> BackendParker {
+        BackendParker {
+            parked: AtomicBool::new(false),
+            waker,
+            pending: Cell::new(None),
+        }
+    }
+
+    // Makes sure that there is a waker for the next park. This must be called
+    // before any queue is locked, since cloning the waker runs code from the
+    // backend.
+    #[inline]
+    pub fn prepare_waker(&self) {
+        let waker = self.pending.take();
+        self.pending
+            .set(Some(waker.unwrap_or_else(|| self.waker.clone())));
+    }
+
+    #[inline]
+    pub unsafe fn prepare_park(&self) {
+        self.parked.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub unsafe fn timed_out(&self) -> bool {
+        self.parked.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub unsafe fn park(&self) {
+        let backend = installed_backend();
+        while self.parked.load(Ordering::Acquire) {
+            backend.park(None);
+        }
+    }
+
+    #[inline]
+    pub unsafe fn park_until(&self, timeout: Instant) -> bool {
+        let backend = installed_backend();
+        while self.parked.load(Ordering::Acquire) {
+            if Instant::now() >= timeout {
+                return false;
+            }
+            backend.park(Some(timeout));
+        }
+        true
+    }
+
+    // Marks the context as unparked and returns the waker which must be used
+    // to resume it once the bucket lock has been released.
+    #[inline]
+    pub unsafe fn unpark_lock(&self) -> Option<Waker> {
+        let waker = self.pending.take();
+        self.parked.store(false, Ordering::Release);
+        waker
+    }
+}
+
+// Returns the backend of a context which is being parked. It must be
+// installed since the context was returned by it.
+#[inline]
+fn installed_backend() -> &'static dyn ParkBackend {
+    match backend() {
+        Some(backend) => backend,
+        None => unreachable!("park backend isn't installed"),
+    }
+}
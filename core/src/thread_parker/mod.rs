@@ -85,4 +85,9 @@ cfg_if! {
     }
 }
 
-pub use self::imp::{thread_yield, ThreadParker, UnparkHandle};
+pub use self::imp::{ThreadParker, UnparkHandle};
+
+mod custom;
+pub use self::custom::{
+    current_context, set_park_backend, thread_yield, BackendParker, ParkBackend,
+};
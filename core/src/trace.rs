@@ -0,0 +1,148 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Integration with `tracing`.
+//
+// When the `tracing` feature is enabled, every call to `park` runs inside a
+// `park` span which records the result of the park once the thread resumes,
+// and every unpark operation emits an event with the details of its
+// `UnparkResult`. All spans and events use the `TRACE` level and the
+// `parking_lot_core` target.
+//
+// Subscribers may themselves use locks built on the parking lot. To avoid
+// endless recursion, nothing is emitted by a thread which parks or unparks
+// while it is already inside a call to the subscriber made from here, or
+// from `parking_lot` which shares the same guard.
+//
+// When the feature is disabled all of this compiles down to nothing.
+
+use crate::parking_lot::{ParkResult, ParkToken, RequeueOp, UnparkResult};
+#[cfg(feature = "tracing")]
+use std::cell::Cell;
+use std::time::Instant;
+
+#[cfg(feature = "tracing")]
+thread_local! {
+    // Whether this thread is currently calling into the subscriber. `const`
+    // initializers need Rust 1.59, which is newer than our MSRV.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static IN_SUBSCRIBER: Cell<bool> = Cell::new(false);
+}
+
+/// Calls into the subscriber through `f`, unless this thread is already doing
+/// so, in which case `None` is returned. This is exported for `parking_lot`.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[inline]
+pub fn guard<T>(f: impl FnOnce() -> T) -> Option<T> {
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            let _ = IN_SUBSCRIBER.try_with(|x| x.set(false));
+        }
+    }
+
+    if IN_SUBSCRIBER.try_with(|x| x.replace(true)).unwrap_or(true) {
+        return None;
+    }
+    let _reset = Reset;
+    Some(f())
+}
+
+#[cfg(feature = "tracing")]
+pub struct ParkSpan(Option<tracing::span::EnteredSpan>);
+
+#[cfg(not(feature = "tracing"))]
+pub struct ParkSpan;
+
+#[cfg(feature = "tracing")]
+impl ParkSpan {
+    #[inline]
+    pub fn enter(key: usize, park_token: ParkToken, timeout: Option<Instant>) -> ParkSpan {
+        ParkSpan(guard(|| {
+            tracing::trace_span!(
+                target: "parking_lot_core",
+                "park",
+                key,
+                park_token = park_token.0,
+                timed = timeout.is_some(),
+                result = tracing::field::Empty,
+            )
+            .entered()
+        }))
+    }
+
+    #[inline]
+    pub fn finish(self, result: ParkResult) -> ParkResult {
+        if let Some(span) = self.0 {
+            // The span is exited when it is dropped by the closure.
+            guard(move || {
+                span.record("result", tracing::field::debug(result));
+            });
+        }
+        result
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl ParkSpan {
+    #[inline(always)]
+    pub fn enter(_key: usize, _park_token: ParkToken, _timeout: Option<Instant>) -> ParkSpan {
+        ParkSpan
+    }
+
+    #[inline(always)]
+    pub fn finish(self, result: ParkResult) -> ParkResult {
+        result
+    }
+}
+
+// Emits an event for an unpark operation on `key`, named after the function
+// which performed it.
+#[cfg(feature = "tracing")]
+#[inline]
+pub fn unpark(op: &'static str, key: usize, result: UnparkResult) {
+    guard(|| {
+        tracing::trace!(
+            target: "parking_lot_core",
+            op,
+            key,
+            unparked_threads = result.unparked_threads,
+            requeued_threads = result.requeued_threads,
+            have_more_threads = result.have_more_threads,
+            be_fair = result.be_fair,
+            "unpark",
+        )
+    });
+}
+
+// Emits an event for a requeue operation from `key_from` to `key_to`.
+#[cfg(feature = "tracing")]
+#[inline]
+pub fn requeue(key_from: usize, key_to: usize, op: RequeueOp, result: UnparkResult) {
+    guard(|| {
+        tracing::trace!(
+            target: "parking_lot_core",
+            key_from,
+            key_to,
+            op = ?op,
+            unparked_threads = result.unparked_threads,
+            requeued_threads = result.requeued_threads,
+            have_more_threads = result.have_more_threads,
+            be_fair = result.be_fair,
+            "unpark_requeue",
+        )
+    });
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub fn unpark(_op: &'static str, _key: usize, _result: UnparkResult) {}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub fn requeue(_key_from: usize, _key_to: usize, _op: RequeueOp, _result: UnparkResult) {}
@@ -0,0 +1,189 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::word_lock::WordLock;
+use core::{
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use std::{sync::mpsc, thread, time::Duration};
+
+/// The kind of wait or lock reported to a `Watchdog`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum LockKind {
+    /// A thread has been parked on the key for longer than the park threshold.
+    ///
+    /// The parking lot doesn't know which kind of lock, if any, uses the key.
+    Parked,
+
+    /// A mutex has been held for longer than the hold threshold.
+    Mutex,
+
+    /// The write lock of a reader-writer lock has been held for longer than
+    /// the hold threshold.
+    RwLockWrite,
+
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A hook which is notified when threads wait on, or hold, a lock for too
+/// long.
+///
+/// Once installed with `set_watchdog`, the parking lot reports every thread
+/// which stays parked on a key for longer than `park_threshold`. Locks built
+/// on top of the parking lot can report locks held for longer than
+/// `hold_threshold` using `watchdog`. The thresholds are read every time a
+/// thread parks or acquires a lock, so they may be changed at run time.
+pub trait Watchdog: Sync {
+    /// How long a thread may stay parked before it is reported, or `None` to
+    /// not report parked threads.
+    ///
+    /// This is called by every thread which is about to park, before it is
+    /// added to the queue.
+    fn park_threshold(&self) -> Option<Duration>;
+
+    /// How long a lock may be held before it is reported, or `None` to not
+    /// report held locks.
+    ///
+    /// A lock which other threads are waiting for is reported by one of them
+    /// as soon as it has been held for longer than this, so that a lock which
+    /// is never released, for example because of a deadlock, is still
+    /// reported. Every long hold is also reported by its holder once the lock
+    /// is released.
+    ///
+    /// The default implementation returns `None`.
+    fn hold_threshold(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Reports that a thread has been parked on `key`, or held the lock at
+    /// address `key`, for `elapsed`.
+    ///
+    /// A parked thread is reported at most once per call to `park`, once the
+    /// threshold has passed. The parked thread is still in the queue at that
+    /// point, so it hands the report over to a dedicated reporter thread and
+    /// goes back to sleep. Reports of parked threads are therefore delivered
+    /// asynchronously, in the order in which they were made, and a slow report
+    /// delays the following ones.
+    ///
+    /// A held lock is reported when it is released, after it has been
+    /// unlocked. It may also be reported once while it is still held, by a
+    /// thread waiting for it, which is not in the queue at that point.
+    ///
+    /// Either way, this function may lock any lock, including the ones built
+    /// on the parking lot.
+    fn report(&self, key: usize, kind: LockKind, elapsed: Duration);
+}
+
+// The installed watchdog. This points to a leaked box since trait object
+// references are too large to be stored in an atomic.
+static WATCHDOG: AtomicPtr<&'static dyn Watchdog> = AtomicPtr::new(ptr::null_mut());
+
+/// Installs a watchdog which is notified of long waits and long-held locks.
+///
+/// Only one watchdog can be installed for the lifetime of the process. If a
+/// watchdog was already installed then the given one is returned as an error.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot_core::{LockKind, Watchdog};
+/// use std::time::Duration;
+///
+/// struct LogWatchdog;
+///
+/// impl Watchdog for LogWatchdog {
+///     fn park_threshold(&self) -> Option<Duration> {
+///         Some(Duration::from_secs(2))
+///     }
+///
+///     fn hold_threshold(&self) -> Option<Duration> {
+///         Some(Duration::from_secs(2))
+///     }
+///
+///     fn report(&self, key: usize, kind: LockKind, elapsed: Duration) {
+///         eprintln!("{:?} {:#x} for {:?}", kind, key, elapsed);
+///     }
+/// }
+///
+/// parking_lot_core::set_watchdog(&LogWatchdog).ok();
+/// ```
+pub fn set_watchdog(watchdog: &'static dyn Watchdog) -> Result<(), &'static dyn Watchdog> {
+    let new = Box::into_raw(Box::new(watchdog));
+    match WATCHDOG.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            // SAFETY: The box was never published.
+            drop(unsafe { Box::from_raw(new) });
+            Err(watchdog)
+        }
+    }
+}
+
+/// Returns the installed watchdog, if any.
+#[inline]
+pub fn watchdog() -> Option<&'static dyn Watchdog> {
+    let watchdog = WATCHDOG.load(Ordering::Acquire);
+    if watchdog.is_null() {
+        None
+    } else {
+        // SAFETY: The box is leaked once it is published.
+        Some(unsafe { *watchdog })
+    }
+}
+
+// Returns the park threshold of the installed watchdog, if parked threads
+// should be reported.
+#[inline]
+pub(crate) fn park_threshold() -> Option<Duration> {
+    watchdog()?.park_threshold()
+}
+
+struct ReporterCell(UnsafeCell<Option<mpsc::Sender<(usize, Duration)>>>);
+
+// The sender is only accessed while holding REPORTER_LOCK.
+unsafe impl Sync for ReporterCell {}
+
+static REPORTER_LOCK: WordLock = WordLock::new();
+static REPORTER: ReporterCell = ReporterCell(UnsafeCell::new(None));
+
+// Reports a thread which has been parked on `key` for `elapsed`, from the
+// reporter thread which is started by the first report.
+//
+// The parked thread is still in the queue when it calls this, so it must not
+// run any code from the watchdog, which could park it a second time. The
+// reporter thread is a regular thread, so the watchdog may do anything there.
+pub(crate) fn report_parked(key: usize, elapsed: Duration) {
+    REPORTER_LOCK.lock();
+    // SAFETY: We hold REPORTER_LOCK.
+    let reporter = unsafe { &mut *REPORTER.0.get() };
+    if reporter.is_none() {
+        let (sender, receiver) = mpsc::channel::<(usize, Duration)>();
+        let spawned = thread::Builder::new()
+            .name("parking_lot watchdog".to_string())
+            .spawn(move || {
+                for (key, elapsed) in receiver {
+                    if let Some(watchdog) = watchdog() {
+                        watchdog.report(key, LockKind::Parked, elapsed);
+                    }
+                }
+            });
+        // If the thread can't be spawned then the report is dropped, and
+        // spawning is attempted again by the next one.
+        if spawned.is_ok() {
+            *reporter = Some(sender);
+        }
+    }
+    if let Some(ref sender) = *reporter {
+        let _ = sender.send((key, elapsed));
+    }
+    // SAFETY: We hold the lock here, as required
+    unsafe { REPORTER_LOCK.unlock() };
+}
@@ -0,0 +1,117 @@
+// Copyright 2018 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::mutex::RawMutex;
+
+/// A lock guard which a condition variable can temporarily release while the
+/// current thread waits for a notification.
+///
+/// This trait is implemented for `MutexGuard`, `RwLockWriteGuard` and
+/// `ReentrantMutexGuard`, which means that a condition variable written
+/// against this trait works with any raw lock type. Mapped guards do not
+/// implement it: the data they point to may be invalidated by another thread
+/// while the lock is released.
+///
+/// # Safety
+///
+/// `unlock_for_wait` must fully release the lock held by the guard and
+/// `relock_after_wait` must re-acquire it, so that the guard is valid again
+/// once it returns.
+pub unsafe trait CondvarGuard {
+    /// Returns an address which identifies the lock held by this guard.
+    ///
+    /// Condition variables use this to detect waits on different locks at the
+    /// same time.
+    fn lock_addr(&self) -> usize;
+
+    /// Returns the raw mutex that threads waiting on a condition variable can
+    /// be requeued onto, if the lock supports it.
+    ///
+    /// See `RawMutexRequeue` for details.
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        None
+    }
+
+    /// Fully releases the lock held by this guard so that the current thread
+    /// can wait on a condition variable.
+    ///
+    /// Returns a value which must be passed back to `relock_after_wait`.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be re-acquired with `relock_after_wait` before the guard
+    /// is used or dropped.
+    unsafe fn unlock_for_wait(&self) -> usize;
+
+    /// Re-acquires the lock released by `unlock_for_wait`.
+    ///
+    /// `token` is the unpark token that the waiting thread was woken up with,
+    /// or `None` if the wait timed out. It is passed on to
+    /// `RawMutexRequeue::relock_after_wait` if this guard has a requeue
+    /// target.
+    ///
+    /// # Safety
+    ///
+    /// `state` must be the value returned by the matching call to
+    /// `unlock_for_wait`.
+    unsafe fn relock_after_wait(&self, state: usize, token: Option<usize>);
+}
+
+/// Additional methods for raw mutexes built on `parking_lot_core` which allow
+/// a condition variable to move its waiting threads directly onto the wait
+/// queue of the mutex instead of waking them up ("wait morphing").
+///
+/// This avoids a thundering herd when all waiters are notified at once: only
+/// one thread is woken up, and the rest are woken up one at a time as the
+/// mutex is unlocked.
+///
+/// # Safety
+///
+/// Threads requeued onto `requeue_key` must eventually be unparked by the
+/// mutex once `mark_parked` or `mark_parked_if_locked` has been called.
+pub unsafe trait RawMutexRequeue {
+    /// Returns the key of the `parking_lot_core` queue that threads waiting
+    /// for this mutex park on.
+    fn requeue_key(&self) -> usize;
+
+    /// Marks the mutex as having parked threads if it is currently locked.
+    /// Returns `false` if the mutex is unlocked, in which case threads should
+    /// be unparked instead of requeued.
+    ///
+    /// This is called while the queue for `requeue_key` is locked.
+    fn mark_parked_if_locked(&self) -> bool;
+
+    /// Marks the mutex as having parked threads.
+    ///
+    /// This is called while the queue for `requeue_key` is locked.
+    fn mark_parked(&self);
+
+    /// Re-acquires the mutex after the current thread waited on a condition
+    /// variable.
+    ///
+    /// `token` is the unpark token that the thread was woken up with, or
+    /// `None` if the wait timed out. Threads which were woken up directly by
+    /// the condition variable rather than requeued receive a token of 0, which
+    /// must not be used by the mutex to indicate a handoff.
+    ///
+    /// # Safety
+    ///
+    /// The current thread must not already hold the mutex, unless it was
+    /// handed off to it as indicated by `token`.
+    unsafe fn relock_after_wait(&self, token: Option<usize>);
+}
+
+// Re-acquires a raw mutex after waiting on a condition variable, going through
+// its requeue target if it has one.
+#[inline]
+pub(crate) unsafe fn relock_raw_mutex<R: RawMutex>(raw: &R, token: Option<usize>) {
+    match raw.requeue_target() {
+        Some(target) => target.relock_after_wait(token),
+        None => raw.lock(),
+    }
+}
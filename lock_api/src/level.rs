@@ -0,0 +1,21 @@
+// Copyright 2018 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/// Additional methods for raw locks which can be assigned a level in a lock
+/// hierarchy.
+///
+/// This can be implemented by both mutexes and reader-writer locks. The
+/// `Mutex` and `RwLock` wrappers expose this as a `with_level` constructor.
+///
+/// What is done with the level is up to the lock implementation. Typically a
+/// thread may only acquire a leveled lock if its level is strictly greater
+/// than that of every leveled lock the thread already holds, and this is only
+/// checked in debug configurations.
+pub trait RawLockLevel: Sized {
+    /// Creates a new raw lock in an unlocked state with the given level.
+    fn with_level(level: u32) -> Self;
+}
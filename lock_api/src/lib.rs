@@ -24,6 +24,16 @@
 //! exported instead of one. (Or 3 guards if your type supports upgradable read
 //! locks, see [extension traits](#extension-traits) below for details)
 //!
+//! Lock guards implement the `CondvarGuard` trait, which allows condition
+//! variables to be written generically over any kind of lock.
+//!
+//! Several locks can be acquired at once without deadlocking using `lock_all`
+//! and `lock_slice`, which work with any raw lock type.
+//!
+//! Counting semaphores work the same way: implement the `RawSemaphore` trait
+//! and export type aliases for `lock_api::Semaphore` and
+//! `lock_api::SemaphorePermit`.
+//!
 //! # Example
 //!
 //! ```
@@ -69,21 +79,29 @@
 //! additional traits for it. Examples of extension features include:
 //!
 //! - Fair unlocking (`RawMutexFair`, `RawRwLockFair`)
-//! - Lock timeouts (`RawMutexTimed`, `RawRwLockTimed`)
+//! - Lock timeouts (`RawMutexTimed`, `RawRwLockTimed`, `RawSemaphoreTimed`)
 //! - Downgradable write locks (`RawRwLockDowngradable`)
 //! - Recursive read locks (`RawRwLockRecursive`)
 //! - Upgradable read locks (`RawRwLockUpgrade`)
+//! - Requeuing threads from a condition variable (`RawMutexRequeue`)
+//! - Contention statistics (`RawLockStats`)
+//! - Detecting that the owner of a mutex died (`RawMutexRobust`)
 //!
 //! The `Mutex` and `RwLock` wrappers will automatically expose this additional
 //! functionality if the raw lock type implements these extension traits.
 //!
 //! # Cargo features
 //!
-//! This crate supports two cargo features:
+//! This crate supports four cargo features:
 //!
 //! - `owning_ref`: Allows your lock types to be used with the `owning_ref` crate.
+//! - `arc_lock`: Enables locking from an `Arc`. This enables types such as
+//!   `ArcMutexGuard`. Note that this requires the `alloc` crate to be present.
 //! - `nightly`: Enables nightly-only features. At the moment the only such
 //!   feature is `const fn` constructors for lock types.
+//! - `track_caller`: Marks the locking methods of the lock types with
+//!   `#[track_caller]`, so that raw locks can record where they were acquired.
+//!   This requires Rust 1.46.
 
 #![no_std]
 #![warn(missing_docs)]
@@ -93,17 +111,38 @@
 #[macro_use]
 extern crate scopeguard;
 
+#[cfg(feature = "arc_lock")]
+extern crate alloc;
+
 /// Marker type which indicates that the Guard type for a lock is `Send`.
 pub struct GuardSend(());
 
 /// Marker type which indicates that the Guard type for a lock is not `Send`.
 pub struct GuardNoSend(*mut ());
 
+mod condvar;
+pub use crate::condvar::*;
+
+mod level;
+pub use crate::level::*;
+
 mod mutex;
 pub use crate::mutex::*;
 
+mod owner;
+pub use crate::owner::*;
+
+mod multi_lock;
+pub use crate::multi_lock::*;
+
 mod remutex;
 pub use crate::remutex::*;
 
 mod rwlock;
 pub use crate::rwlock::*;
+
+mod semaphore;
+pub use crate::semaphore::*;
+
+mod stats;
+pub use crate::stats::*;
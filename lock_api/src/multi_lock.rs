@@ -0,0 +1,657 @@
+// Copyright 2018 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::mutex::{Mutex, MutexGuard, RawMutex, RawMutexTimed};
+use crate::rwlock::{RawRwLock, RawRwLockTimed, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Index, IndexMut};
+
+/// A lock which can be acquired together with other locks by `lock_all`
+/// without risking a deadlock.
+///
+/// This is implemented for `&Mutex`, and for `ReadLock` and `WriteLock` which
+/// select the mode in which a `RwLock` is acquired.
+///
+/// # Safety
+///
+/// `try_lock_raw` must only return `true` if the lock was acquired, and the
+/// guard returned by `make_guard` must release the lock in the same way as
+/// `unlock_raw`.
+pub unsafe trait Lockable {
+    /// RAII guard which releases the lock when dropped.
+    type Guard;
+
+    /// Returns an address which identifies the underlying lock.
+    ///
+    /// `lock_all` uses this to detect locks which were passed more than once.
+    fn lock_addr(&self) -> usize;
+
+    /// Acquires the lock, blocking the current thread until it is able to do
+    /// so.
+    fn lock_raw(&self);
+
+    /// Attempts to acquire the lock without blocking.
+    fn try_lock_raw(&self) -> bool;
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the lock was acquired through this
+    /// `Lockable`.
+    unsafe fn unlock_raw(&self);
+
+    /// Creates a guard which takes over the ownership of the lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the lock was acquired through this
+    /// `Lockable`.
+    unsafe fn make_guard(&self) -> Self::Guard;
+}
+
+/// Additional methods for locks which support acquiring them with a timeout.
+///
+/// # Safety
+///
+/// `try_lock_until_raw` must only return `true` if the lock was acquired.
+pub unsafe trait LockableTimed: Lockable {
+    /// Instant type used for `try_lock_until_raw`.
+    type Instant;
+
+    /// Attempts to acquire the lock until a timeout is reached.
+    fn try_lock_until_raw(&self, timeout: Self::Instant) -> bool;
+}
+
+unsafe impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> Lockable for &'a Mutex<R, T> {
+    type Guard = MutexGuard<'a, R, T>;
+
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        *self as *const Mutex<R, T> as *const () as usize
+    }
+
+    #[inline]
+    fn lock_raw(&self) {
+        unsafe { self.raw().lock() }
+    }
+
+    #[inline]
+    fn try_lock_raw(&self) -> bool {
+        unsafe { self.raw().try_lock() }
+    }
+
+    #[inline]
+    unsafe fn unlock_raw(&self) {
+        self.raw().unlock()
+    }
+
+    #[inline]
+    unsafe fn make_guard(&self) -> MutexGuard<'a, R, T> {
+        (*self).guard()
+    }
+}
+
+unsafe impl<'a, R: RawMutexTimed + 'a, T: ?Sized + 'a> LockableTimed for &'a Mutex<R, T> {
+    type Instant = R::Instant;
+
+    #[inline]
+    fn try_lock_until_raw(&self, timeout: R::Instant) -> bool {
+        unsafe { self.raw().try_lock_until(timeout) }
+    }
+}
+
+/// Selects shared read access to a `RwLock` when acquiring it with `lock_all`.
+pub struct ReadLock<'a, R: RawRwLock, T: ?Sized>(pub &'a RwLock<R, T>);
+
+/// Selects exclusive write access to a `RwLock` when acquiring it with
+/// `lock_all`.
+pub struct WriteLock<'a, R: RawRwLock, T: ?Sized>(pub &'a RwLock<R, T>);
+
+unsafe impl<'a, R: RawRwLock + 'a, T: ?Sized + 'a> Lockable for ReadLock<'a, R, T> {
+    type Guard = RwLockReadGuard<'a, R, T>;
+
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        self.0 as *const RwLock<R, T> as *const () as usize
+    }
+
+    #[inline]
+    fn lock_raw(&self) {
+        unsafe { self.0.raw().lock_shared() }
+    }
+
+    #[inline]
+    fn try_lock_raw(&self) -> bool {
+        unsafe { self.0.raw().try_lock_shared() }
+    }
+
+    #[inline]
+    unsafe fn unlock_raw(&self) {
+        self.0.raw().unlock_shared()
+    }
+
+    #[inline]
+    unsafe fn make_guard(&self) -> RwLockReadGuard<'a, R, T> {
+        self.0.read_guard()
+    }
+}
+
+unsafe impl<'a, R: RawRwLockTimed + 'a, T: ?Sized + 'a> LockableTimed for ReadLock<'a, R, T> {
+    type Instant = R::Instant;
+
+    #[inline]
+    fn try_lock_until_raw(&self, timeout: R::Instant) -> bool {
+        unsafe { self.0.raw().try_lock_shared_until(timeout) }
+    }
+}
+
+unsafe impl<'a, R: RawRwLock + 'a, T: ?Sized + 'a> Lockable for WriteLock<'a, R, T> {
+    type Guard = RwLockWriteGuard<'a, R, T>;
+
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        self.0 as *const RwLock<R, T> as *const () as usize
+    }
+
+    #[inline]
+    fn lock_raw(&self) {
+        unsafe { self.0.raw().lock_exclusive() }
+    }
+
+    #[inline]
+    fn try_lock_raw(&self) -> bool {
+        unsafe { self.0.raw().try_lock_exclusive() }
+    }
+
+    #[inline]
+    unsafe fn unlock_raw(&self) {
+        self.0.raw().unlock_exclusive()
+    }
+
+    #[inline]
+    unsafe fn make_guard(&self) -> RwLockWriteGuard<'a, R, T> {
+        self.0.write_guard()
+    }
+}
+
+unsafe impl<'a, R: RawRwLockTimed + 'a, T: ?Sized + 'a> LockableTimed for WriteLock<'a, R, T> {
+    type Instant = R::Instant;
+
+    #[inline]
+    fn try_lock_until_raw(&self, timeout: R::Instant) -> bool {
+        unsafe { self.0.raw().try_lock_exclusive_until(timeout) }
+    }
+}
+
+// Object-safe subset of `Lockable`, which allows locks of different types to
+// be handled by the same code.
+trait LockOps {
+    fn addr(&self) -> usize;
+    fn try_lock(&self) -> bool;
+    unsafe fn unlock(&self);
+}
+
+impl<L: Lockable> LockOps for L {
+    #[inline]
+    fn addr(&self) -> usize {
+        self.lock_addr()
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.try_lock_raw()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.unlock_raw()
+    }
+}
+
+impl LockOps for &dyn LockOps {
+    #[inline]
+    fn addr(&self) -> usize {
+        (**self).addr()
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        (**self).try_lock()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        (**self).unlock()
+    }
+}
+
+// Acquiring the same lock twice would deadlock, or in the case of a shared lock
+// could deadlock if a writer is waiting for it.
+fn check_distinct<L: LockOps>(locks: &[L]) {
+    for (i, lock) in locks.iter().enumerate() {
+        if locks[..i].iter().any(|other| other.addr() == lock.addr()) {
+            panic!("the same lock was passed to lock_all more than once");
+        }
+    }
+}
+
+// The locks in `locks` which are currently held: the one at index `first`, if
+// any, and all of the ones before index `end`. These are released when this is
+// dropped, which also happens if acquiring one of the other locks panics.
+struct Acquired<'a, L: LockOps> {
+    locks: &'a [L],
+    first: Option<usize>,
+    end: usize,
+}
+
+impl<'a, L: LockOps> Drop for Acquired<'a, L> {
+    fn drop(&mut self) {
+        for (i, lock) in self.locks.iter().enumerate() {
+            if i < self.end || Some(i) == self.first {
+                unsafe { lock.unlock() };
+            }
+        }
+    }
+}
+
+// Acquires all of `locks` without deadlocking, or none of them if
+// `lock_blocking` fails.
+//
+// Only one lock is waited for at a time, using `lock_blocking`, while the
+// others are acquired with `try_lock`. If one of them is contended, all the
+// locks are released again and the contended lock is the one which is waited
+// for next. This never holds a lock while blocking on another one, and the
+// thread only spins through this loop when the locks keep changing hands.
+fn lock_all_raw<L: LockOps>(locks: &[L], mut lock_blocking: impl FnMut(usize) -> bool) -> bool {
+    check_distinct(locks);
+    if locks.is_empty() {
+        return true;
+    }
+
+    let mut first = 0;
+    loop {
+        if !lock_blocking(first) {
+            return false;
+        }
+        let mut acquired = Acquired {
+            locks,
+            first: Some(first),
+            end: 0,
+        };
+        let contended = (0..locks.len()).find(|&i| {
+            if i != first && !locks[i].try_lock() {
+                return true;
+            }
+            acquired.end = i + 1;
+            false
+        });
+        match contended {
+            None => {
+                mem::forget(acquired);
+                return true;
+            }
+            Some(contended) => {
+                drop(acquired);
+                first = contended;
+            }
+        }
+    }
+}
+
+// Attempts to acquire all of `locks` without blocking, releasing the ones which
+// were acquired if any of them is contended.
+fn try_lock_all_raw<L: LockOps>(locks: &[L]) -> bool {
+    check_distinct(locks);
+    let mut acquired = Acquired {
+        locks,
+        first: None,
+        end: 0,
+    };
+    for lock in locks {
+        if !lock.try_lock() {
+            return false;
+        }
+        acquired.end += 1;
+    }
+    mem::forget(acquired);
+    true
+}
+
+/// A set of locks which can be acquired together by `lock_all`.
+///
+/// This is implemented for tuples of up to 8 `Lockable`s.
+pub trait LockSet {
+    /// The guards of the locks in the set.
+    type Guards;
+
+    /// Acquires all the locks in the set without deadlocking, blocking the
+    /// current thread until it is able to do so.
+    fn lock_all(self) -> Self::Guards;
+
+    /// Attempts to acquire all the locks in the set without blocking.
+    ///
+    /// If any of them is contended, none of the locks are held on return.
+    fn try_lock_all(self) -> Option<Self::Guards>;
+}
+
+/// A set of locks which can be acquired together with a timeout by
+/// `try_lock_all_until`.
+///
+/// This is implemented for tuples of up to 8 `LockableTimed`s which use the
+/// same `Instant` type.
+pub trait LockSetTimed: LockSet {
+    /// Instant type used for `try_lock_all_until`.
+    type Instant;
+
+    /// Attempts to acquire all the locks in the set until a timeout is
+    /// reached.
+    ///
+    /// If the timeout is reached, none of the locks are held on return.
+    fn try_lock_all_until(self, timeout: Self::Instant) -> Option<Self::Guards>;
+}
+
+macro_rules! lock_set_tuple {
+    ($first:ident $($name:ident)*; $($idx:tt)*) => {
+        impl<$first: Lockable, $($name: Lockable),*> LockSet for ($first, $($name,)*) {
+            type Guards = ($first::Guard, $($name::Guard,)*);
+
+            fn lock_all(self) -> Self::Guards {
+                let locks: &[&dyn LockOps] = &[$(&self.$idx),*];
+                lock_all_raw(locks, |i| {
+                    $(
+                        if i == $idx {
+                            self.$idx.lock_raw();
+                        }
+                    )*
+                    true
+                });
+                unsafe { ($(self.$idx.make_guard(),)*) }
+            }
+
+            fn try_lock_all(self) -> Option<Self::Guards> {
+                let locks: &[&dyn LockOps] = &[$(&self.$idx),*];
+                if try_lock_all_raw(locks) {
+                    Some(unsafe { ($(self.$idx.make_guard(),)*) })
+                } else {
+                    None
+                }
+            }
+        }
+
+        impl<$first: LockableTimed, $($name: LockableTimed<Instant = $first::Instant>),*>
+            LockSetTimed for ($first, $($name,)*)
+        where
+            $first::Instant: Clone,
+        {
+            type Instant = $first::Instant;
+
+            fn try_lock_all_until(self, timeout: Self::Instant) -> Option<Self::Guards> {
+                let locks: &[&dyn LockOps] = &[$(&self.$idx),*];
+                let acquired = lock_all_raw(locks, |i| {
+                    $(
+                        if i == $idx {
+                            return self.$idx.try_lock_until_raw(timeout.clone());
+                        }
+                    )*
+                    unreachable!()
+                });
+                if acquired {
+                    Some(unsafe { ($(self.$idx.make_guard(),)*) })
+                } else {
+                    None
+                }
+            }
+        }
+    };
+}
+
+lock_set_tuple!(A; 0);
+lock_set_tuple!(A B; 0 1);
+lock_set_tuple!(A B C; 0 1 2);
+lock_set_tuple!(A B C D; 0 1 2 3);
+lock_set_tuple!(A B C D E; 0 1 2 3 4);
+lock_set_tuple!(A B C D E F; 0 1 2 3 4 5);
+lock_set_tuple!(A B C D E F G; 0 1 2 3 4 5 6);
+lock_set_tuple!(A B C D E F G H; 0 1 2 3 4 5 6 7);
+
+/// Acquires several locks at once without deadlocking, like `std::lock` in
+/// C++.
+///
+/// `locks` is a tuple of `&Mutex`, `ReadLock` and `WriteLock` values, and a
+/// tuple of the corresponding guards is returned. Unlike acquiring the locks
+/// one after another, this can't deadlock with another thread which acquires
+/// the same locks in a different order: the current thread only ever blocks on
+/// one lock at a time, and acquires the others with `try_lock`. If one of them
+/// is contended, all the locks are released and the contended lock is the one
+/// which is waited for next.
+///
+/// # Panics
+///
+/// Panics if the same lock is passed more than once.
+///
+/// # Examples
+///
+/// ```
+/// use lock_api::{lock_all, ReadLock, WriteLock};
+/// # type Mutex<T> = lock_api::Mutex<RawSpinlock, T>;
+/// # type RwLock<T> = lock_api::RwLock<RawSpinRwLock, T>;
+/// # use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+/// # pub struct RawSpinlock(AtomicBool);
+/// # unsafe impl lock_api::RawMutex for RawSpinlock {
+/// #     const INIT: RawSpinlock = RawSpinlock(AtomicBool::new(false));
+/// #     type GuardMarker = lock_api::GuardSend;
+/// #     fn lock(&self) { while !self.try_lock() {} }
+/// #     fn try_lock(&self) -> bool { !self.0.swap(true, Ordering::Acquire) }
+/// #     fn unlock(&self) { self.0.store(false, Ordering::Release); }
+/// # }
+/// # pub struct RawSpinRwLock(AtomicUsize);
+/// # unsafe impl lock_api::RawRwLock for RawSpinRwLock {
+/// #     const INIT: RawSpinRwLock = RawSpinRwLock(AtomicUsize::new(0));
+/// #     type GuardMarker = lock_api::GuardSend;
+/// #     fn lock_shared(&self) { while !self.try_lock_shared() {} }
+/// #     fn try_lock_shared(&self) -> bool {
+/// #         let n = self.0.load(Ordering::Relaxed);
+/// #         n != !0 && self.0.compare_exchange(n, n + 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+/// #     }
+/// #     fn unlock_shared(&self) { self.0.fetch_sub(1, Ordering::Release); }
+/// #     fn lock_exclusive(&self) { while !self.try_lock_exclusive() {} }
+/// #     fn try_lock_exclusive(&self) -> bool {
+/// #         self.0.compare_exchange(0, !0, Ordering::Acquire, Ordering::Relaxed).is_ok()
+/// #     }
+/// #     fn unlock_exclusive(&self) { self.0.store(0, Ordering::Release); }
+/// # }
+///
+/// let from = Mutex::new(10);
+/// let to = Mutex::new(0);
+/// let rate = RwLock::new(2);
+/// let log = RwLock::new(Vec::new());
+///
+/// let (mut from, mut to, rate, mut log) =
+///     lock_all((&from, &to, ReadLock(&rate), WriteLock(&log)));
+/// *from -= 5;
+/// *to += 5 * *rate;
+/// log.push("transfer");
+/// ```
+#[inline]
+pub fn lock_all<L: LockSet>(locks: L) -> L::Guards {
+    locks.lock_all()
+}
+
+/// Attempts to acquire several locks at once without blocking.
+///
+/// If any of the locks is contended, `None` is returned and none of them are
+/// held. See `lock_all` for details.
+///
+/// # Panics
+///
+/// Panics if the same lock is passed more than once.
+#[inline]
+pub fn try_lock_all<L: LockSet>(locks: L) -> Option<L::Guards> {
+    locks.try_lock_all()
+}
+
+/// Attempts to acquire several locks at once without deadlocking, until a
+/// timeout is reached.
+///
+/// If the timeout is reached, `None` is returned and none of the locks are
+/// held. See `lock_all` for details.
+///
+/// # Panics
+///
+/// Panics if the same lock is passed more than once.
+#[inline]
+pub fn try_lock_all_until<L: LockSetTimed>(locks: L, timeout: L::Instant) -> Option<L::Guards> {
+    locks.try_lock_all_until(timeout)
+}
+
+/// Acquires two locks at once without deadlocking.
+///
+/// This is a shorthand for `lock_all((a, b))`.
+///
+/// # Panics
+///
+/// Panics if the same lock is passed twice.
+#[inline]
+pub fn lock2<A: Lockable, B: Lockable>(a: A, b: B) -> (A::Guard, B::Guard) {
+    lock_all((a, b))
+}
+
+/// Acquires all the mutexes in a slice without deadlocking.
+///
+/// This works like `lock_all`, except that any number of mutexes can be
+/// acquired. A single guard is returned which releases all of them when
+/// dropped, and which gives access to the protected data by index.
+///
+/// # Panics
+///
+/// Panics if the same mutex appears more than once in the slice.
+#[inline]
+pub fn lock_slice<'a, R: RawMutex, T: ?Sized>(
+    mutexes: &'a [&'a Mutex<R, T>],
+) -> MutexSliceGuard<'a, R, T> {
+    lock_all_raw(mutexes, |i| {
+        mutexes[i].lock_raw();
+        true
+    });
+    MutexSliceGuard {
+        mutexes,
+        marker: PhantomData,
+    }
+}
+
+/// Attempts to acquire all the mutexes in a slice without blocking.
+///
+/// If any of the mutexes is contended, `None` is returned and none of them are
+/// held.
+///
+/// # Panics
+///
+/// Panics if the same mutex appears more than once in the slice.
+#[inline]
+pub fn try_lock_slice<'a, R: RawMutex, T: ?Sized>(
+    mutexes: &'a [&'a Mutex<R, T>],
+) -> Option<MutexSliceGuard<'a, R, T>> {
+    if try_lock_all_raw(mutexes) {
+        Some(MutexSliceGuard {
+            mutexes,
+            marker: PhantomData,
+        })
+    } else {
+        None
+    }
+}
+
+/// Attempts to acquire all the mutexes in a slice without deadlocking, until a
+/// timeout is reached.
+///
+/// If the timeout is reached, `None` is returned and none of the mutexes are
+/// held.
+///
+/// # Panics
+///
+/// Panics if the same mutex appears more than once in the slice.
+#[inline]
+pub fn try_lock_slice_until<'a, R: RawMutexTimed, T: ?Sized>(
+    mutexes: &'a [&'a Mutex<R, T>],
+    timeout: R::Instant,
+) -> Option<MutexSliceGuard<'a, R, T>>
+where
+    R::Instant: Clone,
+{
+    if lock_all_raw(mutexes, |i| mutexes[i].try_lock_until_raw(timeout.clone())) {
+        Some(MutexSliceGuard {
+            mutexes,
+            marker: PhantomData,
+        })
+    } else {
+        None
+    }
+}
+
+/// An RAII guard returned by `lock_slice`, which holds all the mutexes of a
+/// slice and releases them when dropped.
+///
+/// The data protected by the mutex at each index of the slice can be accessed
+/// by indexing the guard.
+#[must_use = "if unused the mutexes will immediately unlock"]
+pub struct MutexSliceGuard<'a, R: RawMutex, T: ?Sized> {
+    mutexes: &'a [&'a Mutex<R, T>],
+    marker: PhantomData<(&'a mut T, R::GuardMarker)>,
+}
+
+unsafe impl<'a, R: RawMutex + Sync + 'a, T: ?Sized + Sync + 'a> Sync for MutexSliceGuard<'a, R, T> {}
+
+impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> MutexSliceGuard<'a, R, T> {
+    /// Returns the number of mutexes held by this guard.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.mutexes.len()
+    }
+
+    /// Returns `true` if this guard doesn't hold any mutex.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.mutexes.is_empty()
+    }
+}
+
+impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> Index<usize> for MutexSliceGuard<'a, R, T> {
+    type Output = T;
+    #[inline]
+    fn index(&self, index: usize) -> &T {
+        unsafe { &*self.mutexes[index].data.get() }
+    }
+}
+
+impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> IndexMut<usize> for MutexSliceGuard<'a, R, T> {
+    #[inline]
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        unsafe { &mut *self.mutexes[index].data.get() }
+    }
+}
+
+impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> Drop for MutexSliceGuard<'a, R, T> {
+    #[inline]
+    fn drop(&mut self) {
+        for mutex in self.mutexes {
+            unsafe { mutex.raw().unlock() };
+        }
+    }
+}
+
+impl<'a, R: RawMutex + 'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug for MutexSliceGuard<'a, R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        for mutex in self.mutexes {
+            list.entry(&unsafe { &*mutex.data.get() });
+        }
+        list.finish()
+    }
+}
@@ -5,12 +5,21 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::condvar::{relock_raw_mutex, CondvarGuard, RawMutexRequeue};
+use crate::level::RawLockLevel;
+use crate::owner::RawLockOwner;
+use crate::stats::RawLockStats;
 use core::cell::UnsafeCell;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem;
 use core::ops::{Deref, DerefMut};
 
+#[cfg(feature = "arc_lock")]
+use alloc::sync::Arc;
+#[cfg(feature = "arc_lock")]
+use core::ptr;
+
 #[cfg(feature = "owning_ref")]
 use owning_ref::StableAddress;
 
@@ -46,6 +55,22 @@ pub unsafe trait RawMutex {
 
     /// Unlocks this mutex.
     fn unlock(&self);
+
+    /// Returns this mutex as a target that threads waiting on a condition
+    /// variable can be requeued onto, if it supports it.
+    ///
+    /// See `RawMutexRequeue` for details.
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        None
+    }
+
+    /// Adds fields describing the thread holding this mutex to the `Debug`
+    /// output of a locked `Mutex` or `ReentrantMutex`.
+    ///
+    /// This does nothing by default. See `RawLockOwner` for details.
+    #[inline]
+    fn debug_owner(&self, _s: &mut fmt::DebugStruct<'_, '_>) {}
 }
 
 /// Additional methods for mutexes which support fair unlocking.
@@ -87,6 +112,117 @@ pub unsafe trait RawMutexTimed: RawMutex {
     fn try_lock_until(&self, timeout: Self::Instant) -> bool;
 }
 
+/// Additional methods for robust mutexes, which detect that the thread or
+/// process holding them died without unlocking them.
+///
+/// When that happens, the next thread to lock the mutex acquires it but is told
+/// that the previous owner died, since the protected data may have been left
+/// in an inconsistent state. It then has the chance to repair the data and call
+/// `mark_consistent`. If the mutex is unlocked without being marked consistent,
+/// it becomes permanently unusable and all further attempts to lock it with
+/// `lock_robust` fail with `RobustLockError::NotRecoverable`.
+///
+/// The plain `RawMutex` methods can't report the death of the owner. They are
+/// expected to acquire the mutex anyway but leave it inconsistent, so that its
+/// new owner can find out with `is_consistent` and the mutex still becomes
+/// unusable if the data isn't repaired. Since they can't report a mutex which
+/// is not recoverable either, implementations should document how they handle
+/// it.
+pub unsafe trait RawMutexRobust: RawMutex {
+    /// Acquires this mutex, blocking the current thread until it is able to do
+    /// so.
+    ///
+    /// Returns `Err(RobustLockError::OwnerDied(()))` if the mutex was acquired
+    /// but its previous owner died while holding it, and
+    /// `Err(RobustLockError::NotRecoverable)` without acquiring the mutex if it
+    /// can no longer be used.
+    fn lock_robust(&self) -> RobustLockResult<()>;
+
+    /// Attempts to acquire this mutex without blocking.
+    ///
+    /// Returns `None` if the mutex is held by a live owner. Otherwise the result
+    /// is the same as for `lock_robust`.
+    fn try_lock_robust(&self) -> Option<RobustLockResult<()>>;
+
+    /// Marks the state protected by this mutex as consistent again after the
+    /// previous owner died, so that unlocking it doesn't make it unusable.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the mutex is held in the current
+    /// context.
+    unsafe fn mark_consistent(&self);
+
+    /// Checks whether the state protected by this mutex is consistent, that is
+    /// whether its previous owner didn't die or it has been marked consistent
+    /// since.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the mutex is held in the current
+    /// context.
+    unsafe fn is_consistent(&self) -> bool;
+}
+
+/// Error returned when locking a robust mutex whose previous owner died.
+///
+/// This is the equivalent of the `EOWNERDEAD` and `ENOTRECOVERABLE` errors
+/// returned by robust POSIX mutexes.
+pub enum RobustLockError<G> {
+    /// The lock was acquired, but its previous owner died while holding it so
+    /// the protected data may be inconsistent. The guard is included so that
+    /// the data can be repaired before the mutex is marked consistent.
+    OwnerDied(G),
+
+    /// The lock was not acquired because a previous owner died and the mutex
+    /// was then unlocked without being marked consistent.
+    NotRecoverable,
+}
+
+/// The result of locking a robust mutex.
+pub type RobustLockResult<G> = Result<G, RobustLockError<G>>;
+
+impl<G> RobustLockError<G> {
+    /// Returns the guard if the lock was acquired despite the error.
+    #[inline]
+    pub fn into_guard(self) -> Option<G> {
+        match self {
+            RobustLockError::OwnerDied(guard) => Some(guard),
+            RobustLockError::NotRecoverable => None,
+        }
+    }
+
+    /// Applies a function to the guard, if there is one.
+    #[inline]
+    pub fn map<U, F>(self, f: F) -> RobustLockError<U>
+    where
+        F: FnOnce(G) -> U,
+    {
+        match self {
+            RobustLockError::OwnerDied(guard) => RobustLockError::OwnerDied(f(guard)),
+            RobustLockError::NotRecoverable => RobustLockError::NotRecoverable,
+        }
+    }
+}
+
+impl<G> fmt::Debug for RobustLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RobustLockError::OwnerDied(_) => f.write_str("OwnerDied(..)"),
+            RobustLockError::NotRecoverable => f.write_str("NotRecoverable"),
+        }
+    }
+}
+
+impl<G> fmt::Display for RobustLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RobustLockError::OwnerDied(_) => f.write_str("previous owner of the mutex died"),
+            RobustLockError::NotRecoverable => f.write_str("mutex is not recoverable"),
+        }
+    }
+}
+
 /// A mutual exclusion primitive useful for protecting shared data
 ///
 /// This mutex will block threads waiting for the lock to become available. The
@@ -97,7 +233,7 @@ pub unsafe trait RawMutexTimed: RawMutex {
 /// ever accessed when the mutex is locked.
 pub struct Mutex<R: RawMutex, T: ?Sized> {
     raw: R,
-    data: UnsafeCell<T>,
+    pub(crate) data: UnsafeCell<T>,
 }
 
 unsafe impl<R: RawMutex + Send, T: ?Sized + Send> Send for Mutex<R, T> {}
@@ -136,7 +272,7 @@ impl<R: RawMutex, T: ?Sized> Mutex<R, T> {
     ///
     /// The lock must be held when calling this method.
     #[inline]
-    unsafe fn guard(&self) -> MutexGuard<'_, R, T> {
+    pub(crate) unsafe fn guard(&self) -> MutexGuard<'_, R, T> {
         MutexGuard {
             mutex: self,
             marker: PhantomData,
@@ -153,6 +289,7 @@ impl<R: RawMutex, T: ?Sized> Mutex<R, T> {
     /// Attempts to lock a mutex in the thread which already holds the lock will
     /// result in a deadlock.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn lock(&self) -> MutexGuard<'_, R, T> {
         self.raw.lock();
         // SAFETY: The lock is held, as required.
@@ -167,6 +304,7 @@ impl<R: RawMutex, T: ?Sized> Mutex<R, T> {
     ///
     /// This function does not block.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_lock(&self) -> Option<MutexGuard<'_, R, T>> {
         if self.raw.try_lock() {
             // SAFETY: The lock is held, as required.
@@ -241,6 +379,7 @@ impl<R: RawMutexTimed, T: ?Sized> Mutex<R, T> {
     /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
     /// be unlocked when the guard is dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_lock_for(&self, timeout: R::Duration) -> Option<MutexGuard<'_, R, T>> {
         if self.raw.try_lock_for(timeout) {
             // SAFETY: The lock is held, as required.
@@ -256,6 +395,7 @@ impl<R: RawMutexTimed, T: ?Sized> Mutex<R, T> {
     /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
     /// be unlocked when the guard is dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_lock_until(&self, timeout: R::Instant) -> Option<MutexGuard<'_, R, T>> {
         if self.raw.try_lock_until(timeout) {
             // SAFETY: The lock is held, as required.
@@ -266,6 +406,165 @@ impl<R: RawMutexTimed, T: ?Sized> Mutex<R, T> {
     }
 }
 
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized> Mutex<R, T> {
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn guard_arc(self: &Arc<Self>) -> ArcMutexGuard<R, T> {
+        ArcMutexGuard {
+            mutex: self.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Acquires a mutex through an `Arc`, blocking the current thread until it
+    /// is able to do so.
+    ///
+    /// This method is similar to the `lock` method, however it requires the
+    /// `Mutex` to be inside of an `Arc` and the resulting mutex guard has no
+    /// lifetime requirements. It can be stored in other structures without
+    /// borrowing the `Mutex`.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn lock_arc(self: &Arc<Self>) -> ArcMutexGuard<R, T> {
+        self.raw.lock();
+        // SAFETY: The lock is held, as required.
+        unsafe { self.guard_arc() }
+    }
+
+    /// Attempts to acquire a mutex through an `Arc`.
+    ///
+    /// This method is similar to the `try_lock` method, however it requires
+    /// the `Mutex` to be inside of an `Arc` and the resulting mutex guard has
+    /// no lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_lock_arc(self: &Arc<Self>) -> Option<ArcMutexGuard<R, T>> {
+        if self.raw.try_lock() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.guard_arc() })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutexTimed, T: ?Sized> Mutex<R, T> {
+    /// Attempts to acquire a lock through an `Arc` until a timeout is reached.
+    ///
+    /// This method is similar to the `try_lock_for` method, however it
+    /// requires the `Mutex` to be inside of an `Arc` and the resulting mutex
+    /// guard has no lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_lock_arc_for(self: &Arc<Self>, timeout: R::Duration) -> Option<ArcMutexGuard<R, T>> {
+        if self.raw.try_lock_for(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.guard_arc() })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire a lock through an `Arc` until a timeout is reached.
+    ///
+    /// This method is similar to the `try_lock_until` method, however it
+    /// requires the `Mutex` to be inside of an `Arc` and the resulting mutex
+    /// guard has no lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_lock_arc_until(
+        self: &Arc<Self>,
+        timeout: R::Instant,
+    ) -> Option<ArcMutexGuard<R, T>> {
+        if self.raw.try_lock_until(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.guard_arc() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: RawMutexRobust, T: ?Sized> Mutex<R, T> {
+    /// Acquires this mutex, blocking the current thread until it is able to do
+    /// so, and reports whether its previous owner died while holding it.
+    ///
+    /// If the previous owner died then the lock is still acquired, but the
+    /// guard is returned inside `RobustLockError::OwnerDied`. The protected data
+    /// should then be repaired and the mutex marked consistent with
+    /// `MutexGuard::mark_consistent` before the guard is dropped, otherwise the
+    /// mutex becomes permanently unusable and `RobustLockError::NotRecoverable`
+    /// is returned from then on.
+    #[inline]
+    pub fn lock_robust(&self) -> RobustLockResult<MutexGuard<'_, R, T>> {
+        match self.raw.lock_robust() {
+            // SAFETY: The lock is held, as required.
+            Ok(()) => Ok(unsafe { self.guard() }),
+            // SAFETY: The lock is held, as required.
+            Err(e) => Err(e.map(|()| unsafe { self.guard() })),
+        }
+    }
+
+    /// Attempts to acquire this mutex without blocking, and reports whether its
+    /// previous owner died while holding it.
+    ///
+    /// If the mutex is held by a live owner then `None` is returned. Otherwise
+    /// the result is the same as for `lock_robust`.
+    #[inline]
+    pub fn try_lock_robust(&self) -> Option<RobustLockResult<MutexGuard<'_, R, T>>> {
+        self.raw.try_lock_robust().map(|result| match result {
+            // SAFETY: The lock is held, as required.
+            Ok(()) => Ok(unsafe { self.guard() }),
+            // SAFETY: The lock is held, as required.
+            Err(e) => Err(e.map(|()| unsafe { self.guard() })),
+        })
+    }
+}
+
+impl<R: RawMutex + RawLockLevel, T> Mutex<R, T> {
+    /// Creates a new mutex in an unlocked state with the given level in the
+    /// lock hierarchy.
+    ///
+    /// Locks created with `new` don't take part in the hierarchy.
+    #[inline]
+    pub fn with_level(val: T, level: u32) -> Mutex<R, T> {
+        Mutex {
+            raw: R::with_level(level),
+            data: UnsafeCell::new(val),
+        }
+    }
+}
+
+impl<R: RawMutex + RawLockOwner, T: ?Sized> Mutex<R, T> {
+    /// Returns information about the thread currently holding this mutex, or
+    /// `None` if it isn't locked.
+    ///
+    /// This is mainly useful for debugging, for example to find out which
+    /// thread is holding a mutex after `try_lock_for` timed out.
+    #[inline]
+    pub fn owner(&self) -> Option<R::Owner> {
+        self.raw.owner()
+    }
+}
+
+impl<R: RawMutex + RawLockStats, T: ?Sized> Mutex<R, T> {
+    /// Returns the contention statistics collected for this mutex.
+    #[inline]
+    pub fn stats(&self) -> R::Stats {
+        self.raw.stats()
+    }
+
+    /// Resets the contention statistics collected for this mutex.
+    #[inline]
+    pub fn reset_stats(&self) {
+        self.raw.reset_stats();
+    }
+}
+
 impl<R: RawMutex, T: ?Sized + Default> Default for Mutex<R, T> {
     #[inline]
     fn default() -> Mutex<R, T> {
@@ -292,9 +591,10 @@ impl<R: RawMutex, T: ?Sized + fmt::Debug> fmt::Debug for Mutex<R, T> {
                     }
                 }
 
-                f.debug_struct("Mutex")
-                    .field("data", &LockedPlaceholder)
-                    .finish()
+                let mut d = f.debug_struct("Mutex");
+                d.field("data", &LockedPlaceholder);
+                self.raw.debug_owner(&mut d);
+                d.finish()
             }
         }
     }
@@ -459,6 +759,32 @@ impl<'a, R: RawMutexFair + 'a, T: ?Sized + 'a> MutexGuard<'a, R, T> {
     }
 }
 
+impl<'a, R: RawMutexRobust + 'a, T: ?Sized + 'a> MutexGuard<'a, R, T> {
+    /// Marks the data protected by the mutex as consistent after its previous
+    /// owner died.
+    ///
+    /// This should be called once the data has been repaired after
+    /// `Mutex::lock_robust` returned `RobustLockError::OwnerDied`. Calling it
+    /// when the previous owner didn't die has no effect.
+    #[inline]
+    pub fn mark_consistent(s: &Self) {
+        // SAFETY: A MutexGuard always holds the lock.
+        unsafe { s.mutex.raw.mark_consistent() }
+    }
+
+    /// Checks whether the data protected by the mutex is consistent.
+    ///
+    /// This returns `false` if the previous owner of the mutex died while
+    /// holding it and the mutex hasn't been marked consistent since. This is
+    /// the only way to find out about the death of the owner when the mutex
+    /// was acquired with `Mutex::lock` instead of `Mutex::lock_robust`.
+    #[inline]
+    pub fn is_consistent(s: &Self) -> bool {
+        // SAFETY: A MutexGuard always holds the lock.
+        unsafe { s.mutex.raw.is_consistent() }
+    }
+}
+
 impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> Deref for MutexGuard<'a, R, T> {
     type Target = T;
     #[inline]
@@ -481,6 +807,29 @@ impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> Drop for MutexGuard<'a, R, T> {
     }
 }
 
+unsafe impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> CondvarGuard for MutexGuard<'a, R, T> {
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        &self.mutex.raw as *const R as usize
+    }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        self.mutex.raw.requeue_target()
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.mutex.raw.unlock();
+        0
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, _state: usize, token: Option<usize>) {
+        relock_raw_mutex(&self.mutex.raw, token);
+    }
+}
+
 impl<'a, R: RawMutex + 'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug for MutexGuard<'a, R, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
@@ -629,3 +978,344 @@ impl<'a, R: RawMutex + 'a, T: fmt::Display + ?Sized + 'a> fmt::Display
 
 #[cfg(feature = "owning_ref")]
 unsafe impl<'a, R: RawMutex + 'a, T: ?Sized + 'a> StableAddress for MappedMutexGuard<'a, R, T> {}
+
+/// An RAII mutex guard returned by the `Arc` locking operations on `Mutex`.
+///
+/// This is similar to the `MutexGuard` struct, except instead of using a
+/// reference to unlock the `Mutex` it uses an `Arc<Mutex>`. This has several
+/// advantages, most notably that it has a `'static` lifetime.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct ArcMutexGuard<R: RawMutex, T: ?Sized> {
+    mutex: Arc<Mutex<R, T>>,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawMutex + Sync, T: ?Sized + Send + Sync> Sync for ArcMutexGuard<R, T> {}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized> ArcMutexGuard<R, T> {
+    /// Returns a reference to the `Mutex` this is guarding, contained in its
+    /// `Arc`.
+    #[inline]
+    pub fn mutex(s: &Self) -> &Arc<Mutex<R, T>> {
+        &s.mutex
+    }
+
+    /// Makes a new `MappedArcMutexGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `ArcMutexGuard` passed
+    /// in already locked the mutex.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ArcMutexGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(s: Self, f: F) -> MappedArcMutexGuard<R, T, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let data: *mut U = f(unsafe { &mut *s.mutex.data.get() });
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        MappedArcMutexGuard {
+            mutex,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedArcMutexGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns `None`.
+    ///
+    /// This operation cannot fail as the `ArcMutexGuard` passed
+    /// in already locked the mutex.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ArcMutexGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedArcMutexGuard<R, T, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let data: *mut U = match f(unsafe { &mut *s.mutex.data.get() }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        Ok(MappedArcMutexGuard {
+            mutex,
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// This is safe because `&mut` guarantees that there exist no other
+    /// references to the data protected by the mutex.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.mutex.raw.unlock();
+        defer!(s.mutex.raw.lock());
+        f()
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutexFair, T: ?Sized> ArcMutexGuard<R, T> {
+    /// Unlocks the mutex using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `MutexGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.mutex.raw.unlock_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        drop(mutex);
+    }
+
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked_fair` method on
+    /// `MutexGuard`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.mutex.raw.unlock_fair();
+        defer!(s.mutex.raw.lock());
+        f()
+    }
+
+    /// Temporarily yields the mutex to a waiting thread if there is one.
+    ///
+    /// This is functionally identical to the `bump` method on `MutexGuard`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        s.mutex.raw.bump();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized> Deref for ArcMutexGuard<R, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized> DerefMut for ArcMutexGuard<R, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized> Drop for ArcMutexGuard<R, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.raw.unlock();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawMutex, T: ?Sized> CondvarGuard for ArcMutexGuard<R, T> {
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        &self.mutex.raw as *const R as usize
+    }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        self.mutex.raw.requeue_target()
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.mutex.raw.unlock();
+        0
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, _state: usize, token: Option<usize>) {
+        relock_raw_mutex(&self.mutex.raw, token);
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: fmt::Debug + ?Sized> fmt::Debug for ArcMutexGuard<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: fmt::Display + ?Sized> fmt::Display for ArcMutexGuard<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawMutex, T: ?Sized> StableAddress for ArcMutexGuard<R, T> {}
+
+/// An RAII mutex guard returned by `ArcMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedArcMutexGuard` and `ArcMutexGuard` is
+/// that the former doesn't support temporarily unlocking and re-locking, since
+/// that could introduce soundness issues if the locked object is modified by
+/// another thread.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct MappedArcMutexGuard<R: RawMutex, T: ?Sized, U: ?Sized> {
+    mutex: Arc<Mutex<R, T>>,
+    data: *mut U,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawMutex + Send + Sync, T: ?Sized + Send, U: ?Sized + Send> Send
+    for MappedArcMutexGuard<R, T, U>
+where
+    R::GuardMarker: Send,
+{
+}
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawMutex + Sync, T: ?Sized + Send + Sync, U: ?Sized + Sync> Sync
+    for MappedArcMutexGuard<R, T, U>
+{
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized, U: ?Sized> MappedArcMutexGuard<R, T, U> {
+    /// Returns a reference to the `Mutex` this is guarding, contained in its
+    /// `Arc`.
+    #[inline]
+    pub fn mutex(s: &Self) -> &Arc<Mutex<R, T>> {
+        &s.mutex
+    }
+
+    /// Makes a new `MappedArcMutexGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedArcMutexGuard` passed
+    /// in already locked the mutex.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedArcMutexGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<V: ?Sized, F>(s: Self, f: F) -> MappedArcMutexGuard<R, T, V>
+    where
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        let data: *mut V = f(unsafe { &mut *s.data });
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        MappedArcMutexGuard {
+            mutex,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedArcMutexGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns `None`.
+    ///
+    /// This operation cannot fail as the `MappedArcMutexGuard` passed
+    /// in already locked the mutex.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedArcMutexGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<V: ?Sized, F>(s: Self, f: F) -> Result<MappedArcMutexGuard<R, T, V>, Self>
+    where
+        F: FnOnce(&mut U) -> Option<&mut V>,
+    {
+        let data: *mut V = match f(unsafe { &mut *s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        Ok(MappedArcMutexGuard {
+            mutex,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutexFair, T: ?Sized, U: ?Sized> MappedArcMutexGuard<R, T, U> {
+    /// Unlocks the mutex using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `MutexGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.mutex.raw.unlock_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        drop(mutex);
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized, U: ?Sized> Deref for MappedArcMutexGuard<R, T, U> {
+    type Target = U;
+    #[inline]
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized, U: ?Sized> DerefMut for MappedArcMutexGuard<R, T, U> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.data }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized, U: ?Sized> Drop for MappedArcMutexGuard<R, T, U> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.raw.unlock();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized, U: fmt::Debug + ?Sized> fmt::Debug for MappedArcMutexGuard<R, T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawMutex, T: ?Sized, U: fmt::Display + ?Sized> fmt::Display
+    for MappedArcMutexGuard<R, T, U>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawMutex, T: ?Sized, U: ?Sized> StableAddress for MappedArcMutexGuard<R, T, U> {}
@@ -0,0 +1,29 @@
+// Copyright 2018 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/// Additional methods for raw locks which keep track of the thread holding
+/// them.
+///
+/// This can be implemented by both mutexes and reader-writer locks. The
+/// `Mutex`, `ReentrantMutex` and `RwLock` wrappers expose this as an `owner`
+/// method. Implementations should also override `RawMutex::debug_owner` or
+/// `RawRwLock::debug_owner` so that the owner shows up in the `Debug` output
+/// of a locked lock.
+///
+/// The owner type is specified as an associated type so that each lock
+/// implementation can choose what it records.
+pub trait RawLockOwner {
+    /// Information about the thread holding a lock.
+    type Owner;
+
+    /// Returns information about the thread currently holding the lock
+    /// exclusively, or `None` if it isn't locked exclusively.
+    ///
+    /// The owner may change as soon as this returns, so this is only suitable
+    /// for debugging.
+    fn owner(&self) -> Option<Self::Owner>;
+}
@@ -6,7 +6,10 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::{
+    condvar::{relock_raw_mutex, CondvarGuard, RawMutexRequeue},
     mutex::{RawMutex, RawMutexFair, RawMutexTimed},
+    owner::RawLockOwner,
+    stats::RawLockStats,
     GuardNoSend,
 };
 use core::{
@@ -55,8 +58,14 @@ struct RawReentrantMutex<R: RawMutex, G: GetThreadId> {
 }
 
 impl<R: RawMutex, G: GetThreadId> RawReentrantMutex<R, G> {
-    #[inline]
-    fn lock_internal<F: FnOnce() -> bool>(&self, try_lock: F) -> bool {
+    // Increments the lock count if the mutex is already held by the current
+    // thread. Otherwise returns the id of the current thread, which must be
+    // passed to `set_owner` once the inner mutex has been acquired.
+    //
+    // This doesn't take the inner lock operation as a closure so that
+    // `#[track_caller]` can see through it.
+    #[inline]
+    fn lock_recursive(&self) -> Result<(), usize> {
         let id = self.get_thread_id.nonzero_thread_id().get();
         if self.owner.load(Ordering::Relaxed) == id {
             self.lock_count.set(
@@ -65,28 +74,41 @@ impl<R: RawMutex, G: GetThreadId> RawReentrantMutex<R, G> {
                     .checked_add(1)
                     .expect("ReentrantMutex lock count overflow"),
             );
+            Ok(())
         } else {
-            if !try_lock() {
-                return false;
-            }
-            self.owner.store(id, Ordering::Relaxed);
-            debug_assert_eq!(self.lock_count.get(), 0);
-            self.lock_count.set(1);
+            Err(id)
         }
-        true
     }
 
     #[inline]
+    fn set_owner(&self, id: usize) {
+        self.owner.store(id, Ordering::Relaxed);
+        debug_assert_eq!(self.lock_count.get(), 0);
+        self.lock_count.set(1);
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     fn lock(&self) {
-        self.lock_internal(|| {
+        if let Err(id) = self.lock_recursive() {
             self.mutex.lock();
-            true
-        });
+            self.set_owner(id);
+        }
     }
 
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     fn try_lock(&self) -> bool {
-        self.lock_internal(|| self.mutex.try_lock())
+        match self.lock_recursive() {
+            Ok(()) => true,
+            Err(id) => {
+                if !self.mutex.try_lock() {
+                    return false;
+                }
+                self.set_owner(id);
+                true
+            }
+        }
     }
 
     #[inline]
@@ -100,6 +122,28 @@ impl<R: RawMutex, G: GetThreadId> RawReentrantMutex<R, G> {
     }
 }
 
+impl<R: RawMutex, G: GetThreadId> RawReentrantMutex<R, G> {
+    // Fully releases the mutex, whatever its lock count, so that the current
+    // thread can wait on a condition variable. Returns the lock count.
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        let lock_count = self.lock_count.replace(0);
+        self.owner.store(0, Ordering::Relaxed);
+        self.mutex.unlock();
+        lock_count
+    }
+
+    // Re-acquires the mutex after waiting on a condition variable and restores
+    // the lock count returned by `unlock_for_wait`.
+    #[inline]
+    unsafe fn relock_after_wait(&self, lock_count: usize, token: Option<usize>) {
+        relock_raw_mutex(&self.mutex, token);
+        let id = self.get_thread_id.nonzero_thread_id().get();
+        self.owner.store(id, Ordering::Relaxed);
+        self.lock_count.set(lock_count);
+    }
+}
+
 impl<R: RawMutexFair, G: GetThreadId> RawReentrantMutex<R, G> {
     #[inline]
     fn unlock_fair(&self) {
@@ -124,13 +168,33 @@ impl<R: RawMutexFair, G: GetThreadId> RawReentrantMutex<R, G> {
 
 impl<R: RawMutexTimed, G: GetThreadId> RawReentrantMutex<R, G> {
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     fn try_lock_until(&self, timeout: R::Instant) -> bool {
-        self.lock_internal(|| self.mutex.try_lock_until(timeout))
+        match self.lock_recursive() {
+            Ok(()) => true,
+            Err(id) => {
+                if !self.mutex.try_lock_until(timeout) {
+                    return false;
+                }
+                self.set_owner(id);
+                true
+            }
+        }
     }
 
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     fn try_lock_for(&self, timeout: R::Duration) -> bool {
-        self.lock_internal(|| self.mutex.try_lock_for(timeout))
+        match self.lock_recursive() {
+            Ok(()) => true,
+            Err(id) => {
+                if !self.mutex.try_lock_for(timeout) {
+                    return false;
+                }
+                self.set_owner(id);
+                true
+            }
+        }
     }
 }
 
@@ -220,6 +284,7 @@ impl<R: RawMutex, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
     /// returned to allow scoped unlock of the lock. When the guard goes out of
     /// scope, the mutex will be unlocked.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn lock(&self) -> ReentrantMutexGuard<'_, R, G, T> {
         self.raw.lock();
         // SAFETY: The lock is held, as required.
@@ -234,6 +299,7 @@ impl<R: RawMutex, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
     ///
     /// This function does not block.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, R, G, T>> {
         if self.raw.try_lock() {
             // SAFETY: The lock is held, as required.
@@ -308,6 +374,7 @@ impl<R: RawMutexTimed, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
     /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
     /// be unlocked when the guard is dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_lock_for(&self, timeout: R::Duration) -> Option<ReentrantMutexGuard<'_, R, G, T>> {
         if self.raw.try_lock_for(timeout) {
             // SAFETY: The lock is held, as required.
@@ -323,6 +390,7 @@ impl<R: RawMutexTimed, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
     /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
     /// be unlocked when the guard is dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_lock_until(&self, timeout: R::Instant) -> Option<ReentrantMutexGuard<'_, R, G, T>> {
         if self.raw.try_lock_until(timeout) {
             // SAFETY: The lock is held, as required.
@@ -333,6 +401,34 @@ impl<R: RawMutexTimed, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
     }
 }
 
+impl<R: RawMutex + RawLockOwner, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
+    /// Returns information about the thread currently holding this mutex, or
+    /// `None` if it isn't locked.
+    ///
+    /// Only the outermost lock taken by the owning thread is recorded.
+    #[inline]
+    pub fn owner(&self) -> Option<R::Owner> {
+        self.raw.mutex.owner()
+    }
+}
+
+impl<R: RawMutex + RawLockStats, G: GetThreadId, T: ?Sized> ReentrantMutex<R, G, T> {
+    /// Returns the contention statistics collected for this mutex.
+    ///
+    /// Recursive locking by the thread which already owns the mutex does not
+    /// touch the underlying raw mutex and is therefore not counted.
+    #[inline]
+    pub fn stats(&self) -> R::Stats {
+        self.raw.mutex.stats()
+    }
+
+    /// Resets the contention statistics collected for this mutex.
+    #[inline]
+    pub fn reset_stats(&self) {
+        self.raw.mutex.reset_stats();
+    }
+}
+
 impl<R: RawMutex, G: GetThreadId, T: ?Sized + Default> Default for ReentrantMutex<R, G, T> {
     #[inline]
     fn default() -> ReentrantMutex<R, G, T> {
@@ -362,9 +458,10 @@ impl<R: RawMutex, G: GetThreadId, T: ?Sized + fmt::Debug> fmt::Debug for Reentra
                     }
                 }
 
-                f.debug_struct("ReentrantMutex")
-                    .field("data", &LockedPlaceholder)
-                    .finish()
+                let mut d = f.debug_struct("ReentrantMutex");
+                d.field("data", &LockedPlaceholder);
+                self.raw.mutex.debug_owner(&mut d);
+                d.finish()
             }
         }
     }
@@ -558,6 +655,30 @@ impl<'a, R: RawMutex + 'a, G: GetThreadId + 'a, T: ?Sized + 'a> Drop
     }
 }
 
+unsafe impl<'a, R: RawMutex + 'a, G: GetThreadId + 'a, T: ?Sized + 'a> CondvarGuard
+    for ReentrantMutexGuard<'a, R, G, T>
+{
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        &self.remutex.raw.mutex as *const R as usize
+    }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        self.remutex.raw.mutex.requeue_target()
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.remutex.raw.unlock_for_wait()
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, state: usize, token: Option<usize>) {
+        self.remutex.raw.relock_after_wait(state, token);
+    }
+}
+
 impl<'a, R: RawMutex + 'a, G: GetThreadId + 'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug
     for ReentrantMutexGuard<'a, R, G, T>
 {
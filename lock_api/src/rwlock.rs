@@ -5,12 +5,21 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::condvar::CondvarGuard;
+use crate::level::RawLockLevel;
+use crate::owner::RawLockOwner;
+use crate::stats::RawLockStats;
 use core::cell::UnsafeCell;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem;
 use core::ops::{Deref, DerefMut};
 
+#[cfg(feature = "arc_lock")]
+use alloc::sync::Arc;
+#[cfg(feature = "arc_lock")]
+use core::ptr;
+
 #[cfg(feature = "owning_ref")]
 use owning_ref::StableAddress;
 
@@ -56,6 +65,13 @@ pub unsafe trait RawRwLock {
 
     /// Releases an exclusive lock.
     fn unlock_exclusive(&self);
+
+    /// Adds fields describing the thread holding this lock exclusively to the
+    /// `Debug` output of a write-locked `RwLock`.
+    ///
+    /// This does nothing by default. See `RawLockOwner` for details.
+    #[inline]
+    fn debug_owner(&self, _s: &mut fmt::DebugStruct<'_, '_>) {}
 }
 
 /// Additional methods for RwLocks which support fair unlocking.
@@ -302,7 +318,7 @@ impl<R: RawRwLock, T: ?Sized> RwLock<R, T> {
     ///
     /// The lock must be held when calling this method.
     #[inline]
-    unsafe fn read_guard(&self) -> RwLockReadGuard<'_, R, T> {
+    pub(crate) unsafe fn read_guard(&self) -> RwLockReadGuard<'_, R, T> {
         RwLockReadGuard {
             rwlock: self,
             marker: PhantomData,
@@ -313,7 +329,7 @@ impl<R: RawRwLock, T: ?Sized> RwLock<R, T> {
     ///
     /// The lock must be held when calling this method.
     #[inline]
-    unsafe fn write_guard(&self) -> RwLockWriteGuard<'_, R, T> {
+    pub(crate) unsafe fn write_guard(&self) -> RwLockWriteGuard<'_, R, T> {
         RwLockWriteGuard {
             rwlock: self,
             marker: PhantomData,
@@ -365,6 +381,7 @@ impl<R: RawRwLock, T: ?Sized> RwLock<R, T> {
     /// Returns an RAII guard which will drop the write access of this `RwLock`
     /// when dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn write(&self) -> RwLockWriteGuard<'_, R, T> {
         self.raw.lock_exclusive();
         // SAFETY: The lock is held, as required.
@@ -379,6 +396,7 @@ impl<R: RawRwLock, T: ?Sized> RwLock<R, T> {
     ///
     /// This function does not block.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, R, T>> {
         if self.raw.try_lock_exclusive() {
             // SAFETY: The lock is held, as required.
@@ -518,6 +536,7 @@ impl<R: RawRwLockTimed, T: ?Sized> RwLock<R, T> {
     /// `None` is returned. Otherwise, an RAII guard is returned which will
     /// release the exclusive access when it is dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_write_for(&self, timeout: R::Duration) -> Option<RwLockWriteGuard<'_, R, T>> {
         if self.raw.try_lock_exclusive_for(timeout) {
             // SAFETY: The lock is held, as required.
@@ -534,6 +553,7 @@ impl<R: RawRwLockTimed, T: ?Sized> RwLock<R, T> {
     /// `None` is returned. Otherwise, an RAII guard is returned which will
     /// release the exclusive access when it is dropped.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_write_until(&self, timeout: R::Instant) -> Option<RwLockWriteGuard<'_, R, T>> {
         if self.raw.try_lock_exclusive_until(timeout) {
             // SAFETY: The lock is held, as required.
@@ -718,6 +738,291 @@ impl<R: RawRwLockUpgradeTimed, T: ?Sized> RwLock<R, T> {
     }
 }
 
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> RwLock<R, T> {
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn read_guard_arc(self: &Arc<Self>) -> ArcRwLockReadGuard<R, T> {
+        ArcRwLockReadGuard {
+            rwlock: self.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn write_guard_arc(self: &Arc<Self>) -> ArcRwLockWriteGuard<R, T> {
+        ArcRwLockWriteGuard {
+            rwlock: self.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Locks this `RwLock` with read access, through an `Arc`.
+    ///
+    /// This method is similar to the `read` method; however, it requires the
+    /// `RwLock` to be inside of an `Arc` and the resulting read guard has no
+    /// lifetime requirements.
+    #[inline]
+    pub fn read_arc(self: &Arc<Self>) -> ArcRwLockReadGuard<R, T> {
+        self.raw.lock_shared();
+        // SAFETY: The lock is held, as required.
+        unsafe { self.read_guard_arc() }
+    }
+
+    /// Attempts to lock this `RwLock` with read access, through an `Arc`.
+    ///
+    /// This method is similar to the `try_read` method; however, it requires
+    /// the `RwLock` to be inside of an `Arc` and the resulting read guard has
+    /// no lifetime requirements.
+    #[inline]
+    pub fn try_read_arc(self: &Arc<Self>) -> Option<ArcRwLockReadGuard<R, T>> {
+        if self.raw.try_lock_shared() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.read_guard_arc() })
+        } else {
+            None
+        }
+    }
+
+    /// Locks this `RwLock` with write access, through an `Arc`.
+    ///
+    /// This method is similar to the `write` method; however, it requires the
+    /// `RwLock` to be inside of an `Arc` and the resulting write guard has no
+    /// lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn write_arc(self: &Arc<Self>) -> ArcRwLockWriteGuard<R, T> {
+        self.raw.lock_exclusive();
+        // SAFETY: The lock is held, as required.
+        unsafe { self.write_guard_arc() }
+    }
+
+    /// Attempts to lock this `RwLock` with write access, through an `Arc`.
+    ///
+    /// This method is similar to the `try_write` method; however, it requires
+    /// the `RwLock` to be inside of an `Arc` and the resulting write guard has
+    /// no lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_write_arc(self: &Arc<Self>) -> Option<ArcRwLockWriteGuard<R, T>> {
+        if self.raw.try_lock_exclusive() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.write_guard_arc() })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockTimed, T: ?Sized> RwLock<R, T> {
+    /// Attempts to acquire this `RwLock` with read access until a timeout is
+    /// reached, through an `Arc`.
+    ///
+    /// This method is similar to the `try_read_for` method; however, it
+    /// requires the `RwLock` to be inside of an `Arc` and the resulting read
+    /// guard has no lifetime requirements.
+    #[inline]
+    pub fn try_read_arc_for(
+        self: &Arc<Self>,
+        timeout: R::Duration,
+    ) -> Option<ArcRwLockReadGuard<R, T>> {
+        if self.raw.try_lock_shared_for(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.read_guard_arc() })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this `RwLock` with read access until a timeout is
+    /// reached, through an `Arc`.
+    ///
+    /// This method is similar to the `try_read_until` method; however, it
+    /// requires the `RwLock` to be inside of an `Arc` and the resulting read
+    /// guard has no lifetime requirements.
+    #[inline]
+    pub fn try_read_arc_until(
+        self: &Arc<Self>,
+        timeout: R::Instant,
+    ) -> Option<ArcRwLockReadGuard<R, T>> {
+        if self.raw.try_lock_shared_until(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.read_guard_arc() })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this `RwLock` with write access until a timeout is
+    /// reached, through an `Arc`.
+    ///
+    /// This method is similar to the `try_write_for` method; however, it
+    /// requires the `RwLock` to be inside of an `Arc` and the resulting write
+    /// guard has no lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_write_arc_for(
+        self: &Arc<Self>,
+        timeout: R::Duration,
+    ) -> Option<ArcRwLockWriteGuard<R, T>> {
+        if self.raw.try_lock_exclusive_for(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.write_guard_arc() })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this `RwLock` with write access until a timeout is
+    /// reached, through an `Arc`.
+    ///
+    /// This method is similar to the `try_write_until` method; however, it
+    /// requires the `RwLock` to be inside of an `Arc` and the resulting write
+    /// guard has no lifetime requirements.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_write_arc_until(
+        self: &Arc<Self>,
+        timeout: R::Instant,
+    ) -> Option<ArcRwLockWriteGuard<R, T>> {
+        if self.raw.try_lock_exclusive_until(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.write_guard_arc() })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgrade, T: ?Sized> RwLock<R, T> {
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn upgradable_guard_arc(self: &Arc<Self>) -> ArcRwLockUpgradableReadGuard<R, T> {
+        ArcRwLockUpgradableReadGuard {
+            rwlock: self.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Locks this `RwLock` with upgradable read access, through an `Arc`.
+    ///
+    /// This method is similar to the `upgradable_read` method; however, it
+    /// requires the `RwLock` to be inside of an `Arc` and the resulting read
+    /// guard has no lifetime requirements.
+    #[inline]
+    pub fn upgradable_read_arc(self: &Arc<Self>) -> ArcRwLockUpgradableReadGuard<R, T> {
+        self.raw.lock_upgradable();
+        // SAFETY: The lock is held, as required.
+        unsafe { self.upgradable_guard_arc() }
+    }
+
+    /// Attempts to lock this `RwLock` with upgradable read access, through an
+    /// `Arc`.
+    ///
+    /// This method is similar to the `try_upgradable_read` method; however, it
+    /// requires the `RwLock` to be inside of an `Arc` and the resulting read
+    /// guard has no lifetime requirements.
+    #[inline]
+    pub fn try_upgradable_read_arc(self: &Arc<Self>) -> Option<ArcRwLockUpgradableReadGuard<R, T>> {
+        if self.raw.try_lock_upgradable() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.upgradable_guard_arc() })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgradeTimed, T: ?Sized> RwLock<R, T> {
+    /// Attempts to lock this `RwLock` with upgradable read access until a
+    /// timeout is reached, through an `Arc`.
+    ///
+    /// This method is similar to the `try_upgradable_read_for` method;
+    /// however, it requires the `RwLock` to be inside of an `Arc` and the
+    /// resulting read guard has no lifetime requirements.
+    #[inline]
+    pub fn try_upgradable_read_arc_for(
+        self: &Arc<Self>,
+        timeout: R::Duration,
+    ) -> Option<ArcRwLockUpgradableReadGuard<R, T>> {
+        if self.raw.try_lock_upgradable_for(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.upgradable_guard_arc() })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to lock this `RwLock` with upgradable read access until a
+    /// timeout is reached, through an `Arc`.
+    ///
+    /// This method is similar to the `try_upgradable_read_until` method;
+    /// however, it requires the `RwLock` to be inside of an `Arc` and the
+    /// resulting read guard has no lifetime requirements.
+    #[inline]
+    pub fn try_upgradable_read_arc_until(
+        self: &Arc<Self>,
+        timeout: R::Instant,
+    ) -> Option<ArcRwLockUpgradableReadGuard<R, T>> {
+        if self.raw.try_lock_upgradable_until(timeout) {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.upgradable_guard_arc() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: RawRwLock + RawLockLevel, T> RwLock<R, T> {
+    /// Creates a new `RwLock` in an unlocked state with the given level in the
+    /// lock hierarchy.
+    ///
+    /// Locks created with `new` don't take part in the hierarchy.
+    #[inline]
+    pub fn with_level(val: T, level: u32) -> RwLock<R, T> {
+        RwLock {
+            raw: R::with_level(level),
+            data: UnsafeCell::new(val),
+        }
+    }
+}
+
+impl<R: RawRwLock + RawLockOwner, T: ?Sized> RwLock<R, T> {
+    /// Returns information about the thread currently holding the write lock,
+    /// or `None` if it isn't write-locked. Readers are not tracked.
+    ///
+    /// This is mainly useful for debugging, for example to find out which
+    /// thread is holding the lock after `try_write_for` timed out.
+    #[inline]
+    pub fn owner(&self) -> Option<R::Owner> {
+        self.raw.owner()
+    }
+}
+
+impl<R: RawRwLock + RawLockStats, T: ?Sized> RwLock<R, T> {
+    /// Returns the contention statistics collected for this `RwLock`.
+    #[inline]
+    pub fn stats(&self) -> R::Stats {
+        self.raw.stats()
+    }
+
+    /// Resets the contention statistics collected for this `RwLock`.
+    #[inline]
+    pub fn reset_stats(&self) {
+        self.raw.reset_stats();
+    }
+}
+
 impl<R: RawRwLock, T: ?Sized + Default> Default for RwLock<R, T> {
     #[inline]
     fn default() -> RwLock<R, T> {
@@ -744,9 +1049,10 @@ impl<R: RawRwLock, T: ?Sized + fmt::Debug> fmt::Debug for RwLock<R, T> {
                     }
                 }
 
-                f.debug_struct("RwLock")
-                    .field("data", &LockedPlaceholder)
-                    .finish()
+                let mut d = f.debug_struct("RwLock");
+                d.field("data", &LockedPlaceholder);
+                self.raw.debug_owner(&mut d);
+                d.finish()
             }
         }
     }
@@ -1098,6 +1404,24 @@ impl<'a, R: RawRwLock + 'a, T: ?Sized + 'a> Drop for RwLockWriteGuard<'a, R, T>
     }
 }
 
+unsafe impl<'a, R: RawRwLock + 'a, T: ?Sized + 'a> CondvarGuard for RwLockWriteGuard<'a, R, T> {
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        &self.rwlock.raw as *const R as usize
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.rwlock.raw.unlock_exclusive();
+        0
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, _state: usize, _token: Option<usize>) {
+        self.rwlock.raw.lock_exclusive();
+    }
+}
+
 impl<'a, R: RawRwLock + 'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug for RwLockWriteGuard<'a, R, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
@@ -1150,6 +1474,7 @@ impl<'a, R: RawRwLockUpgrade + 'a, T: ?Sized + 'a> RwLockUpgradableReadGuard<'a,
 
     /// Atomically upgrades an upgradable read lock lock into a exclusive write lock,
     /// blocking the current thread until it can be acquired.
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn upgrade(s: Self) -> RwLockWriteGuard<'a, R, T> {
         s.rwlock.raw.upgrade();
         let rwlock = s.rwlock;
@@ -1163,6 +1488,7 @@ impl<'a, R: RawRwLockUpgrade + 'a, T: ?Sized + 'a> RwLockUpgradableReadGuard<'a,
     /// Tries to atomically upgrade an upgradable read lock into a exclusive write lock.
     ///
     /// If the access could not be granted at this time, then the current guard is returned.
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_upgrade(s: Self) -> Result<RwLockWriteGuard<'a, R, T>, Self> {
         if s.rwlock.raw.try_upgrade() {
             let rwlock = s.rwlock;
@@ -1248,6 +1574,7 @@ impl<'a, R: RawRwLockUpgradeTimed + 'a, T: ?Sized + 'a> RwLockUpgradableReadGuar
     ///
     /// If the access could not be granted before the timeout expires, then
     /// the current guard is returned.
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_upgrade_for(
         s: Self,
         timeout: R::Duration,
@@ -1270,6 +1597,7 @@ impl<'a, R: RawRwLockUpgradeTimed + 'a, T: ?Sized + 'a> RwLockUpgradableReadGuar
     /// If the access could not be granted before the timeout expires, then
     /// the current guard is returned.
     #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
     pub fn try_upgrade_until(
         s: Self,
         timeout: R::Instant,
@@ -1615,3 +1943,891 @@ unsafe impl<'a, R: RawRwLock + 'a, T: ?Sized + 'a> StableAddress
     for MappedRwLockWriteGuard<'a, R, T>
 {
 }
+
+/// An RAII rwlock guard returned by the `Arc` locking operations on `RwLock`.
+///
+/// This is similar to the `RwLockReadGuard` struct, except instead of using a
+/// reference to unlock the `RwLock` it uses an `Arc<RwLock>`. This has several
+/// advantages, most notably that it has a `'static` lifetime.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct ArcRwLockReadGuard<R: RawRwLock, T: ?Sized> {
+    rwlock: Arc<RwLock<R, T>>,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Send + Sync> Sync for ArcRwLockReadGuard<R, T> {}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> ArcRwLockReadGuard<R, T> {
+    /// Returns a reference to the rwlock, contained in its `Arc`.
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<R, T>> {
+        &s.rwlock
+    }
+
+    // Takes the `Arc` out of the guard without unlocking the `RwLock`.
+    #[inline]
+    fn into_arc(s: Self) -> Arc<RwLock<R, T>> {
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        rwlock
+    }
+
+    /// Makes a new `MappedArcRwLockReadGuard` for a component of the locked
+    /// data.
+    ///
+    /// This operation cannot fail as the `ArcRwLockReadGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ArcRwLockReadGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(s: Self, f: F) -> MappedArcRwLockReadGuard<R, T, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let data: *const U = f(unsafe { &*s.rwlock.data.get() });
+        MappedArcRwLockReadGuard {
+            rwlock: Self::into_arc(s),
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedArcRwLockReadGuard` for a component of
+    /// the locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This operation cannot fail as the `ArcRwLockReadGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ArcRwLockReadGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedArcRwLockReadGuard<R, T, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let data: *const U = match f(unsafe { &*s.rwlock.data.get() }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        Ok(MappedArcRwLockReadGuard {
+            rwlock: Self::into_arc(s),
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked` method on
+    /// `RwLockReadGuard`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.rwlock.raw.unlock_shared();
+        defer!(s.rwlock.raw.lock_shared());
+        f()
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockFair, T: ?Sized> ArcRwLockReadGuard<R, T> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `RwLockReadGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.rwlock.raw.unlock_shared_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        drop(Self::into_arc(s));
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked_fair` method on
+    /// `RwLockReadGuard`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.rwlock.raw.unlock_shared_fair();
+        defer!(s.rwlock.raw.lock_shared());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// This is functionally identical to the `bump` method on `RwLockReadGuard`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        s.rwlock.raw.bump_shared();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> Deref for ArcRwLockReadGuard<R, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> Drop for ArcRwLockReadGuard<R, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_shared();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: fmt::Debug + ?Sized> fmt::Debug for ArcRwLockReadGuard<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: fmt::Display + ?Sized> fmt::Display for ArcRwLockReadGuard<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawRwLock, T: ?Sized> StableAddress for ArcRwLockReadGuard<R, T> {}
+
+/// An RAII rwlock guard returned by the `Arc` locking operations on `RwLock`.
+///
+/// This is similar to the `RwLockWriteGuard` struct, except instead of using a
+/// reference to unlock the `RwLock` it uses an `Arc<RwLock>`. This has several
+/// advantages, most notably that it has a `'static` lifetime.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct ArcRwLockWriteGuard<R: RawRwLock, T: ?Sized> {
+    rwlock: Arc<RwLock<R, T>>,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Send + Sync> Sync for ArcRwLockWriteGuard<R, T> {}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> ArcRwLockWriteGuard<R, T> {
+    /// Returns a reference to the rwlock, contained in its `Arc`.
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<R, T>> {
+        &s.rwlock
+    }
+
+    // Takes the `Arc` out of the guard without unlocking the `RwLock`.
+    #[inline]
+    fn into_arc(s: Self) -> Arc<RwLock<R, T>> {
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        rwlock
+    }
+
+    /// Makes a new `MappedArcRwLockWriteGuard` for a component of the locked
+    /// data.
+    ///
+    /// This operation cannot fail as the `ArcRwLockWriteGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ArcRwLockWriteGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(s: Self, f: F) -> MappedArcRwLockWriteGuard<R, T, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let data: *mut U = f(unsafe { &mut *s.rwlock.data.get() });
+        MappedArcRwLockWriteGuard {
+            rwlock: Self::into_arc(s),
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedArcRwLockWriteGuard` for a component of
+    /// the locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This operation cannot fail as the `ArcRwLockWriteGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ArcRwLockWriteGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedArcRwLockWriteGuard<R, T, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let data: *mut U = match f(unsafe { &mut *s.rwlock.data.get() }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        Ok(MappedArcRwLockWriteGuard {
+            rwlock: Self::into_arc(s),
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked` method on
+    /// `RwLockWriteGuard`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.rwlock.raw.unlock_exclusive();
+        defer!(s.rwlock.raw.lock_exclusive());
+        f()
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockDowngrade, T: ?Sized> ArcRwLockWriteGuard<R, T> {
+    /// Atomically downgrades a write lock into a read lock without allowing any
+    /// writers to take exclusive access of the lock in the meantime.
+    ///
+    /// This is functionally identical to the `downgrade` method on
+    /// `RwLockWriteGuard`.
+    pub fn downgrade(s: Self) -> ArcRwLockReadGuard<R, T> {
+        s.rwlock.raw.downgrade();
+        ArcRwLockReadGuard {
+            rwlock: Self::into_arc(s),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgradeDowngrade, T: ?Sized> ArcRwLockWriteGuard<R, T> {
+    /// Atomically downgrades a write lock into an upgradable read lock without
+    /// allowing any writers to take exclusive access of the lock in the meantime.
+    ///
+    /// This is functionally identical to the `downgrade_to_upgradable` method
+    /// on `RwLockWriteGuard`.
+    pub fn downgrade_to_upgradable(s: Self) -> ArcRwLockUpgradableReadGuard<R, T> {
+        s.rwlock.raw.downgrade_to_upgradable();
+        ArcRwLockUpgradableReadGuard {
+            rwlock: Self::into_arc(s),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockFair, T: ?Sized> ArcRwLockWriteGuard<R, T> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `RwLockWriteGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.rwlock.raw.unlock_exclusive_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        drop(Self::into_arc(s));
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked_fair` method on
+    /// `RwLockWriteGuard`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.rwlock.raw.unlock_exclusive_fair();
+        defer!(s.rwlock.raw.lock_exclusive());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// This is functionally identical to the `bump` method on `RwLockWriteGuard`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        s.rwlock.raw.bump_exclusive();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> Deref for ArcRwLockWriteGuard<R, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> DerefMut for ArcRwLockWriteGuard<R, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.data.get() }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized> Drop for ArcRwLockWriteGuard<R, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_exclusive();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock, T: ?Sized> CondvarGuard for ArcRwLockWriteGuard<R, T> {
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        &self.rwlock.raw as *const R as usize
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.rwlock.raw.unlock_exclusive();
+        0
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, _state: usize, _token: Option<usize>) {
+        self.rwlock.raw.lock_exclusive();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: fmt::Debug + ?Sized> fmt::Debug for ArcRwLockWriteGuard<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: fmt::Display + ?Sized> fmt::Display for ArcRwLockWriteGuard<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawRwLock, T: ?Sized> StableAddress for ArcRwLockWriteGuard<R, T> {}
+
+/// An RAII rwlock guard returned by the `Arc` locking operations on `RwLock`.
+///
+/// This is similar to the `RwLockUpgradableReadGuard` struct, except instead of
+/// using a reference to unlock the `RwLock` it uses an `Arc<RwLock>`. This has
+/// several advantages, most notably that it has a `'static` lifetime.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct ArcRwLockUpgradableReadGuard<R: RawRwLockUpgrade, T: ?Sized> {
+    rwlock: Arc<RwLock<R, T>>,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLockUpgrade + Sync, T: ?Sized + Send + Sync> Sync
+    for ArcRwLockUpgradableReadGuard<R, T>
+{
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgrade, T: ?Sized> ArcRwLockUpgradableReadGuard<R, T> {
+    /// Returns a reference to the rwlock, contained in its `Arc`.
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<R, T>> {
+        &s.rwlock
+    }
+
+    // Takes the `Arc` out of the guard without unlocking the `RwLock`.
+    #[inline]
+    fn into_arc(s: Self) -> Arc<RwLock<R, T>> {
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        rwlock
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked` method on
+    /// `RwLockUpgradableReadGuard`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.rwlock.raw.unlock_upgradable();
+        defer!(s.rwlock.raw.lock_upgradable());
+        f()
+    }
+
+    /// Atomically upgrades an upgradable read lock lock into a exclusive write lock,
+    /// blocking the current thread until it can be acquired.
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn upgrade(s: Self) -> ArcRwLockWriteGuard<R, T> {
+        s.rwlock.raw.upgrade();
+        ArcRwLockWriteGuard {
+            rwlock: Self::into_arc(s),
+            marker: PhantomData,
+        }
+    }
+
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive write lock.
+    ///
+    /// If the access could not be granted at this time, then the current guard is returned.
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_upgrade(s: Self) -> Result<ArcRwLockWriteGuard<R, T>, Self> {
+        if s.rwlock.raw.try_upgrade() {
+            Ok(ArcRwLockWriteGuard {
+                rwlock: Self::into_arc(s),
+                marker: PhantomData,
+            })
+        } else {
+            Err(s)
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgradeFair, T: ?Sized> ArcRwLockUpgradableReadGuard<R, T> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `RwLockUpgradableReadGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.rwlock.raw.unlock_upgradable_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        drop(Self::into_arc(s));
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// This is functionally identical to the `unlocked_fair` method on
+    /// `RwLockUpgradableReadGuard`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        s.rwlock.raw.unlock_upgradable_fair();
+        defer!(s.rwlock.raw.lock_upgradable());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// This is functionally identical to the `bump` method on `RwLockUpgradableReadGuard`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        s.rwlock.raw.bump_upgradable();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgradeDowngrade, T: ?Sized> ArcRwLockUpgradableReadGuard<R, T> {
+    /// Atomically downgrades an upgradable read lock lock into a shared read lock
+    /// without allowing any writers to take exclusive access of the lock in the
+    /// meantime.
+    ///
+    /// This is functionally identical to the `downgrade` method on
+    /// `RwLockUpgradableReadGuard`.
+    pub fn downgrade(s: Self) -> ArcRwLockReadGuard<R, T> {
+        s.rwlock.raw.downgrade_upgradable();
+        ArcRwLockReadGuard {
+            rwlock: Self::into_arc(s),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgradeTimed, T: ?Sized> ArcRwLockUpgradableReadGuard<R, T> {
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive
+    /// write lock, until a timeout is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// the current guard is returned.
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_upgrade_for(
+        s: Self,
+        timeout: R::Duration,
+    ) -> Result<ArcRwLockWriteGuard<R, T>, Self> {
+        if s.rwlock.raw.try_upgrade_for(timeout) {
+            Ok(ArcRwLockWriteGuard {
+                rwlock: Self::into_arc(s),
+                marker: PhantomData,
+            })
+        } else {
+            Err(s)
+        }
+    }
+
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive
+    /// write lock, until a timeout is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// the current guard is returned.
+    #[inline]
+    #[cfg_attr(feature = "track_caller", track_caller)]
+    pub fn try_upgrade_until(
+        s: Self,
+        timeout: R::Instant,
+    ) -> Result<ArcRwLockWriteGuard<R, T>, Self> {
+        if s.rwlock.raw.try_upgrade_until(timeout) {
+            Ok(ArcRwLockWriteGuard {
+                rwlock: Self::into_arc(s),
+                marker: PhantomData,
+            })
+        } else {
+            Err(s)
+        }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgrade, T: ?Sized> Deref for ArcRwLockUpgradableReadGuard<R, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgrade, T: ?Sized> Drop for ArcRwLockUpgradableReadGuard<R, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_upgradable();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgrade, T: fmt::Debug + ?Sized> fmt::Debug
+    for ArcRwLockUpgradableReadGuard<R, T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockUpgrade, T: fmt::Display + ?Sized> fmt::Display
+    for ArcRwLockUpgradableReadGuard<R, T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawRwLockUpgrade, T: ?Sized> StableAddress for ArcRwLockUpgradableReadGuard<R, T> {}
+
+/// An RAII read lock guard returned by `ArcRwLockReadGuard::map`, which can
+/// point to a subfield of the protected data.
+///
+/// The main difference between `MappedArcRwLockReadGuard` and `ArcRwLockReadGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by
+/// another thread.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct MappedArcRwLockReadGuard<R: RawRwLock, T: ?Sized, U: ?Sized> {
+    rwlock: Arc<RwLock<R, T>>,
+    data: *const U,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock + Send + Sync, T: ?Sized + Send + Sync, U: ?Sized + Sync> Send
+    for MappedArcRwLockReadGuard<R, T, U>
+where
+    R::GuardMarker: Send,
+{
+}
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Send + Sync, U: ?Sized + Sync> Sync
+    for MappedArcRwLockReadGuard<R, T, U>
+{
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> MappedArcRwLockReadGuard<R, T, U> {
+    /// Returns a reference to the rwlock, contained in its `Arc`.
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<R, T>> {
+        &s.rwlock
+    }
+
+    /// Make a new `MappedArcRwLockReadGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedArcRwLockReadGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedArcRwLockReadGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<V: ?Sized, F>(s: Self, f: F) -> MappedArcRwLockReadGuard<R, T, V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        let data: *const V = f(unsafe { &*s.data });
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        MappedArcRwLockReadGuard {
+            rwlock,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedArcRwLockReadGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns `None`.
+    ///
+    /// This operation cannot fail as the `MappedArcRwLockReadGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedArcRwLockReadGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<V: ?Sized, F>(s: Self, f: F) -> Result<MappedArcRwLockReadGuard<R, T, V>, Self>
+    where
+        F: FnOnce(&U) -> Option<&V>,
+    {
+        let data: *const V = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        Ok(MappedArcRwLockReadGuard {
+            rwlock,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockFair, T: ?Sized, U: ?Sized> MappedArcRwLockReadGuard<R, T, U> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `RwLockReadGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.rwlock.raw.unlock_shared_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        drop(rwlock);
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> Deref for MappedArcRwLockReadGuard<R, T, U> {
+    type Target = U;
+    #[inline]
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> Drop for MappedArcRwLockReadGuard<R, T, U> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_shared();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: fmt::Debug + ?Sized> fmt::Debug
+    for MappedArcRwLockReadGuard<R, T, U>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: fmt::Display + ?Sized> fmt::Display
+    for MappedArcRwLockReadGuard<R, T, U>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawRwLock, T: ?Sized, U: ?Sized> StableAddress
+    for MappedArcRwLockReadGuard<R, T, U>
+{
+}
+
+/// An RAII write lock guard returned by `ArcRwLockWriteGuard::map`, which can
+/// point to a subfield of the protected data.
+///
+/// The main difference between `MappedArcRwLockWriteGuard` and `ArcRwLockWriteGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by
+/// another thread.
+#[cfg(feature = "arc_lock")]
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct MappedArcRwLockWriteGuard<R: RawRwLock, T: ?Sized, U: ?Sized> {
+    rwlock: Arc<RwLock<R, T>>,
+    data: *mut U,
+    marker: PhantomData<R::GuardMarker>,
+}
+
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock + Send + Sync, T: ?Sized + Send + Sync, U: ?Sized + Send> Send
+    for MappedArcRwLockWriteGuard<R, T, U>
+where
+    R::GuardMarker: Send,
+{
+}
+#[cfg(feature = "arc_lock")]
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Send + Sync, U: ?Sized + Sync> Sync
+    for MappedArcRwLockWriteGuard<R, T, U>
+{
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> MappedArcRwLockWriteGuard<R, T, U> {
+    /// Returns a reference to the rwlock, contained in its `Arc`.
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<R, T>> {
+        &s.rwlock
+    }
+
+    /// Make a new `MappedArcRwLockWriteGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedArcRwLockWriteGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedArcRwLockWriteGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<V: ?Sized, F>(s: Self, f: F) -> MappedArcRwLockWriteGuard<R, T, V>
+    where
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        let data: *mut V = f(unsafe { &mut *s.data });
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        MappedArcRwLockWriteGuard {
+            rwlock,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedArcRwLockWriteGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns `None`.
+    ///
+    /// This operation cannot fail as the `MappedArcRwLockWriteGuard` passed
+    /// in already locked the data.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedArcRwLockWriteGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<V: ?Sized, F>(s: Self, f: F) -> Result<MappedArcRwLockWriteGuard<R, T, V>, Self>
+    where
+        F: FnOnce(&mut U) -> Option<&mut V>,
+    {
+        let data: *mut V = match f(unsafe { &mut *s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        Ok(MappedArcRwLockWriteGuard {
+            rwlock,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLockFair, T: ?Sized, U: ?Sized> MappedArcRwLockWriteGuard<R, T, U> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// This is functionally identical to the `unlock_fair` method on
+    /// `RwLockWriteGuard`.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.rwlock.raw.unlock_exclusive_fair();
+        // Release the Arc without running the unlocking Drop impl.
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        drop(rwlock);
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> Deref for MappedArcRwLockWriteGuard<R, T, U> {
+    type Target = U;
+    #[inline]
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> DerefMut for MappedArcRwLockWriteGuard<R, T, U> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.data }
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: ?Sized> Drop for MappedArcRwLockWriteGuard<R, T, U> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_exclusive();
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: fmt::Debug + ?Sized> fmt::Debug
+    for MappedArcRwLockWriteGuard<R, T, U>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "arc_lock")]
+impl<R: RawRwLock, T: ?Sized, U: fmt::Display + ?Sized> fmt::Display
+    for MappedArcRwLockWriteGuard<R, T, U>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(all(feature = "arc_lock", feature = "owning_ref"))]
+unsafe impl<R: RawRwLock, T: ?Sized, U: ?Sized> StableAddress
+    for MappedArcRwLockWriteGuard<R, T, U>
+{
+}
@@ -0,0 +1,291 @@
+// Copyright 2018 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+
+/// Basic operations for a counting semaphore.
+///
+/// Types implementing this trait can be used by `Semaphore` to form a safe and
+/// fully-functioning semaphore type.
+///
+/// # Safety
+///
+/// Implementations of this trait must ensure that permits are actually
+/// counted: the total number of permits held by all threads can never exceed
+/// the number of permits that the semaphore was created with plus the number of
+/// permits released into it.
+pub unsafe trait RawSemaphore {
+    /// Marker type which determines whether a permit should be `Send`. Use
+    /// one of the `GuardSend` or `GuardNoSend` helper types here.
+    type GuardMarker;
+
+    /// Creates a new semaphore with the given number of available permits.
+    fn new(permits: usize) -> Self;
+
+    /// Acquires `n` permits from this semaphore, blocking the current thread
+    /// until they are available.
+    fn acquire(&self, n: usize);
+
+    /// Attempts to acquire `n` permits from this semaphore without blocking.
+    /// Returns `true` if the permits were successfully acquired and `false`
+    /// otherwise.
+    fn try_acquire(&self, n: usize) -> bool;
+
+    /// Releases `n` permits back into this semaphore.
+    fn release(&self, n: usize);
+
+    /// Returns the number of permits which are currently available.
+    fn available_permits(&self) -> usize;
+}
+
+/// Additional methods for semaphores which support acquiring permits with
+/// timeouts.
+///
+/// The `Duration` and `Instant` types are specified as associated types so that
+/// this trait is usable even in `no_std` environments.
+///
+/// # Safety
+///
+/// The same requirements as for `RawSemaphore` apply to the permits acquired
+/// by the timed methods.
+pub unsafe trait RawSemaphoreTimed: RawSemaphore {
+    /// Duration type used for `try_acquire_for`.
+    type Duration;
+
+    /// Instant type used for `try_acquire_until`.
+    type Instant;
+
+    /// Attempts to acquire `n` permits until a timeout is reached.
+    fn try_acquire_for(&self, n: usize, timeout: Self::Duration) -> bool;
+
+    /// Attempts to acquire `n` permits until a timeout is reached.
+    fn try_acquire_until(&self, n: usize, timeout: Self::Instant) -> bool;
+}
+
+/// A counting semaphore.
+///
+/// A semaphore maintains a number of permits. Threads can acquire one or more
+/// permits, blocking until enough permits are available, and the permits are
+/// released back into the semaphore when the RAII `SemaphorePermit` returned
+/// from an acquire operation is dropped.
+pub struct Semaphore<R: RawSemaphore> {
+    raw: R,
+}
+
+unsafe impl<R: RawSemaphore + Send> Send for Semaphore<R> {}
+unsafe impl<R: RawSemaphore + Sync> Sync for Semaphore<R> {}
+
+impl<R: RawSemaphore> Semaphore<R> {
+    /// Creates a new semaphore with the given number of available permits.
+    #[inline]
+    pub fn new(permits: usize) -> Semaphore<R> {
+        Semaphore {
+            raw: R::new(permits),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The given number of permits must have been acquired.
+    #[inline]
+    unsafe fn permit(&self, permits: usize) -> SemaphorePermit<'_, R> {
+        SemaphorePermit {
+            semaphore: self,
+            permits,
+            marker: PhantomData,
+        }
+    }
+
+    /// Acquires a single permit, blocking the current thread until one is
+    /// available.
+    ///
+    /// An RAII permit is returned which releases the permit back into the
+    /// semaphore when it is dropped.
+    #[inline]
+    pub fn acquire(&self) -> SemaphorePermit<'_, R> {
+        self.acquire_many(1)
+    }
+
+    /// Acquires `n` permits at once, blocking the current thread until they are
+    /// all available.
+    ///
+    /// An RAII permit is returned which releases all `n` permits back into the
+    /// semaphore when it is dropped.
+    #[inline]
+    pub fn acquire_many(&self, n: usize) -> SemaphorePermit<'_, R> {
+        self.raw.acquire(n);
+        // SAFETY: The permits have been acquired, as required.
+        unsafe { self.permit(n) }
+    }
+
+    /// Attempts to acquire a single permit.
+    ///
+    /// If no permit is available at this time, then `None` is returned.
+    /// Otherwise, an RAII permit is returned.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_, R>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Attempts to acquire `n` permits at once.
+    ///
+    /// If the permits are not available at this time, then `None` is returned.
+    /// Otherwise, an RAII permit is returned.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit<'_, R>> {
+        if self.raw.try_acquire(n) {
+            // SAFETY: The permits have been acquired, as required.
+            Some(unsafe { self.permit(n) })
+        } else {
+            None
+        }
+    }
+
+    /// Adds `n` new permits to the semaphore, waking up waiting threads if
+    /// enough permits are now available for them.
+    #[inline]
+    pub fn add_permits(&self, n: usize) {
+        self.raw.release(n);
+    }
+
+    /// Returns the number of permits which are currently available.
+    #[inline]
+    pub fn available_permits(&self) -> usize {
+        self.raw.available_permits()
+    }
+
+    /// Returns the underlying raw semaphore object.
+    ///
+    /// Note that you will most likely need to import the `RawSemaphore` trait
+    /// from `lock_api` to be able to call functions on the raw semaphore.
+    ///
+    /// # Safety
+    ///
+    /// This method is unsafe because it allows releasing permits which are
+    /// still held by a `SemaphorePermit`.
+    #[inline]
+    pub unsafe fn raw(&self) -> &R {
+        &self.raw
+    }
+}
+
+impl<R: RawSemaphoreTimed> Semaphore<R> {
+    /// Attempts to acquire a single permit until a timeout is reached.
+    ///
+    /// If no permit could be acquired before the timeout expired, then `None`
+    /// is returned. Otherwise, an RAII permit is returned.
+    #[inline]
+    pub fn try_acquire_for(&self, timeout: R::Duration) -> Option<SemaphorePermit<'_, R>> {
+        self.try_acquire_many_for(1, timeout)
+    }
+
+    /// Attempts to acquire a single permit until a timeout is reached.
+    ///
+    /// If no permit could be acquired before the timeout expired, then `None`
+    /// is returned. Otherwise, an RAII permit is returned.
+    #[inline]
+    pub fn try_acquire_until(&self, timeout: R::Instant) -> Option<SemaphorePermit<'_, R>> {
+        self.try_acquire_many_until(1, timeout)
+    }
+
+    /// Attempts to acquire `n` permits at once until a timeout is reached.
+    ///
+    /// If the permits could not be acquired before the timeout expired, then
+    /// `None` is returned. Otherwise, an RAII permit is returned.
+    #[inline]
+    pub fn try_acquire_many_for(
+        &self,
+        n: usize,
+        timeout: R::Duration,
+    ) -> Option<SemaphorePermit<'_, R>> {
+        if self.raw.try_acquire_for(n, timeout) {
+            // SAFETY: The permits have been acquired, as required.
+            Some(unsafe { self.permit(n) })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire `n` permits at once until a timeout is reached.
+    ///
+    /// If the permits could not be acquired before the timeout expired, then
+    /// `None` is returned. Otherwise, an RAII permit is returned.
+    #[inline]
+    pub fn try_acquire_many_until(
+        &self,
+        n: usize,
+        timeout: R::Instant,
+    ) -> Option<SemaphorePermit<'_, R>> {
+        if self.raw.try_acquire_until(n, timeout) {
+            // SAFETY: The permits have been acquired, as required.
+            Some(unsafe { self.permit(n) })
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: RawSemaphore> fmt::Debug for Semaphore<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("available_permits", &self.available_permits())
+            .finish()
+    }
+}
+
+/// An RAII implementation of one or more permits acquired from a semaphore.
+/// When this structure is dropped (falls out of scope), the permits are
+/// released back into the semaphore.
+#[must_use = "if unused the permits will immediately be released"]
+pub struct SemaphorePermit<'a, R: RawSemaphore> {
+    semaphore: &'a Semaphore<R>,
+    permits: usize,
+    marker: PhantomData<(&'a Semaphore<R>, R::GuardMarker)>,
+}
+
+unsafe impl<'a, R: RawSemaphore + Sync + 'a> Sync for SemaphorePermit<'a, R> {}
+
+impl<'a, R: RawSemaphore + 'a> SemaphorePermit<'a, R> {
+    /// Returns a reference to the original `Semaphore` object.
+    pub fn semaphore(s: &Self) -> &'a Semaphore<R> {
+        s.semaphore
+    }
+
+    /// Returns the number of permits held by this `SemaphorePermit`.
+    pub fn num_permits(s: &Self) -> usize {
+        s.permits
+    }
+
+    /// Forgets the permits without releasing them back into the semaphore.
+    ///
+    /// This permanently reduces the number of permits in the semaphore.
+    #[inline]
+    pub fn forget(s: Self) {
+        mem::forget(s);
+    }
+}
+
+impl<'a, R: RawSemaphore + 'a> Drop for SemaphorePermit<'a, R> {
+    #[inline]
+    fn drop(&mut self) {
+        self.semaphore.raw.release(self.permits);
+    }
+}
+
+impl<'a, R: RawSemaphore + 'a> fmt::Debug for SemaphorePermit<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2018 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/// Additional methods for raw locks which collect contention statistics.
+///
+/// This can be implemented by both mutexes and reader-writer locks. The
+/// `Mutex`, `ReentrantMutex` and `RwLock` wrappers expose these as `stats`
+/// and `reset_stats` methods.
+///
+/// The statistics type is specified as an associated type so that each lock
+/// implementation can choose what it records.
+pub trait RawLockStats {
+    /// Snapshot of the statistics collected for a lock.
+    type Stats;
+
+    /// Returns a snapshot of the statistics collected for this lock so far.
+    ///
+    /// The statistics are updated concurrently by threads using the lock, so
+    /// the individual counters are not guaranteed to be consistent with each
+    /// other.
+    fn stats(&self) -> Self::Stats;
+
+    /// Resets all statistics collected for this lock.
+    fn reset_stats(&self);
+}
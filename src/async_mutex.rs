@@ -0,0 +1,419 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_mutex::{RawMutex, TaskParkFuture};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use lock_api::RawMutex as RawMutex_;
+
+/// A mutual exclusion primitive for asynchronous code.
+///
+/// This mutex uses the same lock state and queueing as `Mutex`, but waiting
+/// for the lock suspends the current task instead of blocking the current
+/// thread. This makes it possible to hold the lock across `.await` points.
+///
+/// Tasks waiting for the lock are queued in the parking lot in FIFO order and
+/// benefit from the same [eventual fairness](https://trac.webkit.org/changeset/203350)
+/// as `Mutex`: on average every 0.5ms the lock is handed off directly to the
+/// next waiting task. You can also force a fair unlock by calling
+/// `AsyncMutexGuard::unlock_fair`.
+///
+/// Dropping the future returned by `lock` before it completes is always safe:
+/// the task is removed from the queue and, if the lock had already been handed
+/// off to it, the lock is released again.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::AsyncMutex;
+///
+/// async fn increment(counter: &AsyncMutex<u32>) {
+///     let mut guard = counter.lock().await;
+///     *guard += 1;
+/// }
+/// ```
+pub struct AsyncMutex<T: ?Sized> {
+    raw: RawMutex,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> AsyncMutex<T> {
+        AsyncMutex {
+            raw: RawMutex::INIT,
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Creates a new mutex in an unlocked state ready for use.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> AsyncMutex<T> {
+        AsyncMutex {
+            raw: RawMutex::INIT,
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn guard(&self) -> AsyncMutexGuard<'_, T> {
+        AsyncMutexGuard {
+            mutex: self,
+            marker: PhantomData,
+        }
+    }
+
+    /// Acquires the mutex, suspending the current task until it is able to do
+    /// so.
+    ///
+    /// The returned future resolves to an RAII guard once the lock has been
+    /// acquired. When the guard goes out of scope, the mutex will be unlocked.
+    #[inline]
+    pub fn lock(&self) -> AsyncMutexLockFuture<'_, T> {
+        AsyncMutexLockFuture {
+            mutex: self,
+            park: None,
+        }
+    }
+
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        if self.raw.try_lock_task() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.guard() })
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the mutex is currently locked.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `AsyncMutex` mutably, no actual locking
+    /// needs to take place---the mutable borrow statically guarantees no locks
+    /// exist.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for AsyncMutex<T> {
+    #[inline]
+    fn default() -> AsyncMutex<T> {
+        AsyncMutex::new(Default::default())
+    }
+}
+
+impl<T> From<T> for AsyncMutex<T> {
+    #[inline]
+    fn from(t: T) -> AsyncMutex<T> {
+        AsyncMutex::new(t)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("AsyncMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => {
+                struct LockedPlaceholder;
+                impl fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("AsyncMutex")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+/// A future which resolves to an `AsyncMutexGuard` once the mutex has been
+/// acquired.
+///
+/// This is returned by `AsyncMutex::lock`.
+#[must_use = "futures do nothing unless polled"]
+pub struct AsyncMutexLockFuture<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+    park: Option<TaskParkFuture>,
+}
+
+impl<'a, T: ?Sized> Future for AsyncMutexLockFuture<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<AsyncMutexGuard<'a, T>> {
+        let this = self.get_mut();
+        match this.mutex.raw.poll_lock_task(&mut this.park, cx) {
+            // SAFETY: The lock is held, as required.
+            Poll::Ready(()) => Poll::Ready(unsafe { this.mutex.guard() }),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for AsyncMutexLockFuture<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.raw.cancel_lock_task(&mut self.park);
+    }
+}
+
+impl<'a, T: ?Sized> fmt::Debug for AsyncMutexLockFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncMutexLockFuture")
+            .field("parked", &self.park.is_some())
+            .finish()
+    }
+}
+
+/// An RAII implementation of a "scoped lock" of an `AsyncMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked.
+///
+/// Unlike `MutexGuard`, this guard can be sent to another thread, which allows
+/// it to be held across `.await` points in multi-threaded executors.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+#[must_use = "if unused the AsyncMutex will immediately unlock"]
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for AsyncMutexGuard<'a, T> {}
+
+impl<'a, T: ?Sized + 'a> AsyncMutexGuard<'a, T> {
+    /// Returns a reference to the original `AsyncMutex` object.
+    pub fn mutex(s: &Self) -> &'a AsyncMutex<T> {
+        s.mutex
+    }
+
+    /// Unlocks the mutex using a fair unlock protocol.
+    ///
+    /// By default, mutexes are unfair and allow the current task to re-lock
+    /// the mutex before another has the chance to acquire the lock, even if
+    /// that task has been parked for a long time. This is the default because
+    /// it allows much higher throughput as it avoids forcing a context switch
+    /// on every mutex unlock. This can result in one task acquiring a mutex
+    /// many more times than other tasks.
+    ///
+    /// However in some cases it can be beneficial to ensure fairness by forcing
+    /// the lock to pass on to a waiting task if there is one. This is done by
+    /// using this method instead of dropping the `AsyncMutexGuard` normally.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.mutex.raw.unlock_task(true);
+        mem::forget(s);
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for AsyncMutexGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncMutexGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.raw.unlock_task(false);
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug for AsyncMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized + 'a> fmt::Display for AsyncMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::block_on;
+    use crate::AsyncMutex;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::thread;
+
+    fn noop_context<R>(f: impl FnOnce(&mut Context<'_>) -> R) -> R {
+        let waker = crate::util::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        f(&mut cx)
+    }
+
+    #[test]
+    fn smoke() {
+        let m = AsyncMutex::new(());
+        drop(block_on(m.lock()));
+        drop(block_on(m.lock()));
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(AsyncMutex::new(0));
+        let mut threads = Vec::new();
+        for _ in 0..K * 2 {
+            let m = m.clone();
+            threads.push(thread::spawn(move || {
+                for _ in 0..J {
+                    *block_on(m.lock()) += 1;
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*block_on(m.lock()), J * K * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = AsyncMutex::new(());
+        let guard = m.try_lock().unwrap();
+        assert!(m.is_locked());
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn lock_waits_for_unlock() {
+        let m = AsyncMutex::new(1);
+        let guard = m.try_lock().unwrap();
+        let mut future = m.lock();
+        noop_context(|cx| assert!(Pin::new(&mut future).poll(cx).is_pending()));
+        drop(guard);
+        let guard = noop_context(|cx| match Pin::new(&mut future).poll(cx) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("lock not acquired after unlock"),
+        });
+        assert_eq!(*guard, 1);
+    }
+
+    #[test]
+    fn cancel_after_handoff() {
+        let m = AsyncMutex::new(());
+        let guard = m.try_lock().unwrap();
+        let mut future = m.lock();
+        noop_context(|cx| assert!(Pin::new(&mut future).poll(cx).is_pending()));
+
+        // Hand the lock off to the parked task, then drop its future without
+        // polling it again. This must release the lock.
+        crate::AsyncMutexGuard::unlock_fair(guard);
+        assert!(m.is_locked());
+        drop(future);
+        assert!(!m.is_locked());
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn cancel_while_parked() {
+        let m = AsyncMutex::new(());
+        let guard = m.try_lock().unwrap();
+        let mut future = m.lock();
+        noop_context(|cx| assert!(Pin::new(&mut future).poll(cx).is_pending()));
+        drop(future);
+        drop(guard);
+        assert!(!m.is_locked());
+        drop(block_on(m.lock()));
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = AsyncMutex::new(10);
+        assert_eq!(m.into_inner(), 10);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = AsyncMutex::new(10);
+        *m.get_mut() = 20;
+        assert_eq!(m.into_inner(), 20);
+    }
+
+    #[test]
+    fn test_guard_send() {
+        fn send<T: Send>(_: T) {}
+
+        let m = AsyncMutex::new(());
+        send(m.lock());
+        send(m.try_lock().unwrap());
+    }
+
+    #[test]
+    fn test_mutex_debug() {
+        let mutex = AsyncMutex::new(vec![0u8, 10]);
+
+        assert_eq!(format!("{:?}", mutex), "AsyncMutex { data: [0, 10] }");
+        let _lock = mutex.try_lock().unwrap();
+        assert_eq!(format!("{:?}", mutex), "AsyncMutex { data: <locked> }");
+    }
+}
@@ -0,0 +1,548 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_mutex::TaskParkFuture;
+use crate::raw_rwlock::RawRwLock;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use lock_api::RawRwLock as RawRwLock_;
+
+/// A reader-writer lock for asynchronous code.
+///
+/// This lock uses the same lock state and queueing as `RwLock`, but waiting
+/// for the lock suspends the current task instead of blocking the current
+/// thread. This makes it possible to hold the lock across `.await` points.
+///
+/// Like `RwLock`, this lock is task-fair: readers will wait if there is a
+/// pending writer, and waiting tasks are woken up in the order in which they
+/// started waiting, alternating between read phases and write phases.
+///
+/// Dropping a future returned by `read` or `write` before it completes is
+/// always safe: the task is removed from the queue and any lock that had
+/// already been handed off to it is released again.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::AsyncRwLock;
+///
+/// async fn update(lock: &AsyncRwLock<Vec<u32>>) {
+///     let len = lock.read().await.len();
+///     lock.write().await.push(len as u32);
+/// }
+/// ```
+pub struct AsyncRwLock<T: ?Sized> {
+    raw: RawRwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for AsyncRwLock<T> {}
+
+impl<T> AsyncRwLock<T> {
+    /// Creates a new instance of an `AsyncRwLock<T>` which is unlocked.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> AsyncRwLock<T> {
+        AsyncRwLock {
+            raw: RawRwLock::INIT,
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Creates a new instance of an `AsyncRwLock<T>` which is unlocked.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> AsyncRwLock<T> {
+        AsyncRwLock {
+            raw: RawRwLock::INIT,
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Consumes this `AsyncRwLock`, returning the underlying data.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncRwLock<T> {
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn read_guard(&self) -> AsyncRwLockReadGuard<'_, T> {
+        AsyncRwLockReadGuard {
+            rwlock: self,
+            marker: PhantomData,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The lock must be held when calling this method.
+    #[inline]
+    unsafe fn write_guard(&self) -> AsyncRwLockWriteGuard<'_, T> {
+        AsyncRwLockWriteGuard {
+            rwlock: self,
+            marker: PhantomData,
+        }
+    }
+
+    /// Locks this rwlock with shared read access, suspending the current task
+    /// until it can be acquired.
+    ///
+    /// The returned future resolves to an RAII guard once the lock has been
+    /// acquired. Multiple tasks and threads may hold shared access at the same
+    /// time, but a task will wait if there is a pending writer.
+    #[inline]
+    pub fn read(&self) -> AsyncRwLockReadFuture<'_, T> {
+        AsyncRwLockReadFuture {
+            rwlock: self,
+            park: None,
+        }
+    }
+
+    /// Attempts to acquire this rwlock with shared read access.
+    ///
+    /// If the access could not be granted at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned which will release the shared access
+    /// when it is dropped.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_read(&self) -> Option<AsyncRwLockReadGuard<'_, T>> {
+        if self.raw.try_lock_shared_task() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.read_guard() })
+        } else {
+            None
+        }
+    }
+
+    /// Locks this rwlock with exclusive write access, suspending the current
+    /// task until it can be acquired.
+    ///
+    /// The returned future resolves to an RAII guard once the lock has been
+    /// acquired. When the guard goes out of scope, the lock will be released.
+    #[inline]
+    pub fn write(&self) -> AsyncRwLockWriteFuture<'_, T> {
+        AsyncRwLockWriteFuture {
+            rwlock: self,
+            park: None,
+            writer: false,
+        }
+    }
+
+    /// Attempts to lock this rwlock with exclusive write access.
+    ///
+    /// If the lock could not be acquired at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned which will release the lock when
+    /// it is dropped.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_write(&self) -> Option<AsyncRwLockWriteGuard<'_, T>> {
+        if self.raw.try_lock_exclusive_task() {
+            // SAFETY: The lock is held, as required.
+            Some(unsafe { self.write_guard() })
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether this `AsyncRwLock` is currently locked in any way.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `AsyncRwLock` mutably, no actual locking
+    /// needs to take place---the mutable borrow statically guarantees no locks
+    /// exist.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for AsyncRwLock<T> {
+    #[inline]
+    fn default() -> AsyncRwLock<T> {
+        AsyncRwLock::new(Default::default())
+    }
+}
+
+impl<T> From<T> for AsyncRwLock<T> {
+    #[inline]
+    fn from(t: T) -> AsyncRwLock<T> {
+        AsyncRwLock::new(t)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AsyncRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f
+                .debug_struct("AsyncRwLock")
+                .field("data", &&*guard)
+                .finish(),
+            None => {
+                struct LockedPlaceholder;
+                impl fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("AsyncRwLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+/// A future which resolves to an `AsyncRwLockReadGuard` once shared access
+/// has been acquired.
+///
+/// This is returned by `AsyncRwLock::read`.
+#[must_use = "futures do nothing unless polled"]
+pub struct AsyncRwLockReadFuture<'a, T: ?Sized> {
+    rwlock: &'a AsyncRwLock<T>,
+    park: Option<TaskParkFuture>,
+}
+
+impl<'a, T: ?Sized> Future for AsyncRwLockReadFuture<'a, T> {
+    type Output = AsyncRwLockReadGuard<'a, T>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<AsyncRwLockReadGuard<'a, T>> {
+        let this = self.get_mut();
+        match this.rwlock.raw.poll_lock_shared_task(&mut this.park, cx) {
+            // SAFETY: The lock is held, as required.
+            Poll::Ready(()) => Poll::Ready(unsafe { this.rwlock.read_guard() }),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for AsyncRwLockReadFuture<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.cancel_lock_shared_task(&mut self.park);
+    }
+}
+
+impl<'a, T: ?Sized> fmt::Debug for AsyncRwLockReadFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncRwLockReadFuture")
+            .field("parked", &self.park.is_some())
+            .finish()
+    }
+}
+
+/// A future which resolves to an `AsyncRwLockWriteGuard` once exclusive
+/// access has been acquired.
+///
+/// This is returned by `AsyncRwLock::write`.
+#[must_use = "futures do nothing unless polled"]
+pub struct AsyncRwLockWriteFuture<'a, T: ?Sized> {
+    rwlock: &'a AsyncRwLock<T>,
+    park: Option<TaskParkFuture>,
+    writer: bool,
+}
+
+impl<'a, T: ?Sized> Future for AsyncRwLockWriteFuture<'a, T> {
+    type Output = AsyncRwLockWriteGuard<'a, T>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<AsyncRwLockWriteGuard<'a, T>> {
+        let this = self.get_mut();
+        match this
+            .rwlock
+            .raw
+            .poll_lock_exclusive_task(&mut this.park, &mut this.writer, cx)
+        {
+            Poll::Ready(()) => {
+                // The lock now belongs to the guard
+                this.writer = false;
+                // SAFETY: The lock is held, as required.
+                Poll::Ready(unsafe { this.rwlock.write_guard() })
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for AsyncRwLockWriteFuture<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock
+            .raw
+            .cancel_lock_exclusive_task(&mut self.park, self.writer);
+    }
+}
+
+impl<'a, T: ?Sized> fmt::Debug for AsyncRwLockWriteFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncRwLockWriteFuture")
+            .field("parked", &self.park.is_some())
+            .finish()
+    }
+}
+
+/// RAII structure used to release the shared read access of an `AsyncRwLock`
+/// when dropped.
+#[must_use = "if unused the AsyncRwLock will immediately unlock"]
+pub struct AsyncRwLockReadGuard<'a, T: ?Sized> {
+    rwlock: &'a AsyncRwLock<T>,
+    marker: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for AsyncRwLockReadGuard<'a, T> {}
+
+impl<'a, T: ?Sized + 'a> AsyncRwLockReadGuard<'a, T> {
+    /// Returns a reference to the original `AsyncRwLock` object.
+    pub fn rwlock(s: &Self) -> &'a AsyncRwLock<T> {
+        s.rwlock
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncRwLockReadGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncRwLockReadGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_shared_task();
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug for AsyncRwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized + 'a> fmt::Display for AsyncRwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// RAII structure used to release the exclusive write access of an
+/// `AsyncRwLock` when dropped.
+#[must_use = "if unused the AsyncRwLock will immediately unlock"]
+pub struct AsyncRwLockWriteGuard<'a, T: ?Sized> {
+    rwlock: &'a AsyncRwLock<T>,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for AsyncRwLockWriteGuard<'a, T> {}
+
+impl<'a, T: ?Sized + 'a> AsyncRwLockWriteGuard<'a, T> {
+    /// Returns a reference to the original `AsyncRwLock` object.
+    pub fn rwlock(s: &Self) -> &'a AsyncRwLock<T> {
+        s.rwlock
+    }
+
+    /// Unlocks the `AsyncRwLock` using a fair unlock protocol.
+    ///
+    /// By default, `AsyncRwLock` is unfair and allows the current task to
+    /// re-lock the `AsyncRwLock` before another has the chance to acquire the
+    /// lock, even if that task has been parked for a long time. This is the
+    /// default because it allows much higher throughput as it avoids forcing a
+    /// context switch on every unlock.
+    ///
+    /// However in some cases it can be beneficial to ensure fairness by forcing
+    /// the lock to pass on to a waiting task if there is one. This is done by
+    /// using this method instead of dropping the `AsyncRwLockWriteGuard`
+    /// normally.
+    #[inline]
+    pub fn unlock_fair(s: Self) {
+        s.rwlock.raw.unlock_exclusive_task(true);
+        mem::forget(s);
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncRwLockWriteGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for AsyncRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.unlock_exclusive_task(false);
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized + 'a> fmt::Debug for AsyncRwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized + 'a> fmt::Display for AsyncRwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{block_on, noop_waker};
+    use crate::AsyncRwLock;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::Context;
+    use std::thread;
+
+    #[test]
+    fn smoke() {
+        let l = AsyncRwLock::new(());
+        drop(block_on(l.read()));
+        drop(block_on(l.write()));
+        drop((block_on(l.read()), block_on(l.read())));
+        drop(block_on(l.write()));
+    }
+
+    #[test]
+    fn frob() {
+        const N: u32 = 10;
+        const M: u32 = 1000;
+
+        let r = Arc::new(AsyncRwLock::new(0u32));
+        let mut threads = Vec::new();
+        for i in 0..N {
+            let r = r.clone();
+            threads.push(thread::spawn(move || {
+                for _ in 0..M {
+                    if i % 2 == 0 {
+                        *block_on(r.write()) += 1;
+                    } else {
+                        drop(block_on(r.read()));
+                    }
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*block_on(r.read()), N / 2 * M);
+    }
+
+    #[test]
+    fn try_read_write() {
+        let l = AsyncRwLock::new(0);
+        let read = l.try_read().unwrap();
+        assert!(l.try_read().is_some());
+        assert!(l.try_write().is_none());
+        drop(read);
+        let write = l.try_write().unwrap();
+        assert!(l.try_read().is_none());
+        drop(write);
+        assert!(!l.is_locked());
+    }
+
+    #[test]
+    fn writer_waits_for_readers() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let l = AsyncRwLock::new(0);
+        let read = l.try_read().unwrap();
+        let mut write = l.write();
+        assert!(Pin::new(&mut write).poll(&mut cx).is_pending());
+
+        // New readers must wait behind the pending writer
+        assert!(l.try_read().is_none());
+        drop(read);
+        assert!(Pin::new(&mut write).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn cancel_pending_writer() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let l = AsyncRwLock::new(0);
+        let read = l.try_read().unwrap();
+        let mut write = l.write();
+        assert!(Pin::new(&mut write).poll(&mut cx).is_pending());
+        drop(write);
+
+        // The abandoned writer must not block new readers
+        assert!(l.try_read().is_some());
+        drop(read);
+        assert!(!l.is_locked());
+        assert!(l.try_write().is_some());
+    }
+
+    #[test]
+    fn cancel_queued_reader() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let l = AsyncRwLock::new(0);
+        let write = l.try_write().unwrap();
+        let mut read = l.read();
+        assert!(Pin::new(&mut read).poll(&mut cx).is_pending());
+        let mut write2 = l.write();
+        assert!(Pin::new(&mut write2).poll(&mut cx).is_pending());
+
+        // Dropping the reader which was woken up must not prevent the writer
+        // queued behind it from acquiring the lock.
+        drop(write);
+        drop(read);
+        assert!(Pin::new(&mut write2).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_guard_send() {
+        fn send<T: Send>(_: T) {}
+
+        let l = AsyncRwLock::new(());
+        send(l.read());
+        send(l.write());
+        send(l.try_read().unwrap());
+    }
+
+    #[test]
+    fn test_rwlock_debug() {
+        let x = AsyncRwLock::new(vec![0u8, 10]);
+
+        assert_eq!(format!("{:?}", x), "AsyncRwLock { data: [0, 10] }");
+        let _lock = x.try_write().unwrap();
+        assert_eq!(format!("{:?}", x), "AsyncRwLock { data: <locked> }");
+    }
+}
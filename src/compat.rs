@@ -0,0 +1,467 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Drop-in replacements for the synchronization primitives of `std::sync`.
+//!
+//! The types in this module have the same API as their counterparts in the
+//! standard library, including poisoning, `LockResult` return types and
+//! `Condvar` methods which take the guard by value, but they are implemented
+//! on top of the parking lot. A crate can switch over by replacing
+//! `use std::sync::...` with `use parking_lot::compat::...`, and the remaining
+//! `std::sync` items such as `Arc` and `mpsc` are re-exported here so that
+//! existing imports keep working.
+//!
+//! `Mutex` and `RwLock` are aliases of `PoisonMutex` and `PoisonRwLock`. The
+//! main `parking_lot` types should be preferred in new code since they avoid
+//! the overhead of poisoning and provide more features. As with the main types,
+//! their `new` functions are only `const` with the `nightly` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use parking_lot::compat::{Arc, Condvar, Mutex};
+//! use std::thread;
+//!
+//! let pair = Arc::new((Mutex::new(false), Condvar::new()));
+//! let pair2 = pair.clone();
+//!
+//! thread::spawn(move || {
+//!     let (lock, cvar) = &*pair2;
+//!     *lock.lock().unwrap() = true;
+//!     cvar.notify_one();
+//! });
+//!
+//! let (lock, cvar) = &*pair;
+//! let mut started = lock.lock().unwrap();
+//! while !*started {
+//!     started = cvar.wait(started).unwrap();
+//! }
+//! ```
+
+use crate::condvar;
+use crate::once;
+use crate::poison;
+use core::fmt;
+use std::time::Duration;
+
+pub use crate::condvar::WaitTimeoutResult;
+pub use std::sync::{
+    atomic, mpsc, Arc, LockResult, PoisonError, TryLockError, TryLockResult, Weak,
+};
+
+/// A mutual exclusion primitive useful for protecting shared data, with the
+/// same API as `std::sync::Mutex`.
+pub type Mutex<T> = crate::PoisonMutex<T>;
+
+/// An RAII implementation of a "scoped lock" of a mutex, with the same API as
+/// `std::sync::MutexGuard`.
+pub type MutexGuard<'a, T> = crate::PoisonMutexGuard<'a, T>;
+
+/// A reader-writer lock, with the same API as `std::sync::RwLock`.
+pub type RwLock<T> = crate::PoisonRwLock<T>;
+
+/// RAII structure used to release the shared read access of a lock when
+/// dropped, with the same API as `std::sync::RwLockReadGuard`.
+pub type RwLockReadGuard<'a, T> = crate::PoisonRwLockReadGuard<'a, T>;
+
+/// RAII structure used to release the exclusive write access of a lock when
+/// dropped, with the same API as `std::sync::RwLockWriteGuard`.
+pub type RwLockWriteGuard<'a, T> = crate::PoisonRwLockWriteGuard<'a, T>;
+
+/// A Condition Variable, with the same API as `std::sync::Condvar`.
+///
+/// This wraps a `Condvar` which only works with the `Mutex` type of this
+/// module. Unlike the standard library, waiting threads are requeued onto the
+/// mutex when they are notified instead of all being woken up at once.
+#[derive(Default)]
+pub struct Condvar(condvar::Condvar);
+
+impl Condvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    #[inline]
+    pub const fn new() -> Condvar {
+        Condvar(condvar::Condvar::new())
+    }
+
+    /// Blocks the current thread until this condition variable receives a
+    /// notification.
+    ///
+    /// This function will atomically unlock the mutex held by `guard` and
+    /// block the current thread, and re-acquire the lock before returning.
+    /// Spurious wakeups are possible, so the condition should be checked again
+    /// in a loop.
+    ///
+    /// An error is returned if the mutex was poisoned when it was re-acquired,
+    /// but the guard is returned inside of it regardless.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the `Condvar`
+    /// with a different `Mutex` object.
+    #[inline]
+    pub fn wait<'a, T>(&self, mut guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        self.0.wait(&mut guard.guard);
+        let poisoned = MutexGuard::mutex(&guard).is_poisoned();
+        poison::map_result(poisoned, guard)
+    }
+
+    /// Blocks the current thread until the provided condition becomes false.
+    ///
+    /// `condition` is checked immediately; if it returns `true`, this will
+    /// `wait` for the next notification then check again. This repeats until
+    /// `condition` returns `false`.
+    ///
+    /// An error is returned if the mutex was poisoned when it was re-acquired,
+    /// but the guard is returned inside of it regardless.
+    #[inline]
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        condition: F,
+    ) -> LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        self.0.wait_while(&mut guard.guard, condition);
+        let poisoned = MutexGuard::mutex(&guard).is_poisoned();
+        poison::map_result(poisoned, guard)
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified duration.
+    ///
+    /// The returned `WaitTimeoutResult` indicates whether the timeout elapsed.
+    /// Like `wait`, the lock is re-acquired regardless.
+    #[inline]
+    pub fn wait_timeout<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        dur: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let result = self.0.wait_for(&mut guard.guard, dur);
+        let poisoned = MutexGuard::mutex(&guard).is_poisoned();
+        poison::map_result(poisoned, (guard, result))
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified duration. For each notification, `condition` is checked;
+    /// if it returns `true`, the thread goes back to waiting.
+    ///
+    /// The returned `WaitTimeoutResult` indicates whether the timeout elapsed
+    /// without the condition being met.
+    #[inline]
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        dur: Duration,
+        condition: F,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let result = self.0.wait_while_for(&mut guard.guard, condition, dur);
+        let poisoned = MutexGuard::mutex(&guard).is_poisoned();
+        poison::map_result(poisoned, (guard, result))
+    }
+
+    /// Wakes up one blocked thread on this condvar.
+    #[inline]
+    pub fn notify_one(&self) {
+        self.0.notify_one();
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    #[inline]
+    pub fn notify_all(&self) {
+        self.0.notify_all();
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Condvar { .. }")
+    }
+}
+
+/// A synchronization primitive which can be used to run a one-time global
+/// initialization, with the same API as `std::sync::Once`.
+#[derive(Default)]
+pub struct Once(once::Once);
+
+impl Once {
+    /// Creates a new `Once` value.
+    #[inline]
+    pub const fn new() -> Once {
+        Once(once::Once::new())
+    }
+
+    /// Performs an initialization routine once and only once.
+    ///
+    /// # Panics
+    ///
+    /// If the closure panics, this `Once` is poisoned and all future calls to
+    /// `call_once` will also panic.
+    #[inline]
+    pub fn call_once<F>(&self, f: F)
+    where
+        F: FnOnce(),
+    {
+        self.0.call_once(f);
+    }
+
+    /// Performs the same function as `call_once` except ignores poisoning.
+    ///
+    /// The closure `f` is passed a `OnceState` which indicates whether a
+    /// previous initialization routine panicked.
+    #[inline]
+    pub fn call_once_force<F>(&self, f: F)
+    where
+        F: FnOnce(&OnceState),
+    {
+        self.0.call_once_force(|state| f(&OnceState(state)));
+    }
+
+    /// Returns `true` if some `call_once` call has completed successfully.
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.0.state().done()
+    }
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Once { .. }")
+    }
+}
+
+/// State yielded to `Once::call_once_force`'s closure, with the same API as
+/// `std::sync::OnceState`.
+#[derive(Debug)]
+pub struct OnceState(once::OnceState);
+
+impl OnceState {
+    /// Returns `true` if a previous initialization routine of the associated
+    /// `Once` panicked.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.0.poisoned()
+    }
+}
+
+struct BarrierState {
+    count: usize,
+    generation_id: usize,
+}
+
+/// A barrier enables multiple threads to synchronize the beginning of some
+/// computation, with the same API as `std::sync::Barrier`.
+pub struct Barrier {
+    lock: crate::Mutex<BarrierState>,
+    cvar: condvar::Condvar,
+    num_threads: usize,
+}
+
+impl Barrier {
+    /// Creates a new barrier that can block a given number of threads.
+    ///
+    /// A barrier will block `n`-1 threads which call `wait` and then wake up
+    /// all threads at once when the `n`th thread calls `wait`.
+    #[inline]
+    pub fn new(n: usize) -> Barrier {
+        Barrier {
+            lock: crate::Mutex::new(BarrierState {
+                count: 0,
+                generation_id: 0,
+            }),
+            cvar: condvar::Condvar::new(),
+            num_threads: n,
+        }
+    }
+
+    /// Blocks the current thread until all threads have rendezvoused here.
+    ///
+    /// Barriers are re-usable after all threads have rendezvoused once. A
+    /// single (arbitrary) thread will receive a `BarrierWaitResult` that
+    /// returns `true` from `is_leader` when returning from this function, and
+    /// all other threads will receive a result that returns `false`.
+    pub fn wait(&self) -> BarrierWaitResult {
+        let mut lock = self.lock.lock();
+        let local_gen = lock.generation_id;
+        lock.count += 1;
+        if lock.count < self.num_threads {
+            self.cvar
+                .wait_while(&mut lock, |state| state.generation_id == local_gen);
+            BarrierWaitResult(false)
+        } else {
+            lock.count = 0;
+            lock.generation_id = lock.generation_id.wrapping_add(1);
+            self.cvar.notify_all();
+            BarrierWaitResult(true)
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Barrier { .. }")
+    }
+}
+
+/// A `BarrierWaitResult` is returned by `Barrier::wait` when all threads in the
+/// `Barrier` have rendezvoused.
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Returns `true` if this thread is the "leader thread" for the call to
+    /// `Barrier::wait`.
+    ///
+    /// Only one thread will have `true` returned from their result, all other
+    /// threads will have `false` returned.
+    #[inline]
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl fmt::Debug for BarrierWaitResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BarrierWaitResult")
+            .field("is_leader", &self.0)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arc, Barrier, Condvar, Mutex, Once};
+    use std::panic;
+    use std::sync::mpsc::{channel, TryRecvError};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_condvar_wait() {
+        let pair = Arc::new((Mutex::new(0), Condvar::new()));
+        let pair2 = pair.clone();
+        thread::spawn(move || {
+            let (lock, cvar) = &*pair2;
+            *lock.lock().unwrap() += 1;
+            cvar.notify_one();
+        });
+
+        let (lock, cvar) = &*pair;
+        let guard = cvar.wait_while(lock.lock().unwrap(), |n| *n == 0).unwrap();
+        assert_eq!(*guard, 1);
+    }
+
+    #[test]
+    fn test_condvar_wait_timeout() {
+        let m = Mutex::new(0);
+        let c = Condvar::new();
+        let (guard, result) = c
+            .wait_timeout(m.lock().unwrap(), Duration::from_millis(10))
+            .unwrap();
+        assert!(result.timed_out());
+        let (guard, result) = c
+            .wait_timeout_while(guard, Duration::from_millis(10), |n| *n == 0)
+            .unwrap();
+        assert!(result.timed_out());
+        assert_eq!(*guard, 0);
+    }
+
+    #[test]
+    fn test_condvar_wait_poisoned() {
+        let pair = Arc::new((Mutex::new(0), Condvar::new()));
+        let pair2 = pair.clone();
+
+        let (lock, cvar) = &*pair;
+        let guard = lock.lock().unwrap();
+        thread::spawn(move || {
+            let (lock, cvar) = &*pair2;
+            let _guard = lock.lock().unwrap();
+            cvar.notify_one();
+            panic!();
+        });
+        let guard = cvar.wait(guard).unwrap_err().into_inner();
+        assert_eq!(*guard, 0);
+    }
+
+    #[test]
+    fn test_once() {
+        static O: Once = Once::new();
+        let mut a = 0;
+        O.call_once(|| a += 1);
+        O.call_once(|| a += 1);
+        assert_eq!(a, 1);
+        assert!(O.is_completed());
+    }
+
+    #[test]
+    fn test_once_poison() {
+        static O: Once = Once::new();
+
+        let t = panic::catch_unwind(|| {
+            O.call_once(|| panic!());
+        });
+        assert!(t.is_err());
+        assert!(!O.is_completed());
+
+        let mut called = false;
+        O.call_once_force(|state| {
+            assert!(state.is_poisoned());
+            called = true;
+        });
+        assert!(called);
+        assert!(O.is_completed());
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn test_static_locks() {
+        use super::RwLock;
+
+        static M: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+        static RW: RwLock<i32> = RwLock::new(0);
+
+        M.lock().unwrap().push(1);
+        *RW.write().unwrap() += 1;
+        assert_eq!(*M.lock().unwrap(), [1]);
+        assert_eq!(*RW.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_barrier() {
+        const N: usize = 10;
+
+        let barrier = Arc::new(Barrier::new(N));
+        let (tx, rx) = channel();
+
+        for _ in 0..N - 1 {
+            let c = barrier.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                tx.send(c.wait().is_leader()).unwrap();
+            });
+        }
+
+        // At this point, all spawned threads should be blocked,
+        // so we shouldn't get anything from the port
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        let mut leader_found = barrier.wait().is_leader();
+
+        // Now, the barrier is cleared and we should get data.
+        for _ in 0..N - 1 {
+            if rx.recv().unwrap() {
+                assert!(!leader_found);
+                leader_found = true;
+            }
+        }
+        assert!(leader_found);
+    }
+}
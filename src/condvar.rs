@@ -7,7 +7,7 @@
 
 use crate::mutex::MutexGuard;
 use crate::raw_mutex::{RawMutex, TOKEN_HANDOFF, TOKEN_NORMAL};
-use crate::{deadlock, util};
+use crate::{trace, util};
 use core::{
     fmt, ptr,
     sync::atomic::{AtomicPtr, Ordering},
@@ -19,7 +19,7 @@ use std::time::{Duration, Instant};
 /// A type indicating whether a timed wait on a condition variable returned
 /// due to a time out or not.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub struct WaitTimeoutResult(bool);
+pub struct WaitTimeoutResult(pub(crate) bool);
 
 impl WaitTimeoutResult {
     /// Returns whether the wait was known to have timed out.
@@ -236,6 +236,7 @@ impl Condvar {
                 TOKEN_NORMAL
             };
             let res = parking_lot_core::unpark_requeue(from, to, validate, callback);
+            trace::condvar_requeue(from, to, res);
 
             res.unparked_threads + res.requeued_threads
         }
@@ -301,6 +302,7 @@ impl Condvar {
             let result;
             let mut bad_mutex = false;
             let mut requeued = false;
+            let owner = mutex.owner().save();
             {
                 let addr = self as *const _ as usize;
                 let lock_addr = mutex as *const _ as *mut _;
@@ -353,10 +355,12 @@ impl Condvar {
 
             // ... and re-lock it once we are done sleeping
             if result == ParkResult::Unparked(TOKEN_HANDOFF) {
-                deadlock::acquire_resource(mutex as *const _ as usize);
+                mutex.acquired_by_handoff();
             } else {
-                mutex.lock();
+                mutex.lock_unchecked();
             }
+            mutex.owner().restore(owner);
+            mutex.hold().start();
 
             WaitTimeoutResult(!(result.is_unparked() || requeued))
         }
@@ -395,6 +399,137 @@ impl Condvar {
         let deadline = util::to_deadline(timeout);
         self.wait_until_internal(unsafe { MutexGuard::mutex(mutex_guard).raw() }, deadline)
     }
+
+    /// Blocks the current thread until the provided condition becomes false.
+    ///
+    /// `condition` is checked immediately; if not met (returns `true`), this
+    /// will `wait` for the next notification then check again. This repeats
+    /// until `condition` returns `false`, in which case this function returns.
+    ///
+    /// This function will atomically unlock the mutex specified (represented by
+    /// `mutex_guard`) and block the current thread. This means that any calls
+    /// to `notify_*()` which happen logically after the mutex is unlocked are
+    /// candidates to wake this thread up. When this function call returns, the
+    /// lock specified will have been re-acquired.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the `Condvar`
+    /// with a different `Mutex` object.
+    #[inline]
+    pub fn wait_while<T, F>(&self, mutex_guard: &mut MutexGuard<'_, T>, condition: F)
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait_while_until_internal(mutex_guard, condition, None);
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified time instant. For each notification, `condition` is
+    /// checked; if it returns `true`, the thread goes back to waiting.
+    ///
+    /// The semantics of this function are equivalent to `wait_while()` except
+    /// that the thread will be blocked roughly until `timeout` is reached. This
+    /// method should not be used for precise timing due to anomalies such as
+    /// preemption or platform differences that may not cause the maximum
+    /// amount of time waited to be precisely `timeout`.
+    ///
+    /// Note that the best effort is made to ensure that the time waited is
+    /// measured with a monotonic clock, and not affected by the changes made to
+    /// the system time.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed without the condition being met.
+    ///
+    /// Like `wait_while`, the lock specified will be re-acquired when this
+    /// function returns, regardless of whether the timeout elapsed or not.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the `Condvar`
+    /// with a different `Mutex` object.
+    #[inline]
+    pub fn wait_while_until<T, F>(
+        &self,
+        mutex_guard: &mut MutexGuard<'_, T>,
+        condition: F,
+        timeout: Instant,
+    ) -> WaitTimeoutResult
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait_while_until_internal(mutex_guard, condition, Some(timeout))
+    }
+
+    /// Waits on this condition variable for a notification, timing out after a
+    /// specified duration. For each notification, `condition` is checked; if
+    /// it returns `true`, the thread goes back to waiting.
+    ///
+    /// The semantics of this function are equivalent to `wait_while()` except
+    /// that the thread will be blocked for roughly no longer than `timeout`.
+    /// The timeout covers the whole call: the time spent in earlier waits is
+    /// deducted when going back to waiting after a spurious wakeup. This
+    /// method should not be used for precise timing due to anomalies such as
+    /// preemption or platform differences that may not cause the maximum
+    /// amount of time waited to be precisely `timeout`.
+    ///
+    /// Note that the best effort is made to ensure that the time waited is
+    /// measured with a monotonic clock, and not affected by the changes made to
+    /// the system time.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed without the condition being met.
+    ///
+    /// Like `wait_while`, the lock specified will be re-acquired when this
+    /// function returns, regardless of whether the timeout elapsed or not.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the given `timeout` is so large that it can't be added to the current time.
+    /// This panic is not possible if the crate is built with the `nightly` feature, then a too
+    /// large `timeout` becomes equivalent to just calling `wait_while`.
+    #[inline]
+    pub fn wait_while_for<T, F>(
+        &self,
+        mutex_guard: &mut MutexGuard<'_, T>,
+        condition: F,
+        timeout: Duration,
+    ) -> WaitTimeoutResult
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        // Converting to a deadline once means that every wait in the loop only
+        // gets the time which is left over from the previous ones.
+        let deadline = util::to_deadline(timeout);
+        self.wait_while_until_internal(mutex_guard, condition, deadline)
+    }
+
+    fn wait_while_until_internal<T, F>(
+        &self,
+        mutex_guard: &mut MutexGuard<'_, T>,
+        mut condition: F,
+        timeout: Option<Instant>,
+    ) -> WaitTimeoutResult
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut **mutex_guard) {
+            let result =
+                self.wait_until_internal(unsafe { MutexGuard::mutex(mutex_guard).raw() }, timeout);
+
+            // The condition may have changed while we were re-acquiring the
+            // lock after timing out, so only report a timeout if it still
+            // holds.
+            if result.timed_out() {
+                return WaitTimeoutResult(condition(&mut **mutex_guard));
+            }
+        }
+        WaitTimeoutResult(false)
+    }
 }
 
 impl Default for Condvar {
@@ -476,6 +611,74 @@ mod tests {
         }
     }
 
+    #[test]
+    fn wait_while() {
+        let data = Arc::new((Mutex::new(0), Condvar::new()));
+        let data2 = data.clone();
+        let _t = thread::spawn(move || {
+            let (lock, cond) = &*data2;
+            for _ in 0..5 {
+                *lock.lock() += 1;
+                cond.notify_one();
+            }
+        });
+
+        let (lock, cond) = &*data;
+        let mut g = lock.lock();
+        cond.wait_while(&mut g, |count| *count < 5);
+        assert_eq!(*g, 5);
+    }
+
+    #[test]
+    fn wait_while_for() {
+        let m = Arc::new(Mutex::new(false));
+        let m2 = m.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        // The condition is already false, so no waiting happens.
+        let mut g = m.lock();
+        let timeout_res = c.wait_while_for(&mut g, |_| false, Duration::from_secs(0));
+        assert!(!timeout_res.timed_out());
+
+        // Time out while the condition keeps holding, even though there are
+        // spurious notifications in between.
+        let start = Instant::now();
+        let timeout = Duration::from_millis(100);
+        let _t = thread::spawn(move || {
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(10));
+                let _g = m2.lock();
+                c2.notify_one();
+            }
+        });
+        let timeout_res = c.wait_while_for(&mut g, |ready| !*ready, timeout);
+        assert!(timeout_res.timed_out());
+        assert!(Instant::now() - start >= timeout);
+        assert!(!*g);
+    }
+
+    #[test]
+    fn wait_while_until() {
+        let m = Arc::new(Mutex::new(false));
+        let m2 = m.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        let mut g = m.lock();
+        let _t = thread::spawn(move || {
+            *m2.lock() = true;
+            c2.notify_one();
+        });
+        let timeout_res = c.wait_while_until(
+            &mut g,
+            |ready| !*ready,
+            Instant::now() + Duration::from_secs(60),
+        );
+        assert!(!timeout_res.timed_out());
+        assert!(*g);
+    }
+
     #[test]
     fn notify_one_return_true() {
         let m = Arc::new(Mutex::new(()));
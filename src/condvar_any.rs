@@ -0,0 +1,704 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::condvar::WaitTimeoutResult;
+use crate::raw_mutex::TOKEN_NORMAL;
+use crate::util;
+use core::{
+    cell::Cell,
+    fmt, mem,
+    ops::DerefMut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lock_api::{CondvarGuard, RawMutexRequeue};
+use parking_lot_core::{self, FilterOp, ParkResult, RequeueOp, UnparkResult, DEFAULT_PARK_TOKEN};
+use std::time::{Duration, Instant};
+
+/// A Condition Variable which works with any kind of lock.
+///
+/// This type is the same as `Condvar`, except that it can be used with any
+/// lock guard implementing `lock_api::CondvarGuard` instead of only with
+/// `MutexGuard`. This includes the guards of `Mutex`, `FairMutex`,
+/// `ReentrantMutex` and the write guards of `RwLock`, as well as the guards of
+/// any lock built with `lock_api`.
+///
+/// When waiting with a `ReentrantMutexGuard`, the mutex is fully released
+/// regardless of how many times the current thread has locked it, and the
+/// lock count is restored once the wait is over.
+///
+/// If the raw lock supports it (see `lock_api::RawMutexRequeue`), `notify_one`
+/// and `notify_all` move the waiting threads directly onto the queue of the
+/// lock instead of waking them up, like `Condvar` does. Otherwise all the
+/// waiting threads are woken up and compete for the lock.
+///
+/// Each condvar can be used with only one lock at a time. Any attempt to use
+/// multiple locks on the same condition variable simultaneously will result in
+/// a runtime panic. However it is possible to switch to a different lock if
+/// there are no threads currently waiting on the condition variable.
+///
+/// Mapped guards can't be used with `CondvarAny`, since the data they point to
+/// could be invalidated by another thread while the lock is released.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::{CondvarAny, RwLock};
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let pair = Arc::new((RwLock::new(false), CondvarAny::new()));
+/// let pair2 = pair.clone();
+///
+/// // Inside of our lock, spawn a new thread, and then wait for it to start
+/// thread::spawn(move|| {
+///     let &(ref lock, ref cvar) = &*pair2;
+///     let mut started = lock.write();
+///     *started = true;
+///     cvar.notify_one();
+/// });
+///
+/// // wait for the thread to start up
+/// let &(ref lock, ref cvar) = &*pair;
+/// let mut started = lock.write();
+/// cvar.wait_while(&mut started, |started| !*started);
+/// ```
+pub struct CondvarAny {
+    /// Address of the lock that threads are currently waiting with, or 0 if
+    /// there are no waiting threads.
+    state: AtomicUsize,
+    /// Queue key of the requeue target of that lock, or 0 if it doesn't have
+    /// one.
+    requeue_key: AtomicUsize,
+    /// Requeue target of that lock. This is only accessed while holding the
+    /// queue lock of the condvar.
+    requeue: Cell<Option<*const dyn RawMutexRequeue>>,
+}
+
+unsafe impl Send for CondvarAny {}
+unsafe impl Sync for CondvarAny {}
+
+impl CondvarAny {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    #[inline]
+    pub const fn new() -> CondvarAny {
+        CondvarAny {
+            state: AtomicUsize::new(0),
+            requeue_key: AtomicUsize::new(0),
+            requeue: Cell::new(None),
+        }
+    }
+
+    // Resets the state once there are no more waiting threads. Must be called
+    // while holding the queue lock.
+    #[inline]
+    fn clear_state(&self) {
+        self.state.store(0, Ordering::Relaxed);
+        self.requeue_key.store(0, Ordering::Relaxed);
+        self.requeue.set(None);
+    }
+
+    /// Wakes up one blocked thread on this condvar.
+    ///
+    /// Returns whether a thread was woken up.
+    ///
+    /// If there is a blocked thread on this condition variable, then it will
+    /// be woken up from its call to `wait` or `wait_timeout`. Calls to
+    /// `notify_one` are not buffered in any way.
+    ///
+    /// To wake up all threads, see `notify_all()`.
+    #[inline]
+    pub fn notify_one(&self) -> bool {
+        // Nothing to do if there are no waiting threads
+        let state = self.state.load(Ordering::Relaxed);
+        if state == 0 {
+            return false;
+        }
+
+        self.notify_one_slow(state)
+    }
+
+    #[cold]
+    fn notify_one_slow(&self, lock_addr: usize) -> bool {
+        let from = self as *const _ as usize;
+        let to = self.requeue_key.load(Ordering::Relaxed);
+        if to == 0 {
+            let callback = |result: UnparkResult| {
+                // Clear our state if there are no more waiting threads
+                if !result.have_more_threads {
+                    self.clear_state();
+                }
+                TOKEN_NORMAL
+            };
+            // SAFETY:
+            //   * `from` is an address we control.
+            //   * `callback` does not panic or call into any function of `parking_lot`.
+            let res = unsafe { parking_lot_core::unpark_one(from, callback) };
+            return res.unparked_threads != 0;
+        }
+
+        // Unpark one thread or requeue it onto the lock
+        let validate = || {
+            // Make sure that our state still refers to the same lock. If not
+            // then it means that all threads on the current lock were woken up
+            // and new waiting threads switched to a different lock. In that
+            // case we can get away with doing nothing.
+            if self.state.load(Ordering::Relaxed) != lock_addr
+                || self.requeue_key.load(Ordering::Relaxed) != to
+            {
+                return RequeueOp::Abort;
+            }
+
+            // Unpark one thread if the lock is unlocked, otherwise just
+            // requeue it to the lock.
+            //
+            // SAFETY: The requeue target is valid for as long as there are
+            // threads waiting with its lock.
+            let target = unsafe { &*self.requeue.get().unwrap() };
+            if target.mark_parked_if_locked() {
+                RequeueOp::RequeueOne
+            } else {
+                RequeueOp::UnparkOne
+            }
+        };
+        let callback = |_op, result: UnparkResult| {
+            // Clear our state if there are no more waiting threads
+            if !result.have_more_threads {
+                self.clear_state();
+            }
+            TOKEN_NORMAL
+        };
+        // SAFETY:
+        //   * `from` and `to` are addresses of queues that we control.
+        //   * `validate`/`callback` does not panic or call into any function of `parking_lot`.
+        let res = unsafe { parking_lot_core::unpark_requeue(from, to, validate, callback) };
+
+        res.unparked_threads + res.requeued_threads != 0
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    ///
+    /// Returns the number of threads woken up.
+    ///
+    /// This method will ensure that any current waiters on the condition
+    /// variable are awoken. Calls to `notify_all()` are not buffered in any
+    /// way.
+    ///
+    /// To wake up only one thread, see `notify_one()`.
+    #[inline]
+    pub fn notify_all(&self) -> usize {
+        // Nothing to do if there are no waiting threads
+        let state = self.state.load(Ordering::Relaxed);
+        if state == 0 {
+            return 0;
+        }
+
+        self.notify_all_slow(state)
+    }
+
+    #[cold]
+    fn notify_all_slow(&self, lock_addr: usize) -> usize {
+        let from = self as *const _ as usize;
+        let to = self.requeue_key.load(Ordering::Relaxed);
+        if to == 0 {
+            let filter = |_| FilterOp::Unpark;
+            let callback = |_| {
+                // Clear our state since we are going to unpark all threads.
+                self.clear_state();
+                TOKEN_NORMAL
+            };
+            // SAFETY:
+            //   * `from` is an address we control.
+            //   * `filter`/`callback` does not panic or call into any function of `parking_lot`.
+            let res = unsafe { parking_lot_core::unpark_filter(from, filter, callback) };
+            return res.unparked_threads;
+        }
+
+        // Unpark one thread and requeue the rest onto the lock
+        let target = Cell::new(None);
+        let validate = || {
+            // Make sure that our state still refers to the same lock. If not
+            // then it means that all threads on the current lock were woken up
+            // and new waiting threads switched to a different lock. In that
+            // case we can get away with doing nothing.
+            if self.state.load(Ordering::Relaxed) != lock_addr
+                || self.requeue_key.load(Ordering::Relaxed) != to
+            {
+                return RequeueOp::Abort;
+            }
+
+            // Clear our state since we are going to unpark or requeue all
+            // threads.
+            //
+            // SAFETY: The requeue target is valid for as long as there are
+            // threads waiting with its lock.
+            let requeue = unsafe { &*self.requeue.get().unwrap() };
+            target.set(Some(requeue));
+            self.clear_state();
+
+            // Unpark one thread if the lock is unlocked, otherwise just
+            // requeue everything to the lock.
+            if requeue.mark_parked_if_locked() {
+                RequeueOp::RequeueAll
+            } else {
+                RequeueOp::UnparkOneRequeueRest
+            }
+        };
+        let callback = |op, result: UnparkResult| {
+            // If we requeued threads to the lock, mark it as having parked
+            // threads. The RequeueAll case is already handled above.
+            if op == RequeueOp::UnparkOneRequeueRest && result.requeued_threads != 0 {
+                target.get().unwrap().mark_parked();
+            }
+            TOKEN_NORMAL
+        };
+        // SAFETY:
+        //   * `from` and `to` are addresses of queues that we control.
+        //   * `validate`/`callback` does not panic or call into any function of `parking_lot`.
+        let res = unsafe { parking_lot_core::unpark_requeue(from, to, validate, callback) };
+
+        res.unparked_threads + res.requeued_threads
+    }
+
+    /// Blocks the current thread until this condition variable receives a
+    /// notification.
+    ///
+    /// This function will atomically unlock the lock specified (represented by
+    /// `guard`) and block the current thread. This means that any calls to
+    /// `notify_*()` which happen logically after the lock is unlocked are
+    /// candidates to wake this thread up. When this function call returns, the
+    /// lock specified will have been re-acquired.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the
+    /// `CondvarAny` with a different lock object.
+    #[inline]
+    pub fn wait<G: CondvarGuard>(&self, guard: &mut G) {
+        self.wait_until_internal(guard, None);
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified time instant.
+    ///
+    /// The semantics of this function are equivalent to `wait()` except that
+    /// the thread will be blocked roughly until `timeout` is reached.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed.
+    ///
+    /// Like `wait`, the lock specified will be re-acquired when this function
+    /// returns, regardless of whether the timeout elapsed or not.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the
+    /// `CondvarAny` with a different lock object.
+    #[inline]
+    pub fn wait_until<G: CondvarGuard>(
+        &self,
+        guard: &mut G,
+        timeout: Instant,
+    ) -> WaitTimeoutResult {
+        self.wait_until_internal(guard, Some(timeout))
+    }
+
+    // This is a non-generic function to reduce the monomorphization cost of
+    // using `wait_until`.
+    fn wait_until_internal(
+        &self,
+        guard: &dyn CondvarGuard,
+        timeout: Option<Instant>,
+    ) -> WaitTimeoutResult {
+        unsafe {
+            let result;
+            let mut bad_lock = false;
+            let mut requeued = false;
+            let mut lock_state = 0;
+            {
+                let addr = self as *const _ as usize;
+                let lock_addr = guard.lock_addr();
+                let requeue = guard.requeue_target();
+                let validate = || {
+                    // Ensure we don't use two different locks with the same
+                    // CondvarAny at the same time. This is done while locked
+                    // to avoid races with notify_one
+                    let state = self.state.load(Ordering::Relaxed);
+                    if state == 0 {
+                        self.state.store(lock_addr, Ordering::Relaxed);
+                        if let Some(requeue) = requeue {
+                            self.requeue_key
+                                .store(requeue.requeue_key(), Ordering::Relaxed);
+                            // SAFETY: The reference is only used while there
+                            // are threads waiting with the lock, which keep
+                            // it borrowed.
+                            self.requeue.set(Some(mem::transmute::<
+                                &dyn RawMutexRequeue,
+                                *const dyn RawMutexRequeue,
+                            >(requeue)));
+                        }
+                    } else if state != lock_addr {
+                        bad_lock = true;
+                        return false;
+                    }
+                    true
+                };
+                let before_sleep = || {
+                    // Unlock the lock before sleeping...
+                    lock_state = guard.unlock_for_wait();
+                };
+                let timed_out = |k, was_last_thread| {
+                    // If we were requeued to the lock, then we did not time out.
+                    // We'll just park ourselves on the lock again when we try
+                    // to lock it later.
+                    requeued = k != addr;
+
+                    // If we were the last thread on the queue then we need to
+                    // clear our state. This is normally done by the
+                    // notify_{one,all} functions when not timing out.
+                    if !requeued && was_last_thread {
+                        self.clear_state();
+                    }
+                };
+                result = parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    DEFAULT_PARK_TOKEN,
+                    timeout,
+                );
+            }
+
+            // Panic if we tried to use multiple locks with a CondvarAny. Note
+            // that at this point the guard is still locked. It will be
+            // unlocked by the unwinding logic.
+            if bad_lock {
+                panic!("attempted to use a condition variable with more than one lock");
+            }
+
+            // ... and re-lock it once we are done sleeping
+            let token = match result {
+                ParkResult::Unparked(token) => Some(token.0),
+                _ => None,
+            };
+            guard.relock_after_wait(lock_state, token);
+
+            WaitTimeoutResult(!(result.is_unparked() || requeued))
+        }
+    }
+
+    /// Waits on this condition variable for a notification, timing out after a
+    /// specified duration.
+    ///
+    /// The semantics of this function are equivalent to `wait()` except that
+    /// the thread will be blocked for roughly no longer than `timeout`.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed.
+    ///
+    /// Like `wait`, the lock specified will be re-acquired when this function
+    /// returns, regardless of whether the timeout elapsed or not.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the given `timeout` is so large that it can't be added to the current time.
+    /// This panic is not possible if the crate is built with the `nightly` feature, then a too
+    /// large `timeout` becomes equivalent to just calling `wait`.
+    #[inline]
+    pub fn wait_for<G: CondvarGuard>(&self, guard: &mut G, timeout: Duration) -> WaitTimeoutResult {
+        let deadline = util::to_deadline(timeout);
+        self.wait_until_internal(guard, deadline)
+    }
+
+    /// Blocks the current thread until the provided condition becomes false.
+    ///
+    /// `condition` is checked immediately; if not met (returns `true`), this
+    /// will `wait` for the next notification then check again. This repeats
+    /// until `condition` returns `false`, in which case this function returns.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the
+    /// `CondvarAny` with a different lock object.
+    #[inline]
+    pub fn wait_while<G, T, F>(&self, guard: &mut G, condition: F)
+    where
+        G: CondvarGuard + DerefMut<Target = T>,
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait_while_until_internal(guard, condition, None);
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified time instant. For each notification, `condition` is
+    /// checked; if it returns `true`, the thread goes back to waiting.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed without the condition being met.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the
+    /// `CondvarAny` with a different lock object.
+    #[inline]
+    pub fn wait_while_until<G, T, F>(
+        &self,
+        guard: &mut G,
+        condition: F,
+        timeout: Instant,
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + DerefMut<Target = T>,
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait_while_until_internal(guard, condition, Some(timeout))
+    }
+
+    /// Waits on this condition variable for a notification, timing out after a
+    /// specified duration. For each notification, `condition` is checked; if
+    /// it returns `true`, the thread goes back to waiting.
+    ///
+    /// The timeout covers the whole call, not each individual wait.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed without the condition being met.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the given `timeout` is so large that it can't be added to the current time.
+    /// This panic is not possible if the crate is built with the `nightly` feature, then a too
+    /// large `timeout` becomes equivalent to just calling `wait_while`.
+    #[inline]
+    pub fn wait_while_for<G, T, F>(
+        &self,
+        guard: &mut G,
+        condition: F,
+        timeout: Duration,
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + DerefMut<Target = T>,
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = util::to_deadline(timeout);
+        self.wait_while_until_internal(guard, condition, deadline)
+    }
+
+    fn wait_while_until_internal<G, T, F>(
+        &self,
+        guard: &mut G,
+        mut condition: F,
+        timeout: Option<Instant>,
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + DerefMut<Target = T>,
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut **guard) {
+            let result = self.wait_until_internal(guard, timeout);
+
+            // The condition may have changed while we were re-acquiring the
+            // lock after timing out, so only report a timeout if it still
+            // holds.
+            if result.timed_out() {
+                return WaitTimeoutResult(condition(&mut **guard));
+            }
+        }
+        WaitTimeoutResult(false)
+    }
+}
+
+impl Default for CondvarAny {
+    #[inline]
+    fn default() -> CondvarAny {
+        CondvarAny::new()
+    }
+}
+
+impl fmt::Debug for CondvarAny {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("CondvarAny { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CondvarAny, FairMutex, Mutex, MutexGuard, ReentrantMutex, RwLock};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn notify_one_mutex() {
+        let m = Arc::new(Mutex::new(()));
+        let m2 = m.clone();
+        let c = Arc::new(CondvarAny::new());
+        let c2 = c.clone();
+
+        let mut g = m.lock();
+        let _t = thread::spawn(move || {
+            let _g = m2.lock();
+            c2.notify_one();
+        });
+        c.wait(&mut g);
+    }
+
+    #[test]
+    fn notify_all_rwlock() {
+        const N: usize = 10;
+
+        let data = Arc::new((RwLock::new(0), CondvarAny::new()));
+        let handles: Vec<_> = (0..N)
+            .map(|_| {
+                let data = data.clone();
+                thread::spawn(move || {
+                    let (lock, cond) = &*data;
+                    let mut cnt = lock.write();
+                    *cnt += 1;
+                    cond.notify_all();
+                    cond.wait_while(&mut cnt, |cnt| *cnt != 0);
+                })
+            })
+            .collect();
+
+        let (lock, cond) = &*data;
+        let mut cnt = lock.write();
+        cond.wait_while(&mut cnt, |cnt| *cnt != N);
+        *cnt = 0;
+        cond.notify_all();
+        drop(cnt);
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_all_fair_mutex() {
+        const N: usize = 10;
+
+        let data = Arc::new((FairMutex::new(false), CondvarAny::new()));
+        let handles: Vec<_> = (0..N)
+            .map(|_| {
+                let data = data.clone();
+                thread::spawn(move || {
+                    let (lock, cond) = &*data;
+                    let mut ready = lock.lock();
+                    cond.wait_while(&mut ready, |ready| !*ready);
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(50));
+        let (lock, cond) = &*data;
+        *lock.lock() = true;
+        cond.notify_all();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn reentrant_mutex() {
+        let m = Arc::new(ReentrantMutex::new(()));
+        let m2 = m.clone();
+        let c = Arc::new(CondvarAny::new());
+        let c2 = c.clone();
+
+        let _outer = m.lock();
+        let mut g = m.lock();
+        let t = thread::spawn(move || {
+            // This can only lock the mutex once both levels have been released
+            let _g = m2.lock();
+            c2.notify_one();
+        });
+        c.wait(&mut g);
+        t.join().unwrap();
+
+        // Both levels are held again
+        drop(g);
+        let m3 = m.clone();
+        assert!(thread::spawn(move || m3.try_lock().is_none())
+            .join()
+            .unwrap());
+    }
+
+    #[test]
+    fn wait_for_rwlock() {
+        let m = RwLock::new(());
+        let c = CondvarAny::new();
+        let mut g = m.write();
+        let timeout_res = c.wait_for(&mut g, Duration::from_millis(10));
+        assert!(timeout_res.timed_out());
+        drop(g);
+        assert!(m.try_read().is_some());
+    }
+
+    #[test]
+    fn requeue() {
+        let m = Arc::new(Mutex::new(()));
+        let m2 = m.clone();
+        let c = Arc::new(CondvarAny::new());
+        let c2 = c.clone();
+        let t = thread::spawn(move || {
+            let mut g = m2.lock();
+            c2.wait(&mut g);
+        });
+
+        let mut g = m.lock();
+        while !c.notify_one() {
+            // Wait for the thread to get into wait()
+            MutexGuard::bump(&mut g);
+        }
+        // The thread should have been requeued to the mutex, which we wake up now.
+        drop(g);
+        t.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn two_locks() {
+        let m = Arc::new(Mutex::new(()));
+        let m2 = m.clone();
+        let rw = RwLock::new(());
+        let c = Arc::new(CondvarAny::new());
+        let c2 = c.clone();
+
+        // Make sure we don't leave the child thread dangling
+        struct PanicGuard<'a>(&'a CondvarAny);
+        impl<'a> Drop for PanicGuard<'a> {
+            fn drop(&mut self) {
+                self.0.notify_one();
+            }
+        }
+
+        let (tx, rx) = channel();
+        let g = m.lock();
+        let _t = thread::spawn(move || {
+            let mut g = m2.lock();
+            tx.send(()).unwrap();
+            c2.wait(&mut g);
+        });
+        drop(g);
+        rx.recv().unwrap();
+        let _g = m.lock();
+        let _guard = PanicGuard(&c);
+        c.wait(&mut rw.write());
+    }
+
+    #[test]
+    fn test_debug_condvar_any() {
+        let c = CondvarAny::new();
+        assert_eq!(format!("{:?}", &c), "CondvarAny { .. }");
+    }
+}
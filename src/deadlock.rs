@@ -32,10 +32,109 @@
 //! });
 //! } // only for #[cfg]
 //! ```
+//!
+//! # Lock order checking
+//!
+//! The `lock_order_detection` feature flag enables a separate check which
+//! records the order in which each thread acquires locks, and reports lock
+//! order inversions the first time they are observed: if one thread acquires
+//! lock A and then lock B while another thread acquires B and then A, then the
+//! two threads can deadlock, even if the program never actually does so.
+//!
+//! Locks are identified by their address. Everything recorded about a lock is
+//! forgotten when it is dropped, so its memory can safely be reused by another
+//! lock. This doesn't apply to the locks which can be placed in memory shared
+//! between processes, such as `RawSharedMutex`, since they have no
+//! destructor.
+//!
+//! ```
+//! #[cfg(feature = "lock_order_detection")]
+//! { // only for #[cfg]
+//! use parking_lot::{deadlock, Mutex};
+//!
+//! let a = Mutex::new(());
+//! let b = Mutex::new(());
+//!
+//! {
+//!     let _a = a.lock();
+//!     let _b = b.lock();
+//! }
+//! {
+//!     let _b = b.lock();
+//!     let _a = a.lock();
+//! }
+//!
+//! for violation in deadlock::check_lock_order() {
+//!     println!("Lock order inversion in thread {}", violation.thread_id());
+//!     println!("{:#?}", violation.backtrace());
+//!     println!("Previously acquired in the opposite order at:");
+//!     println!("{:#?}", violation.previous_backtrace());
+//! }
+//! } // only for #[cfg]
+//! ```
+//!
+//! # Lock levels
+//!
+//! Locks can be given a numeric level in a lock hierarchy by creating them
+//! with `Mutex::with_level`, `FairMutex::with_level`, `RwLock::with_level` or
+//! `FairRwLock::with_level`. When the `lock_levels` feature flag is enabled, a
+//! thread may only acquire a leveled lock if its level is strictly greater
+//! than the level of every leveled lock the thread already holds. Locks
+//! created with `new` don't take part in the hierarchy.
+//!
+//! Only blocking acquisitions are checked. Acquiring a lock with `try_lock`
+//! or one of its timed variants can't deadlock, so it is always allowed,
+//! which lets `lock_all` acquire leveled locks in any order. The level of a
+//! lock acquired this way is still taken into account for later
+//! acquisitions.
+//!
+//! Unlike lock order checking, the very first acquisition which goes against
+//! the hierarchy is caught, which makes this suitable for catching ordering
+//! bugs deterministically in tests. By default the offending acquisition
+//! panics before attempting to take the lock. Alternatively panicking can be
+//! disabled with `set_lock_level_panic`, in which case violations are recorded
+//! and can be retrieved with `check_lock_levels`.
+//!
+//! Without the feature the level is ignored and takes up no space.
+//!
+//! ```
+//! #[cfg(feature = "lock_levels")]
+//! { // only for #[cfg]
+//! use parking_lot::{deadlock, Mutex};
+//!
+//! let config = Mutex::with_level(0, 1);
+//! let cache = Mutex::with_level(0, 2);
+//!
+//! deadlock::set_lock_level_panic(false);
+//! {
+//!     let _cache = cache.lock();
+//!     let _config = config.lock();
+//! }
+//! deadlock::set_lock_level_panic(true);
+//!
+//! for violation in deadlock::check_lock_levels() {
+//!     println!(
+//!         "Acquired a lock with level {} while holding level {} in thread {}",
+//!         violation.acquired_level(),
+//!         violation.held_level(),
+//!         violation.thread_id()
+//!     );
+//!     println!("{:#?}", violation.backtrace());
+//! }
+//! } // only for #[cfg]
+//! ```
 
 #[cfg(feature = "deadlock_detection")]
 pub use parking_lot_core::deadlock::check_deadlock;
+#[cfg(feature = "lock_order_detection")]
+pub(crate) use parking_lot_core::deadlock::forget_resource;
 pub(crate) use parking_lot_core::deadlock::{acquire_resource, release_resource};
+#[cfg(feature = "lock_levels")]
+pub(crate) use parking_lot_core::deadlock::{acquire_resource_level, check_resource_level};
+#[cfg(feature = "lock_levels")]
+pub use parking_lot_core::deadlock::{check_lock_levels, set_lock_level_panic, LockLevelViolation};
+#[cfg(feature = "lock_order_detection")]
+pub use parking_lot_core::deadlock::{check_lock_order, LockOrderViolation};
 
 #[cfg(test)]
 #[cfg(feature = "deadlock_detection")]
@@ -232,3 +331,394 @@ mod tests {
         assert!(!check_deadlock());
     }
 }
+
+#[cfg(test)]
+#[cfg(feature = "lock_order_detection")]
+mod lock_order_tests {
+    use crate::{Mutex, RwLock};
+    use parking_lot_core::deadlock::{check_lock_order, LockOrderViolation};
+    use std::thread;
+
+    // We need to serialize these tests since lock order checking uses global
+    // state.
+    lazy_static::lazy_static! {
+        static ref LOCK_ORDER_LOCK: Mutex<()> = Mutex::new(());
+        static ref M1: Mutex<()> = Mutex::new(());
+        static ref M2: Mutex<()> = Mutex::new(());
+        static ref M3: Mutex<()> = Mutex::new(());
+        static ref M4: Mutex<()> = Mutex::new(());
+        static ref M5: Mutex<()> = Mutex::new(());
+        static ref R1: RwLock<()> = RwLock::new(());
+        static ref R2: RwLock<()> = RwLock::new(());
+    }
+
+    fn key<T>(raw: &T) -> usize {
+        raw as *const T as usize
+    }
+
+    fn violations(a: usize, b: usize) -> Vec<LockOrderViolation> {
+        check_lock_order()
+            .into_iter()
+            .filter(|v| {
+                (v.held_resource() == a && v.acquired_resource() == b)
+                    || (v.held_resource() == b && v.acquired_resource() == a)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mutex_inversion() {
+        let _guard = LOCK_ORDER_LOCK.lock();
+        let (k1, k2) = unsafe { (key(M1.raw()), key(M2.raw())) };
+
+        thread::spawn(|| {
+            let _g1 = M1.lock();
+            let _g2 = M2.lock();
+        })
+        .join()
+        .unwrap();
+        assert!(violations(k1, k2).is_empty());
+
+        thread::spawn(|| {
+            let _g2 = M2.lock();
+            let _g1 = M1.lock();
+        })
+        .join()
+        .unwrap();
+        let v = violations(k1, k2);
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0].held_resource(), k2);
+        assert_eq!(v[0].acquired_resource(), k1);
+
+        // Only reported the first time
+        {
+            let _g2 = M2.lock();
+            let _g1 = M1.lock();
+        }
+        assert!(violations(k1, k2).is_empty());
+    }
+
+    #[test]
+    fn test_transitive_inversion() {
+        let _guard = LOCK_ORDER_LOCK.lock();
+        let (k3, k5) = unsafe { (key(M3.raw()), key(M5.raw())) };
+
+        for _ in 0..2 {
+            let g3 = M3.lock();
+            let _g4 = M4.lock();
+            drop(g3);
+            let _g5 = M5.lock();
+        }
+        assert!(violations(k3, k5).is_empty());
+
+        // M3 -> M4 -> M5 implies M3 -> M5 even though they were never held
+        // together.
+        {
+            let _g5 = M5.lock();
+            let _g3 = M3.lock();
+        }
+        assert_eq!(violations(k3, k5).len(), 1);
+    }
+
+    #[inline(never)]
+    fn lock_pair(first: &Mutex<()>, second: &Mutex<()>) {
+        let _g1 = first.lock();
+        let _g2 = second.lock();
+    }
+
+    #[inline(never)]
+    fn lock_pair_closing(first: &Mutex<()>, second: &Mutex<()>) {
+        let _g1 = first.lock();
+        let _g2 = second.lock();
+    }
+
+    #[test]
+    fn test_inversion_backtrace() {
+        let _guard = LOCK_ORDER_LOCK.lock();
+        let (a, b, c) = (Mutex::new(()), Mutex::new(()), Mutex::new(()));
+        let (ka, kc) = unsafe { (key(a.raw()), key(c.raw())) };
+
+        lock_pair(&a, &b);
+        lock_pair_closing(&b, &c);
+        lock_pair(&c, &a);
+
+        // The previous backtrace is the one of the B -> C edge, which acquired
+        // the held lock.
+        let v = violations(ka, kc);
+        assert_eq!(v.len(), 1);
+        let previous = v[0]
+            .previous_backtrace()
+            .frames()
+            .iter()
+            .flat_map(|frame| frame.symbols())
+            .filter_map(|symbol| symbol.name().map(|name| name.to_string()))
+            .collect::<Vec<_>>();
+        assert!(previous
+            .iter()
+            .any(|name| name.contains("lock_pair_closing")));
+    }
+
+    #[test]
+    fn test_rwlock_inversion() {
+        let _guard = LOCK_ORDER_LOCK.lock();
+        let (k1, k2) = unsafe { (key(R1.raw()), key(R2.raw())) };
+
+        {
+            let _g1 = R1.read();
+            let _g2 = R2.write();
+        }
+        {
+            let _g2 = R2.read();
+            let _g1 = R1.write();
+        }
+
+        // The two resources registered by each RwLock are only reported once.
+        let count = check_lock_order()
+            .iter()
+            .filter(|v| v.held_resource() == k2 || v.held_resource() == k2 + 1)
+            .filter(|v| v.acquired_resource() == k1 || v.acquired_resource() == k1 + 1)
+            .count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_adjacent_inversion() {
+        let _guard = LOCK_ORDER_LOCK.lock();
+        // Unless other features add fields to them, these are one byte apart.
+        let locks = [Mutex::new(()), Mutex::new(())];
+        let (k1, k2) = unsafe { (key(locks[0].raw()), key(locks[1].raw())) };
+
+        {
+            let _g1 = locks[0].lock();
+            let _g2 = locks[1].lock();
+        }
+        {
+            let _g2 = locks[1].lock();
+            let _g1 = locks[0].lock();
+        }
+        assert_eq!(violations(k1, k2).len(), 1);
+    }
+
+    #[test]
+    fn test_forget_on_drop() {
+        let _guard = LOCK_ORDER_LOCK.lock();
+        let mut a = Mutex::new(());
+        let b = Mutex::new(());
+        let (ka, kb) = unsafe { (key(a.raw()), key(b.raw())) };
+
+        {
+            let _ga = a.lock();
+            let _gb = b.lock();
+        }
+
+        // The old lock is dropped and the new one reuses its address.
+        a = Mutex::new(());
+        assert_eq!(unsafe { key(a.raw()) }, ka);
+        {
+            let _gb = b.lock();
+            let _ga = a.lock();
+        }
+        assert!(violations(ka, kb).is_empty());
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "lock_levels")]
+mod lock_level_tests {
+    use crate::{lock_all, Condvar, FairMutex, Mutex, RwLock};
+    use parking_lot_core::deadlock::{check_lock_levels, set_lock_level_panic};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // We need to serialize these tests since whether violations panic is a
+    // global setting.
+    lazy_static::lazy_static! {
+        static ref LOCK_LEVEL_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn test_increasing_levels() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = Mutex::with_level((), 1);
+        let b = RwLock::with_level((), 2);
+        let c = FairMutex::with_level((), 3);
+
+        let _a = a.lock();
+        let _b = b.read();
+        let _c = c.lock();
+    }
+
+    #[test]
+    fn test_decreasing_level_panics() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = Arc::new(Mutex::with_level((), 1));
+        let b = Arc::new(Mutex::with_level((), 2));
+
+        let (a2, b2) = (a.clone(), b.clone());
+        let result = thread::spawn(move || {
+            let _b = b2.lock();
+            let _a = a2.lock();
+        })
+        .join();
+        assert!(result.is_err());
+
+        // The check happens before acquiring, so neither lock is left held.
+        assert!(a.try_lock().is_some());
+        assert!(b.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_equal_level_panics() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = Arc::new(RwLock::with_level((), 1));
+        let b = Arc::new(RwLock::with_level((), 1));
+
+        let (a2, b2) = (a.clone(), b.clone());
+        let result = thread::spawn(move || {
+            let _a = a2.read();
+            let _b = b2.write();
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(b.try_write().is_some());
+    }
+
+    #[test]
+    fn test_try_lock_unchecked() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = Arc::new(Mutex::with_level((), 1));
+        let b = Arc::new(RwLock::with_level((), 2));
+
+        // Acquiring a lock without blocking can't deadlock, so it isn't
+        // checked, but its level is still recorded.
+        let (a2, b2) = (a.clone(), b.clone());
+        let result = thread::spawn(move || {
+            let _b = b2.read();
+            assert!(a2.try_lock().is_some());
+            assert!(a2.try_lock_for(Duration::from_millis(1)).is_some());
+            let _a = a2.try_lock().unwrap();
+            drop(b2.try_write());
+        })
+        .join();
+        assert!(result.is_ok());
+
+        let (a2, b2) = (a.clone(), b.clone());
+        let result = thread::spawn(move || {
+            let _b = b2.try_write().unwrap();
+            let _a = a2.lock();
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(a.try_lock().is_some());
+        assert!(b.try_write().is_some());
+    }
+
+    #[test]
+    fn test_lock_all() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let base = Mutex::with_level((), 0);
+        let lo = Mutex::with_level((), 1);
+        let hi = Mutex::with_level((), 2);
+
+        // `lock_all` tries to acquire the other locks while holding the first
+        // one, whatever their order.
+        let _base = base.lock();
+        drop(lock_all((&hi, &lo)));
+        drop(lock_all((&lo, &hi)));
+        assert!(lo.try_lock().is_some());
+        assert!(hi.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_released_levels() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = Mutex::with_level((), 1);
+        let b = Mutex::with_level((), 2);
+        let c = Mutex::new(());
+
+        // Unleveled locks are ignored, and a level stops counting once its
+        // lock is released.
+        drop(b.lock());
+        let _c = c.lock();
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+
+    #[test]
+    fn test_recursive_read() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = RwLock::with_level((), 1);
+
+        let _r1 = a.read();
+        let _r2 = a.read_recursive();
+    }
+
+    #[test]
+    fn test_condvar_relock() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let pair = Arc::new((Mutex::with_level(false, 2), Condvar::new()));
+        let outer = Mutex::with_level((), 1);
+
+        let pair2 = pair.clone();
+        let notifier = thread::spawn(move || {
+            *pair2.0.lock() = true;
+            pair2.1.notify_all();
+        });
+
+        let _outer = outer.lock();
+        let mut started = pair.0.lock();
+        while !*started {
+            pair.1.wait(&mut started);
+        }
+        drop(started);
+        notifier.join().unwrap();
+    }
+
+    #[test]
+    fn test_condvar_relock_out_of_order() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let pair = Arc::new((Mutex::with_level(false, 1), Condvar::new()));
+        let inner = Mutex::with_level((), 2);
+
+        let pair2 = pair.clone();
+        let notifier = thread::spawn(move || {
+            *pair2.0.lock() = true;
+            pair2.1.notify_all();
+        });
+
+        // Reacquiring the mutex after the wait isn't a new acquisition, so
+        // the higher level lock acquired in the meantime is allowed.
+        let mut started = pair.0.lock();
+        let _inner = inner.lock();
+        while !*started {
+            pair.1.wait(&mut started);
+        }
+        drop(started);
+        notifier.join().unwrap();
+    }
+
+    #[test]
+    fn test_report() {
+        let _guard = LOCK_LEVEL_LOCK.lock();
+        let a = Mutex::with_level((), 1);
+        let b = Mutex::with_level((), 2);
+        let (ka, kb) = unsafe { (a.raw() as *const _ as usize, b.raw() as *const _ as usize) };
+
+        set_lock_level_panic(false);
+        {
+            let _b = b.lock();
+            let _a = a.lock();
+        }
+        set_lock_level_panic(true);
+
+        let violations: Vec<_> = check_lock_levels()
+            .into_iter()
+            .filter(|v| v.acquired_resource() == ka)
+            .collect();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].held_resource(), kb);
+        assert_eq!(violations[0].held_level(), 2);
+        assert_eq!(violations[0].acquired_level(), 1);
+    }
+}
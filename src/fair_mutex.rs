@@ -253,7 +253,10 @@ mod tests {
 
         assert_eq!(format!("{:?}", mutex), "Mutex { data: [0, 10] }");
         let _lock = mutex.lock();
+        #[cfg(not(feature = "owner_tracking"))]
         assert_eq!(format!("{:?}", mutex), "Mutex { data: <locked> }");
+        #[cfg(feature = "owner_tracking")]
+        assert!(format!("{:?}", mutex).starts_with("Mutex { data: <locked>, owner: LockOwner {"));
     }
 
     #[cfg(feature = "serde")]
@@ -0,0 +1,176 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_fair_rwlock::RawFairRwLock;
+
+/// A reader-writer lock that is always fair, useful for protecting shared data
+///
+/// This behaves like `RwLock`, except that every unlock hands the lock off
+/// directly to the threads waiting for it, as if `unlock_fair` had been called
+/// on the guard. A thread releasing the lock therefore can't immediately
+/// re-acquire it ahead of the threads that were already queued, at the cost of
+/// throughput.
+///
+/// The regular `RwLock` only becomes fair on average through eventual
+/// fairness, which doesn't bound how long a waiting thread may lose to faster
+/// but later ones. Using this type instead avoids having to remember to call
+/// `unlock_fair` on every guard.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::FairRwLock;
+///
+/// let lock = FairRwLock::new(5);
+///
+/// // many reader locks can be held at once
+/// {
+///     let r1 = lock.read();
+///     let r2 = lock.read();
+///     assert_eq!(*r1, 5);
+///     assert_eq!(*r2, 5);
+/// } // read locks are dropped at this point
+///
+/// // only one write lock may be held, however
+/// {
+///     let mut w = lock.write();
+///     *w += 1;
+///     assert_eq!(*w, 6);
+/// } // write lock is dropped here
+/// ```
+pub type FairRwLock<T> = lock_api::RwLock<RawFairRwLock, T>;
+
+/// RAII structure used to release the shared read access of a lock when
+/// dropped.
+pub type FairRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawFairRwLock, T>;
+
+/// RAII structure used to release the exclusive write access of a lock when
+/// dropped.
+pub type FairRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawFairRwLock, T>;
+
+/// An RAII read lock guard returned by `FairRwLockReadGuard::map`, which can
+/// point to a subfield of the protected data.
+///
+/// The main difference between `MappedFairRwLockReadGuard` and
+/// `FairRwLockReadGuard` is that the former doesn't support temporarily
+/// unlocking and re-locking, since that could introduce soundness issues if the
+/// locked object is modified by another thread.
+pub type MappedFairRwLockReadGuard<'a, T> = lock_api::MappedRwLockReadGuard<'a, RawFairRwLock, T>;
+
+/// An RAII write lock guard returned by `FairRwLockWriteGuard::map`, which can
+/// point to a subfield of the protected data.
+///
+/// The main difference between `MappedFairRwLockWriteGuard` and
+/// `FairRwLockWriteGuard` is that the former doesn't support temporarily
+/// unlocking and re-locking, since that could introduce soundness issues if the
+/// locked object is modified by another thread.
+pub type MappedFairRwLockWriteGuard<'a, T> = lock_api::MappedRwLockWriteGuard<'a, RawFairRwLock, T>;
+
+/// RAII structure used to release the upgradable read access of a lock when
+/// dropped.
+pub type FairRwLockUpgradableReadGuard<'a, T> =
+    lock_api::RwLockUpgradableReadGuard<'a, RawFairRwLock, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::{FairRwLock, FairRwLockUpgradableReadGuard, FairRwLockWriteGuard};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn wait_for_parked<T>(lock: &FairRwLock<T>, count: usize) {
+        let addr = unsafe { lock.raw() } as *const _ as usize;
+        while parking_lot_core::parked_threads(addr).len() != count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let l = FairRwLock::new(());
+        drop(l.read());
+        drop(l.write());
+        drop(l.upgradable_read());
+        drop((l.read(), l.read()));
+        drop((l.read(), l.upgradable_read()));
+        drop(l.write());
+    }
+
+    #[test]
+    fn test_rw_arc() {
+        let arc = Arc::new(FairRwLock::new(0));
+        let arc2 = arc.clone();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            let mut lock = arc2.write();
+            for _ in 0..10 {
+                let tmp = *lock;
+                *lock = -1;
+                thread::yield_now();
+                *lock = tmp + 1;
+            }
+            tx.send(()).unwrap();
+        });
+
+        // Readers try to catch the writer in the act
+        let mut children = Vec::new();
+        for _ in 0..5 {
+            let arc3 = arc.clone();
+            children.push(thread::spawn(move || {
+                let lock = arc3.read();
+                assert!(*lock >= 0);
+            }));
+        }
+
+        // Wait for children to pass their asserts
+        for r in children {
+            assert!(r.join().is_ok());
+        }
+
+        // Wait for writer to finish
+        rx.recv().unwrap();
+        let lock = arc.read();
+        assert_eq!(*lock, 10);
+    }
+
+    #[test]
+    fn test_upgrade_downgrade() {
+        let l = FairRwLock::new(0);
+        let u = l.upgradable_read();
+        let mut w = FairRwLockUpgradableReadGuard::upgrade(u);
+        *w += 1;
+        let r = FairRwLockWriteGuard::downgrade(w);
+        assert_eq!(*r, 1);
+        assert!(l.try_write().is_none());
+        drop(r);
+        assert_eq!(*l.try_write().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_unlock_hands_off() {
+        let l = Arc::new(FairRwLock::new(0));
+        let guard = l.write();
+
+        let l2 = l.clone();
+        let t = thread::spawn(move || {
+            let mut guard = l2.write();
+            *guard += 1;
+            thread::sleep(Duration::from_millis(10));
+        });
+        wait_for_parked(&l, 1);
+
+        // The lock now belongs to the waiting writer, so it can't be taken
+        // again straight away.
+        drop(guard);
+        assert!(l.try_read().is_none());
+        assert!(l.try_write().is_none());
+        t.join().unwrap();
+        assert_eq!(*l.read(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Thin wrappers around the Linux futex system call, used by the lock types
+// which wait directly on their own state word instead of in the parking lot.
+//
+// Each operation is parameterized by a `FutexScope`, which selects between
+// futexes that are private to the current process and futexes which may be
+// placed in memory shared with other processes.
+
+use core::{cell::Cell, ptr, sync::atomic::AtomicU32};
+use std::{
+    io,
+    sync::Once,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+// x32 Linux uses a non-standard type for tv_nsec in timespec.
+// See https://sourceware.org/bugzilla/show_bug.cgi?id=16437
+#[cfg(all(target_arch = "x86_64", target_pointer_width = "32"))]
+#[allow(non_camel_case_types)]
+type tv_nsec_t = i64;
+#[cfg(not(all(target_arch = "x86_64", target_pointer_width = "32")))]
+#[allow(non_camel_case_types)]
+type tv_nsec_t = libc::c_long;
+
+// Selects whether a futex may be shared between processes.
+pub trait FutexScope {
+    // Flags added to every futex operation.
+    const FLAGS: libc::c_int;
+}
+
+// The futex is only accessed by the current process, which lets the kernel
+// skip looking up the underlying memory mapping.
+pub struct Private;
+
+impl FutexScope for Private {
+    const FLAGS: libc::c_int = libc::FUTEX_PRIVATE_FLAG;
+}
+
+// The futex may be in memory shared with other processes.
+pub struct Shared;
+
+impl FutexScope for Shared {
+    const FLAGS: libc::c_int = 0;
+}
+
+pub fn errno() -> libc::c_int {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *libc::__errno_location()
+    }
+    #[cfg(target_os = "android")]
+    unsafe {
+        *libc::__errno()
+    }
+}
+
+// Blocks the current thread as long as the futex contains `expected`, until it
+// is woken up by `futex_wake` or the timeout is reached. Spurious wake-ups are
+// possible. Returns false only if the timeout was reached.
+#[inline]
+pub fn futex_wait<S: FutexScope>(
+    futex: &AtomicU32,
+    expected: u32,
+    timeout: Option<Instant>,
+) -> bool {
+    let ts = match timeout {
+        Some(timeout) => {
+            let now = Instant::now();
+            if timeout <= now {
+                return false;
+            }
+            let diff = timeout - now;
+            if diff.as_secs() as libc::time_t as u64 != diff.as_secs() {
+                // Timeout overflowed, just sleep indefinitely
+                None
+            } else {
+                Some(libc::timespec {
+                    tv_sec: diff.as_secs() as libc::time_t,
+                    tv_nsec: diff.subsec_nanos() as tv_nsec_t,
+                })
+            }
+        }
+        None => None,
+    };
+    let ts_ptr = ts
+        .as_ref()
+        .map(|ts_ref| ts_ref as *const _)
+        .unwrap_or(ptr::null());
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex,
+            libc::FUTEX_WAIT | S::FLAGS,
+            expected,
+            ts_ptr,
+        )
+    };
+    debug_assert!(r == 0 || r == -1);
+    if r == -1 {
+        debug_assert!(
+            errno() == libc::EINTR
+                || errno() == libc::EAGAIN
+                || (ts.is_some() && errno() == libc::ETIMEDOUT)
+        );
+        return errno() != libc::ETIMEDOUT;
+    }
+    true
+}
+
+// Wakes up at most `count` threads waiting on the futex. Returns the number of
+// threads which were woken up.
+#[inline]
+pub fn futex_wake<S: FutexScope>(futex: &AtomicU32, count: libc::c_int) -> usize {
+    let r = unsafe { libc::syscall(libc::SYS_futex, futex, libc::FUTEX_WAKE | S::FLAGS, count) };
+    debug_assert!(r >= 0);
+    r as usize
+}
+
+// Wakes up all threads waiting on the futex.
+#[inline]
+#[allow(clippy::legacy_numeric_constants)] // c_int::MAX requires Rust 1.43
+pub fn futex_wake_all<S: FutexScope>(futex: &AtomicU32) -> usize {
+    futex_wake::<S>(futex, libc::c_int::max_value())
+}
+
+thread_local! {
+    // Kernel thread ID of the current thread, or 0 if it isn't known yet.
+    // `const` initializers need Rust 1.59, which is newer than our MSRV.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static TID: Cell<u32> = Cell::new(0);
+}
+
+// Returns the kernel thread ID of the current thread, which the PI and robust
+// futex protocols store in the futex to identify its owner. This is cached so
+// that locking doesn't need a system call.
+#[inline]
+pub fn current_tid() -> u32 {
+    TID.try_with(|tid| match tid.get() {
+        0 => cache_tid(tid),
+        cached => cached,
+    })
+    .unwrap_or_else(|_| gettid())
+}
+
+#[cold]
+fn cache_tid(tid: &Cell<u32>) -> u32 {
+    // The child of a `fork` inherits the cached ID of the thread which forked
+    // it, so the cache is reset in the child. This is registered before
+    // anything is cached.
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        extern "C" fn reset_tid() {
+            let _ = TID.try_with(|tid| tid.set(0));
+        }
+        let r = unsafe { libc::pthread_atfork(None, None, Some(reset_tid)) };
+        debug_assert_eq!(r, 0);
+    });
+
+    let current = gettid();
+    tid.set(current);
+    current
+}
+
+#[inline]
+fn gettid() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+// Acquires a priority-inheritance futex on behalf of the current thread,
+// boosting the priority of its owner while blocked. The futex must contain the
+// thread ID of its owner, or 0 if it is unlocked. Returns false only if the
+// timeout was reached.
+pub fn futex_lock_pi<S: FutexScope>(futex: &AtomicU32, timeout: Option<Instant>) -> bool {
+    // FUTEX_LOCK_PI takes an absolute timeout measured against CLOCK_REALTIME.
+    let ts = timeout.and_then(|timeout| {
+        let now = Instant::now();
+        let remaining = if timeout > now {
+            timeout - now
+        } else {
+            Default::default()
+        };
+        let realtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .checked_add(remaining)?;
+        if realtime.as_secs() as libc::time_t as u64 != realtime.as_secs() {
+            // Timeout overflowed, just sleep indefinitely
+            return None;
+        }
+        Some(libc::timespec {
+            tv_sec: realtime.as_secs() as libc::time_t,
+            tv_nsec: realtime.subsec_nanos() as tv_nsec_t,
+        })
+    });
+    let ts_ptr = ts
+        .as_ref()
+        .map(|ts_ref| ts_ref as *const _)
+        .unwrap_or(ptr::null());
+    loop {
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                futex,
+                libc::FUTEX_LOCK_PI | S::FLAGS,
+                0,
+                ts_ptr,
+            )
+        };
+        if r == 0 {
+            return true;
+        }
+        match errno() {
+            libc::ETIMEDOUT => return false,
+            // EAGAIN means that the owner is exiting, so try again.
+            libc::EINTR | libc::EAGAIN => {}
+            e => panic!("FUTEX_LOCK_PI failed: {}", io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+// Releases a priority-inheritance futex held by the current thread and hands
+// it over to the highest priority waiter.
+#[inline]
+pub fn futex_unlock_pi<S: FutexScope>(futex: &AtomicU32) {
+    let r = unsafe { libc::syscall(libc::SYS_futex, futex, libc::FUTEX_UNLOCK_PI | S::FLAGS) };
+    debug_assert_eq!(r, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_tid, gettid};
+    use std::thread;
+
+    #[test]
+    fn cached_tid() {
+        let tid = current_tid();
+        assert_eq!(tid, gettid());
+        assert_eq!(current_tid(), tid);
+
+        let other = thread::spawn(current_tid).join().unwrap();
+        assert_ne!(other, tid);
+
+        // The child of a `fork` must not use the ID cached by its parent. It
+        // only makes system calls, since other threads of the test binary may
+        // have held locks or the allocator when it was forked.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let ok = current_tid() == gettid() && current_tid() != tid;
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+}
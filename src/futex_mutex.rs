@@ -0,0 +1,130 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_futex_mutex::RawFutexMutex;
+
+/// A mutual exclusion primitive which waits directly on a Linux futex.
+///
+/// This behaves like `Mutex`, except that contended threads sleep on the
+/// mutex's own 32-bit state word with `FUTEX_WAIT_PRIVATE` instead of going
+/// through the parking lot's global hash table. This avoids contention on hash
+/// table buckets shared with unrelated locks, at the cost of a larger mutex
+/// and no eventual fairness. Threads are woken up in an unspecified order.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::FutexMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let data = Arc::new(FutexMutex::new(0));
+/// let threads: Vec<_> = (0..10)
+///     .map(|_| {
+///         let data = Arc::clone(&data);
+///         thread::spawn(move || *data.lock() += 1)
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(*data.lock(), 10);
+/// ```
+pub type FutexMutex<T> = lock_api::Mutex<RawFutexMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of a `FutexMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type FutexMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawFutexMutex, T>;
+
+/// An RAII mutex guard returned by `FutexMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+pub type MappedFutexMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawFutexMutex, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::FutexMutex;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn smoke() {
+        let m = FutexMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(FutexMutex::new(0));
+
+        fn inc(m: &FutexMutex<u32>) {
+            for _ in 0..J {
+                *m.lock() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..K {
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+        }
+
+        drop(tx);
+        for _ in 0..2 * K {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = FutexMutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn try_lock_for() {
+        let m = Arc::new(FutexMutex::new(0));
+        let guard = m.lock();
+
+        let start = Instant::now();
+        assert!(m.try_lock_for(Duration::from_millis(50)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // A thread which timed out must not prevent the next one from being
+        // woken up.
+        let m2 = m.clone();
+        let t = thread::spawn(move || *m2.lock() += 1);
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(*m.try_lock_for(Duration::from_secs(10)).unwrap(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_futex_rwlock::RawFutexRwLock;
+
+/// A reader-writer lock which waits directly on a Linux futex.
+///
+/// This behaves like `RwLock`, except that contended threads sleep on the
+/// lock's own state with `FUTEX_WAIT_PRIVATE` instead of going through the
+/// parking lot's global hash table. Upgradable reads and downgrading are not
+/// supported.
+///
+/// This lock prefers writers: once a writer is waiting for the lock, new
+/// readers block until the writer has acquired and released it. This means
+/// that recursive read locks can deadlock.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::FutexRwLock;
+///
+/// let lock = FutexRwLock::new(5);
+///
+/// // many reader locks can be held at once
+/// {
+///     let r1 = lock.read();
+///     let r2 = lock.read();
+///     assert_eq!(*r1, 5);
+///     assert_eq!(*r2, 5);
+/// } // read locks are dropped at this point
+///
+/// // only one write lock may be held, however
+/// {
+///     let mut w = lock.write();
+///     *w += 1;
+///     assert_eq!(*w, 6);
+/// } // write lock is dropped here
+/// ```
+pub type FutexRwLock<T> = lock_api::RwLock<RawFutexRwLock, T>;
+
+/// RAII structure used to release the shared read access of a `FutexRwLock`
+/// when dropped.
+pub type FutexRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawFutexRwLock, T>;
+
+/// RAII structure used to release the exclusive write access of a
+/// `FutexRwLock` when dropped.
+pub type FutexRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawFutexRwLock, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::FutexRwLock;
+    use rand::Rng;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn smoke() {
+        let l = FutexRwLock::new(());
+        drop(l.read());
+        drop(l.write());
+        drop((l.read(), l.read()));
+        drop(l.write());
+    }
+
+    #[test]
+    fn frob() {
+        const N: u32 = 10;
+        const M: u32 = 1000;
+
+        let r = Arc::new(FutexRwLock::new(()));
+
+        let (tx, rx) = channel::<()>();
+        for _ in 0..N {
+            let tx = tx.clone();
+            let r = r.clone();
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..M {
+                    if rng.gen_bool(1.0 / N as f64) {
+                        drop(r.write());
+                    } else {
+                        drop(r.read());
+                    }
+                }
+                drop(tx);
+            });
+        }
+        drop(tx);
+        let _ = rx.recv();
+    }
+
+    #[test]
+    fn test_rw_arc() {
+        let arc = Arc::new(FutexRwLock::new(0));
+        let arc2 = arc.clone();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            let mut lock = arc2.write();
+            for _ in 0..10 {
+                let tmp = *lock;
+                *lock = -1;
+                thread::yield_now();
+                *lock = tmp + 1;
+            }
+            tx.send(()).unwrap();
+        });
+
+        // Readers try to catch the writer in the act
+        let mut children = Vec::new();
+        for _ in 0..5 {
+            let arc3 = arc.clone();
+            children.push(thread::spawn(move || {
+                let lock = arc3.read();
+                assert!(*lock >= 0);
+            }));
+        }
+
+        // Wait for children to pass their asserts
+        for r in children {
+            assert!(r.join().is_ok());
+        }
+
+        // Wait for writer to finish
+        rx.recv().unwrap();
+        let lock = arc.read();
+        assert_eq!(*lock, 10);
+    }
+
+    #[test]
+    fn test_writer_blocks_new_readers() {
+        let lock = Arc::new(FutexRwLock::new(()));
+        let reader = lock.read();
+
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || drop(lock2.write()));
+        while lock.try_read_for(Duration::from_millis(1)).is_some() {
+            thread::yield_now();
+        }
+
+        drop(reader);
+        writer.join().unwrap();
+        assert!(lock.try_read().is_some());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let lock = Arc::new(FutexRwLock::new(0));
+        let writer = lock.write();
+        assert!(lock.try_read_for(Duration::from_millis(20)).is_none());
+        assert!(lock.try_write_for(Duration::from_millis(20)).is_none());
+        drop(writer);
+
+        // Threads which timed out must not prevent others from being woken up.
+        let reader = lock.read();
+        assert!(lock.try_write_for(Duration::from_millis(20)).is_none());
+        let done = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let lock = lock.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    if i % 2 == 0 {
+                        *lock.write() += 1;
+                    } else {
+                        let _guard = lock.read();
+                    }
+                    done.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        drop(reader);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+        assert_eq!(*lock.read(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::once_cell::OnceCell;
+use core::{cell::Cell, fmt, ops::Deref};
+use std::panic::RefUnwindSafe;
+
+/// A value which is initialized on the first access.
+///
+/// The initialization closure is run at most once, by the first thread to
+/// dereference the `Lazy`; any other threads accessing it concurrently block
+/// until the value is available. If the closure panics, the `Lazy` is poisoned
+/// and all future accesses will panic as well.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Lazy;
+/// use std::collections::HashMap;
+///
+/// static TABLE: Lazy<HashMap<u32, &'static str>> = Lazy::new(|| {
+///     let mut m = HashMap::new();
+///     m.insert(1, "one");
+///     m.insert(2, "two");
+///     m
+/// });
+///
+/// assert_eq!(TABLE.get(&2), Some(&"two"));
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: Cell<Option<F>>,
+}
+
+// The closure is only ever taken out of `init` by the thread which runs the
+// initialization, while holding the lock of the cell.
+unsafe impl<T, F: Send> Sync for Lazy<T, F> where OnceCell<T>: Sync {}
+
+impl<T, F: RefUnwindSafe> RefUnwindSafe for Lazy<T, F> where OnceCell<T>: RefUnwindSafe {}
+
+impl<T, F> Lazy<T, F> {
+    /// Creates a new lazy value with the given initializing function.
+    #[inline]
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Forces the evaluation of this lazy value and returns a reference to the
+    /// result. This is equivalent to the `Deref` impl, but is explicit.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Lazy::force(...)`. A method would interfere with methods of the same
+    /// name on the contents of the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous initialization panicked.
+    #[inline]
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+
+    /// Returns a reference to the value if it has already been initialized.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Lazy::get(...)`. A method would interfere with methods of the same
+    /// name on the contents of the value.
+    #[inline]
+    pub fn get(this: &Lazy<T, F>) -> Option<&T> {
+        this.cell.get()
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: Default> Default for Lazy<T> {
+    #[inline]
+    fn default() -> Lazy<T> {
+        Lazy::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lazy").field("cell", &self.cell).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Lazy;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn smoke_lazy() {
+        let mut called = 0;
+        {
+            let x = Lazy::new(|| {
+                called += 1;
+                92
+            });
+            assert!(Lazy::get(&x).is_none());
+            assert_eq!(*x, 92);
+            assert_eq!(*x, 92);
+            assert_eq!(Lazy::get(&x), Some(&92));
+        }
+        assert_eq!(called, 1);
+    }
+
+    #[test]
+    fn static_lazy() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        static X: Lazy<Vec<usize>> = Lazy::new(|| {
+            RUNS.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2, 3]
+        });
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| thread::spawn(|| assert_eq!(X.len(), 3)))
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn poison_lazy() {
+        let x: Lazy<u32> = Lazy::new(|| panic!());
+        assert!(panic::catch_unwind(|| *x).is_err());
+        assert!(panic::catch_unwind(|| *x).is_err());
+        assert!(Lazy::get(&x).is_none());
+    }
+
+    #[test]
+    fn test_lazy_debug() {
+        let x = Lazy::new(|| 1);
+        assert_eq!(format!("{:?}", x), "Lazy { cell: OnceCell(<uninit>) }");
+        Lazy::force(&x);
+        assert_eq!(format!("{:?}", x), "Lazy { cell: OnceCell(1) }");
+    }
+}
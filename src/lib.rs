@@ -7,41 +7,161 @@
 
 //! This library provides implementations of `Mutex`, `RwLock`, `Condvar` and
 //! `Once` that are smaller, faster and more flexible than those in the Rust
-//! standard library. It also provides a `ReentrantMutex` type.
+//! standard library, along with the `OnceCell` and `Lazy` types built on top of
+//! `Once`. It also provides a `ReentrantMutex` type, a `CondvarAny`
+//! type which works with any kind of lock, a counting `Semaphore`, as well as
+//! `AsyncMutex` and `AsyncRwLock` types for use in asynchronous code.
+//!
+//! The `compat` module provides drop-in replacements for the `std::sync`
+//! primitives, with the same poisoning API, for migrating existing code.
 
 #![warn(missing_docs)]
 #![warn(rust_2018_idioms)]
-#![cfg_attr(feature = "nightly", feature(asm))]
+#![cfg_attr(feature = "nightly", feature(asm, const_fn))]
 
+#[cfg(feature = "tracing")]
+extern crate tracing_crate as tracing;
+
+mod async_mutex;
+mod async_rwlock;
 mod condvar;
+mod condvar_any;
 mod elision;
 mod fair_mutex;
+mod fair_rwlock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod futex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod futex_mutex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod futex_rwlock;
+mod lazy;
+mod lock_level;
+mod multi_lock;
 mod mutex;
 mod once;
+mod once_cell;
+mod owner;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod pi_mutex;
+mod poison;
+mod poison_mutex;
+mod poison_rwlock;
 mod raw_mutex;
 mod raw_fair_mutex;
+mod raw_fair_rwlock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod raw_futex_mutex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod raw_futex_rwlock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod raw_pi_mutex;
+mod raw_policy_rwlock;
 mod raw_rwlock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod raw_robust_mutex;
+mod raw_semaphore;
 mod remutex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod robust_mutex;
 mod rwlock;
+mod semaphore;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod shared_condvar;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod shared_mutex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod shared_rwlock;
+mod stats;
+mod trace;
 mod util;
+mod watchdog;
+
+pub mod compat;
 
-#[cfg(feature = "deadlock_detection")]
+#[cfg(any(
+    feature = "deadlock_detection",
+    feature = "lock_order_detection",
+    feature = "lock_levels"
+))]
 pub mod deadlock;
-#[cfg(not(feature = "deadlock_detection"))]
+#[cfg(not(any(
+    feature = "deadlock_detection",
+    feature = "lock_order_detection",
+    feature = "lock_levels"
+)))]
 mod deadlock;
 
+pub use self::async_mutex::{AsyncMutex, AsyncMutexGuard, AsyncMutexLockFuture};
+pub use self::async_rwlock::{
+    AsyncRwLock, AsyncRwLockReadFuture, AsyncRwLockReadGuard, AsyncRwLockWriteFuture,
+    AsyncRwLockWriteGuard,
+};
 pub use self::condvar::{Condvar, WaitTimeoutResult};
+pub use self::condvar_any::CondvarAny;
+pub use self::multi_lock::{
+    lock2, lock_all, lock_slice, try_lock_all, try_lock_all_for, try_lock_all_until,
+    try_lock_slice, try_lock_slice_for, try_lock_slice_until, ReadLock, WriteLock,
+};
 pub use self::mutex::{MappedMutexGuard, Mutex, MutexGuard};
+#[cfg(feature = "arc_lock")]
+pub use self::mutex::{ArcMutexGuard, MappedArcMutexGuard};
 pub use self::fair_mutex::{MappedFairMutexGuard, FairMutex, FairMutexGuard};
+pub use self::fair_rwlock::{
+    FairRwLock, FairRwLockReadGuard, FairRwLockUpgradableReadGuard, FairRwLockWriteGuard,
+    MappedFairRwLockReadGuard, MappedFairRwLockWriteGuard,
+};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::futex_mutex::{FutexMutex, FutexMutexGuard, MappedFutexMutexGuard};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::futex_rwlock::{FutexRwLock, FutexRwLockReadGuard, FutexRwLockWriteGuard};
+pub use self::lazy::Lazy;
 pub use self::once::{Once, OnceState};
+pub use self::once_cell::OnceCell;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::pi_mutex::{MappedPiMutexGuard, PiMutex, PiMutexGuard};
+pub use self::poison_mutex::{PoisonMutex, PoisonMutexGuard};
+pub use self::poison_rwlock::{PoisonRwLock, PoisonRwLockReadGuard, PoisonRwLockWriteGuard};
 pub use self::raw_mutex::RawMutex;
 pub use self::raw_fair_mutex::RawFairMutex;
+pub use self::raw_fair_rwlock::RawFairRwLock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::raw_futex_mutex::{RawFutexMutex, RawSharedMutex};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::raw_futex_rwlock::{RawFutexRwLock, RawSharedRwLock};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::raw_pi_mutex::RawPiMutex;
+pub use self::raw_policy_rwlock::{
+    PhaseFairPolicy, RawPhaseFairRwLock, RawPolicyRwLock, RawReaderPreferringRwLock,
+    RawWriterPreferringRwLock, ReaderPreferringPolicy, RwLockPolicy, WriterPreferringPolicy,
+};
 pub use self::raw_rwlock::RawRwLock;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::raw_robust_mutex::RawRobustMutex;
+pub use self::raw_semaphore::RawSemaphore;
 pub use self::remutex::{
     MappedReentrantMutexGuard, RawThreadId, ReentrantMutex, ReentrantMutexGuard,
 };
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::robust_mutex::{MappedRobustMutexGuard, RobustMutex, RobustMutexGuard};
 pub use self::rwlock::{
     MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock, RwLockReadGuard,
     RwLockUpgradableReadGuard, RwLockWriteGuard,
 };
+#[cfg(feature = "arc_lock")]
+pub use self::rwlock::{
+    ArcRwLockReadGuard, ArcRwLockUpgradableReadGuard, ArcRwLockWriteGuard,
+    MappedArcRwLockReadGuard, MappedArcRwLockWriteGuard,
+};
+pub use self::semaphore::{Semaphore, SemaphorePermit};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::shared_condvar::SharedCondvar;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::shared_mutex::{MappedSharedMutexGuard, SharedMutex, SharedMutexGuard};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::shared_rwlock::{SharedRwLock, SharedRwLockReadGuard, SharedRwLockWriteGuard};
+#[cfg(feature = "owner_tracking")]
+pub use self::owner::LockOwner;
+#[cfg(feature = "stats")]
+pub use self::stats::LockStats;
 pub use ::lock_api;
@@ -0,0 +1,72 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Lock levels for checking the lock hierarchy.
+//!
+//! Locks created with `with_level` remember their level when the
+//! `lock_levels` feature flag is enabled, and acquiring them is checked
+//! against the levels of the locks already held by the current thread. See
+//! the `deadlock` module for details.
+//!
+//! When the feature is disabled the level takes up no space and is ignored.
+
+#[cfg(feature = "lock_levels")]
+use crate::deadlock;
+
+// Level of a lock in the lock hierarchy, embedded in every raw lock.
+#[cfg(feature = "lock_levels")]
+#[derive(Clone, Copy)]
+pub(crate) struct LockLevel(Option<u32>);
+
+#[cfg(not(feature = "lock_levels"))]
+#[derive(Clone, Copy)]
+pub(crate) struct LockLevel;
+
+#[cfg(feature = "lock_levels")]
+impl LockLevel {
+    pub(crate) const INIT: LockLevel = LockLevel(None);
+
+    #[inline]
+    pub(crate) fn new(level: u32) -> LockLevel {
+        LockLevel(Some(level))
+    }
+
+    // Must be called before attempting to acquire the lock identified by
+    // `key`, since this panics if the acquisition is out of order.
+    #[inline]
+    pub(crate) fn check(self, key: usize) {
+        if let Some(level) = self.0 {
+            deadlock::check_resource_level(key, level);
+        }
+    }
+
+    // Must be called after the lock identified by `key` has been acquired.
+    // The level is forgotten when the lock is released in the deadlock
+    // detector.
+    #[inline]
+    pub(crate) unsafe fn acquire(self, key: usize) {
+        if let Some(level) = self.0 {
+            deadlock::acquire_resource_level(key, level);
+        }
+    }
+}
+
+#[cfg(not(feature = "lock_levels"))]
+impl LockLevel {
+    pub(crate) const INIT: LockLevel = LockLevel;
+
+    #[inline(always)]
+    pub(crate) fn new(_level: u32) -> LockLevel {
+        LockLevel
+    }
+
+    #[inline(always)]
+    pub(crate) fn check(self, _key: usize) {}
+
+    #[inline(always)]
+    pub(crate) unsafe fn acquire(self, _key: usize) {}
+}
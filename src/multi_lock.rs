@@ -0,0 +1,239 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::util;
+use lock_api::{LockSetTimed, Mutex, MutexSliceGuard, RawMutexTimed};
+use std::time::{Duration, Instant};
+
+pub use lock_api::{
+    lock2, lock_all, lock_slice, try_lock_all, try_lock_all_until, try_lock_slice,
+    try_lock_slice_until, ReadLock, WriteLock,
+};
+
+/// Attempts to acquire several locks at once without deadlocking, until a
+/// timeout is reached.
+///
+/// If the timeout is reached, `None` is returned and none of the locks are
+/// held. See `lock_all` for details.
+///
+/// # Panics
+///
+/// Panics if the same lock is passed more than once.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::{try_lock_all_for, Mutex, RwLock, WriteLock};
+/// use std::time::Duration;
+///
+/// let a = Mutex::new(0);
+/// let b = RwLock::new(0);
+///
+/// let _guard = a.lock();
+/// assert!(try_lock_all_for((&a, WriteLock(&b)), Duration::from_millis(10)).is_none());
+/// ```
+#[inline]
+pub fn try_lock_all_for<L>(locks: L, timeout: Duration) -> Option<L::Guards>
+where
+    L: LockSetTimed<Instant = Instant>,
+{
+    match util::to_deadline(timeout) {
+        Some(deadline) => lock_api::try_lock_all_until(locks, deadline),
+        None => Some(lock_api::lock_all(locks)),
+    }
+}
+
+/// Attempts to acquire all the mutexes in a slice without deadlocking, until a
+/// timeout is reached.
+///
+/// If the timeout is reached, `None` is returned and none of the mutexes are
+/// held. See `lock_slice` for details.
+///
+/// # Panics
+///
+/// Panics if the same mutex appears more than once in the slice.
+#[inline]
+pub fn try_lock_slice_for<'a, R, T>(
+    mutexes: &'a [&'a Mutex<R, T>],
+    timeout: Duration,
+) -> Option<MutexSliceGuard<'a, R, T>>
+where
+    R: RawMutexTimed<Instant = Instant>,
+    T: ?Sized,
+{
+    match util::to_deadline(timeout) {
+        Some(deadline) => lock_api::try_lock_slice_until(mutexes, deadline),
+        None => Some(lock_api::lock_slice(mutexes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        lock2, lock_all, lock_slice, try_lock_all, try_lock_all_for, try_lock_slice,
+        try_lock_slice_for, Mutex, MutexGuard, ReadLock, RwLock, WriteLock,
+    };
+    use lock_api::Lockable;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lock2_opposite_orders() {
+        const N: usize = 1000;
+
+        let a = Arc::new(Mutex::new(0));
+        let b = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let (a, b) = (a.clone(), b.clone());
+                thread::spawn(move || {
+                    for _ in 0..N {
+                        let (mut x, mut y) = if i % 2 == 0 {
+                            lock2(&*a, &*b)
+                        } else {
+                            let (y, x) = lock2(&*b, &*a);
+                            (x, y)
+                        };
+                        *x += 1;
+                        *y += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*a.lock(), 4 * N);
+        assert_eq!(*b.lock(), 4 * N);
+    }
+
+    #[test]
+    fn test_lock_all_rwlock_modes() {
+        let a = Mutex::new(1);
+        let b = RwLock::new(2);
+        let c = RwLock::new(3);
+
+        let _reader = b.read();
+        {
+            let (mut a, b, mut c) = lock_all((&a, ReadLock(&b), WriteLock(&c)));
+            *a += *b;
+            *c += *b;
+        }
+        assert_eq!(*a.lock(), 3);
+        assert_eq!(*c.read(), 5);
+    }
+
+    #[test]
+    fn test_try_lock_all() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let c = RwLock::new(());
+
+        let guard = c.read();
+        assert!(try_lock_all((&a, &b, WriteLock(&c))).is_none());
+
+        // The locks which were acquired before the contended one were
+        // released again.
+        assert!(a.try_lock().is_some());
+        assert!(b.try_lock().is_some());
+        assert!(try_lock_all((&a, &b, ReadLock(&c))).is_some());
+        drop(guard);
+        assert!(try_lock_all((&a, &b, WriteLock(&c))).is_some());
+    }
+
+    #[test]
+    fn test_try_lock_all_for() {
+        let a = Arc::new(Mutex::new(0));
+        let b = Arc::new(Mutex::new(0));
+
+        let guard = b.lock();
+        assert!(try_lock_all_for((&*a, &*b), Duration::from_millis(10)).is_none());
+        assert!(a.try_lock().is_some());
+
+        let (a2, b2) = (a.clone(), b.clone());
+        let t = thread::spawn(move || {
+            let (mut a, mut b) = try_lock_all_for((&*a2, &*b2), Duration::from_secs(10)).unwrap();
+            *a += 1;
+            *b += 1;
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(*a.lock() + *b.lock(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_lock_all_same_lock() {
+        let a = RwLock::new(());
+        let _guards = lock2(ReadLock(&a), ReadLock(&a));
+    }
+
+    // Mutex which panics whenever an attempt is made to acquire it.
+    struct PanickingMutex(Mutex<()>);
+
+    unsafe impl<'a> Lockable for &'a PanickingMutex {
+        type Guard = MutexGuard<'a, ()>;
+
+        fn lock_addr(&self) -> usize {
+            (&self.0).lock_addr()
+        }
+
+        fn lock_raw(&self) {
+            panic!("lock_raw");
+        }
+
+        fn try_lock_raw(&self) -> bool {
+            panic!("try_lock_raw");
+        }
+
+        unsafe fn unlock_raw(&self) {
+            (&self.0).unlock_raw()
+        }
+
+        unsafe fn make_guard(&self) -> MutexGuard<'a, ()> {
+            (&self.0).make_guard()
+        }
+    }
+
+    #[test]
+    fn test_lock_all_unwind() {
+        let a = Mutex::new(());
+        let b = PanickingMutex(Mutex::new(()));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| drop(lock_all((&a, &b)))));
+        assert!(result.is_err());
+        assert!(a.try_lock().is_some());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| drop(try_lock_all((&a, &b)))));
+        assert!(result.is_err());
+        assert!(a.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_lock_slice() {
+        let mutexes: Vec<_> = (0..5).map(Mutex::new).collect();
+        let refs: Vec<_> = mutexes.iter().rev().collect();
+        {
+            let mut guard = lock_slice(&refs);
+            assert_eq!(guard.len(), 5);
+            for i in 0..guard.len() {
+                guard[i] *= 2;
+            }
+            assert_eq!(format!("{:?}", guard), "[8, 6, 4, 2, 0]");
+            assert!(mutexes.iter().all(|m| m.try_lock().is_none()));
+        }
+        assert!(mutexes.iter().all(|m| m.try_lock().is_some()));
+        assert!(lock_slice::<crate::RawMutex, i32>(&[]).is_empty());
+
+        let _guard = mutexes[2].lock();
+        assert!(try_lock_slice(&refs).is_none());
+        assert!(try_lock_slice_for(&refs, Duration::from_millis(10)).is_none());
+        assert_eq!(mutexes.iter().filter(|m| m.try_lock().is_none()).count(), 1);
+    }
+}
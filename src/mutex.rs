@@ -102,6 +102,16 @@ pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawMutex, T>;
 /// thread.
 pub type MappedMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawMutex, T>;
 
+/// An RAII mutex guard returned by `Mutex::lock_arc`, which keeps the mutex
+/// alive through an `Arc` and therefore has a `'static` lifetime.
+#[cfg(feature = "arc_lock")]
+pub type ArcMutexGuard<T> = lock_api::ArcMutexGuard<RawMutex, T>;
+
+/// An RAII mutex guard returned by `ArcMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+#[cfg(feature = "arc_lock")]
+pub type MappedArcMutexGuard<T, U> = lock_api::MappedArcMutexGuard<RawMutex, T, U>;
+
 #[cfg(test)]
 mod tests {
     use crate::{Condvar, Mutex};
@@ -287,7 +297,10 @@ mod tests {
 
         assert_eq!(format!("{:?}", mutex), "Mutex { data: [0, 10] }");
         let _lock = mutex.lock();
+        #[cfg(not(feature = "owner_tracking"))]
         assert_eq!(format!("{:?}", mutex), "Mutex { data: <locked> }");
+        #[cfg(feature = "owner_tracking")]
+        assert!(format!("{:?}", mutex).starts_with("Mutex { data: <locked>, owner: LockOwner {"));
     }
 
     #[cfg(feature = "serde")]
@@ -302,4 +315,33 @@ mod tests {
         assert_eq!(*(mutex.lock()), *(deserialized.lock()));
         assert_eq!(contents, *(deserialized.lock()));
     }
+
+    #[cfg(feature = "arc_lock")]
+    #[test]
+    fn test_arc_lock() {
+        use crate::ArcMutexGuard;
+
+        // The guard doesn't borrow the mutex, so it can outlive the local `Arc`.
+        fn lock_static(mutex: Arc<Mutex<Vec<i32>>>) -> ArcMutexGuard<Vec<i32>> {
+            mutex.lock_arc()
+        }
+
+        let mutex = Arc::new(Mutex::new(vec![1, 2]));
+        let mut guard = lock_static(mutex.clone());
+        assert!(mutex.try_lock_arc().is_none());
+        ArcMutexGuard::unlocked(&mut guard, || {
+            drop(mutex.try_lock_arc().unwrap());
+        });
+        assert!(Arc::ptr_eq(ArcMutexGuard::mutex(&guard), &mutex));
+
+        guard.push(3);
+        let mut mapped = ArcMutexGuard::map(guard, |v| &mut v[0]);
+        *mapped = 10;
+        drop(mapped);
+        assert_eq!(*mutex.lock(), vec![10, 2, 3]);
+
+        let guard = mutex.lock_arc();
+        assert!(ArcMutexGuard::try_map(guard, |v| v.get_mut(5)).is_err());
+        assert!(mutex.try_lock().is_some());
+    }
 }
@@ -5,6 +5,7 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::trace;
 use crate::util::UncheckedOptionExt;
 use core::{
     fmt, mem,
@@ -147,6 +148,9 @@ impl Once {
     /// }
     /// ```
     ///
+    /// To store the result of the initialization without resorting to a
+    /// `static mut`, use `OnceCell` or `Lazy` instead.
+    ///
     /// # Panics
     ///
     /// The closure `f` will only be executed once if this is called
@@ -163,7 +167,10 @@ impl Once {
         }
 
         let mut f = Some(f);
-        self.call_once_slow(false, &mut |_| unsafe { f.take().unchecked_unwrap()() });
+        self.call_once_slow(false, &mut |_| {
+            unsafe { f.take().unchecked_unwrap()() };
+            true
+        });
     }
 
     /// Performs the same function as `call_once` except ignores poisoning.
@@ -185,11 +192,28 @@ impl Once {
         }
 
         let mut f = Some(f);
-        self.call_once_slow(true, &mut |state| unsafe {
-            f.take().unchecked_unwrap()(state)
+        self.call_once_slow(true, &mut |state| {
+            unsafe { f.take().unchecked_unwrap()(state) };
+            true
         });
     }
 
+    // Like `call_once_force`, except that the `Once` is only marked as done if
+    // the closure returns `true`. Otherwise it is left unlocked so that the
+    // next caller can attempt the initialization again.
+    #[inline]
+    pub(crate) fn call_once_try<F>(&self, f: F)
+    where
+        F: FnOnce() -> bool,
+    {
+        if self.0.load(Ordering::Acquire) == DONE_BIT {
+            return;
+        }
+
+        let mut f = Some(f);
+        self.call_once_slow(true, &mut |_| unsafe { f.take().unchecked_unwrap()() });
+    }
+
     // This is a non-generic function to reduce the monomorphization cost of
     // using `call_once` (this isn't exactly a trivial or small implementation).
     //
@@ -201,8 +225,11 @@ impl Once {
     // Finally, this takes an `FnMut` instead of a `FnOnce` because there's
     // currently no way to take an `FnOnce` and call it via virtual dispatch
     // without some allocation overhead.
+    //
+    // The closure returns whether the initialization completed. If it didn't,
+    // the state is reset so that another call can try again.
     #[cold]
-    fn call_once_slow(&self, ignore_poison: bool, f: &mut dyn FnMut(OnceState)) {
+    fn call_once_slow(&self, ignore_poison: bool, f: &mut dyn FnMut(OnceState) -> bool) {
         let mut spinwait = SpinWait::new();
         let mut state = self.0.load(Ordering::Relaxed);
         loop {
@@ -301,11 +328,15 @@ impl Once {
         } else {
             OnceState::New
         };
-        f(once_state);
+        let span = trace::OnceSpan::enter(self as *const _ as usize, once_state.poisoned());
+        let done = f(once_state);
+        span.finish(done);
         mem::forget(guard);
 
-        // Now unlock the state, set the done bit and unpark all threads
-        let state = self.0.swap(DONE_BIT, Ordering::Release);
+        // Now unlock the state, set the done bit if the closure completed and
+        // unpark all threads
+        let new_state = if done { DONE_BIT } else { 0 };
+        let state = self.0.swap(new_state, Ordering::Release);
         if state & PARKED_BIT != 0 {
             unsafe {
                 let addr = self as *const _ as usize;
@@ -0,0 +1,319 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::once::Once;
+use crate::util::UncheckedOptionExt;
+use core::{cell::UnsafeCell, fmt};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+
+// Uninhabited error type used to implement `get_or_init` on top of
+// `get_or_try_init`.
+enum Void {}
+
+/// A thread-safe cell which can be written to only once.
+///
+/// Unlike `Once`, a `OnceCell` stores the value produced by its
+/// initialization, and the initialization is allowed to fail: if the closure
+/// passed to `get_or_try_init` returns an error, the cell is left uninitialized
+/// and the next caller will run its own closure. If the initialization panics,
+/// the cell is also left uninitialized instead of being poisoned.
+///
+/// Like `Once`, the state of the cell only takes up 1 byte, and threads which
+/// find the initialization in progress are parked until it completes.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::OnceCell;
+///
+/// static CONFIG: OnceCell<String> = OnceCell::new();
+///
+/// fn config() -> &'static str {
+///     CONFIG.get_or_init(|| "loaded".to_string())
+/// }
+///
+/// assert!(CONFIG.get().is_none());
+/// assert_eq!(config(), "loaded");
+/// assert_eq!(CONFIG.get().map(|s| s.as_str()), Some("loaded"));
+/// ```
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<Option<T>>,
+}
+
+// Sharing a OnceCell between threads allows one thread to store a value which
+// is then read by others, which requires T to be both Send and Sync.
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+// A panicking initialization leaves the cell uninitialized, so there is no
+// broken state to observe.
+impl<T: RefUnwindSafe + UnwindSafe> RefUnwindSafe for OnceCell<T> {}
+impl<T: UnwindSafe> UnwindSafe for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    /// Creates a new empty cell.
+    #[inline]
+    pub const fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Returns a reference to the value of the cell, or `None` if the cell is
+    /// not initialized yet.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        if self.once.state().done() {
+            // SAFETY: The value is never modified once the cell is initialized.
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the value of the cell, or `None` if the
+    /// cell is not initialized yet.
+    ///
+    /// Since this call borrows the `OnceCell` mutably, no actual locking needs
+    /// to take place.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { (*self.value.get()).as_mut() }
+    }
+
+    /// Sets the value of the cell to `value`.
+    ///
+    /// Returns `Err(value)` if the cell was already initialized, possibly by
+    /// an initialization which was in progress on another thread.
+    #[inline]
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| unsafe { value.take().unchecked_unwrap() });
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// Returns the value of the cell, initializing it with `f` if the cell is
+    /// empty.
+    ///
+    /// If several threads call this concurrently, only one of them runs its
+    /// closure; the others block until the value is available. If `f` panics,
+    /// the panic is propagated to the caller and the cell stays uninitialized.
+    ///
+    /// Calling this from within `f` on the same cell will deadlock.
+    #[inline]
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.get_or_try_init(|| Ok::<T, Void>(f())) {
+            Ok(value) => value,
+            Err(void) => match void {},
+        }
+    }
+
+    /// Returns the value of the cell, initializing it with `f` if the cell is
+    /// empty. If `f` returns an error, the error is returned to the caller and
+    /// the cell stays uninitialized.
+    ///
+    /// If several threads call this concurrently, only one of them runs its
+    /// closure at a time; the others block until it completes. If it fails,
+    /// one of the blocked threads runs its own closure next.
+    ///
+    /// Calling this from within `f` on the same cell will deadlock.
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+
+        let mut result = Ok(());
+        self.once.call_once_try(|| match f() {
+            Ok(value) => {
+                // SAFETY: We hold the lock of the `Once`, so nobody else is
+                // accessing the value.
+                unsafe { *self.value.get() = Some(value) };
+                true
+            }
+            Err(e) => {
+                result = Err(e);
+                false
+            }
+        });
+        result?;
+
+        // SAFETY: The `Once` is done, so the value has been set and is never
+        // modified again.
+        Ok(unsafe { (*self.value.get()).as_ref().unchecked_unwrap() })
+    }
+
+    /// Takes the value out of the cell, leaving it uninitialized.
+    #[inline]
+    pub fn take(&mut self) -> Option<T> {
+        self.once = Once::new();
+        unsafe { (*self.value.get()).take() }
+    }
+
+    /// Consumes the cell, returning the wrapped value if it was initialized.
+    #[inline]
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    #[inline]
+    fn default() -> OnceCell<T> {
+        OnceCell::new()
+    }
+}
+
+impl<T> From<T> for OnceCell<T> {
+    #[inline]
+    fn from(value: T) -> OnceCell<T> {
+        let cell = OnceCell::new();
+        let _ = cell.set(value);
+        cell
+    }
+}
+
+impl<T: Clone> Clone for OnceCell<T> {
+    #[inline]
+    fn clone(&self) -> OnceCell<T> {
+        match self.get() {
+            Some(value) => OnceCell::from(value.clone()),
+            None => OnceCell::new(),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("OnceCell").field(value).finish(),
+            None => f.write_str("OnceCell(<uninit>)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::OnceCell;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn smoke_once_cell() {
+        let c = OnceCell::new();
+        assert!(c.get().is_none());
+        assert_eq!(*c.get_or_init(|| 1), 1);
+        assert_eq!(*c.get_or_init(|| 2), 1);
+        assert_eq!(c.get(), Some(&1));
+        assert_eq!(c.set(3), Err(3));
+    }
+
+    #[test]
+    fn set_and_take() {
+        let mut c = OnceCell::new();
+        assert_eq!(c.set(1), Ok(()));
+        assert_eq!(c.set(2), Err(2));
+        *c.get_mut().unwrap() += 1;
+        assert_eq!(c.take(), Some(2));
+        assert!(c.get().is_none());
+        assert_eq!(c.set(3), Ok(()));
+        assert_eq!(c.into_inner(), Some(3));
+    }
+
+    #[test]
+    fn try_init_error() {
+        let c = OnceCell::new();
+        assert_eq!(c.get_or_try_init(|| Err("fail")), Err("fail"));
+        assert!(c.get().is_none());
+        assert_eq!(c.get_or_try_init(|| Ok::<_, ()>(5)), Ok(&5));
+        assert_eq!(c.get_or_try_init(|| Err(())), Ok(&5));
+    }
+
+    #[test]
+    fn panic_leaves_uninit() {
+        let c = OnceCell::new();
+        let t = panic::catch_unwind(|| {
+            c.get_or_init(|| panic!());
+        });
+        assert!(t.is_err());
+        assert!(c.get().is_none());
+        assert_eq!(*c.get_or_init(|| 1), 1);
+    }
+
+    #[test]
+    fn stampede_once_cell() {
+        static C: OnceCell<usize> = OnceCell::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..10)
+            .map(|i| {
+                let runs = runs.clone();
+                thread::spawn(move || {
+                    let value = *C.get_or_init(|| {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        thread::yield_now();
+                        i
+                    });
+                    assert_eq!(C.get(), Some(&value));
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_init_retried_by_waiter() {
+        let c = Arc::new(OnceCell::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let (c, attempts) = (c.clone(), attempts.clone());
+                thread::spawn(move || {
+                    // Only the first attempt fails
+                    let _ = c.get_or_try_init(|| {
+                        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                            thread::yield_now();
+                            Err(())
+                        } else {
+                            Ok(7)
+                        }
+                    });
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(c.get(), Some(&7));
+    }
+
+    #[test]
+    fn test_once_cell_debug() {
+        let c = OnceCell::new();
+        assert_eq!(format!("{:?}", c), "OnceCell(<uninit>)");
+        c.set(1).unwrap();
+        assert_eq!(format!("{:?}", c), "OnceCell(1)");
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Tracking of the threads holding locks.
+//!
+//! This is enabled via the `owner_tracking` feature flag, which makes every
+//! `Mutex`, `FairMutex`, `ReentrantMutex` and `RwLock` remember which thread
+//! holds it exclusively and where in the source code the lock was acquired.
+//! The `owner_backtrace` feature flag additionally captures a backtrace of
+//! each acquisition. The owner is shown by the `Debug` implementation of a
+//! locked lock and can be queried with its `owner` method. The
+//! `owner_tracking` feature requires Rust 1.46 for `#[track_caller]`.
+//!
+//! When the feature is disabled the owner takes up no space and all of the
+//! bookkeeping compiles down to nothing.
+
+#[cfg(feature = "owner_backtrace")]
+use backtrace::Backtrace;
+#[cfg(feature = "owner_tracking")]
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+#[cfg(feature = "owner_tracking")]
+use parking_lot_core::SpinWait;
+#[cfg(feature = "owner_tracking")]
+use std::thread::{self, Thread, ThreadId};
+
+/// Information about the thread holding a lock.
+///
+/// This is returned by the `owner` method of `Mutex`, `FairMutex`,
+/// `ReentrantMutex` and `RwLock`. For an `RwLock`, only the thread holding the
+/// write lock is tracked.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Mutex;
+/// use std::sync::Arc;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let mutex = Arc::new(Mutex::new(0));
+/// let _guard = mutex.lock();
+///
+/// let mutex2 = mutex.clone();
+/// thread::spawn(move || {
+///     if mutex2.try_lock_for(Duration::from_millis(10)).is_none() {
+///         let owner = mutex2.owner().unwrap();
+///         println!("mutex held by {:?}", owner);
+///     }
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+#[cfg(feature = "owner_tracking")]
+#[derive(Clone)]
+pub struct LockOwner {
+    thread: Thread,
+    location: Option<&'static Location<'static>>,
+    #[cfg(feature = "owner_backtrace")]
+    backtrace: Backtrace,
+}
+
+#[cfg(feature = "owner_tracking")]
+impl LockOwner {
+    /// The thread holding the lock.
+    #[inline]
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// The id of the thread holding the lock.
+    #[inline]
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread.id()
+    }
+
+    /// The name of the thread holding the lock, if it has one.
+    #[inline]
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread.name()
+    }
+
+    /// The location in the source code at which the lock was acquired.
+    ///
+    /// This is `None` if the lock was reacquired by a condition variable
+    /// which wasn't able to preserve the location of the original
+    /// acquisition.
+    #[inline]
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
+    }
+
+    /// The backtrace of the acquisition of the lock.
+    ///
+    /// The backtrace is captured without symbols, call `resolve` on a copy of
+    /// it to look them up.
+    #[cfg(feature = "owner_backtrace")]
+    #[inline]
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+#[cfg(feature = "owner_tracking")]
+impl fmt::Debug for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("LockOwner");
+        d.field("thread_id", &self.thread.id())
+            .field("thread_name", &self.thread.name());
+        match self.location {
+            Some(location) => d.field("location", &format_args!("{}", location)),
+            None => d.field("location", &None::<()>),
+        };
+        d.finish()
+    }
+}
+
+// Owner embedded in every raw lock. Only the thread holding the lock updates
+// it, but other threads may read it at any time, so it is protected by a tiny
+// spinlock which is only ever held for a few instructions.
+#[cfg(feature = "owner_tracking")]
+pub(crate) struct OwnerCell {
+    locked: AtomicBool,
+    owner: UnsafeCell<Option<LockOwner>>,
+}
+
+#[cfg(not(feature = "owner_tracking"))]
+pub(crate) struct OwnerCell;
+
+// Owner saved by a thread while it temporarily releases a lock, for example
+// while waiting on a condition variable.
+#[cfg(feature = "owner_tracking")]
+pub(crate) struct SavedOwner(Option<LockOwner>);
+
+#[cfg(not(feature = "owner_tracking"))]
+pub(crate) struct SavedOwner;
+
+#[cfg(feature = "owner_tracking")]
+unsafe impl Sync for OwnerCell {}
+
+#[cfg(feature = "owner_tracking")]
+impl OwnerCell {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: OwnerCell = OwnerCell {
+        locked: AtomicBool::new(false),
+        owner: UnsafeCell::new(None),
+    };
+
+    fn with<T>(&self, f: impl FnOnce(&mut Option<LockOwner>) -> T) -> T {
+        let mut spinwait = SpinWait::new();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if !spinwait.spin() {
+                thread::yield_now();
+            }
+        }
+        // SAFETY: We hold the spinlock. The closures passed in don't panic.
+        let result = f(unsafe { &mut *self.owner.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+
+    fn set(&self, location: Option<&'static Location<'static>>) {
+        let owner = LockOwner {
+            thread: thread::current(),
+            location,
+            #[cfg(feature = "owner_backtrace")]
+            backtrace: Backtrace::new_unresolved(),
+        };
+        // The previous owner is dropped outside the spinlock.
+        let _previous = self.with(|current| current.replace(owner));
+    }
+
+    // Must be called by the current thread after acquiring the lock.
+    #[track_caller]
+    #[inline]
+    pub(crate) fn acquire(&self) {
+        self.set(Some(Location::caller()));
+    }
+
+    // Must be called by the current thread after reacquiring the lock after
+    // waiting on a condition variable.
+    #[inline]
+    pub(crate) fn relock(&self) {
+        self.set(None);
+    }
+
+    // Must be called by the current thread before releasing the lock.
+    #[inline]
+    pub(crate) fn release(&self) {
+        let _previous = self.with(|current| current.take());
+    }
+
+    #[inline]
+    pub(crate) fn save(&self) -> SavedOwner {
+        SavedOwner(self.get())
+    }
+
+    // Must be called by the current thread after reacquiring a lock which it
+    // released after calling `save`.
+    #[inline]
+    pub(crate) fn restore(&self, saved: SavedOwner) {
+        match saved.0 {
+            Some(owner) => {
+                let _previous = self.with(|current| current.replace(owner));
+            }
+            None => self.relock(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get(&self) -> Option<LockOwner> {
+        self.with(|current| current.clone())
+    }
+
+    #[inline]
+    pub(crate) fn debug(&self, s: &mut fmt::DebugStruct<'_, '_>) {
+        if let Some(owner) = self.get() {
+            s.field("owner", &owner);
+        }
+    }
+}
+
+#[cfg(not(feature = "owner_tracking"))]
+impl OwnerCell {
+    pub(crate) const INIT: OwnerCell = OwnerCell;
+
+    #[inline(always)]
+    pub(crate) fn acquire(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn relock(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn release(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn save(&self) -> SavedOwner {
+        SavedOwner
+    }
+
+    #[inline(always)]
+    pub(crate) fn restore(&self, _saved: SavedOwner) {}
+}
+
+#[cfg(all(test, feature = "owner_tracking"))]
+mod tests {
+    use crate::{Condvar, FairMutex, Mutex, ReentrantMutex, RwLock, RwLockUpgradableReadGuard};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mutex_owner() {
+        let m = Mutex::new(0);
+        assert!(m.owner().is_none());
+
+        let (guard, line) = (m.lock(), line!());
+        let owner = m.owner().unwrap();
+        assert_eq!(owner.thread_id(), thread::current().id());
+        let location = owner.location().unwrap();
+        assert_eq!(location.file(), file!());
+        assert_eq!(location.line(), line);
+        drop(guard);
+        assert!(m.owner().is_none());
+
+        let (_guard, line) = (m.try_lock().unwrap(), line!());
+        assert_eq!(m.owner().unwrap().location().unwrap().line(), line);
+    }
+
+    #[test]
+    fn test_try_lock_for_reports_owner() {
+        let m = Arc::new(FairMutex::new(0));
+        let m2 = m.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let holder = thread::Builder::new()
+            .name("holder".to_string())
+            .spawn(move || {
+                let _guard = m2.lock();
+                tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+            })
+            .unwrap();
+
+        rx.recv().unwrap();
+        assert!(m.try_lock_for(Duration::from_millis(10)).is_none());
+        let owner = m.owner().unwrap();
+        assert_eq!(owner.thread_name(), Some("holder"));
+        assert!(format!("{:?}", m).contains("holder"));
+        holder.join().unwrap();
+        assert!(m.owner().is_none());
+    }
+
+    #[test]
+    fn test_rwlock_owner() {
+        let l = RwLock::new(0);
+        {
+            let _read = l.read();
+            assert!(l.owner().is_none());
+        }
+
+        let (write, line) = (l.write(), line!());
+        assert_eq!(l.owner().unwrap().location().unwrap().line(), line);
+        drop(write);
+        assert!(l.owner().is_none());
+
+        let upgradable = l.upgradable_read();
+        assert!(l.owner().is_none());
+        let (write, line) = (RwLockUpgradableReadGuard::upgrade(upgradable), line!());
+        assert_eq!(l.owner().unwrap().location().unwrap().line(), line);
+        let _read = crate::RwLockWriteGuard::downgrade(write);
+        assert!(l.owner().is_none());
+    }
+
+    #[test]
+    fn test_reentrant_mutex_owner() {
+        let m = ReentrantMutex::new(0);
+        let (_outer, line) = (m.lock(), line!());
+        let _inner = m.lock();
+        assert_eq!(m.owner().unwrap().location().unwrap().line(), line);
+    }
+
+    #[test]
+    fn test_condvar_preserves_owner() {
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+        let notifier = thread::spawn(move || {
+            *pair2.0.lock() = true;
+            pair2.1.notify_all();
+        });
+
+        let (mut started, line) = (pair.0.lock(), line!());
+        while !*started {
+            pair.1.wait(&mut started);
+        }
+        let owner = pair.0.owner().unwrap();
+        assert_eq!(owner.thread_id(), thread::current().id());
+        assert_eq!(owner.location().unwrap().line(), line);
+        drop(started);
+        notifier.join().unwrap();
+    }
+}
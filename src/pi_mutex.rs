@@ -0,0 +1,139 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_pi_mutex::RawPiMutex;
+
+/// A mutual exclusion primitive which avoids priority inversion.
+///
+/// This behaves like `Mutex`, except that a thread which blocks on the mutex
+/// lends its scheduling priority to the thread holding it until it is
+/// unlocked. This is useful when real-time threads share data with lower
+/// priority threads, which would otherwise be able to hold up a real-time
+/// thread for as long as other threads keep them from running.
+///
+/// Contended locking and unlocking go through the kernel, which makes this
+/// mutex slower than `Mutex` under contention, and it is always fair. See
+/// `RawPiMutex` for details.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::PiMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let data = Arc::new(PiMutex::new(0));
+/// let threads: Vec<_> = (0..10)
+///     .map(|_| {
+///         let data = Arc::clone(&data);
+///         thread::spawn(move || *data.lock() += 1)
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(*data.lock(), 10);
+/// ```
+pub type PiMutex<T> = lock_api::Mutex<RawPiMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of a `PiMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type PiMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawPiMutex, T>;
+
+/// An RAII mutex guard returned by `PiMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+pub type MappedPiMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawPiMutex, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::PiMutex;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn smoke() {
+        let m = PiMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(PiMutex::new(0));
+
+        fn inc(m: &PiMutex<u32>) {
+            for _ in 0..J {
+                *m.lock() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..2 * K {
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+        }
+
+        drop(tx);
+        for _ in 0..2 * K {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = PiMutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn try_lock_for() {
+        let m = Arc::new(PiMutex::new(0));
+        let guard = m.lock();
+
+        let m2 = m.clone();
+        let t = thread::spawn(move || {
+            let start = Instant::now();
+            assert!(m2.try_lock_for(Duration::from_millis(50)).is_none());
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        });
+        t.join().unwrap();
+
+        // The lock is handed over to the waiting thread when it is unlocked.
+        let m2 = m.clone();
+        let t = thread::spawn(move || *m2.try_lock_for(Duration::from_secs(10)).unwrap() += 1);
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn recursive_lock() {
+        let m = PiMutex::new(());
+        let _guard = m.lock();
+        let _guard2 = m.lock();
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LockResult, PoisonError};
+use std::thread;
+
+/// Poison flag shared by the poisoning lock wrappers, which records whether a
+/// thread panicked while holding the lock exclusively.
+pub(crate) struct Flag {
+    failed: AtomicBool,
+}
+
+impl Flag {
+    #[inline]
+    pub(crate) const fn new() -> Flag {
+        Flag {
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts tracking a newly acquired exclusive lock.
+    ///
+    /// The returned `Guard` must be passed to `done` when the lock is released.
+    #[inline]
+    pub(crate) fn guard(&self) -> Guard {
+        Guard {
+            panicking: thread::panicking(),
+        }
+    }
+
+    /// Poisons the lock if the current thread started panicking while holding
+    /// it.
+    ///
+    /// A thread which was already panicking when it acquired the lock, for
+    /// example in a destructor, doesn't poison it.
+    #[inline]
+    pub(crate) fn done(&self, guard: &Guard) {
+        if !guard.panicking && thread::panicking() {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn clear(&self) {
+        self.failed.store(false, Ordering::Relaxed)
+    }
+}
+
+/// Whether the current thread was panicking when it acquired a lock.
+pub(crate) struct Guard {
+    panicking: bool,
+}
+
+/// Wraps `value` in a `PoisonError` if `poisoned` is set.
+#[inline]
+pub(crate) fn map_result<T>(poisoned: bool, value: T) -> LockResult<T> {
+    if poisoned {
+        Err(PoisonError::new(value))
+    } else {
+        Ok(value)
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::poison;
+use crate::raw_mutex::RawMutex;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use lock_api::{CondvarGuard, RawMutexRequeue};
+use std::sync::{LockResult, TryLockError, TryLockResult};
+
+/// A mutual exclusion primitive which is poisoned when a thread panics while
+/// holding it, like `std::sync::Mutex`.
+///
+/// This wraps a `lock_api::Mutex` and returns the same `LockResult` and
+/// `TryLockResult` types as the standard library, which makes it easier to
+/// migrate code which relies on poisoning. Use `Mutex` instead if you don't
+/// need poisoning. `new` uses `RawMutex`, but any other raw mutex type can be
+/// used through `from_mutex`.
+///
+/// If a thread panics while holding the lock, the mutex is marked as poisoned
+/// once the guard is dropped, and from then on every attempt to lock it returns
+/// a `PoisonError`. The guard can still be obtained from the error with
+/// `PoisonError::into_inner`, and the poison flag can be cleared with
+/// `clear_poison` once the protected data has been repaired.
+///
+/// The guard implements `lock_api::CondvarGuard`, so it can be used with
+/// `CondvarAny`.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::PoisonMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let lock = Arc::new(PoisonMutex::new(0));
+/// let lock2 = lock.clone();
+///
+/// let _ = thread::spawn(move || {
+///     let _guard = lock2.lock().unwrap();
+///     panic!(); // the mutex gets poisoned
+/// })
+/// .join();
+///
+/// assert!(lock.is_poisoned());
+/// let mut guard = match lock.lock() {
+///     Ok(guard) => guard,
+///     Err(poisoned) => poisoned.into_inner(),
+/// };
+/// *guard += 1;
+/// drop(guard);
+///
+/// lock.clear_poison();
+/// assert_eq!(*lock.lock().unwrap(), 1);
+/// ```
+pub struct PoisonMutex<T: ?Sized, R: lock_api::RawMutex = RawMutex> {
+    poison: poison::Flag,
+    inner: lock_api::Mutex<R, T>,
+}
+
+impl<T> PoisonMutex<T> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> PoisonMutex<T> {
+        PoisonMutex {
+            poison: poison::Flag::new(),
+            inner: lock_api::Mutex::new(val),
+        }
+    }
+
+    /// Creates a new mutex in an unlocked state ready for use.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> PoisonMutex<T> {
+        PoisonMutex::from_mutex(lock_api::Mutex::new(val))
+    }
+}
+
+impl<T, R: lock_api::RawMutex> PoisonMutex<T, R> {
+    /// Adds poisoning to an existing mutex, which may use any raw mutex type.
+    ///
+    /// The new `PoisonMutex` is not poisoned.
+    #[inline]
+    pub fn from_mutex(mutex: lock_api::Mutex<R, T>) -> PoisonMutex<T, R> {
+        PoisonMutex {
+            poison: poison::Flag::new(),
+            inner: mutex,
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    ///
+    /// An error is returned if the mutex is poisoned, but the data is returned
+    /// inside of it regardless.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        poison::map_result(poisoned, self.inner.into_inner())
+    }
+}
+
+impl<T: ?Sized, R: lock_api::RawMutex> PoisonMutex<T, R> {
+    #[inline]
+    fn guard<'a>(&'a self, guard: lock_api::MutexGuard<'a, R, T>) -> PoisonMutexGuard<'a, T, R> {
+        PoisonMutexGuard {
+            lock: self,
+            poison: self.poison.guard(),
+            guard,
+        }
+    }
+
+    /// Acquires the mutex, blocking the current thread until it is able to do
+    /// so.
+    ///
+    /// An error is returned if the mutex is poisoned, but the lock is acquired
+    /// regardless and its guard can be extracted from the error.
+    ///
+    /// Attempts to lock a mutex in the thread which already holds the lock will
+    /// result in a deadlock.
+    #[inline]
+    pub fn lock(&self) -> LockResult<PoisonMutexGuard<'_, T, R>> {
+        let guard = self.guard(self.inner.lock());
+        poison::map_result(self.poison.get(), guard)
+    }
+
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then
+    /// `TryLockError::WouldBlock` is returned. If the mutex is poisoned, the
+    /// lock is acquired and its guard is returned inside of
+    /// `TryLockError::Poisoned`.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_lock(&self) -> TryLockResult<PoisonMutexGuard<'_, T, R>> {
+        match self.inner.try_lock() {
+            Some(guard) => Ok(poison::map_result(self.poison.get(), self.guard(guard))?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Determines whether the mutex is poisoned.
+    ///
+    /// Another thread may poison the mutex at any time, so a `false` result
+    /// should not be trusted without holding the lock.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poisoned state of the mutex.
+    ///
+    /// This should only be done after the data protected by the mutex has been
+    /// restored to a consistent state.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `PoisonMutex` mutably, no actual locking
+    /// needs to take place. An error is returned if the mutex is poisoned, but
+    /// the reference is returned inside of it regardless.
+    #[inline]
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        poison::map_result(poisoned, self.inner.get_mut())
+    }
+}
+
+impl<T: Default, R: lock_api::RawMutex> Default for PoisonMutex<T, R> {
+    #[inline]
+    fn default() -> PoisonMutex<T, R> {
+        PoisonMutex::from_mutex(Default::default())
+    }
+}
+
+impl<T, R: lock_api::RawMutex> From<T> for PoisonMutex<T, R> {
+    #[inline]
+    fn from(t: T) -> PoisonMutex<T, R> {
+        PoisonMutex::from_mutex(lock_api::Mutex::new(t))
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: lock_api::RawMutex> fmt::Debug for PoisonMutex<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("PoisonMutex");
+        match self.inner.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => {
+                struct LockedPlaceholder;
+                impl fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                d.field("data", &LockedPlaceholder)
+            }
+        };
+        d.field("poisoned", &self.poison.get()).finish()
+    }
+}
+
+/// An RAII implementation of a "scoped lock" of a `PoisonMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked, and
+/// the mutex will be poisoned if the thread is panicking.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+#[must_use = "if unused the PoisonMutex will immediately unlock"]
+pub struct PoisonMutexGuard<'a, T: ?Sized, R: lock_api::RawMutex = RawMutex> {
+    lock: &'a PoisonMutex<T, R>,
+    poison: poison::Guard,
+    pub(crate) guard: lock_api::MutexGuard<'a, R, T>,
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawMutex + 'a> PoisonMutexGuard<'a, T, R> {
+    /// Returns a reference to the original `PoisonMutex` object.
+    #[inline]
+    pub fn mutex(s: &Self) -> &'a PoisonMutex<T, R> {
+        s.lock
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawMutex + 'a> Deref for PoisonMutexGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawMutex + 'a> DerefMut for PoisonMutexGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawMutex + 'a> Drop for PoisonMutexGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        // The flag is updated before the inner guard unlocks the mutex.
+        self.lock.poison.done(&self.poison);
+    }
+}
+
+unsafe impl<'a, T: ?Sized + 'a, R: lock_api::RawMutex + 'a> CondvarGuard
+    for PoisonMutexGuard<'a, T, R>
+{
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        self.guard.lock_addr()
+    }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        self.guard.requeue_target()
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.guard.unlock_for_wait()
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, state: usize, token: Option<usize>) {
+        self.guard.relock_after_wait(state, token)
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized + 'a, R: lock_api::RawMutex + 'a> fmt::Debug
+    for PoisonMutexGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized + 'a, R: lock_api::RawMutex + 'a> fmt::Display
+    for PoisonMutexGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CondvarAny, FairMutex, PoisonMutex};
+    use std::sync::{Arc, TryLockError};
+    use std::thread;
+
+    fn poison(m: &Arc<PoisonMutex<i32>>) {
+        let m = m.clone();
+        let _ = thread::spawn(move || {
+            let _guard = m.lock().unwrap();
+            panic!("test panic in inner thread to poison mutex");
+        })
+        .join();
+    }
+
+    #[test]
+    fn smoke() {
+        let m = PoisonMutex::new(0);
+        *m.lock().unwrap() += 1;
+        *m.try_lock().unwrap() += 1;
+        assert!(!m.is_poisoned());
+        assert_eq!(m.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let m = PoisonMutex::new(());
+        let guard = m.lock().unwrap();
+        match m.try_lock() {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("locked twice"),
+        }
+        drop(guard);
+        assert!(m.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_poison() {
+        let m = Arc::new(PoisonMutex::new(1));
+        poison(&m);
+        assert!(m.is_poisoned());
+
+        // The lock is still acquired and usable through the error.
+        *m.lock().unwrap_err().into_inner() += 1;
+        match m.try_lock() {
+            Err(TryLockError::Poisoned(e)) => assert_eq!(*e.into_inner(), 2),
+            _ => panic!("poison was not reported"),
+        }
+
+        m.clear_poison();
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_into_inner_get_mut_poison() {
+        let m = Arc::new(PoisonMutex::new(1));
+        poison(&m);
+        let mut m = Arc::try_unwrap(m).unwrap();
+        *m.get_mut().unwrap_err().into_inner() = 2;
+        assert_eq!(m.into_inner().unwrap_err().into_inner(), 2);
+    }
+
+    #[test]
+    fn test_lock_in_unwind_does_not_poison() {
+        let m = Arc::new(PoisonMutex::new(1));
+        let m2 = m.clone();
+        let _ = thread::spawn(move || {
+            struct Unwinder {
+                i: Arc<PoisonMutex<i32>>,
+            }
+            impl Drop for Unwinder {
+                fn drop(&mut self) {
+                    *self.i.lock().unwrap() += 1;
+                }
+            }
+            let _u = Unwinder { i: m2 };
+            panic!();
+        })
+        .join();
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_condvar() {
+        let pair = Arc::new((PoisonMutex::new(false), CondvarAny::new()));
+        let pair2 = pair.clone();
+        thread::spawn(move || {
+            *pair2.0.lock().unwrap() = true;
+            pair2.1.notify_one();
+        });
+        let mut started = pair.0.lock().unwrap();
+        pair.1.wait_while(&mut started, |started| !*started);
+        assert!(*started);
+    }
+
+    #[test]
+    fn test_other_raw_mutex() {
+        let m = PoisonMutex::from_mutex(FairMutex::new(0));
+        *m.lock().unwrap() += 1;
+        assert_eq!(m.into_inner().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_debug() {
+        let m = PoisonMutex::new(vec![0u8, 10]);
+        assert_eq!(
+            format!("{:?}", m),
+            "PoisonMutex { data: [0, 10], poisoned: false }"
+        );
+        let _lock = m.lock();
+        assert_eq!(
+            format!("{:?}", m),
+            "PoisonMutex { data: <locked>, poisoned: false }"
+        );
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::poison;
+use crate::raw_rwlock::RawRwLock;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use lock_api::{CondvarGuard, RawMutexRequeue};
+use std::sync::{LockResult, TryLockError, TryLockResult};
+
+/// A reader-writer lock which is poisoned when a thread panics while holding
+/// it exclusively, like `std::sync::RwLock`.
+///
+/// This wraps a `lock_api::RwLock` and returns the same `LockResult` and
+/// `TryLockResult` types as the standard library, which makes it easier to
+/// migrate code which relies on poisoning. Use `RwLock` instead if you don't
+/// need poisoning. `new` uses `RawRwLock`, but any other raw reader-writer lock
+/// type can be used through `from_rwlock`.
+///
+/// The lock is poisoned when a thread panics while holding a write guard.
+/// Panicking while holding a read guard doesn't poison the lock, since readers
+/// can't leave the data in an inconsistent state. Once poisoned, every attempt
+/// to lock it returns a `PoisonError`, from which the guard can still be
+/// obtained with `PoisonError::into_inner`. The poison flag can be cleared with
+/// `clear_poison` once the protected data has been repaired.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::PoisonRwLock;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let lock = Arc::new(PoisonRwLock::new(0));
+/// let lock2 = lock.clone();
+///
+/// let _ = thread::spawn(move || {
+///     let _guard = lock2.write().unwrap();
+///     panic!(); // the lock gets poisoned
+/// })
+/// .join();
+///
+/// assert!(lock.is_poisoned());
+/// assert!(lock.read().is_err());
+///
+/// lock.clear_poison();
+/// assert_eq!(*lock.read().unwrap(), 0);
+/// ```
+pub struct PoisonRwLock<T: ?Sized, R: lock_api::RawRwLock = RawRwLock> {
+    poison: poison::Flag,
+    inner: lock_api::RwLock<R, T>,
+}
+
+impl<T> PoisonRwLock<T> {
+    /// Creates a new instance of a `PoisonRwLock<T>` which is unlocked.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> PoisonRwLock<T> {
+        PoisonRwLock {
+            poison: poison::Flag::new(),
+            inner: lock_api::RwLock::new(val),
+        }
+    }
+
+    /// Creates a new instance of a `PoisonRwLock<T>` which is unlocked.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> PoisonRwLock<T> {
+        PoisonRwLock::from_rwlock(lock_api::RwLock::new(val))
+    }
+}
+
+impl<T, R: lock_api::RawRwLock> PoisonRwLock<T, R> {
+    /// Adds poisoning to an existing reader-writer lock, which may use any raw
+    /// reader-writer lock type.
+    ///
+    /// The new `PoisonRwLock` is not poisoned.
+    #[inline]
+    pub fn from_rwlock(rwlock: lock_api::RwLock<R, T>) -> PoisonRwLock<T, R> {
+        PoisonRwLock {
+            poison: poison::Flag::new(),
+            inner: rwlock,
+        }
+    }
+
+    /// Consumes this `PoisonRwLock`, returning the underlying data.
+    ///
+    /// An error is returned if the lock is poisoned, but the data is returned
+    /// inside of it regardless.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        poison::map_result(poisoned, self.inner.into_inner())
+    }
+}
+
+impl<T: ?Sized, R: lock_api::RawRwLock> PoisonRwLock<T, R> {
+    #[inline]
+    fn read_guard<'a>(
+        &'a self,
+        guard: lock_api::RwLockReadGuard<'a, R, T>,
+    ) -> PoisonRwLockReadGuard<'a, T, R> {
+        PoisonRwLockReadGuard { lock: self, guard }
+    }
+
+    #[inline]
+    fn write_guard<'a>(
+        &'a self,
+        guard: lock_api::RwLockWriteGuard<'a, R, T>,
+    ) -> PoisonRwLockWriteGuard<'a, T, R> {
+        PoisonRwLockWriteGuard {
+            lock: self,
+            poison: self.poison.guard(),
+            guard,
+        }
+    }
+
+    /// Locks this `PoisonRwLock` with shared read access, blocking the current
+    /// thread until it can be acquired.
+    ///
+    /// An error is returned if the lock is poisoned, but the lock is acquired
+    /// regardless and its guard can be extracted from the error.
+    ///
+    /// Note that attempts to recursively acquire a read lock on a `RwLock` when
+    /// the current thread already holds one may result in a deadlock.
+    #[inline]
+    pub fn read(&self) -> LockResult<PoisonRwLockReadGuard<'_, T, R>> {
+        let guard = self.read_guard(self.inner.read());
+        poison::map_result(self.poison.get(), guard)
+    }
+
+    /// Attempts to acquire this `PoisonRwLock` with shared read access.
+    ///
+    /// If the access could not be granted at this time, then
+    /// `TryLockError::WouldBlock` is returned. If the lock is poisoned, it is
+    /// acquired and its guard is returned inside of `TryLockError::Poisoned`.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_read(&self) -> TryLockResult<PoisonRwLockReadGuard<'_, T, R>> {
+        match self.inner.try_read() {
+            Some(guard) => Ok(poison::map_result(
+                self.poison.get(),
+                self.read_guard(guard),
+            )?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Locks this `PoisonRwLock` with exclusive write access, blocking the
+    /// current thread until it can be acquired.
+    ///
+    /// An error is returned if the lock is poisoned, but the lock is acquired
+    /// regardless and its guard can be extracted from the error.
+    #[inline]
+    pub fn write(&self) -> LockResult<PoisonRwLockWriteGuard<'_, T, R>> {
+        let guard = self.write_guard(self.inner.write());
+        poison::map_result(self.poison.get(), guard)
+    }
+
+    /// Attempts to lock this `PoisonRwLock` with exclusive write access.
+    ///
+    /// If the access could not be granted at this time, then
+    /// `TryLockError::WouldBlock` is returned. If the lock is poisoned, it is
+    /// acquired and its guard is returned inside of `TryLockError::Poisoned`.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_write(&self) -> TryLockResult<PoisonRwLockWriteGuard<'_, T, R>> {
+        match self.inner.try_write() {
+            Some(guard) => Ok(poison::map_result(
+                self.poison.get(),
+                self.write_guard(guard),
+            )?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Determines whether the lock is poisoned.
+    ///
+    /// Another thread may poison the lock at any time, so a `false` result
+    /// should not be trusted without holding the lock.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poisoned state of the lock.
+    ///
+    /// This should only be done after the data protected by the lock has been
+    /// restored to a consistent state.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `PoisonRwLock` mutably, no actual locking
+    /// needs to take place. An error is returned if the lock is poisoned, but
+    /// the reference is returned inside of it regardless.
+    #[inline]
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        poison::map_result(poisoned, self.inner.get_mut())
+    }
+}
+
+impl<T: Default, R: lock_api::RawRwLock> Default for PoisonRwLock<T, R> {
+    #[inline]
+    fn default() -> PoisonRwLock<T, R> {
+        PoisonRwLock::from_rwlock(Default::default())
+    }
+}
+
+impl<T, R: lock_api::RawRwLock> From<T> for PoisonRwLock<T, R> {
+    #[inline]
+    fn from(t: T) -> PoisonRwLock<T, R> {
+        PoisonRwLock::from_rwlock(lock_api::RwLock::new(t))
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: lock_api::RawRwLock> fmt::Debug for PoisonRwLock<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("PoisonRwLock");
+        match self.inner.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => {
+                struct LockedPlaceholder;
+                impl fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                d.field("data", &LockedPlaceholder)
+            }
+        };
+        d.field("poisoned", &self.poison.get()).finish()
+    }
+}
+
+/// RAII structure used to release the shared read access of a `PoisonRwLock`
+/// when dropped.
+#[must_use = "if unused the PoisonRwLock will immediately unlock"]
+pub struct PoisonRwLockReadGuard<'a, T: ?Sized, R: lock_api::RawRwLock = RawRwLock> {
+    lock: &'a PoisonRwLock<T, R>,
+    guard: lock_api::RwLockReadGuard<'a, R, T>,
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> PoisonRwLockReadGuard<'a, T, R> {
+    /// Returns a reference to the original `PoisonRwLock` object.
+    #[inline]
+    pub fn rwlock(s: &Self) -> &'a PoisonRwLock<T, R> {
+        s.lock
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> Deref for PoisonRwLockReadGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized + 'a, R: lock_api::RawRwLock + 'a> fmt::Debug
+    for PoisonRwLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized + 'a, R: lock_api::RawRwLock + 'a> fmt::Display
+    for PoisonRwLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// RAII structure used to release the exclusive write access of a
+/// `PoisonRwLock` when dropped. The lock will be poisoned if the thread is
+/// panicking.
+#[must_use = "if unused the PoisonRwLock will immediately unlock"]
+pub struct PoisonRwLockWriteGuard<'a, T: ?Sized, R: lock_api::RawRwLock = RawRwLock> {
+    lock: &'a PoisonRwLock<T, R>,
+    poison: poison::Guard,
+    guard: lock_api::RwLockWriteGuard<'a, R, T>,
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> PoisonRwLockWriteGuard<'a, T, R> {
+    /// Returns a reference to the original `PoisonRwLock` object.
+    #[inline]
+    pub fn rwlock(s: &Self) -> &'a PoisonRwLock<T, R> {
+        s.lock
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> Deref for PoisonRwLockWriteGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> DerefMut
+    for PoisonRwLockWriteGuard<'a, T, R>
+{
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> Drop for PoisonRwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        // The flag is updated before the inner guard unlocks the lock.
+        self.lock.poison.done(&self.poison);
+    }
+}
+
+unsafe impl<'a, T: ?Sized + 'a, R: lock_api::RawRwLock + 'a> CondvarGuard
+    for PoisonRwLockWriteGuard<'a, T, R>
+{
+    #[inline]
+    fn lock_addr(&self) -> usize {
+        self.guard.lock_addr()
+    }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn RawMutexRequeue> {
+        self.guard.requeue_target()
+    }
+
+    #[inline]
+    unsafe fn unlock_for_wait(&self) -> usize {
+        self.guard.unlock_for_wait()
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, state: usize, token: Option<usize>) {
+        self.guard.relock_after_wait(state, token)
+    }
+}
+
+impl<'a, T: fmt::Debug + ?Sized + 'a, R: lock_api::RawRwLock + 'a> fmt::Debug
+    for PoisonRwLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized + 'a, R: lock_api::RawRwLock + 'a> fmt::Display
+    for PoisonRwLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CondvarAny, PoisonRwLock};
+    use std::sync::{Arc, TryLockError};
+    use std::thread;
+
+    #[test]
+    fn smoke() {
+        let l = PoisonRwLock::new(0);
+        drop(l.read().unwrap());
+        *l.write().unwrap() += 1;
+        drop((l.read().unwrap(), l.try_read().unwrap()));
+        *l.try_write().unwrap() += 1;
+        assert_eq!(l.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let l = PoisonRwLock::new(());
+        let read = l.read().unwrap();
+        match l.try_write() {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("write locked while reading"),
+        }
+        drop(read);
+        let write = l.write().unwrap();
+        match l.try_read() {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("read locked while writing"),
+        }
+        drop(write);
+    }
+
+    #[test]
+    fn test_rw_arc_poison_wr() {
+        let arc = Arc::new(PoisonRwLock::new(1));
+        let arc2 = arc.clone();
+        let _ = thread::spawn(move || {
+            let _lock = arc2.write().unwrap();
+            panic!();
+        })
+        .join();
+        assert!(arc.is_poisoned());
+        assert_eq!(*arc.read().unwrap_err().into_inner(), 1);
+        match arc.try_write() {
+            Err(TryLockError::Poisoned(e)) => *e.into_inner() += 1,
+            _ => panic!("poison was not reported"),
+        }
+
+        arc.clear_poison();
+        assert_eq!(*arc.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_rw_arc_no_poison_rr() {
+        let arc = Arc::new(PoisonRwLock::new(1));
+        let arc2 = arc.clone();
+        let _ = thread::spawn(move || {
+            let _lock = arc2.read().unwrap();
+            panic!();
+        })
+        .join();
+        assert!(!arc.is_poisoned());
+        assert_eq!(*arc.write().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_into_inner_get_mut_poison() {
+        let arc = Arc::new(PoisonRwLock::new(1));
+        let arc2 = arc.clone();
+        let _ = thread::spawn(move || {
+            let _lock = arc2.write().unwrap();
+            panic!();
+        })
+        .join();
+        let mut l = Arc::try_unwrap(arc).unwrap();
+        *l.get_mut().unwrap_err().into_inner() = 2;
+        assert_eq!(l.into_inner().unwrap_err().into_inner(), 2);
+    }
+
+    #[test]
+    fn test_condvar() {
+        let pair = Arc::new((PoisonRwLock::new(false), CondvarAny::new()));
+        let pair2 = pair.clone();
+        thread::spawn(move || {
+            *pair2.0.write().unwrap() = true;
+            pair2.1.notify_one();
+        });
+        let mut started = pair.0.write().unwrap();
+        pair.1.wait_while(&mut started, |started| !*started);
+        assert!(*started);
+    }
+
+    #[test]
+    fn test_debug() {
+        let l = PoisonRwLock::new(vec![0u8, 10]);
+        assert_eq!(
+            format!("{:?}", l),
+            "PoisonRwLock { data: [0, 10], poisoned: false }"
+        );
+        let _lock = l.write();
+        assert_eq!(
+            format!("{:?}", l),
+            "PoisonRwLock { data: <locked>, poisoned: false }"
+        );
+    }
+}
@@ -17,11 +17,13 @@ unsafe impl lock_api::RawMutex for RawFairMutex {
     type GuardMarker = <RawMutex as lock_api::RawMutex>::GuardMarker;
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn lock(&self) {
         self.0.lock()
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock(&self) -> bool {
         self.0.try_lock()
     }
@@ -30,6 +32,17 @@ unsafe impl lock_api::RawMutex for RawFairMutex {
     fn unlock(&self) {
         self.unlock_fair()
     }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn lock_api::RawMutexRequeue> {
+        self.0.requeue_target()
+    }
+
+    #[cfg(feature = "owner_tracking")]
+    #[inline]
+    fn debug_owner(&self, s: &mut core::fmt::DebugStruct<'_, '_>) {
+        self.0.debug_owner(s)
+    }
 }
 
 unsafe impl lock_api::RawMutexFair for RawFairMutex {
@@ -49,12 +62,46 @@ unsafe impl lock_api::RawMutexTimed for RawFairMutex {
     type Instant = <RawMutex as lock_api::RawMutexTimed>::Instant;
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_until(&self, timeout: Self::Instant) -> bool {
         self.0.try_lock_until(timeout)
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_for(&self, timeout: Self::Duration) -> bool {
         self.0.try_lock_for(timeout)
     }
 }
+
+impl lock_api::RawLockLevel for RawFairMutex {
+    #[inline]
+    fn with_level(level: u32) -> RawFairMutex {
+        RawFairMutex(<RawMutex as lock_api::RawLockLevel>::with_level(level))
+    }
+}
+
+#[cfg(feature = "owner_tracking")]
+impl lock_api::RawLockOwner for RawFairMutex {
+    type Owner = crate::LockOwner;
+
+    #[inline]
+    fn owner(&self) -> Option<crate::LockOwner> {
+        lock_api::RawLockOwner::owner(&self.0)
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawFairMutex {
+    type Stats = <RawMutex as lock_api::RawLockStats>::Stats;
+
+    #[inline]
+    fn stats(&self) -> Self::Stats {
+        self.0.stats()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.0.reset_stats()
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_rwlock::RawRwLock;
+use lock_api::{RawRwLockFair, RawRwLockUpgradeFair};
+
+/// Raw fair reader-writer lock type backed by the parking lot.
+pub struct RawFairRwLock(RawRwLock);
+
+unsafe impl lock_api::RawRwLock for RawFairRwLock {
+    const INIT: Self = RawFairRwLock(<RawRwLock as lock_api::RawRwLock>::INIT);
+
+    type GuardMarker = <RawRwLock as lock_api::RawRwLock>::GuardMarker;
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn lock_exclusive(&self) {
+        self.0.lock_exclusive()
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn try_lock_exclusive(&self) -> bool {
+        self.0.try_lock_exclusive()
+    }
+
+    #[inline]
+    fn unlock_exclusive(&self) {
+        self.unlock_exclusive_fair()
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        self.0.lock_shared()
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        self.0.try_lock_shared()
+    }
+
+    #[inline]
+    fn unlock_shared(&self) {
+        self.unlock_shared_fair()
+    }
+
+    #[cfg(feature = "owner_tracking")]
+    #[inline]
+    fn debug_owner(&self, s: &mut core::fmt::DebugStruct<'_, '_>) {
+        self.0.debug_owner(s)
+    }
+}
+
+unsafe impl lock_api::RawRwLockFair for RawFairRwLock {
+    #[inline]
+    fn unlock_shared_fair(&self) {
+        self.0.unlock_shared_fair()
+    }
+
+    #[inline]
+    fn unlock_exclusive_fair(&self) {
+        self.0.unlock_exclusive_fair()
+    }
+
+    #[inline]
+    fn bump_shared(&self) {
+        self.0.bump_shared()
+    }
+
+    #[inline]
+    fn bump_exclusive(&self) {
+        self.0.bump_exclusive()
+    }
+}
+
+unsafe impl lock_api::RawRwLockDowngrade for RawFairRwLock {
+    #[inline]
+    fn downgrade(&self) {
+        self.0.downgrade()
+    }
+}
+
+unsafe impl lock_api::RawRwLockTimed for RawFairRwLock {
+    type Duration = <RawRwLock as lock_api::RawRwLockTimed>::Duration;
+    type Instant = <RawRwLock as lock_api::RawRwLockTimed>::Instant;
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+        self.0.try_lock_shared_for(timeout)
+    }
+
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Self::Instant) -> bool {
+        self.0.try_lock_shared_until(timeout)
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn try_lock_exclusive_for(&self, timeout: Self::Duration) -> bool {
+        self.0.try_lock_exclusive_for(timeout)
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn try_lock_exclusive_until(&self, timeout: Self::Instant) -> bool {
+        self.0.try_lock_exclusive_until(timeout)
+    }
+}
+
+unsafe impl lock_api::RawRwLockRecursive for RawFairRwLock {
+    #[inline]
+    fn lock_shared_recursive(&self) {
+        self.0.lock_shared_recursive()
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive(&self) -> bool {
+        self.0.try_lock_shared_recursive()
+    }
+}
+
+unsafe impl lock_api::RawRwLockRecursiveTimed for RawFairRwLock {
+    #[inline]
+    fn try_lock_shared_recursive_for(&self, timeout: Self::Duration) -> bool {
+        self.0.try_lock_shared_recursive_for(timeout)
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive_until(&self, timeout: Self::Instant) -> bool {
+        self.0.try_lock_shared_recursive_until(timeout)
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgrade for RawFairRwLock {
+    #[inline]
+    fn lock_upgradable(&self) {
+        self.0.lock_upgradable()
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        self.0.try_lock_upgradable()
+    }
+
+    #[inline]
+    fn unlock_upgradable(&self) {
+        self.unlock_upgradable_fair()
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn upgrade(&self) {
+        self.0.upgrade()
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn try_upgrade(&self) -> bool {
+        self.0.try_upgrade()
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgradeFair for RawFairRwLock {
+    #[inline]
+    fn unlock_upgradable_fair(&self) {
+        self.0.unlock_upgradable_fair()
+    }
+
+    #[inline]
+    fn bump_upgradable(&self) {
+        self.0.bump_upgradable()
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgradeDowngrade for RawFairRwLock {
+    #[inline]
+    fn downgrade_upgradable(&self) {
+        self.0.downgrade_upgradable()
+    }
+
+    #[inline]
+    fn downgrade_to_upgradable(&self) {
+        self.0.downgrade_to_upgradable()
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgradeTimed for RawFairRwLock {
+    #[inline]
+    fn try_lock_upgradable_until(&self, timeout: Self::Instant) -> bool {
+        self.0.try_lock_upgradable_until(timeout)
+    }
+
+    #[inline]
+    fn try_lock_upgradable_for(&self, timeout: Self::Duration) -> bool {
+        self.0.try_lock_upgradable_for(timeout)
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn try_upgrade_until(&self, timeout: Self::Instant) -> bool {
+        self.0.try_upgrade_until(timeout)
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
+    fn try_upgrade_for(&self, timeout: Self::Duration) -> bool {
+        self.0.try_upgrade_for(timeout)
+    }
+}
+
+impl lock_api::RawLockLevel for RawFairRwLock {
+    #[inline]
+    fn with_level(level: u32) -> RawFairRwLock {
+        RawFairRwLock(<RawRwLock as lock_api::RawLockLevel>::with_level(level))
+    }
+}
+
+#[cfg(feature = "owner_tracking")]
+impl lock_api::RawLockOwner for RawFairRwLock {
+    type Owner = crate::LockOwner;
+
+    #[inline]
+    fn owner(&self) -> Option<crate::LockOwner> {
+        lock_api::RawLockOwner::owner(&self.0)
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawFairRwLock {
+    type Stats = <RawRwLock as lock_api::RawLockStats>::Stats;
+
+    #[inline]
+    fn stats(&self) -> Self::Stats {
+        self.0.stats()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.0.reset_stats()
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{
+    deadlock,
+    futex::{futex_wait, futex_wake, FutexScope, Private, Shared},
+    stats::LockCounters,
+    util,
+};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use lock_api::GuardNoSend;
+use parking_lot_core::SpinWait;
+use std::time::Instant;
+
+/// This bit is set in the `state` of a futex mutex when that mutex is locked by some thread.
+const LOCKED_BIT: u32 = 0b01;
+/// This bit is set in the `state` of a futex mutex before a thread goes to sleep waiting for it.
+/// Since waiting threads are not tracked, it stays set until the mutex is unlocked.
+const PARKED_BIT: u32 = 0b10;
+
+/// Mutex state shared by `RawFutexMutex` and `RawSharedMutex`, using the same
+/// bits as `RawMutex` in a 32-bit futex word.
+///
+/// # State table:
+///
+/// PARKED_BIT | LOCKED_BIT | Description
+///     0      |     0      | The mutex is not locked.
+/// -----------+------------+------------------------------------------------------------------
+///     0      |     1      | The mutex is locked and no thread is waiting for it.
+/// -----------+------------+------------------------------------------------------------------
+///     1      |     1      | The mutex is locked and threads may be waiting for it, so unlocking
+///            |            | it must wake one of them up.
+#[repr(C)]
+pub(crate) struct FutexMutexState<S> {
+    state: AtomicU32,
+    scope: PhantomData<S>,
+}
+
+impl<S: FutexScope> FutexMutexState<S> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: Self = FutexMutexState {
+        state: AtomicU32::new(0),
+        scope: PhantomData,
+    };
+
+    #[inline]
+    pub(crate) fn try_lock_fast(&self) -> bool {
+        self.state
+            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn unlock(&self) {
+        if self.state.swap(0, Ordering::Release) & PARKED_BIT != 0 {
+            futex_wake::<S>(&self.state, 1);
+        }
+    }
+
+    #[cold]
+    pub(crate) fn lock_slow(&self, timeout: Option<Instant>, stats: Option<&LockCounters>) -> bool {
+        // Spin a few times while the lock is held but nobody is waiting for it,
+        // since the owner is likely to release it soon.
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state == LOCKED_BIT && spinwait.spin() {
+            if let Some(stats) = stats {
+                stats.spin();
+            }
+            state = self.state.load(Ordering::Relaxed);
+        }
+        if state == 0 && self.try_lock() {
+            return true;
+        }
+
+        loop {
+            // Mark the mutex as parked before sleeping. If it happened to be
+            // unlocked then we now own it, at the cost of a spurious wake-up
+            // when we unlock it since we can't tell whether others are waiting.
+            if self.state.swap(LOCKED_BIT | PARKED_BIT, Ordering::Acquire) & LOCKED_BIT == 0 {
+                return true;
+            }
+
+            // A thread which times out leaves PARKED_BIT set, which is harmless.
+            if let Some(stats) = stats {
+                stats.park();
+            }
+            if !futex_wait::<S>(&self.state, LOCKED_BIT | PARKED_BIT, timeout) {
+                return false;
+            }
+        }
+    }
+}
+
+/// Raw mutex type which waits directly on its own state with a Linux futex.
+///
+/// Unlike `RawMutex`, contended threads don't go through the global parking
+/// lot hash table, which avoids the cost of bucket collisions with unrelated
+/// locks at the price of taking up 4 bytes instead of 1. It doesn't support
+/// fair unlocking or requeuing from a `Condvar`, and threads blocked on it are
+/// not visible to the deadlock detector.
+pub struct RawFutexMutex {
+    state: FutexMutexState<Private>,
+
+    /// Contention statistics, which take up no space unless the `stats` feature is enabled.
+    stats: LockCounters,
+}
+
+unsafe impl lock_api::RawMutex for RawFutexMutex {
+    const INIT: RawFutexMutex = RawFutexMutex {
+        state: FutexMutexState::INIT,
+        stats: LockCounters::INIT,
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock(&self) {
+        if !self.state.try_lock_fast() {
+            self.stats
+                .slow_path(|| self.state.lock_slow(None, Some(&self.stats)));
+        } else {
+            self.stats.fast_path();
+        }
+        self.stats.lock_exclusive();
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        if self.state.try_lock() {
+            self.stats.fast_path();
+            self.stats.lock_exclusive();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.stats.unlock_exclusive();
+        self.state.unlock();
+    }
+}
+
+unsafe impl lock_api::RawMutexTimed for RawFutexMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_until(&self, timeout: Instant) -> bool {
+        self.try_lock_deadline(Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.try_lock_deadline(util::to_deadline(timeout))
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawFutexMutex {
+    type Stats = crate::LockStats;
+
+    #[inline]
+    fn stats(&self) -> crate::LockStats {
+        self.stats.get()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+}
+
+#[cfg(feature = "lock_order_detection")]
+impl Drop for RawFutexMutex {
+    #[inline]
+    fn drop(&mut self) {
+        deadlock::forget_resource(self as *const _ as usize);
+    }
+}
+
+impl RawFutexMutex {
+    #[inline]
+    fn try_lock_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result = if self.state.try_lock_fast() {
+            self.stats.fast_path();
+            true
+        } else {
+            self.stats
+                .slow_path(|| self.state.lock_slow(timeout, Some(&self.stats)))
+        };
+        if result {
+            self.stats.lock_exclusive();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+}
+
+/// Raw mutex type which can be placed in memory shared between processes.
+///
+/// This uses the same algorithm as `RawFutexMutex`, except that it waits on a
+/// futex without `FUTEX_PRIVATE_FLAG` so that threads of other processes which
+/// map the same memory can wake each other up. Its layout is a single
+/// zero-initialized 32-bit word, regardless of the enabled features, so a
+/// zero-filled memory region is a valid unlocked mutex.
+///
+/// This type is only available on Linux and Android.
+#[repr(C)]
+pub struct RawSharedMutex {
+    state: FutexMutexState<Shared>,
+}
+
+unsafe impl lock_api::RawMutex for RawSharedMutex {
+    const INIT: RawSharedMutex = RawSharedMutex {
+        state: FutexMutexState::INIT,
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock(&self) {
+        if !self.state.try_lock_fast() {
+            self.state.lock_slow(None, None);
+        }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        let result = self.state.try_lock();
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.state.unlock();
+    }
+}
+
+unsafe impl lock_api::RawMutexTimed for RawSharedMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_until(&self, timeout: Instant) -> bool {
+        self.try_lock_deadline(Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.try_lock_deadline(util::to_deadline(timeout))
+    }
+}
+
+impl RawSharedMutex {
+    #[inline]
+    fn try_lock_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result = self.state.try_lock_fast() || self.state.lock_slow(timeout, None);
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+}
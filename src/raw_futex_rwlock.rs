@@ -0,0 +1,632 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{
+    deadlock,
+    futex::{futex_wait, futex_wake, futex_wake_all, FutexScope, Private, Shared},
+    stats::LockCounters,
+    util,
+};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use lock_api::GuardNoSend;
+use parking_lot_core::SpinWait;
+use std::time::Instant;
+
+// The lower 30 bits of the state hold the number of readers, or `WRITE_LOCKED`
+// if the lock is held by a writer.
+const READ_LOCKED: u32 = 1;
+const MASK: u32 = (1 << 30) - 1;
+const WRITE_LOCKED: u32 = MASK;
+const MAX_READERS: u32 = MASK - 1;
+// Set when readers are waiting for the lock on the `state` futex.
+const READERS_WAITING: u32 = 1 << 30;
+// Set when writers are waiting for the lock on the `writer_notify` futex.
+const WRITERS_WAITING: u32 = 1 << 31;
+
+#[inline]
+fn is_unlocked(state: u32) -> bool {
+    state & MASK == 0
+}
+
+#[inline]
+fn is_write_locked(state: u32) -> bool {
+    state & MASK == WRITE_LOCKED
+}
+
+#[inline]
+fn has_readers_waiting(state: u32) -> bool {
+    state & READERS_WAITING != 0
+}
+
+#[inline]
+fn has_writers_waiting(state: u32) -> bool {
+    state & WRITERS_WAITING != 0
+}
+
+// Readers are blocked as soon as any thread is waiting, which prevents
+// writer starvation.
+#[inline]
+fn is_read_lockable(state: u32) -> bool {
+    state & MASK < MAX_READERS && !has_readers_waiting(state) && !has_writers_waiting(state)
+}
+
+/// Reader-writer lock state shared by `RawFutexRwLock` and `RawSharedRwLock`.
+#[repr(C)]
+pub(crate) struct FutexRwLockState<S> {
+    /// Reader count or `WRITE_LOCKED`, plus the `READERS_WAITING` and
+    /// `WRITERS_WAITING` bits. Readers sleep on this futex.
+    state: AtomicU32,
+
+    /// Incremented every time a writer is woken up. Writers sleep on this
+    /// futex so that unlocking can wake a single writer.
+    writer_notify: AtomicU32,
+
+    scope: PhantomData<S>,
+}
+
+impl<S: FutexScope> FutexRwLockState<S> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: Self = FutexRwLockState {
+        state: AtomicU32::new(0),
+        writer_notify: AtomicU32::new(0),
+        scope: PhantomData,
+    };
+
+    #[inline]
+    pub(crate) fn try_lock_exclusive_fast(&self) -> bool {
+        self.state
+            .compare_exchange_weak(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn try_lock_exclusive(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn unlock_exclusive(&self) {
+        let state = self.state.fetch_sub(WRITE_LOCKED, Ordering::Release) - WRITE_LOCKED;
+        debug_assert!(is_unlocked(state));
+        if has_writers_waiting(state) || has_readers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn try_lock_shared_fast(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        is_read_lockable(state)
+            && self
+                .state
+                .compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    #[inline]
+    pub(crate) fn try_lock_shared(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while is_read_lockable(state) {
+            match self.state.compare_exchange_weak(
+                state,
+                state + READ_LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => state = x,
+            }
+        }
+        false
+    }
+
+    #[inline]
+    pub(crate) fn unlock_shared(&self) {
+        let state = self.state.fetch_sub(READ_LOCKED, Ordering::Release) - READ_LOCKED;
+
+        // Readers only wait while a writer holds the lock or is waiting for
+        // it, so only a waiting writer needs to be woken up here.
+        if is_unlocked(state) && has_writers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    #[cold]
+    pub(crate) fn lock_shared_slow(
+        &self,
+        timeout: Option<Instant>,
+        stats: Option<&LockCounters>,
+    ) -> bool {
+        let mut state = self.spin_shared(stats);
+        loop {
+            if is_read_lockable(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => {
+                        state = x;
+                        continue;
+                    }
+                }
+            }
+
+            if state & MASK == MAX_READERS {
+                panic!("RwLock reader count overflow");
+            }
+
+            // Make sure the readers waiting bit is set before going to sleep.
+            if !has_readers_waiting(state) {
+                if let Err(x) = self.state.compare_exchange(
+                    state,
+                    state | READERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // A reader which times out leaves the readers waiting bit set. This
+            // is harmless since it is cleared as soon as the lock is unlocked.
+            if let Some(stats) = stats {
+                stats.park();
+            }
+            if !futex_wait::<S>(&self.state, state | READERS_WAITING, timeout) {
+                return false;
+            }
+            state = self.spin_shared(stats);
+        }
+    }
+
+    #[cold]
+    pub(crate) fn lock_exclusive_slow(
+        &self,
+        timeout: Option<Instant>,
+        stats: Option<&LockCounters>,
+    ) -> bool {
+        let mut state = self.spin_exclusive(stats);
+
+        // Once we have slept we can't know whether other writers are also
+        // waiting, so we have to keep the writers waiting bit set when we
+        // acquire the lock.
+        let mut other_writers_waiting = 0;
+        loop {
+            if is_unlocked(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | WRITE_LOCKED | other_writers_waiting,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => {
+                        state = x;
+                        continue;
+                    }
+                }
+            }
+
+            // Make sure the writers waiting bit is set before going to sleep.
+            if !has_writers_waiting(state) {
+                if let Err(x) = self.state.compare_exchange(
+                    state,
+                    state | WRITERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+            other_writers_waiting = WRITERS_WAITING;
+
+            // Read the notification counter before checking the state again,
+            // so that we don't miss a wake-up which happens in between.
+            let seq = self.writer_notify.load(Ordering::Acquire);
+            state = self.state.load(Ordering::Relaxed);
+            if is_unlocked(state) || !has_writers_waiting(state) {
+                continue;
+            }
+
+            // A writer which times out leaves the writers waiting bit set. The
+            // next unlock then clears it and either wakes up another writer or
+            // falls back to waking up the readers.
+            if let Some(stats) = stats {
+                stats.park();
+            }
+            if !futex_wait::<S>(&self.writer_notify, seq, timeout) {
+                return false;
+            }
+            state = self.spin_exclusive(stats);
+        }
+    }
+
+    // Wakes up a waiting writer if there is one, or all waiting readers
+    // otherwise. This is called with the lock unlocked.
+    #[cold]
+    fn wake_writer_or_readers(&self, mut state: u32) {
+        debug_assert!(is_unlocked(state));
+
+        // Only writers are waiting, so wake one of them up.
+        if state == WRITERS_WAITING {
+            match self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    self.wake_writer();
+                    return;
+                }
+                Err(x) => state = x,
+            }
+        }
+
+        // Both readers and writers are waiting. Leave the readers waiting and
+        // only wake up a writer, unless there was none left to wake up.
+        if state == READERS_WAITING + WRITERS_WAITING {
+            if self
+                .state
+                .compare_exchange(state, READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                // The lock got locked by someone else, who will wake up the
+                // waiting threads when they unlock it.
+                return;
+            }
+            if self.wake_writer() {
+                return;
+            }
+            state = READERS_WAITING;
+        }
+
+        // Only readers are waiting, so wake all of them up.
+        if state == READERS_WAITING
+            && self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            futex_wake_all::<S>(&self.state);
+        }
+    }
+
+    #[inline]
+    fn wake_writer(&self) -> bool {
+        self.writer_notify.fetch_add(1, Ordering::Release);
+        futex_wake::<S>(&self.writer_notify, 1) != 0
+    }
+
+    // Spins for a bit while the lock is held by a writer and nobody is waiting
+    // for it yet, since the writer is likely to release it soon.
+    #[inline]
+    fn spin_shared(&self, stats: Option<&LockCounters>) -> u32 {
+        self.spin_until(stats, |state| {
+            !is_write_locked(state) || has_readers_waiting(state) || has_writers_waiting(state)
+        })
+    }
+
+    // Spins for a bit while the lock is held and no other writer is waiting.
+    #[inline]
+    fn spin_exclusive(&self, stats: Option<&LockCounters>) -> u32 {
+        self.spin_until(stats, |state| {
+            is_unlocked(state) || has_writers_waiting(state)
+        })
+    }
+
+    #[inline]
+    fn spin_until(&self, stats: Option<&LockCounters>, f: impl Fn(u32) -> bool) -> u32 {
+        let mut spinwait = SpinWait::new();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if f(state) || !spinwait.spin() {
+                return state;
+            }
+            if let Some(stats) = stats {
+                stats.spin();
+            }
+        }
+    }
+}
+
+/// Raw reader-writer lock type which waits directly on its own state with a
+/// Linux futex.
+///
+/// Unlike `RawRwLock`, contended threads don't go through the global parking
+/// lot hash table, which avoids the cost of bucket collisions with unrelated
+/// locks at the price of taking up 8 bytes. It doesn't support upgradable
+/// reads, downgrading or fair unlocking, and threads blocked on it are not
+/// visible to the deadlock detector.
+///
+/// Writers are preferred: once a writer is waiting, new readers block until
+/// it has acquired and released the lock.
+pub struct RawFutexRwLock {
+    state: FutexRwLockState<Private>,
+
+    /// Contention statistics, which take up no space unless the `stats` feature is enabled.
+    stats: LockCounters,
+}
+
+unsafe impl lock_api::RawRwLock for RawFutexRwLock {
+    const INIT: RawFutexRwLock = RawFutexRwLock {
+        state: FutexRwLockState::INIT,
+        stats: LockCounters::INIT,
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        if !self.state.try_lock_exclusive_fast() {
+            self.stats
+                .slow_path(|| self.state.lock_exclusive_slow(None, Some(&self.stats)));
+        } else {
+            self.stats.fast_path();
+        }
+        self.stats.lock_exclusive();
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        if self.state.try_lock_exclusive() {
+            self.stats.fast_path();
+            self.stats.lock_exclusive();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    fn unlock_exclusive(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.stats.unlock_exclusive();
+        self.state.unlock_exclusive();
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        if !self.state.try_lock_shared_fast() {
+            self.stats
+                .slow_path(|| self.state.lock_shared_slow(None, Some(&self.stats)));
+        } else {
+            self.stats.fast_path();
+        }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        if self.state.try_lock_shared() {
+            self.stats.fast_path();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    fn unlock_shared(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.state.unlock_shared();
+    }
+}
+
+unsafe impl lock_api::RawRwLockTimed for RawFutexRwLock {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+        self.try_lock_shared_deadline(util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Self::Instant) -> bool {
+        self.try_lock_shared_deadline(Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_exclusive_deadline(util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
+        self.try_lock_exclusive_deadline(Some(timeout))
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawFutexRwLock {
+    type Stats = crate::LockStats;
+
+    #[inline]
+    fn stats(&self) -> crate::LockStats {
+        self.stats.get()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+}
+
+#[cfg(feature = "lock_order_detection")]
+impl Drop for RawFutexRwLock {
+    #[inline]
+    fn drop(&mut self) {
+        deadlock::forget_resource(self as *const _ as usize);
+    }
+}
+
+impl RawFutexRwLock {
+    #[inline]
+    fn try_lock_shared_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result = if self.state.try_lock_shared_fast() {
+            self.stats.fast_path();
+            true
+        } else {
+            self.stats
+                .slow_path(|| self.state.lock_shared_slow(timeout, Some(&self.stats)))
+        };
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn try_lock_exclusive_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result = if self.state.try_lock_exclusive_fast() {
+            self.stats.fast_path();
+            true
+        } else {
+            self.stats
+                .slow_path(|| self.state.lock_exclusive_slow(timeout, Some(&self.stats)))
+        };
+        if result {
+            self.stats.lock_exclusive();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+}
+
+/// Raw reader-writer lock type which can be placed in memory shared between
+/// processes.
+///
+/// This uses the same algorithm as `RawFutexRwLock`, except that it waits on
+/// futexes without `FUTEX_PRIVATE_FLAG` so that threads of other processes
+/// which map the same memory can wake each other up. Its layout is two
+/// zero-initialized 32-bit words, regardless of the enabled features, so a
+/// zero-filled memory region is a valid unlocked lock.
+///
+/// This type is only available on Linux and Android.
+#[repr(C)]
+pub struct RawSharedRwLock {
+    state: FutexRwLockState<Shared>,
+}
+
+unsafe impl lock_api::RawRwLock for RawSharedRwLock {
+    const INIT: RawSharedRwLock = RawSharedRwLock {
+        state: FutexRwLockState::INIT,
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        if !self.state.try_lock_exclusive_fast() {
+            self.state.lock_exclusive_slow(None, None);
+        }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        let result = self.state.try_lock_exclusive();
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock_exclusive(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.state.unlock_exclusive();
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        if !self.state.try_lock_shared_fast() {
+            self.state.lock_shared_slow(None, None);
+        }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        let result = self.state.try_lock_shared();
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock_shared(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.state.unlock_shared();
+    }
+}
+
+unsafe impl lock_api::RawRwLockTimed for RawSharedRwLock {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+        self.try_lock_shared_deadline(util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Self::Instant) -> bool {
+        self.try_lock_shared_deadline(Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_exclusive_deadline(util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
+        self.try_lock_exclusive_deadline(Some(timeout))
+    }
+}
+
+impl RawSharedRwLock {
+    #[inline]
+    fn try_lock_shared_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result =
+            self.state.try_lock_shared_fast() || self.state.lock_shared_slow(timeout, None);
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn try_lock_exclusive_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result =
+            self.state.try_lock_exclusive_fast() || self.state.lock_exclusive_slow(timeout, None);
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+}
@@ -5,13 +5,25 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use crate::{deadlock, util};
+use crate::{
+    deadlock,
+    lock_level::LockLevel,
+    owner::OwnerCell,
+    stats::LockCounters,
+    trace, util,
+    watchdog::{HoldTimer, LockKind},
+};
 use core::{
+    future::Future,
+    pin::Pin,
     sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
     time::Duration,
 };
-use lock_api::{GuardNoSend, RawMutex as RawMutex_};
-use parking_lot_core::{self, ParkResult, SpinWait, UnparkResult, UnparkToken, DEFAULT_PARK_TOKEN};
+use lock_api::GuardNoSend;
+use parking_lot_core::{
+    self, ParkFuture, ParkResult, SpinWait, UnparkResult, UnparkToken, DEFAULT_PARK_TOKEN,
+};
 use std::time::Instant;
 
 // UnparkToken used to indicate that that the target thread should attempt to
@@ -22,6 +34,10 @@ pub(crate) const TOKEN_NORMAL: UnparkToken = UnparkToken(0);
 // thread directly without unlocking it.
 pub(crate) const TOKEN_HANDOFF: UnparkToken = UnparkToken(1);
 
+// Future used to park an asynchronous task waiting for a lock. The callback
+// is a plain function since it only needs the key to find the lock state.
+pub(crate) type TaskParkFuture = ParkFuture<fn(usize, bool)>;
+
 /// This bit is set in the `state` of a `RawMutex` when that mutex is locked by some thread.
 const LOCKED_BIT: u8 = 0b01;
 /// This bit is set in the `state` of a `RawMutex` just before parking a thread. A thread is being
@@ -53,28 +69,45 @@ pub struct RawMutex {
     ///            |            | PARKED_BIT is not set (which would result in those threads
     ///            |            | potentially never getting woken up).
     state: AtomicU8,
+
+    /// Contention statistics, which take up no space unless the `stats` feature is enabled.
+    stats: LockCounters,
+
+    /// Level in the lock hierarchy, which takes up no space unless the `lock_levels` feature is
+    /// enabled.
+    level: LockLevel,
+
+    /// Thread holding the mutex, which takes up no space unless the `owner_tracking` feature is
+    /// enabled.
+    owner: OwnerCell,
+
+    /// Time at which the mutex was locked, which takes up no space unless the `watchdog` feature
+    /// is enabled.
+    hold: HoldTimer,
 }
 
 unsafe impl lock_api::RawMutex for RawMutex {
     const INIT: RawMutex = RawMutex {
         state: AtomicU8::new(0),
+        stats: LockCounters::INIT,
+        level: LockLevel::INIT,
+        owner: OwnerCell::INIT,
+        hold: HoldTimer::INIT,
     };
 
     type GuardMarker = GuardNoSend;
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn lock(&self) {
-        if self
-            .state
-            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            self.lock_slow(None);
-        }
-        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        self.level.check(self as *const _ as usize);
+        self.lock_unchecked();
+        self.owner.acquire();
+        self.hold.start();
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock(&self) -> bool {
         let mut state = self.state.load(Ordering::Relaxed);
         loop {
@@ -88,7 +121,11 @@ unsafe impl lock_api::RawMutex for RawMutex {
                 Ordering::Relaxed,
             ) {
                 Ok(_) => {
-                    unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+                    self.stats.fast_path();
+                    self.stats.lock_exclusive();
+                    unsafe { self.deadlock_acquire() };
+                    self.owner.acquire();
+                    self.hold.start();
                     return true;
                 }
                 Err(x) => state = x,
@@ -99,14 +136,28 @@ unsafe impl lock_api::RawMutex for RawMutex {
     #[inline]
     fn unlock(&self) {
         unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         if self
             .state
             .compare_exchange(LOCKED_BIT, 0, Ordering::Release, Ordering::Relaxed)
-            .is_ok()
+            .is_err()
         {
-            return;
+            self.unlock_slow(false);
         }
-        self.unlock_slow(false);
+        hold.report(self as *const _ as usize, LockKind::Mutex);
+    }
+
+    #[inline]
+    fn requeue_target(&self) -> Option<&dyn lock_api::RawMutexRequeue> {
+        Some(self)
+    }
+
+    #[cfg(feature = "owner_tracking")]
+    #[inline]
+    fn debug_owner(&self, s: &mut core::fmt::DebugStruct<'_, '_>) {
+        self.owner.debug(s);
     }
 }
 
@@ -114,14 +165,17 @@ unsafe impl lock_api::RawMutexFair for RawMutex {
     #[inline]
     fn unlock_fair(&self) {
         unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         if self
             .state
             .compare_exchange(LOCKED_BIT, 0, Ordering::Release, Ordering::Relaxed)
-            .is_ok()
+            .is_err()
         {
-            return;
+            self.unlock_slow(true);
         }
-        self.unlock_slow(true);
+        hold.report(self as *const _ as usize, LockKind::Mutex);
     }
 
     #[inline]
@@ -137,41 +191,174 @@ unsafe impl lock_api::RawMutexTimed for RawMutex {
     type Instant = Instant;
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_until(&self, timeout: Instant) -> bool {
         let result = if self
             .state
             .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_slow(Some(timeout))
+            self.stats.slow_path(|| self.lock_slow(Some(timeout)))
         };
         if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            self.stats.lock_exclusive();
+            unsafe { self.deadlock_acquire() };
+            self.owner.acquire();
+            self.hold.start();
         }
         result
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_for(&self, timeout: Duration) -> bool {
         let result = if self
             .state
             .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_slow(util::to_deadline(timeout))
+            self.stats
+                .slow_path(|| self.lock_slow(util::to_deadline(timeout)))
         };
         if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            self.stats.lock_exclusive();
+            unsafe { self.deadlock_acquire() };
+            self.owner.acquire();
+            self.hold.start();
         }
         result
     }
 }
 
+unsafe impl lock_api::RawMutexRequeue for RawMutex {
+    #[inline]
+    fn requeue_key(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    #[inline]
+    fn mark_parked_if_locked(&self) -> bool {
+        RawMutex::mark_parked_if_locked(self)
+    }
+
+    #[inline]
+    fn mark_parked(&self) {
+        RawMutex::mark_parked(self)
+    }
+
+    #[inline]
+    unsafe fn relock_after_wait(&self, token: Option<usize>) {
+        // The thread that unparked us may have passed the lock on to us
+        // directly without unlocking it.
+        if token == Some(TOKEN_HANDOFF.0) {
+            self.acquired_by_handoff();
+        } else {
+            self.lock_unchecked();
+        }
+        self.owner.relock();
+        self.hold.start();
+    }
+}
+
+impl lock_api::RawLockLevel for RawMutex {
+    #[inline]
+    fn with_level(level: u32) -> RawMutex {
+        RawMutex {
+            state: AtomicU8::new(0),
+            stats: LockCounters::INIT,
+            level: LockLevel::new(level),
+            owner: OwnerCell::INIT,
+            hold: HoldTimer::INIT,
+        }
+    }
+}
+
+#[cfg(feature = "owner_tracking")]
+impl lock_api::RawLockOwner for RawMutex {
+    type Owner = crate::LockOwner;
+
+    #[inline]
+    fn owner(&self) -> Option<crate::LockOwner> {
+        self.owner.get()
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawMutex {
+    type Stats = crate::LockStats;
+
+    #[inline]
+    fn stats(&self) -> crate::LockStats {
+        self.stats.get()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+}
+
+#[cfg(feature = "lock_order_detection")]
+impl Drop for RawMutex {
+    #[inline]
+    fn drop(&mut self) {
+        deadlock::forget_resource(self as *const _ as usize);
+    }
+}
+
 impl RawMutex {
+    // Used by Condvar when a waiting thread was handed the mutex directly by
+    // the thread that unlocked it.
+    #[inline]
+    pub(crate) unsafe fn acquired_by_handoff(&self) {
+        self.stats.lock_exclusive();
+        self.deadlock_acquire();
+    }
+
+    // Used by Condvar to preserve the owner of the mutex while waiting.
+    #[inline]
+    pub(crate) fn owner(&self) -> &OwnerCell {
+        &self.owner
+    }
+
+    // Used by Condvar to keep timing the mutex once it is reacquired.
+    #[inline]
+    pub(crate) fn hold(&self) -> &HoldTimer {
+        &self.hold
+    }
+
+    // Registers the mutex as held by the current thread with the deadlock
+    // detector and the lock level checker.
+    #[inline]
+    unsafe fn deadlock_acquire(&self) {
+        deadlock::acquire_resource(self as *const _ as usize);
+        self.level.acquire(self as *const _ as usize);
+    }
+
+    // Locks the mutex without checking its level. This is used when a lock
+    // which was already held is reacquired, since panicking at that point
+    // would leave a guard for an unlocked mutex.
+    #[inline]
+    pub(crate) fn lock_unchecked(&self) {
+        if self
+            .state
+            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.stats.slow_path(|| self.lock_slow(None));
+        } else {
+            self.stats.fast_path();
+        }
+        self.stats.lock_exclusive();
+        unsafe { self.deadlock_acquire() };
+    }
+
     // Used by Condvar when requeuing threads to us, must be called while
     // holding the queue lock.
     #[inline]
@@ -202,9 +389,10 @@ impl RawMutex {
 
     #[cold]
     fn lock_slow(&self, timeout: Option<Instant>) -> bool {
+        let span = trace::LockSpan::enter("mutex", self as *const _ as usize, timeout);
         let mut spinwait = SpinWait::new();
         let mut state = self.state.load(Ordering::Relaxed);
-        loop {
+        let acquired = loop {
             // Grab the lock if it isn't locked, even if there is a queue on it
             if state & LOCKED_BIT == 0 {
                 match self.state.compare_exchange_weak(
@@ -213,7 +401,7 @@ impl RawMutex {
                     Ordering::Acquire,
                     Ordering::Relaxed,
                 ) {
-                    Ok(_) => return true,
+                    Ok(_) => break true,
                     Err(x) => state = x,
                 }
                 continue;
@@ -221,6 +409,7 @@ impl RawMutex {
 
             // If there is no queue, try spinning a few times
             if state & PARKED_BIT == 0 && spinwait.spin() {
+                self.stats.spin();
                 state = self.state.load(Ordering::Relaxed);
                 continue;
             }
@@ -252,6 +441,7 @@ impl RawMutex {
             //   * `addr` is an address we control.
             //   * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
             //   * `before_sleep` does not call `park`, nor does it panic.
+            self.stats.park();
             match unsafe {
                 parking_lot_core::park(
                     addr,
@@ -259,12 +449,12 @@ impl RawMutex {
                     before_sleep,
                     timed_out,
                     DEFAULT_PARK_TOKEN,
-                    timeout,
+                    self.hold.park_deadline(timeout),
                 )
             } {
                 // The thread that unparked us passed the lock on to us
                 // directly without unlocking it.
-                ParkResult::Unparked(TOKEN_HANDOFF) => return true,
+                ParkResult::Unparked(TOKEN_HANDOFF) => break true,
 
                 // We were unparked normally, try acquiring the lock again
                 ParkResult::Unparked(_) => (),
@@ -272,14 +462,20 @@ impl RawMutex {
                 // The validation function failed, try locking again
                 ParkResult::Invalid => (),
 
-                // Timeout expired
-                ParkResult::TimedOut => return false,
+                // Timeout expired, or the current hold of the lock may need
+                // to be reported to the watchdog
+                ParkResult::TimedOut => {
+                    if self.hold.timed_out(addr, LockKind::Mutex, timeout) {
+                        break false;
+                    }
+                }
             }
 
             // Loop back and try locking again
             spinwait.reset();
             state = self.state.load(Ordering::Relaxed);
-        }
+        };
+        span.finish(acquired)
     }
 
     #[cold]
@@ -296,6 +492,7 @@ impl RawMutex {
                 if !result.have_more_threads {
                     self.state.store(LOCKED_BIT, Ordering::Relaxed);
                 }
+                self.stats.handoff(!force_fair);
                 return TOKEN_HANDOFF;
             }
 
@@ -316,10 +513,180 @@ impl RawMutex {
         }
     }
 
+    // Attempts to acquire the mutex on behalf of an asynchronous task. This
+    // doesn't register the lock with the deadlock detector since the lock
+    // isn't owned by the current thread.
+    #[inline]
+    pub(crate) fn try_lock_task(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & LOCKED_BIT != 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state | LOCKED_BIT,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.stats.fast_path();
+                    self.stats.lock_exclusive();
+                    return true;
+                }
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    // Releases a mutex acquired by an asynchronous task.
+    #[inline]
+    pub(crate) fn unlock_task(&self, force_fair: bool) {
+        self.stats.unlock_exclusive();
+        if self
+            .state
+            .compare_exchange(LOCKED_BIT, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        self.unlock_slow(force_fair);
+    }
+
+    // Returns whether the mutex is currently locked.
+    #[inline]
+    pub(crate) fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & LOCKED_BIT != 0
+    }
+
+    // Drives the acquisition of the mutex by an asynchronous task. This is the
+    // asynchronous counterpart of lock_slow: the task is parked in the same
+    // queue as blocked threads and can also receive the lock via a handoff.
+    // Tasks never spin since that would block the executor.
+    pub(crate) fn poll_lock_task(
+        &self,
+        park: &mut Option<TaskParkFuture>,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        loop {
+            // Check whether we have been unparked yet
+            if let Some(ref mut future) = *park {
+                match Pin::new(future).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => {
+                        *park = None;
+
+                        // The thread that unparked us passed the lock on to us
+                        // directly without unlocking it.
+                        if result == ParkResult::Unparked(TOKEN_HANDOFF) {
+                            self.stats.lock_exclusive();
+                            return Poll::Ready(());
+                        }
+                    }
+                }
+            }
+
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                // Grab the lock if it isn't locked, even if there is a queue on it
+                if state & LOCKED_BIT == 0 {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        state | LOCKED_BIT,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => {
+                            self.stats.lock_exclusive();
+                            return Poll::Ready(());
+                        }
+                        Err(x) => state = x,
+                    }
+                    continue;
+                }
+
+                // Set the parked bit
+                if state & PARKED_BIT == 0 {
+                    if let Err(x) = self.state.compare_exchange_weak(
+                        state,
+                        state | PARKED_BIT,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        state = x;
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            // Park our task until we are woken up by an unlock. The future is
+            // polled on the next iteration of the loop.
+            let addr = self as *const _ as usize;
+            let validate = || self.state.load(Ordering::Relaxed) == LOCKED_BIT | PARKED_BIT;
+            let before_sleep = || {};
+            // SAFETY:
+            //   * `addr` is an address we control.
+            //   * `validate`/`task_cancelled` does not panic or call into any function of
+            //     `parking_lot`.
+            //   * `before_sleep` does not call `park`, nor does it panic.
+            self.stats.park();
+            *park = Some(unsafe {
+                parking_lot_core::park_async(
+                    addr,
+                    validate,
+                    before_sleep,
+                    task_cancelled as fn(usize, bool),
+                    DEFAULT_PARK_TOKEN,
+                    None,
+                )
+            });
+        }
+    }
+
+    // Cleans up after an asynchronous lock operation that is abandoned before
+    // it completed.
+    pub(crate) fn cancel_lock_task(&self, park: &mut Option<TaskParkFuture>) {
+        let token = match park.take() {
+            Some(mut future) => future.cancel(),
+            None => return,
+        };
+        match token {
+            // The lock was handed off to us, release it again
+            Some(TOKEN_HANDOFF) => self.unlock_task(false),
+
+            // We were woken up to try acquiring the lock. Pass this on to
+            // another parked thread if the mutex is still unlocked.
+            Some(_) if self.try_lock_task() => self.unlock_task(false),
+
+            // Either we were removed from the queue before being unparked, or
+            // another thread holds the lock and will wake up the next thread.
+            _ => (),
+        }
+    }
+
     #[cold]
     fn bump_slow(&self) {
+        let owner = self.owner.save();
         unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         self.unlock_slow(true);
-        self.lock();
+        self.lock_unchecked();
+        self.owner.restore(owner);
+        self.hold.resume(hold);
+    }
+}
+
+// Invoked while the queue is locked when an asynchronous lock operation is
+// cancelled while its task is still parked.
+fn task_cancelled(key: usize, was_last_thread: bool) {
+    // Clear the parked bit if we were the last parked thread
+    if was_last_thread {
+        // SAFETY: The key is the address of the RawMutex which the task was
+        // waiting for, which outlives the task's ParkFuture.
+        let mutex = unsafe { &*(key as *const RawMutex) };
+        mutex.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,159 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{
+    deadlock,
+    futex::{current_tid, futex_lock_pi, futex_unlock_pi, Private},
+    stats::LockCounters,
+    util,
+};
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use lock_api::GuardNoSend;
+use std::time::Instant;
+
+/// This bit (`FUTEX_WAITERS`) is set by the kernel in the `state` of a PI mutex
+/// when threads are blocked on it, in which case unlocking it must go through
+/// the kernel.
+const WAITERS_BIT: u32 = 0x8000_0000;
+
+/// Raw mutex type which implements priority inheritance using a Linux PI
+/// futex.
+///
+/// The state is a 32-bit word holding the kernel thread ID of the owner, or 0
+/// when the mutex is unlocked. Uncontended locking and unlocking only need an
+/// atomic operation on that word, but contended threads block in the kernel
+/// with `FUTEX_LOCK_PI`, which temporarily raises the priority of the owner to
+/// that of the highest priority waiter. This prevents a low-priority thread
+/// holding the lock from delaying a real-time thread indefinitely because
+/// medium-priority threads keep it from running.
+///
+/// On unlock the kernel hands the lock directly over to the highest priority
+/// waiter, so this mutex is always fair. It doesn't support requeuing from a
+/// `Condvar`, and threads blocked on it are not visible to the deadlock
+/// detector. Locking a `RawPiMutex` which is already held by the current
+/// thread panics since the kernel detects the deadlock.
+///
+/// This type is only available on Linux and Android.
+pub struct RawPiMutex {
+    state: AtomicU32,
+
+    /// Contention statistics, which take up no space unless the `stats` feature is enabled.
+    stats: LockCounters,
+}
+
+unsafe impl lock_api::RawMutex for RawPiMutex {
+    const INIT: RawPiMutex = RawPiMutex {
+        state: AtomicU32::new(0),
+        stats: LockCounters::INIT,
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock(&self) {
+        self.try_lock_deadline(None);
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        if self
+            .state
+            .compare_exchange(0, current_tid(), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.stats.fast_path();
+            self.stats.lock_exclusive();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.stats.unlock_exclusive();
+
+        // The state can only change from under us if the kernel sets the
+        // waiters bit, in which case it has to pick the next owner.
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WAITERS_BIT != 0
+            || self
+                .state
+                .compare_exchange(state, 0, Ordering::Release, Ordering::Relaxed)
+                .is_err()
+        {
+            futex_unlock_pi::<Private>(&self.state);
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutexTimed for RawPiMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_until(&self, timeout: Instant) -> bool {
+        self.try_lock_deadline(Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.try_lock_deadline(util::to_deadline(timeout))
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawPiMutex {
+    type Stats = crate::LockStats;
+
+    #[inline]
+    fn stats(&self) -> crate::LockStats {
+        self.stats.get()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+}
+
+#[cfg(feature = "lock_order_detection")]
+impl Drop for RawPiMutex {
+    #[inline]
+    fn drop(&mut self) {
+        deadlock::forget_resource(self as *const _ as usize);
+    }
+}
+
+impl RawPiMutex {
+    #[inline]
+    fn try_lock_deadline(&self, timeout: Option<Instant>) -> bool {
+        let result = if self
+            .state
+            .compare_exchange_weak(0, current_tid(), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.stats.fast_path();
+            true
+        } else {
+            self.stats.slow_path(|| {
+                self.stats.park();
+                futex_lock_pi::<Private>(&self.state, timeout)
+            })
+        };
+        if result {
+            self.stats.lock_exclusive();
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+}
@@ -0,0 +1,1214 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_mutex::{TOKEN_HANDOFF, TOKEN_NORMAL};
+use crate::util;
+use core::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lock_api::GuardNoSend;
+use parking_lot_core::{
+    self, deadlock, FilterOp, ParkResult, ParkToken, SpinWait, UnparkResult, UnparkToken,
+};
+use std::time::{Duration, Instant};
+
+// This implementation uses 2 wait queues. All threads waiting to acquire the
+// lock are parked in the main queue at key [addr], and the thread holding the
+// upgradable lock parks alone at key [addr + 1] while it waits for the
+// remaining readers to exit during an upgrade.
+//
+// Whenever the lock is released while threads are parked in the main queue,
+// the releasing thread picks which of them to wake up according to the policy
+// and hands the lock over to them directly. This allows the policy to be
+// enforced exactly, but means that all unlocking is fair. To make this choice,
+// the number of parked threads of each kind is tracked while the queue is
+// locked.
+
+// There is at least one thread in the main queue.
+const PARKED_BIT: usize = 0b00001;
+// There is at least one thread waiting for an exclusive lock in the main queue.
+// PARKED_BIT must be set.
+const WRITERS_PARKED_BIT: usize = 0b00010;
+// The holder of the upgradable lock is waiting for the remaining readers to exit
+// the lock so that it can upgrade. UPGRADABLE_BIT must be set.
+const UPGRADING_BIT: usize = 0b00100;
+// A writer is holding an exclusive lock. The reader count must be zero.
+const WRITER_BIT: usize = 0b01000;
+// A reader is holding an upgradable lock. The reader count includes it.
+const UPGRADABLE_BIT: usize = 0b10000;
+// Mask of bits used to count readers.
+const READERS_MASK: usize = !0b11111;
+// Base unit for counting readers.
+const ONE_READER: usize = 0b100000;
+
+// Token indicating what type of lock a queued thread is trying to acquire. This
+// is also the amount which is added to the state when the lock is acquired.
+const TOKEN_SHARED: ParkToken = ParkToken(ONE_READER);
+const TOKEN_UPGRADABLE: ParkToken = ParkToken(ONE_READER | UPGRADABLE_BIT);
+const TOKEN_EXCLUSIVE: ParkToken = ParkToken(WRITER_BIT);
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Policy deciding how a `RawPolicyRwLock` arbitrates between readers and
+/// writers.
+///
+/// This trait is sealed and implemented by `ReaderPreferringPolicy`,
+/// `WriterPreferringPolicy` and `PhaseFairPolicy`.
+pub trait RwLockPolicy: sealed::Sealed {
+    /// Whether new readers wait when writers are queued, even if the lock is
+    /// currently only held by readers.
+    #[doc(hidden)]
+    const READERS_WAIT_FOR_WRITERS: bool;
+
+    /// Whether queued readers are preferred over queued writers when a lock is
+    /// released, depending on whether that lock was an exclusive lock.
+    #[doc(hidden)]
+    fn prefer_readers(after_writer: bool) -> bool;
+}
+
+/// Policy which lets readers acquire a `RawPolicyRwLock` whenever no writer
+/// holds it.
+///
+/// New readers never wait for queued writers, and all queued readers are woken
+/// up before any writer. This maximizes read throughput for read-mostly data
+/// such as caches, but writers can be starved by a continuous stream of
+/// readers.
+pub struct ReaderPreferringPolicy(());
+
+impl sealed::Sealed for ReaderPreferringPolicy {}
+
+impl RwLockPolicy for ReaderPreferringPolicy {
+    const READERS_WAIT_FOR_WRITERS: bool = false;
+
+    #[inline]
+    fn prefer_readers(_after_writer: bool) -> bool {
+        true
+    }
+}
+
+/// Policy which always prefers writers in a `RawPolicyRwLock`.
+///
+/// New readers wait as long as a writer is queued, and queued writers are
+/// always woken up before queued readers. This minimizes write latency, but
+/// readers can be starved by a continuous stream of writers.
+pub struct WriterPreferringPolicy(());
+
+impl sealed::Sealed for WriterPreferringPolicy {}
+
+impl RwLockPolicy for WriterPreferringPolicy {
+    const READERS_WAIT_FOR_WRITERS: bool = true;
+
+    #[inline]
+    fn prefer_readers(_after_writer: bool) -> bool {
+        false
+    }
+}
+
+/// Policy which alternates between read phases and write phases in a
+/// `RawPolicyRwLock`.
+///
+/// New readers wait as long as a writer is queued. When a writer releases the
+/// lock, all queued readers are admitted together, and when the last of them
+/// exits, the next queued writer is admitted. Neither readers nor writers can
+/// be starved, and a reader waits for at most one writer.
+///
+/// The lock is handed over through the queue in arrival order: writers are
+/// admitted one at a time in the order in which they were queued, and each
+/// read phase admits every reader queued so far, including those which
+/// arrived after a queued writer. The only exception is when recursive
+/// readers enter the lock while it is being handed over to a writer, in which
+/// case that writer has to queue again.
+pub struct PhaseFairPolicy(());
+
+impl sealed::Sealed for PhaseFairPolicy {}
+
+impl RwLockPolicy for PhaseFairPolicy {
+    const READERS_WAIT_FOR_WRITERS: bool = true;
+
+    #[inline]
+    fn prefer_readers(after_writer: bool) -> bool {
+        after_writer
+    }
+}
+
+/// Raw reader-writer lock type backed by the parking lot, with a selectable
+/// policy for arbitrating between readers and writers.
+///
+/// `RawRwLock` lets readers and writers in roughly in the order in which they
+/// arrived, with eventual fairness. This type instead enforces the given
+/// `RwLockPolicy` exactly, by always handing the lock over directly to the
+/// threads chosen by the policy when it is released. This makes every unlock
+/// fair, so the lock has lower throughput than `RawRwLock` under contention.
+///
+/// The upgradable lock counts as a reader for the policy. While its holder is
+/// waiting to upgrade, new readers wait regardless of the policy.
+///
+/// Use one of the `RawReaderPreferringRwLock`, `RawWriterPreferringRwLock` or
+/// `RawPhaseFairRwLock` aliases with `lock_api::RwLock`:
+///
+/// ```
+/// use parking_lot::{lock_api, RawPhaseFairRwLock};
+///
+/// let lock = lock_api::RwLock::<RawPhaseFairRwLock, _>::new(5);
+/// assert_eq!(*lock.read(), 5);
+/// *lock.write() += 1;
+/// assert_eq!(*lock.read(), 6);
+/// ```
+pub struct RawPolicyRwLock<P: RwLockPolicy> {
+    state: AtomicUsize,
+
+    // Number of threads in the main queue waiting for each kind of lock. These
+    // are only accessed while the queue is locked.
+    parked_shared: AtomicUsize,
+    parked_upgradable: AtomicUsize,
+    parked_exclusive: AtomicUsize,
+
+    policy: PhantomData<P>,
+}
+
+/// Reader-writer lock type which lets readers in whenever no writer holds it.
+///
+/// See `ReaderPreferringPolicy` for details.
+pub type RawReaderPreferringRwLock = RawPolicyRwLock<ReaderPreferringPolicy>;
+
+/// Reader-writer lock type which always prefers writers.
+///
+/// See `WriterPreferringPolicy` for details.
+pub type RawWriterPreferringRwLock = RawPolicyRwLock<WriterPreferringPolicy>;
+
+/// Reader-writer lock type which alternates between read and write phases.
+///
+/// See `PhaseFairPolicy` for details.
+pub type RawPhaseFairRwLock = RawPolicyRwLock<PhaseFairPolicy>;
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLock for RawPolicyRwLock<P> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = RawPolicyRwLock {
+        state: AtomicUsize::new(0),
+        parked_shared: AtomicUsize::new(0),
+        parked_upgradable: AtomicUsize::new(0),
+        parked_exclusive: AtomicUsize::new(0),
+        policy: PhantomData,
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        if self
+            .state
+            .compare_exchange_weak(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            let result = self.lock_slow(TOKEN_EXCLUSIVE, false, None);
+            debug_assert!(result);
+        }
+        self.deadlock_acquire();
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        let result = self.try_lock(TOKEN_EXCLUSIVE, false);
+        if result {
+            self.deadlock_acquire();
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock_exclusive(&self) {
+        self.deadlock_release();
+        if self
+            .state
+            .compare_exchange(WRITER_BIT, 0, Ordering::Release, Ordering::Relaxed)
+            .is_err()
+        {
+            self.unlock_slow(WRITER_BIT, 0, true);
+        }
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        if !self.try_lock_fast(TOKEN_SHARED, false) {
+            let result = self.lock_slow(TOKEN_SHARED, false, None);
+            debug_assert!(result);
+        }
+        self.deadlock_acquire();
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        let result = self.try_lock(TOKEN_SHARED, false);
+        if result {
+            self.deadlock_acquire();
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock_shared(&self) {
+        self.deadlock_release();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            let readers = state & READERS_MASK;
+            if readers == ONE_READER && state & PARKED_BIT != 0 {
+                return self.unlock_slow(ONE_READER, 0, false);
+            }
+            if readers == 2 * ONE_READER && state & UPGRADING_BIT != 0 {
+                return self.unlock_shared_upgrading_slow();
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state - ONE_READER,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockFair for RawPolicyRwLock<P> {
+    #[inline]
+    fn unlock_shared_fair(&self) {
+        // Unlocking is always fair.
+        lock_api::RawRwLock::unlock_shared(self);
+    }
+
+    #[inline]
+    fn unlock_exclusive_fair(&self) {
+        // Unlocking is always fair.
+        lock_api::RawRwLock::unlock_exclusive(self);
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockDowngrade for RawPolicyRwLock<P> {
+    #[inline]
+    fn downgrade(&self) {
+        if self
+            .state
+            .compare_exchange(WRITER_BIT, ONE_READER, Ordering::Release, Ordering::Relaxed)
+            .is_err()
+        {
+            self.unlock_slow(WRITER_BIT, ONE_READER, true);
+        }
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockTimed for RawPolicyRwLock<P> {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+        self.try_lock_deadline(TOKEN_SHARED, false, util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Self::Instant) -> bool {
+        self.try_lock_deadline(TOKEN_SHARED, false, Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_deadline(TOKEN_EXCLUSIVE, false, util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
+        self.try_lock_deadline(TOKEN_EXCLUSIVE, false, Some(timeout))
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockRecursive for RawPolicyRwLock<P> {
+    #[inline]
+    fn lock_shared_recursive(&self) {
+        if !self.try_lock_fast(TOKEN_SHARED, true) {
+            let result = self.lock_slow(TOKEN_SHARED, true, None);
+            debug_assert!(result);
+        }
+        self.deadlock_acquire();
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive(&self) -> bool {
+        let result = self.try_lock(TOKEN_SHARED, true);
+        if result {
+            self.deadlock_acquire();
+        }
+        result
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockRecursiveTimed for RawPolicyRwLock<P> {
+    #[inline]
+    fn try_lock_shared_recursive_for(&self, timeout: Self::Duration) -> bool {
+        self.try_lock_deadline(TOKEN_SHARED, true, util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive_until(&self, timeout: Self::Instant) -> bool {
+        self.try_lock_deadline(TOKEN_SHARED, true, Some(timeout))
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockUpgrade for RawPolicyRwLock<P> {
+    #[inline]
+    fn lock_upgradable(&self) {
+        if !self.try_lock_fast(TOKEN_UPGRADABLE, false) {
+            let result = self.lock_slow(TOKEN_UPGRADABLE, false, None);
+            debug_assert!(result);
+        }
+        self.deadlock_acquire();
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        let result = self.try_lock(TOKEN_UPGRADABLE, false);
+        if result {
+            self.deadlock_acquire();
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock_upgradable(&self) {
+        self.deadlock_release();
+        self.release(ONE_READER | UPGRADABLE_BIT);
+    }
+
+    #[inline]
+    fn upgrade(&self) {
+        if !self.try_upgrade_fast() {
+            let result = self.upgrade_slow(None);
+            debug_assert!(result);
+        }
+    }
+
+    #[inline]
+    fn try_upgrade(&self) -> bool {
+        self.try_upgrade_fast()
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockUpgradeFair for RawPolicyRwLock<P> {
+    #[inline]
+    fn unlock_upgradable_fair(&self) {
+        // Unlocking is always fair.
+        lock_api::RawRwLockUpgrade::unlock_upgradable(self);
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockUpgradeDowngrade for RawPolicyRwLock<P> {
+    #[inline]
+    fn downgrade_upgradable(&self) {
+        self.release(UPGRADABLE_BIT);
+    }
+
+    #[inline]
+    fn downgrade_to_upgradable(&self) {
+        if self
+            .state
+            .compare_exchange(
+                WRITER_BIT,
+                ONE_READER | UPGRADABLE_BIT,
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            self.unlock_slow(WRITER_BIT, ONE_READER | UPGRADABLE_BIT, true);
+        }
+    }
+}
+
+unsafe impl<P: RwLockPolicy> lock_api::RawRwLockUpgradeTimed for RawPolicyRwLock<P> {
+    #[inline]
+    fn try_lock_upgradable_until(&self, timeout: Instant) -> bool {
+        self.try_lock_deadline(TOKEN_UPGRADABLE, false, Some(timeout))
+    }
+
+    #[inline]
+    fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
+        self.try_lock_deadline(TOKEN_UPGRADABLE, false, util::to_deadline(timeout))
+    }
+
+    #[inline]
+    fn try_upgrade_until(&self, timeout: Instant) -> bool {
+        self.try_upgrade_fast() || self.upgrade_slow(Some(timeout))
+    }
+
+    #[inline]
+    fn try_upgrade_for(&self, timeout: Duration) -> bool {
+        self.try_upgrade_fast() || self.upgrade_slow(util::to_deadline(timeout))
+    }
+}
+
+// Which kind of parked threads to wake up when the lock is released.
+#[derive(Clone, Copy, PartialEq)]
+enum Wake {
+    None,
+    Writer,
+    Readers { upgradable: bool },
+}
+
+#[cfg(feature = "lock_order_detection")]
+impl<P: RwLockPolicy> Drop for RawPolicyRwLock<P> {
+    #[inline]
+    fn drop(&mut self) {
+        deadlock::forget_resource(self as *const _ as usize);
+    }
+}
+
+impl<P: RwLockPolicy> RawPolicyRwLock<P> {
+    // Checks whether a lock described by the given token can be acquired in the
+    // given state.
+    #[inline]
+    fn can_acquire(state: usize, token: ParkToken, recursive: bool) -> bool {
+        if token == TOKEN_EXCLUSIVE {
+            return state & (WRITER_BIT | READERS_MASK) == 0;
+        }
+        if state & WRITER_BIT != 0 || (token == TOKEN_UPGRADABLE && state & UPGRADABLE_BIT != 0) {
+            return false;
+        }
+
+        // A thread which already holds a read lock must not wait for threads
+        // which are themselves waiting for it to exit.
+        recursive
+            || (state & UPGRADING_BIT == 0
+                && !(P::READERS_WAIT_FOR_WRITERS && state & WRITERS_PARKED_BIT != 0))
+    }
+
+    #[inline]
+    fn try_lock_fast(&self, token: ParkToken, recursive: bool) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        if !Self::can_acquire(state, token, recursive) {
+            return false;
+        }
+        let new_state = state
+            .checked_add(token.0)
+            .expect("RwLock reader count overflow");
+        self.state
+            .compare_exchange_weak(state, new_state, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    fn try_lock(&self, token: ParkToken, recursive: bool) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if !Self::can_acquire(state, token, recursive) {
+                return false;
+            }
+            let new_state = state
+                .checked_add(token.0)
+                .expect("RwLock reader count overflow");
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    #[inline]
+    fn try_lock_deadline(
+        &self,
+        token: ParkToken,
+        recursive: bool,
+        timeout: Option<Instant>,
+    ) -> bool {
+        let result =
+            self.try_lock_fast(token, recursive) || self.lock_slow(token, recursive, timeout);
+        if result {
+            self.deadlock_acquire();
+        }
+        result
+    }
+
+    #[inline]
+    fn try_upgrade_fast(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & READERS_MASK != ONE_READER {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state - (ONE_READER | UPGRADABLE_BIT) + WRITER_BIT,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    // Releases part of a read lock, waking up parked threads if there are any.
+    #[inline]
+    fn release(&self, release: usize) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & PARKED_BIT == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state - release,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+        self.unlock_slow(release, 0, false);
+    }
+
+    // Returns the counter of parked threads waiting for the given kind of lock.
+    #[inline]
+    fn parked_count(&self, token: ParkToken) -> &AtomicUsize {
+        if token == TOKEN_EXCLUSIVE {
+            &self.parked_exclusive
+        } else if token == TOKEN_UPGRADABLE {
+            &self.parked_upgradable
+        } else {
+            &self.parked_shared
+        }
+    }
+
+    // Returns the parked bits matching the threads in the main queue. This
+    // must be called while the queue is locked.
+    #[inline]
+    fn parked_bits(&self) -> usize {
+        let exclusive = self.parked_exclusive.load(Ordering::Relaxed);
+        let total = exclusive
+            + self.parked_shared.load(Ordering::Relaxed)
+            + self.parked_upgradable.load(Ordering::Relaxed);
+        let mut bits = 0;
+        if total != 0 {
+            bits |= PARKED_BIT;
+        }
+        if exclusive != 0 {
+            bits |= WRITERS_PARKED_BIT;
+        }
+        bits
+    }
+
+    // Decides which parked threads can be given the lock in the given state.
+    // This must be called while the main queue is locked.
+    fn choose_wake(&self, state: usize, prefer_readers: bool) -> Wake {
+        let exclusive = self.parked_exclusive.load(Ordering::Relaxed);
+        let writer = exclusive != 0 && state & (WRITER_BIT | READERS_MASK) == 0;
+        let upgradable =
+            self.parked_upgradable.load(Ordering::Relaxed) != 0 && state & UPGRADABLE_BIT == 0;
+        let readers = (self.parked_shared.load(Ordering::Relaxed) != 0 || upgradable)
+            && state & (WRITER_BIT | UPGRADING_BIT) == 0
+            && (prefer_readers || !P::READERS_WAIT_FOR_WRITERS || exclusive == 0);
+        if readers && (prefer_readers || !writer) {
+            Wake::Readers { upgradable }
+        } else if writer {
+            Wake::Writer
+        } else {
+            Wake::None
+        }
+    }
+
+    #[cold]
+    fn lock_slow(&self, token: ParkToken, recursive: bool, timeout: Option<Instant>) -> bool {
+        let parked_bits = if token == TOKEN_EXCLUSIVE {
+            PARKED_BIT | WRITERS_PARKED_BIT
+        } else {
+            PARKED_BIT
+        };
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if Self::can_acquire(state, token, recursive) {
+                let new_state = state
+                    .checked_add(token.0)
+                    .expect("RwLock reader count overflow");
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // If there are no parked threads, try spinning a few times
+            if state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Set the parked bits
+            if state & parked_bits != parked_bits {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | parked_bits,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Park our thread until we are woken up by an unlock
+            let addr = self as *const _ as usize;
+            let validate = || {
+                let state = self.state.load(Ordering::Relaxed);
+                if state & parked_bits != parked_bits || Self::can_acquire(state, token, recursive)
+                {
+                    return false;
+                }
+                self.parked_count(token).fetch_add(1, Ordering::Relaxed);
+                true
+            };
+            let before_sleep = || {};
+            let timed_out = |_, _| {
+                self.parked_count(token).fetch_sub(1, Ordering::Relaxed);
+                self.update_parked_bits();
+            };
+            // SAFETY:
+            // * `addr` is an address we control.
+            // * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
+            // * `before_sleep` does not call `park`, nor does it panic.
+            match unsafe {
+                parking_lot_core::park(addr, validate, before_sleep, timed_out, token, timeout)
+            } {
+                // The thread that unparked us passed the lock on to us
+                // directly without unlocking it.
+                ParkResult::Unparked(TOKEN_HANDOFF) => return true,
+
+                // We were unparked normally, try acquiring the lock again
+                ParkResult::Unparked(_) => (),
+
+                // The validation function failed, try locking again
+                ParkResult::Invalid => (),
+
+                // Timeout expired. Threads which were waiting behind us may
+                // be able to acquire the lock now.
+                ParkResult::TimedOut => {
+                    if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+                        self.unlock_slow(0, 0, false);
+                    }
+                    return false;
+                }
+            }
+
+            // Loop back and try locking again
+            spinwait.reset();
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    // Updates the parked bits to match the threads in the main queue. This must
+    // be called while the queue is locked.
+    fn update_parked_bits(&self) {
+        let parked_bits = self.parked_bits();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            let new_state = (state & !(PARKED_BIT | WRITERS_PARKED_BIT)) | parked_bits;
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    // Subtracts `release` from the state and adds `acquire` to it, and hands the
+    // lock over to the parked threads chosen by the policy. `after_writer`
+    // indicates whether the released lock was an exclusive lock.
+    #[cold]
+    fn unlock_slow(&self, mut release: usize, mut acquire: usize, after_writer: bool) {
+        let prefer_readers = P::prefer_readers(after_writer);
+        loop {
+            let wake = Cell::new(None);
+            let granted = Cell::new(0);
+            let wake_again = Cell::new(false);
+
+            let addr = self as *const _ as usize;
+            let filter = |token: ParkToken| -> FilterOp {
+                // Decide which threads to wake up once the queue is locked
+                let w = match wake.get() {
+                    Some(w) => w,
+                    None => {
+                        let state = self.state.load(Ordering::Relaxed) - release + acquire;
+                        let w = self.choose_wake(state, prefer_readers);
+                        wake.set(Some(w));
+                        w
+                    }
+                };
+                let unpark = match w {
+                    Wake::None => return FilterOp::Stop,
+                    Wake::Writer if granted.get() != 0 => return FilterOp::Stop,
+                    Wake::Writer => token == TOKEN_EXCLUSIVE,
+                    Wake::Readers { upgradable } => {
+                        token == TOKEN_SHARED
+                            || (token == TOKEN_UPGRADABLE
+                                && upgradable
+                                && granted.get() & UPGRADABLE_BIT == 0)
+                    }
+                };
+                if unpark {
+                    granted.set(granted.get() + token.0);
+                    self.parked_count(token).fetch_sub(1, Ordering::Relaxed);
+                    FilterOp::Unpark
+                } else {
+                    FilterOp::Skip
+                }
+            };
+            let callback = |_result: UnparkResult| -> UnparkToken {
+                let granted = granted.get();
+                let parked_bits = self.parked_bits();
+                let mut state = self.state.load(Ordering::Relaxed);
+                loop {
+                    let mut new_state = state - release + acquire;
+
+                    // Readers may have entered or left the lock since the
+                    // threads to wake up were chosen. If the lock can no
+                    // longer be handed over, the woken threads try to acquire
+                    // it themselves instead.
+                    let handoff = granted != 0
+                        && match wake.get() {
+                            Some(Wake::Writer) => new_state & (WRITER_BIT | READERS_MASK) == 0,
+                            Some(Wake::Readers { .. }) => {
+                                new_state & (WRITER_BIT | UPGRADING_BIT) == 0
+                                    && granted & new_state & UPGRADABLE_BIT == 0
+                            }
+                            _ => false,
+                        };
+                    if handoff {
+                        new_state += granted;
+                    }
+                    new_state = (new_state & !(PARKED_BIT | WRITERS_PARKED_BIT)) | parked_bits;
+
+                    match self.state.compare_exchange_weak(
+                        state,
+                        new_state,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => {
+                            // If the remaining parked threads could acquire the
+                            // lock now, we need to try waking them up again.
+                            wake_again
+                                .set(self.choose_wake(new_state, prefer_readers) != Wake::None);
+                            return if handoff { TOKEN_HANDOFF } else { TOKEN_NORMAL };
+                        }
+                        Err(x) => state = x,
+                    }
+                }
+            };
+            // SAFETY:
+            // * `addr` is an address we control.
+            // * `filter` and `callback` do not panic or call into any function of `parking_lot`.
+            unsafe {
+                parking_lot_core::unpark_filter(addr, filter, callback);
+            }
+
+            if !wake_again.get() {
+                return;
+            }
+            release = 0;
+            acquire = 0;
+        }
+    }
+
+    // Hands the lock over to the holder of the upgradable lock waiting to
+    // upgrade it, as the last other reader exits.
+    #[cold]
+    fn unlock_shared_upgrading_slow(&self) {
+        let addr = self as *const _ as usize + 1;
+        let callback = |result: UnparkResult| {
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                // Recursive readers may have entered the lock in the meantime,
+                // in which case the last of them hands the lock over instead.
+                let handoff =
+                    result.unparked_threads != 0 && state & READERS_MASK == 2 * ONE_READER;
+                let new_state = if handoff {
+                    state - ((2 * ONE_READER) | UPGRADABLE_BIT | UPGRADING_BIT) + WRITER_BIT
+                } else if state & READERS_MASK == 2 * ONE_READER {
+                    // The upgrading thread hasn't parked yet, so make it retry.
+                    (state - ONE_READER) & !UPGRADING_BIT
+                } else {
+                    state - ONE_READER
+                };
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return if handoff { TOKEN_HANDOFF } else { TOKEN_NORMAL },
+                    Err(x) => state = x,
+                }
+            }
+        };
+        // SAFETY:
+        // * `addr` is an address we control.
+        // * `callback` does not panic or call into any function of `parking_lot`.
+        unsafe {
+            parking_lot_core::unpark_one(addr, callback);
+        }
+    }
+
+    #[cold]
+    fn upgrade_slow(&self, timeout: Option<Instant>) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Upgrade once we are the only remaining reader
+            if state & READERS_MASK == ONE_READER {
+                match self.state.compare_exchange_weak(
+                    state,
+                    (state & !UPGRADING_BIT) - (ONE_READER | UPGRADABLE_BIT) + WRITER_BIT,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // Prevent new readers from entering and wait for the others to exit
+            if state & UPGRADING_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | UPGRADING_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            let addr = self as *const _ as usize + 1;
+            let validate = || {
+                let state = self.state.load(Ordering::Relaxed);
+                state & UPGRADING_BIT != 0 && state & READERS_MASK != ONE_READER
+            };
+            let before_sleep = || {};
+            let timed_out = |_, _| {
+                self.state.fetch_and(!UPGRADING_BIT, Ordering::Relaxed);
+            };
+            // SAFETY:
+            // * `addr` is an address we control.
+            // * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
+            // * `before_sleep` does not call `park`, nor does it panic.
+            match unsafe {
+                parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    TOKEN_EXCLUSIVE,
+                    timeout,
+                )
+            } {
+                // The last reader passed the lock on to us directly.
+                ParkResult::Unparked(TOKEN_HANDOFF) => return true,
+
+                // Readers which were waiting for us may be able to acquire the
+                // lock now.
+                ParkResult::TimedOut => {
+                    if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+                        self.unlock_slow(0, 0, false);
+                    }
+                    return false;
+                }
+
+                ParkResult::Unparked(_) | ParkResult::Invalid => (),
+            }
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    fn deadlock_acquire(&self) {
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        unsafe { deadlock::acquire_linked_resource(self as *const _ as usize + 1) };
+    }
+
+    #[inline]
+    fn deadlock_release(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        unsafe { deadlock::release_resource(self as *const _ as usize + 1) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lock_api::{RwLockUpgradableReadGuard, RwLockWriteGuard};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    type RwLock<P, T> = lock_api::RwLock<RawPolicyRwLock<P>, T>;
+
+    // Waits until the given number of threads are parked in the main queue.
+    fn wait_for_parked<P: RwLockPolicy, T>(lock: &RwLock<P, T>, count: usize) {
+        let addr = unsafe { lock.raw() } as *const _ as usize;
+        while parking_lot_core::parked_threads(addr).len() != count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn smoke<P: RwLockPolicy + 'static>() {
+        let l = RwLock::<P, _>::new(0);
+        {
+            let _a = l.read();
+            let _b = l.read();
+            assert!(l.try_write().is_none());
+            let c = l.upgradable_read();
+            assert!(l.try_upgradable_read().is_none());
+            let c = RwLockUpgradableReadGuard::try_upgrade(c).unwrap_err();
+            drop(_a);
+            drop(_b);
+            let mut c = RwLockUpgradableReadGuard::upgrade(c);
+            *c += 1;
+            assert!(l.try_read().is_none());
+            let c = RwLockWriteGuard::downgrade_to_upgradable(c);
+            assert!(l.try_read().is_some());
+            let c = RwLockUpgradableReadGuard::downgrade(c);
+            assert!(l.try_upgradable_read().is_some());
+            drop(c);
+        }
+        let w = l.write();
+        assert!(l.try_read_for(Duration::from_millis(10)).is_none());
+        assert!(l
+            .try_upgradable_read_for(Duration::from_millis(10))
+            .is_none());
+        let r = RwLockWriteGuard::downgrade(w);
+        assert!(l.try_write_for(Duration::from_millis(10)).is_none());
+        let _r2 = l.read_recursive();
+        drop(r);
+        assert_eq!(*l.read(), 1);
+    }
+
+    fn frob<P: RwLockPolicy + Send + Sync + 'static>() {
+        const N: u32 = 8;
+        const M: u32 = 1000;
+
+        let l = Arc::new(RwLock::<P, _>::new(0u32));
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let l = l.clone();
+                thread::spawn(move || {
+                    for j in 0..M {
+                        match (i + j) % 5 {
+                            0 => *l.write() += 1,
+                            1 => {
+                                let u = l.upgradable_read();
+                                *RwLockUpgradableReadGuard::upgrade(u) += 1;
+                            }
+                            2 => {
+                                let u = l.upgradable_read();
+                                drop(RwLockUpgradableReadGuard::downgrade(u));
+                            }
+                            3 => drop(l.try_write_for(Duration::from_micros(10))),
+                            _ => drop(l.read()),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*l.read(), N * M * 2 / 5);
+    }
+
+    // Parks a reader, a writer and another reader behind a held write lock,
+    // then returns the order in which they acquired the lock.
+    fn wake_order<P: RwLockPolicy + Send + Sync + 'static>() -> Vec<&'static str> {
+        let l = Arc::new(RwLock::<P, _>::new(()));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let guard = l.write();
+        let threads: Vec<_> = [("r1", false), ("w", true), ("r2", false)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(name, write))| {
+                let l2 = l.clone();
+                let order = order.clone();
+                let t = thread::spawn(move || {
+                    if write {
+                        let _guard = l2.write();
+                        order.lock().unwrap().push(name);
+                        thread::sleep(Duration::from_millis(20));
+                    } else {
+                        let _guard = l2.read();
+                        order.lock().unwrap().push(name);
+                        thread::sleep(Duration::from_millis(20));
+                    }
+                });
+                wait_for_parked(&l, i + 1);
+                t
+            })
+            .collect();
+        drop(guard);
+        for t in threads {
+            t.join().unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    fn reader_enters_past_writer<P: RwLockPolicy + Send + Sync + 'static>() -> bool {
+        let l = Arc::new(RwLock::<P, _>::new(()));
+        let guard = l.read();
+        let l2 = l.clone();
+        let t = thread::spawn(move || drop(l2.write()));
+        wait_for_parked(&l, 1);
+        let result = l.try_read().is_some();
+        drop(guard);
+        t.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_smoke() {
+        smoke::<ReaderPreferringPolicy>();
+        smoke::<WriterPreferringPolicy>();
+        smoke::<PhaseFairPolicy>();
+    }
+
+    #[test]
+    fn test_frob() {
+        frob::<ReaderPreferringPolicy>();
+        frob::<WriterPreferringPolicy>();
+        frob::<PhaseFairPolicy>();
+    }
+
+    #[test]
+    fn test_reader_preferring() {
+        assert!(reader_enters_past_writer::<ReaderPreferringPolicy>());
+        let order = wake_order::<ReaderPreferringPolicy>();
+        assert_eq!(order[2], "w");
+    }
+
+    #[test]
+    fn test_writer_preferring() {
+        assert!(!reader_enters_past_writer::<WriterPreferringPolicy>());
+        let order = wake_order::<WriterPreferringPolicy>();
+        assert_eq!(order[0], "w");
+    }
+
+    #[test]
+    fn test_phase_fair() {
+        assert!(!reader_enters_past_writer::<PhaseFairPolicy>());
+        let order = wake_order::<PhaseFairPolicy>();
+        assert_eq!(order[2], "w");
+
+        // Once the readers are done, the writer goes before new readers.
+        let l = Arc::new(RwLock::<PhaseFairPolicy, _>::new(()));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let guard = l.read();
+        let (l2, order2) = (l.clone(), order.clone());
+        let writer = thread::spawn(move || {
+            let _guard = l2.write();
+            order2.lock().unwrap().push("w");
+            thread::sleep(Duration::from_millis(20));
+        });
+        wait_for_parked(&l, 1);
+        let (l2, order2) = (l.clone(), order.clone());
+        let reader = thread::spawn(move || {
+            let _guard = l2.read();
+            order2.lock().unwrap().push("r");
+        });
+        wait_for_parked(&l, 2);
+        drop(guard);
+        writer.join().unwrap();
+        reader.join().unwrap();
+        assert_eq!(*order.lock().unwrap(), ["w", "r"]);
+    }
+
+    #[test]
+    fn test_phase_fair_writer_order() {
+        const N: usize = 4;
+
+        let l = Arc::new(RwLock::<PhaseFairPolicy, _>::new(()));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let guard = l.write();
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let (l2, order2) = (l.clone(), order.clone());
+                let t = thread::spawn(move || {
+                    let _guard = l2.write();
+                    order2.lock().unwrap().push(i);
+                });
+                wait_for_parked(&l, i + 1);
+                t
+            })
+            .collect();
+        drop(guard);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), (0..N).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_writer_timeout_releases_readers() {
+        let l = Arc::new(RwLock::<WriterPreferringPolicy, _>::new(()));
+        let guard = l.read();
+        let l2 = l.clone();
+        let writer = thread::spawn(move || {
+            assert!(l2.try_write_for(Duration::from_millis(50)).is_none());
+        });
+        wait_for_parked(&l, 1);
+        let l2 = l.clone();
+        let reader = thread::spawn(move || drop(l2.read()));
+
+        // The reader gets in once the writer gives up, while we still hold
+        // our read lock.
+        writer.join().unwrap();
+        reader.join().unwrap();
+        drop(guard);
+    }
+
+    #[test]
+    fn test_upgrade_waits_for_readers() {
+        let l = Arc::new(RwLock::<PhaseFairPolicy, _>::new(0));
+        let u = l.upgradable_read();
+        let l2 = l.clone();
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || {
+            let _guard = l2.read();
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+        rx.recv().unwrap();
+        let u =
+            RwLockUpgradableReadGuard::try_upgrade_for(u, Duration::from_millis(10)).unwrap_err();
+        *RwLockUpgradableReadGuard::upgrade(u) += 1;
+        t.join().unwrap();
+        assert_eq!(*l.read(), 1);
+    }
+}
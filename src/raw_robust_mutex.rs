@@ -0,0 +1,312 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{
+    deadlock,
+    futex::{current_tid, errno, futex_wait, futex_wake, futex_wake_all, Shared},
+    util,
+};
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use lock_api::{GuardNoSend, RobustLockError, RobustLockResult};
+use parking_lot_core::SpinWait;
+use std::{fs, io, time::Instant};
+
+/// These bits of the `state` hold the thread ID of the owner of the mutex, or 0
+/// if it isn't locked.
+const TID_MASK: u32 = 0x3fff_ffff;
+/// This bit is set in the `state` while the owner of the mutex acquired it from
+/// an owner which died, until it marks the mutex as consistent.
+const OWNER_DIED_BIT: u32 = 0x4000_0000;
+/// This bit is set in the `state` before a thread goes to sleep waiting for the
+/// mutex. Since waiting threads are not tracked, it stays set until the mutex
+/// is unlocked.
+const WAITERS_BIT: u32 = 0x8000_0000;
+/// The `state` of a mutex which was unlocked without being marked consistent.
+/// Thread IDs are limited to 2^22 so this never matches a real owner.
+const NOT_RECOVERABLE: u32 = TID_MASK;
+
+/// How long a waiting thread sleeps before checking whether the owner of the
+/// mutex is still alive. This bounds how long it takes to notice the death of
+/// an owner.
+const OWNER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Checks whether the thread with the given ID still exists.
+fn is_thread_alive(tid: u32) -> bool {
+    if unsafe { libc::kill(tid as libc::pid_t, 0) } != 0 {
+        // EPERM means that the thread exists but belongs to another user.
+        return errno() != libc::ESRCH;
+    }
+
+    // A process which exited remains a zombie until it is reaped by its
+    // parent, so look at its state as well. The command name in parentheses
+    // may itself contain parentheses, so search from the end.
+    match fs::read(format!("/proc/{}/stat", tid)) {
+        Ok(stat) => match stat.iter().rposition(|&b| b == b')') {
+            Some(i) => {
+                let state = stat.get(i + 2).cloned();
+                state != Some(b'Z') && state != Some(b'X')
+            }
+            None => true,
+        },
+        Err(e) => e.kind() != io::ErrorKind::NotFound,
+    }
+}
+
+/// Raw mutex type which detects that its owner died while holding it.
+///
+/// The mutex is a single zero-initialized 32-bit word which holds the kernel
+/// thread ID of its owner, and which can be placed in memory shared between
+/// processes like `RawSharedMutex`. A thread which has been waiting for the
+/// mutex for a while checks whether the owner is still alive, and if it isn't,
+/// takes over the lock and reports that the owner died through
+/// `lock_api::RawMutexRobust`.
+///
+/// # Limitations
+///
+/// The kernel robust futex list (`set_robust_list` and `FUTEX_OWNER_DIED`)
+/// isn't used since each thread has only one, which is owned by the C library.
+/// Owner death is therefore detected by polling, which has these limitations:
+///
+/// - A thread waiting for the mutex only checks whether the owner is alive
+///   every 100ms, so it may take that long to notice its death.
+/// - The owner is identified by its thread ID alone. If the owner dies and its
+///   thread ID is reused by a new thread before a waiting thread checks it, the
+///   death goes unnoticed and the mutex stays locked forever. The kernel reuses
+///   thread IDs only after cycling through all of them, so this is unlikely
+///   unless threads are created at a very high rate or `pid_max` is small.
+/// - Every process using the mutex must be in the same PID namespace.
+///
+/// # Plain locking
+///
+/// The plain `lock_api::RawMutex` methods acquire a mutex whose owner died but
+/// leave it inconsistent, just like `lock_robust` does, so the mutex becomes
+/// unusable if it is unlocked without being marked consistent. Only `try_lock`
+/// never checks whether the owner is alive. If the mutex is not recoverable
+/// then `lock` panics, since it has no way to report it, while `try_lock` and
+/// the timed methods fail.
+///
+/// This type is only available on Linux and Android.
+#[repr(C)]
+pub struct RawRobustMutex {
+    state: AtomicU32,
+}
+
+unsafe impl lock_api::RawMutex for RawRobustMutex {
+    const INIT: RawRobustMutex = RawRobustMutex {
+        state: AtomicU32::new(0),
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    #[inline]
+    fn lock(&self) {
+        if !self.acquired(self.lock_deadline(None)) {
+            panic!("mutex is not recoverable");
+        }
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        let result = self
+            .state
+            .compare_exchange(0, current_tid(), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+
+        // Only the owner changes this bit, so it can't change concurrently.
+        if self.state.load(Ordering::Relaxed) & OWNER_DIED_BIT != 0 {
+            // The data was never repaired, so nobody may lock the mutex again.
+            self.state.store(NOT_RECOVERABLE, Ordering::Release);
+            futex_wake_all::<Shared>(&self.state);
+        } else if self.state.swap(0, Ordering::Release) & WAITERS_BIT != 0 {
+            futex_wake::<Shared>(&self.state, 1);
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutexTimed for RawRobustMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_until(&self, timeout: Instant) -> bool {
+        self.acquired(self.lock_deadline(Some(timeout)))
+    }
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.acquired(self.lock_deadline(util::to_deadline(timeout)))
+    }
+}
+
+unsafe impl lock_api::RawMutexRobust for RawRobustMutex {
+    #[inline]
+    fn lock_robust(&self) -> RobustLockResult<()> {
+        self.lock_deadline(None).unwrap()
+    }
+
+    fn try_lock_robust(&self) -> Option<RobustLockResult<()>> {
+        let tid = current_tid();
+        let mut state = self.state.load(Ordering::Relaxed);
+        let result = loop {
+            let (new_state, result) = if state == NOT_RECOVERABLE {
+                return Some(Err(RobustLockError::NotRecoverable));
+            } else if state & TID_MASK == 0 {
+                (tid | (state & WAITERS_BIT), Ok(()))
+            } else if is_thread_alive(state & TID_MASK) {
+                return None;
+            } else {
+                (
+                    tid | OWNER_DIED_BIT | (state & WAITERS_BIT),
+                    Err(RobustLockError::OwnerDied(())),
+                )
+            };
+            match self.state.compare_exchange(
+                state,
+                new_state,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break result,
+                Err(x) => state = x,
+            }
+        };
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        Some(result)
+    }
+
+    #[inline]
+    unsafe fn mark_consistent(&self) {
+        self.state.fetch_and(!OWNER_DIED_BIT, Ordering::Relaxed);
+    }
+
+    #[inline]
+    unsafe fn is_consistent(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & OWNER_DIED_BIT == 0
+    }
+}
+
+impl RawRobustMutex {
+    // Acquires the mutex, returning `None` if the timeout was reached.
+    #[inline]
+    fn lock_deadline(&self, timeout: Option<Instant>) -> Option<RobustLockResult<()>> {
+        let tid = current_tid();
+        let result = if self
+            .state
+            .compare_exchange_weak(0, tid, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(Ok(()))
+        } else {
+            self.lock_slow(tid, timeout)
+        };
+        if let Some(Ok(())) | Some(Err(RobustLockError::OwnerDied(()))) = result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    // Converts the result of a robust lock operation into the result of a
+    // plain one. If the previous owner died the mutex is left inconsistent,
+    // which its new owner can check with `is_consistent`.
+    #[inline]
+    fn acquired(&self, result: Option<RobustLockResult<()>>) -> bool {
+        match result {
+            Some(Ok(())) | Some(Err(RobustLockError::OwnerDied(()))) => true,
+            Some(Err(RobustLockError::NotRecoverable)) | None => false,
+        }
+    }
+
+    #[cold]
+    fn lock_slow(&self, tid: u32, timeout: Option<Instant>) -> Option<RobustLockResult<()>> {
+        // Spin a few times while the lock is held but nobody is waiting for it,
+        // since the owner is likely to release it soon.
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & (TID_MASK | WAITERS_BIT) != 0
+            && state & WAITERS_BIT == 0
+            && state != NOT_RECOVERABLE
+            && spinwait.spin()
+        {
+            state = self.state.load(Ordering::Relaxed);
+        }
+
+        // Once we have slept, other threads may be sleeping as well, so the
+        // waiters bit must be set when acquiring the mutex.
+        let mut waiters = 0;
+        let mut check_owner = false;
+        loop {
+            if state == NOT_RECOVERABLE {
+                return Some(Err(RobustLockError::NotRecoverable));
+            }
+
+            let owner = state & TID_MASK;
+            let acquire = if owner == 0 {
+                Some((tid | waiters | (state & WAITERS_BIT), Ok(())))
+            } else if check_owner && !is_thread_alive(owner) {
+                Some((
+                    tid | OWNER_DIED_BIT | waiters | (state & WAITERS_BIT),
+                    Err(RobustLockError::OwnerDied(())),
+                ))
+            } else {
+                None
+            };
+            if let Some((new_state, result)) = acquire {
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some(result),
+                    Err(x) => {
+                        state = x;
+                        continue;
+                    }
+                }
+            }
+
+            // Mark the mutex as having waiters before sleeping.
+            if state & WAITERS_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | WAITERS_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Wake up periodically to check whether the owner died, unless the
+            // timeout comes first.
+            let poll = Instant::now() + OWNER_CHECK_INTERVAL;
+            let (deadline, polling) = match timeout {
+                Some(timeout) if timeout <= poll => (timeout, false),
+                _ => (poll, true),
+            };
+            waiters = WAITERS_BIT;
+            check_owner = !futex_wait::<Shared>(&self.state, state | WAITERS_BIT, Some(deadline));
+            if check_owner && !polling {
+                return None;
+            }
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+}
@@ -6,13 +6,22 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::elision::{have_elision, AtomicElisionExt};
-use crate::raw_mutex::{TOKEN_HANDOFF, TOKEN_NORMAL};
-use crate::util;
+use crate::raw_mutex::{TaskParkFuture, TOKEN_HANDOFF, TOKEN_NORMAL};
+use crate::{
+    lock_level::LockLevel,
+    owner::OwnerCell,
+    stats::LockCounters,
+    trace, util,
+    watchdog::{HoldTimer, LockKind},
+};
 use core::{
     cell::Cell,
+    future::Future,
+    pin::Pin,
     sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
 };
-use lock_api::{GuardNoSend, RawRwLock as RawRwLock_, RawRwLockUpgrade};
+use lock_api::{GuardNoSend, RawRwLock as RawRwLock_};
 use parking_lot_core::{
     self, deadlock, FilterOp, ParkResult, ParkToken, SpinWait, UnparkResult, UnparkToken,
 };
@@ -54,35 +63,56 @@ const TOKEN_UPGRADABLE: ParkToken = ParkToken(ONE_READER | UPGRADABLE_BIT);
 /// Raw reader-writer lock type backed by the parking lot.
 pub struct RawRwLock {
     state: AtomicUsize,
+
+    // Contention statistics, which take up no space unless the `stats`
+    // feature is enabled.
+    stats: LockCounters,
+
+    // Level in the lock hierarchy, which takes up no space unless the
+    // `lock_levels` feature is enabled.
+    level: LockLevel,
+
+    // Thread holding the lock exclusively, which takes up no space unless the
+    // `owner_tracking` feature is enabled.
+    owner: OwnerCell,
+
+    // Time at which the lock was locked exclusively, which takes up no space
+    // unless the `watchdog` feature is enabled.
+    hold: HoldTimer,
 }
 
 unsafe impl lock_api::RawRwLock for RawRwLock {
     const INIT: RawRwLock = RawRwLock {
         state: AtomicUsize::new(0),
+        stats: LockCounters::INIT,
+        level: LockLevel::INIT,
+        owner: OwnerCell::INIT,
+        hold: HoldTimer::INIT,
     };
 
     type GuardMarker = GuardNoSend;
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn lock_exclusive(&self) {
-        if self
-            .state
-            .compare_exchange_weak(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            let result = self.lock_exclusive_slow(None);
-            debug_assert!(result);
-        }
-        self.deadlock_acquire();
+        self.check_level();
+        self.lock_exclusive_unchecked();
+        self.owner.acquire();
+        self.hold.start();
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_exclusive(&self) -> bool {
         if self
             .state
             .compare_exchange(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            self.stats.fast_path();
+            self.stats.lock_exclusive();
+            self.owner.acquire();
+            self.hold.start();
             self.deadlock_acquire();
             true
         } else {
@@ -93,23 +123,23 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
     #[inline]
     fn unlock_exclusive(&self) {
         self.deadlock_release();
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         if self
             .state
             .compare_exchange(WRITER_BIT, 0, Ordering::Release, Ordering::Relaxed)
-            .is_ok()
+            .is_err()
         {
-            return;
+            self.unlock_exclusive_slow(false);
         }
-        self.unlock_exclusive_slow(false);
+        hold.report(self as *const _ as usize, LockKind::RwLockWrite);
     }
 
     #[inline]
     fn lock_shared(&self) {
-        if !self.try_lock_shared_fast(false) {
-            let result = self.lock_shared_slow(false, None);
-            debug_assert!(result);
-        }
-        self.deadlock_acquire();
+        self.check_level();
+        self.lock_shared_unchecked();
     }
 
     #[inline]
@@ -120,6 +150,7 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
             self.try_lock_shared_slow(false)
         };
         if result {
+            self.stats.fast_path();
             self.deadlock_acquire();
         }
         result
@@ -137,6 +168,12 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
             self.unlock_shared_slow();
         }
     }
+
+    #[cfg(feature = "owner_tracking")]
+    #[inline]
+    fn debug_owner(&self, s: &mut core::fmt::DebugStruct<'_, '_>) {
+        self.owner.debug(s);
+    }
 }
 
 unsafe impl lock_api::RawRwLockFair for RawRwLock {
@@ -149,14 +186,17 @@ unsafe impl lock_api::RawRwLockFair for RawRwLock {
     #[inline]
     fn unlock_exclusive_fair(&self) {
         self.deadlock_release();
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         if self
             .state
             .compare_exchange(WRITER_BIT, 0, Ordering::Release, Ordering::Relaxed)
-            .is_ok()
+            .is_err()
         {
-            return;
+            self.unlock_exclusive_slow(true);
         }
-        self.unlock_exclusive_slow(true);
+        hold.report(self as *const _ as usize, LockKind::RwLockWrite);
     }
 
     #[inline]
@@ -179,6 +219,9 @@ unsafe impl lock_api::RawRwLockFair for RawRwLock {
 unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
     #[inline]
     fn downgrade(&self) {
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         let state = self
             .state
             .fetch_add(ONE_READER - WRITER_BIT, Ordering::Release);
@@ -187,6 +230,7 @@ unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
         if state & PARKED_BIT != 0 {
             self.downgrade_slow();
         }
+        hold.report(self as *const _ as usize, LockKind::RwLockWrite);
     }
 }
 
@@ -197,9 +241,11 @@ unsafe impl lock_api::RawRwLockTimed for RawRwLock {
     #[inline]
     fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
         let result = if self.try_lock_shared_fast(false) {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_shared_slow(false, util::to_deadline(timeout))
+            self.stats
+                .slow_path(|| self.lock_shared_slow(false, util::to_deadline(timeout)))
         };
         if result {
             self.deadlock_acquire();
@@ -210,9 +256,11 @@ unsafe impl lock_api::RawRwLockTimed for RawRwLock {
     #[inline]
     fn try_lock_shared_until(&self, timeout: Self::Instant) -> bool {
         let result = if self.try_lock_shared_fast(false) {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_shared_slow(false, Some(timeout))
+            self.stats
+                .slow_path(|| self.lock_shared_slow(false, Some(timeout)))
         };
         if result {
             self.deadlock_acquire();
@@ -221,34 +269,46 @@ unsafe impl lock_api::RawRwLockTimed for RawRwLock {
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
         let result = if self
             .state
             .compare_exchange_weak(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_exclusive_slow(util::to_deadline(timeout))
+            self.stats
+                .slow_path(|| self.lock_exclusive_slow(util::to_deadline(timeout)))
         };
         if result {
+            self.stats.lock_exclusive();
+            self.owner.acquire();
+            self.hold.start();
             self.deadlock_acquire();
         }
         result
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
         let result = if self
             .state
             .compare_exchange_weak(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_exclusive_slow(Some(timeout))
+            self.stats
+                .slow_path(|| self.lock_exclusive_slow(Some(timeout)))
         };
         if result {
+            self.stats.lock_exclusive();
+            self.owner.acquire();
+            self.hold.start();
             self.deadlock_acquire();
         }
         result
@@ -258,9 +318,12 @@ unsafe impl lock_api::RawRwLockTimed for RawRwLock {
 unsafe impl lock_api::RawRwLockRecursive for RawRwLock {
     #[inline]
     fn lock_shared_recursive(&self) {
+        self.check_level();
         if !self.try_lock_shared_fast(true) {
-            let result = self.lock_shared_slow(true, None);
+            let result = self.stats.slow_path(|| self.lock_shared_slow(true, None));
             debug_assert!(result);
+        } else {
+            self.stats.fast_path();
         }
         self.deadlock_acquire();
     }
@@ -273,6 +336,7 @@ unsafe impl lock_api::RawRwLockRecursive for RawRwLock {
             self.try_lock_shared_slow(true)
         };
         if result {
+            self.stats.fast_path();
             self.deadlock_acquire();
         }
         result
@@ -283,9 +347,11 @@ unsafe impl lock_api::RawRwLockRecursiveTimed for RawRwLock {
     #[inline]
     fn try_lock_shared_recursive_for(&self, timeout: Self::Duration) -> bool {
         let result = if self.try_lock_shared_fast(true) {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_shared_slow(true, util::to_deadline(timeout))
+            self.stats
+                .slow_path(|| self.lock_shared_slow(true, util::to_deadline(timeout)))
         };
         if result {
             self.deadlock_acquire();
@@ -296,9 +362,11 @@ unsafe impl lock_api::RawRwLockRecursiveTimed for RawRwLock {
     #[inline]
     fn try_lock_shared_recursive_until(&self, timeout: Self::Instant) -> bool {
         let result = if self.try_lock_shared_fast(true) {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_shared_slow(true, Some(timeout))
+            self.stats
+                .slow_path(|| self.lock_shared_slow(true, Some(timeout)))
         };
         if result {
             self.deadlock_acquire();
@@ -310,11 +378,8 @@ unsafe impl lock_api::RawRwLockRecursiveTimed for RawRwLock {
 unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
     #[inline]
     fn lock_upgradable(&self) {
-        if !self.try_lock_upgradable_fast() {
-            let result = self.lock_upgradable_slow(None);
-            debug_assert!(result);
-        }
-        self.deadlock_acquire();
+        self.check_level();
+        self.lock_upgradable_unchecked();
     }
 
     #[inline]
@@ -325,6 +390,7 @@ unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
             self.try_lock_upgradable_slow()
         };
         if result {
+            self.stats.fast_path();
             self.deadlock_acquire();
         }
         result
@@ -352,20 +418,27 @@ unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn upgrade(&self) {
         let state = self.state.fetch_sub(
             (ONE_READER | UPGRADABLE_BIT) - WRITER_BIT,
             Ordering::Relaxed,
         );
         if state & READERS_MASK != ONE_READER {
-            let result = self.upgrade_slow(None);
+            let result = self.stats.slow_path(|| self.upgrade_slow(None));
             debug_assert!(result);
+        } else {
+            self.stats.fast_path();
         }
+        self.stats.lock_exclusive();
+        self.owner.acquire();
+        self.hold.start();
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_upgrade(&self) -> bool {
-        if self
+        let result = if self
             .state
             .compare_exchange_weak(
                 ONE_READER | UPGRADABLE_BIT,
@@ -378,7 +451,14 @@ unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
             true
         } else {
             self.try_upgrade_slow()
+        };
+        if result {
+            self.stats.fast_path();
+            self.stats.lock_exclusive();
+            self.owner.acquire();
+            self.hold.start();
         }
+        result
     }
 }
 
@@ -425,6 +505,9 @@ unsafe impl lock_api::RawRwLockUpgradeDowngrade for RawRwLock {
 
     #[inline]
     fn downgrade_to_upgradable(&self) {
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         let state = self.state.fetch_add(
             (ONE_READER | UPGRADABLE_BIT) - WRITER_BIT,
             Ordering::Release,
@@ -434,6 +517,7 @@ unsafe impl lock_api::RawRwLockUpgradeDowngrade for RawRwLock {
         if state & PARKED_BIT != 0 {
             self.downgrade_to_upgradable_slow();
         }
+        hold.report(self as *const _ as usize, LockKind::RwLockWrite);
     }
 }
 
@@ -441,9 +525,11 @@ unsafe impl lock_api::RawRwLockUpgradeTimed for RawRwLock {
     #[inline]
     fn try_lock_upgradable_until(&self, timeout: Instant) -> bool {
         let result = if self.try_lock_upgradable_fast() {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_upgradable_slow(Some(timeout))
+            self.stats
+                .slow_path(|| self.lock_upgradable_slow(Some(timeout)))
         };
         if result {
             self.deadlock_acquire();
@@ -454,9 +540,11 @@ unsafe impl lock_api::RawRwLockUpgradeTimed for RawRwLock {
     #[inline]
     fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
         let result = if self.try_lock_upgradable_fast() {
+            self.stats.fast_path();
             true
         } else {
-            self.lock_upgradable_slow(util::to_deadline(timeout))
+            self.stats
+                .slow_path(|| self.lock_upgradable_slow(util::to_deadline(timeout)))
         };
         if result {
             self.deadlock_acquire();
@@ -465,32 +553,95 @@ unsafe impl lock_api::RawRwLockUpgradeTimed for RawRwLock {
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_upgrade_until(&self, timeout: Instant) -> bool {
         let state = self.state.fetch_sub(
             (ONE_READER | UPGRADABLE_BIT) - WRITER_BIT,
             Ordering::Relaxed,
         );
-        if state & READERS_MASK == ONE_READER {
+        let result = if state & READERS_MASK == ONE_READER {
+            self.stats.fast_path();
             true
         } else {
-            self.upgrade_slow(Some(timeout))
+            self.stats.slow_path(|| self.upgrade_slow(Some(timeout)))
+        };
+        if result {
+            self.stats.lock_exclusive();
+            self.owner.acquire();
+            self.hold.start();
         }
+        result
     }
 
     #[inline]
+    #[cfg_attr(feature = "owner_tracking", track_caller)]
     fn try_upgrade_for(&self, timeout: Duration) -> bool {
         let state = self.state.fetch_sub(
             (ONE_READER | UPGRADABLE_BIT) - WRITER_BIT,
             Ordering::Relaxed,
         );
-        if state & READERS_MASK == ONE_READER {
+        let result = if state & READERS_MASK == ONE_READER {
+            self.stats.fast_path();
             true
         } else {
-            self.upgrade_slow(util::to_deadline(timeout))
+            self.stats
+                .slow_path(|| self.upgrade_slow(util::to_deadline(timeout)))
+        };
+        if result {
+            self.stats.lock_exclusive();
+            self.owner.acquire();
+            self.hold.start();
+        }
+        result
+    }
+}
+
+impl lock_api::RawLockLevel for RawRwLock {
+    #[inline]
+    fn with_level(level: u32) -> RawRwLock {
+        RawRwLock {
+            state: AtomicUsize::new(0),
+            stats: LockCounters::INIT,
+            level: LockLevel::new(level),
+            owner: OwnerCell::INIT,
+            hold: HoldTimer::INIT,
         }
     }
 }
 
+#[cfg(feature = "owner_tracking")]
+impl lock_api::RawLockOwner for RawRwLock {
+    type Owner = crate::LockOwner;
+
+    #[inline]
+    fn owner(&self) -> Option<crate::LockOwner> {
+        self.owner.get()
+    }
+}
+
+#[cfg(feature = "stats")]
+impl lock_api::RawLockStats for RawRwLock {
+    type Stats = crate::LockStats;
+
+    #[inline]
+    fn stats(&self) -> crate::LockStats {
+        self.stats.get()
+    }
+
+    #[inline]
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+}
+
+#[cfg(feature = "lock_order_detection")]
+impl Drop for RawRwLock {
+    #[inline]
+    fn drop(&mut self) {
+        deadlock::forget_resource(self as *const _ as usize);
+    }
+}
+
 impl RawRwLock {
     #[inline(always)]
     fn try_lock_shared_fast(&self, recursive: bool) -> bool {
@@ -598,6 +749,7 @@ impl RawRwLock {
 
     #[cold]
     fn lock_exclusive_slow(&self, timeout: Option<Instant>) -> bool {
+        let span = trace::LockSpan::enter("rwlock_exclusive", self as *const _ as usize, timeout);
         let try_lock = |state: &mut usize| {
             loop {
                 if *state & (WRITER_BIT | UPGRADABLE_BIT) != 0 {
@@ -625,11 +777,11 @@ impl RawRwLock {
             WRITER_BIT | UPGRADABLE_BIT,
         );
         if timed_out {
-            return false;
+            return span.finish(false);
         }
 
         // Step 2: wait for all remaining readers to exit the lock.
-        self.wait_for_readers(timeout, 0)
+        span.finish(self.wait_for_readers(timeout, 0))
     }
 
     #[cold]
@@ -643,6 +795,7 @@ impl RawRwLock {
                     new_state |= PARKED_BIT;
                 }
                 self.state.store(new_state, Ordering::Release);
+                self.stats.handoff(!force_fair);
                 TOKEN_HANDOFF
             } else {
                 // Clear the parked bit if there are no more parked threads.
@@ -662,6 +815,7 @@ impl RawRwLock {
 
     #[cold]
     fn lock_shared_slow(&self, recursive: bool, timeout: Option<Instant>) -> bool {
+        let span = trace::LockSpan::enter("rwlock_shared", self as *const _ as usize, timeout);
         let try_lock = |state: &mut usize| {
             let mut spinwait_shared = SpinWait::new();
             loop {
@@ -704,7 +858,7 @@ impl RawRwLock {
                 *state = self.state.load(Ordering::Relaxed);
             }
         };
-        self.lock_common(timeout, TOKEN_SHARED, try_lock, WRITER_BIT)
+        span.finish(self.lock_common(timeout, TOKEN_SHARED, try_lock, WRITER_BIT))
     }
 
     #[cold]
@@ -729,6 +883,7 @@ impl RawRwLock {
 
     #[cold]
     fn lock_upgradable_slow(&self, timeout: Option<Instant>) -> bool {
+        let span = trace::LockSpan::enter("rwlock_upgradable", self as *const _ as usize, timeout);
         let try_lock = |state: &mut usize| {
             let mut spinwait_shared = SpinWait::new();
             loop {
@@ -758,12 +913,12 @@ impl RawRwLock {
                 *state = self.state.load(Ordering::Relaxed);
             }
         };
-        self.lock_common(
+        span.finish(self.lock_common(
             timeout,
             TOKEN_UPGRADABLE,
             try_lock,
             WRITER_BIT | UPGRADABLE_BIT,
-        )
+        ))
     }
 
     #[cold]
@@ -804,7 +959,10 @@ impl RawRwLock {
                         Ordering::Relaxed,
                         Ordering::Relaxed,
                     ) {
-                        Ok(_) => return TOKEN_HANDOFF,
+                        Ok(_) => {
+                            self.stats.handoff(!force_fair);
+                            return TOKEN_HANDOFF;
+                        }
                         Err(x) => state = x,
                     }
                 }
@@ -856,7 +1014,8 @@ impl RawRwLock {
 
     #[cold]
     fn upgrade_slow(&self, timeout: Option<Instant>) -> bool {
-        self.wait_for_readers(timeout, ONE_READER | UPGRADABLE_BIT)
+        let span = trace::LockSpan::enter("rwlock_upgrade", self as *const _ as usize, timeout);
+        span.finish(self.wait_for_readers(timeout, ONE_READER | UPGRADABLE_BIT))
     }
 
     #[cold]
@@ -894,21 +1053,27 @@ impl RawRwLock {
     #[cold]
     fn bump_shared_slow(&self) {
         self.unlock_shared();
-        self.lock_shared();
+        self.lock_shared_unchecked();
     }
 
     #[cold]
     fn bump_exclusive_slow(&self) {
+        let owner = self.owner.save();
         self.deadlock_release();
+        self.stats.unlock_exclusive();
+        self.owner.release();
+        let hold = self.hold.stop();
         self.unlock_exclusive_slow(true);
-        self.lock_exclusive();
+        self.lock_exclusive_unchecked();
+        self.owner.restore(owner);
+        self.hold.resume(hold);
     }
 
     #[cold]
     fn bump_upgradable_slow(&self) {
         self.deadlock_release();
         self.unlock_upgradable_slow(true);
-        self.lock_upgradable();
+        self.lock_upgradable_unchecked();
     }
 
     /// Common code for waking up parked threads after releasing WRITER_BIT or
@@ -967,6 +1132,7 @@ impl RawRwLock {
         while state & READERS_MASK != 0 {
             // Spin a few times to wait for readers to exit
             if spinwait.spin() {
+                self.stats.spin();
                 state = self.state.load(Ordering::Relaxed);
                 continue;
             }
@@ -997,6 +1163,7 @@ impl RawRwLock {
             //   * `addr` is an address we control.
             //   * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
             //   * `before_sleep` does not call `park`, nor does it panic.
+            self.stats.park();
             let park_result = unsafe {
                 parking_lot_core::park(
                     addr,
@@ -1064,6 +1231,7 @@ impl RawRwLock {
 
             // If there are no parked threads, try spinning a few times.
             if state & (PARKED_BIT | WRITER_PARKED_BIT) == 0 && spinwait.spin() {
+                self.stats.spin();
                 state = self.state.load(Ordering::Relaxed);
                 continue;
             }
@@ -1099,8 +1267,10 @@ impl RawRwLock {
             // * `addr` is an address we control.
             // * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
             // * `before_sleep` does not call `park`, nor does it panic.
+            self.stats.park();
+            let deadline = self.hold.park_deadline(timeout);
             let park_result = unsafe {
-                parking_lot_core::park(addr, validate, before_sleep, timed_out, token, timeout)
+                parking_lot_core::park(addr, validate, before_sleep, timed_out, token, deadline)
             };
             match park_result {
                 // The thread that unparked us passed the lock on to us
@@ -1113,8 +1283,13 @@ impl RawRwLock {
                 // The validation function failed, try locking again
                 ParkResult::Invalid => (),
 
-                // Timeout expired
-                ParkResult::TimedOut => return false,
+                // Timeout expired, or the current write lock may need to be
+                // reported to the watchdog
+                ParkResult::TimedOut => {
+                    if self.hold.timed_out(addr, LockKind::RwLockWrite, timeout) {
+                        return false;
+                    }
+                }
             }
 
             // Loop back and try locking again
@@ -1123,10 +1298,400 @@ impl RawRwLock {
         }
     }
 
+    // Attempts to acquire a shared lock on behalf of an asynchronous task.
+    // This doesn't use hardware lock elision or register the lock with the
+    // deadlock detector since the lock isn't owned by the current thread.
+    #[inline]
+    pub(crate) fn try_lock_shared_task(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER_BIT != 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state
+                    .checked_add(ONE_READER)
+                    .expect("RwLock reader count overflow"),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.stats.fast_path();
+                    return true;
+                }
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    // Releases a shared lock acquired by an asynchronous task.
+    #[inline]
+    pub(crate) fn unlock_shared_task(&self) {
+        let state = self.state.fetch_sub(ONE_READER, Ordering::Release);
+        if state & (READERS_MASK | WRITER_PARKED_BIT) == (ONE_READER | WRITER_PARKED_BIT) {
+            self.unlock_shared_slow();
+        }
+    }
+
+    // Attempts to acquire an exclusive lock on behalf of an asynchronous task.
+    #[inline]
+    pub(crate) fn try_lock_exclusive_task(&self) -> bool {
+        let result = self
+            .state
+            .compare_exchange(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if result {
+            self.stats.fast_path();
+            self.stats.lock_exclusive();
+        }
+        result
+    }
+
+    // Releases an exclusive lock acquired by an asynchronous task.
+    #[inline]
+    pub(crate) fn unlock_exclusive_task(&self, force_fair: bool) {
+        self.stats.unlock_exclusive();
+        if self
+            .state
+            .compare_exchange(WRITER_BIT, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        self.unlock_exclusive_slow(force_fair);
+    }
+
+    // Returns whether the lock is currently held by any reader or writer.
+    #[inline]
+    pub(crate) fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & (READERS_MASK | WRITER_BIT) != 0
+    }
+
+    // Drives the acquisition of a shared lock by an asynchronous task. This
+    // is the asynchronous counterpart of lock_shared_slow.
+    pub(crate) fn poll_lock_shared_task(
+        &self,
+        park: &mut Option<TaskParkFuture>,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        loop {
+            // Check whether we have been unparked yet
+            if let Some(ref mut future) = *park {
+                match Pin::new(future).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => {
+                        *park = None;
+
+                        // The thread that unparked us passed the lock on to us
+                        // directly without unlocking it.
+                        if result == ParkResult::Unparked(TOKEN_HANDOFF) {
+                            return Poll::Ready(());
+                        }
+                    }
+                }
+            }
+
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                // This is the same condition as try_lock_shared_fast
+                if state & WRITER_BIT == 0 {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        state
+                            .checked_add(ONE_READER)
+                            .expect("RwLock reader count overflow"),
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return Poll::Ready(()),
+                        Err(x) => state = x,
+                    }
+                    continue;
+                }
+
+                // Set the parked bit
+                if state & PARKED_BIT == 0 {
+                    if let Err(x) = self.state.compare_exchange_weak(
+                        state,
+                        state | PARKED_BIT,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        state = x;
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            // Park our task until we are woken up by an unlock
+            *park = Some(self.park_task(TOKEN_SHARED, WRITER_BIT));
+        }
+    }
+
+    // Drives the acquisition of an exclusive lock by an asynchronous task.
+    // This is the asynchronous counterpart of lock_exclusive_slow, `writer`
+    // tracks whether the task already owns WRITER_BIT.
+    pub(crate) fn poll_lock_exclusive_task(
+        &self,
+        park: &mut Option<TaskParkFuture>,
+        writer: &mut bool,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        'outer: loop {
+            // Check whether we have been unparked yet
+            if let Some(ref mut future) = *park {
+                match Pin::new(future).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => {
+                        *park = None;
+
+                        // The thread that unparked us passed WRITER_BIT on to
+                        // us directly without unlocking it.
+                        if result == ParkResult::Unparked(TOKEN_HANDOFF) {
+                            *writer = true;
+                        }
+                    }
+                }
+            }
+
+            // Step 1: grab exclusive ownership of WRITER_BIT
+            if !*writer {
+                let mut state = self.state.load(Ordering::Relaxed);
+                loop {
+                    if state & (WRITER_BIT | UPGRADABLE_BIT) == 0 {
+                        // Grab WRITER_BIT if it isn't set, even if there are
+                        // parked threads.
+                        match self.state.compare_exchange_weak(
+                            state,
+                            state | WRITER_BIT,
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => {
+                                *writer = true;
+                                break;
+                            }
+                            Err(x) => state = x,
+                        }
+                        continue;
+                    }
+
+                    // Set the parked bit
+                    if state & PARKED_BIT == 0 {
+                        if let Err(x) = self.state.compare_exchange_weak(
+                            state,
+                            state | PARKED_BIT,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        ) {
+                            state = x;
+                            continue;
+                        }
+                    }
+
+                    // Park our task until we are woken up by an unlock
+                    *park = Some(self.park_task(TOKEN_EXCLUSIVE, WRITER_BIT | UPGRADABLE_BIT));
+                    continue 'outer;
+                }
+            }
+
+            // Step 2: wait for all remaining readers to exit the lock.
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                if state & READERS_MASK == 0 {
+                    self.stats.lock_exclusive();
+                    return Poll::Ready(());
+                }
+
+                // Set the parked bit
+                if state & WRITER_PARKED_BIT == 0 {
+                    if let Err(x) = self.state.compare_exchange_weak(
+                        state,
+                        state | WRITER_PARKED_BIT,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        state = x;
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            // Park our task until the last reader wakes us up.
+            // Using the 2nd key at addr + 1
+            let addr = self as *const _ as usize + 1;
+            let validate = || {
+                let state = self.state.load(Ordering::Relaxed);
+                state & READERS_MASK != 0 && state & WRITER_PARKED_BIT != 0
+            };
+            let before_sleep = || {};
+            let timed_out: fn(usize, bool) = |_, _| {};
+            // SAFETY:
+            //   * `addr` is an address we control.
+            //   * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
+            //   * `before_sleep` does not call `park`, nor does it panic.
+            self.stats.park();
+            *park = Some(unsafe {
+                parking_lot_core::park_async(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    TOKEN_EXCLUSIVE,
+                    None,
+                )
+            });
+        }
+    }
+
+    // Cleans up after an asynchronous shared lock operation that is abandoned
+    // before it completed.
+    pub(crate) fn cancel_lock_shared_task(&self, park: &mut Option<TaskParkFuture>) {
+        let token = match park.take() {
+            Some(mut future) => future.cancel(),
+            None => return,
+        };
+        match token {
+            // The lock was handed off to us, release it again
+            Some(TOKEN_HANDOFF) => self.unlock_shared_task(),
+
+            // We were woken up to try acquiring the lock, pass this on
+            Some(_) => self.wake_parked_threads_after_cancel(),
+
+            // We were removed from the queue before being unparked
+            None => (),
+        }
+    }
+
+    // Cleans up after an asynchronous exclusive lock operation that is
+    // abandoned before it completed.
+    pub(crate) fn cancel_lock_exclusive_task(
+        &self,
+        park: &mut Option<TaskParkFuture>,
+        mut writer: bool,
+    ) {
+        let token = park.take().and_then(|mut future| future.cancel());
+        if token == Some(TOKEN_HANDOFF) {
+            writer = true;
+        }
+
+        if writer {
+            // We need to release WRITER_BIT, like a timed out lock_exclusive
+            // would. The parked bit for the writer queue is cleared as well
+            // since we are no longer waiting for readers.
+            self.state
+                .fetch_and(!(WRITER_BIT | WRITER_PARKED_BIT), Ordering::Relaxed);
+            self.wake_parked_threads_after_cancel();
+        } else if token.is_some() {
+            // We were woken up to try acquiring the lock, pass this on
+            self.wake_parked_threads_after_cancel();
+        }
+    }
+
+    // Parks an asynchronous task in the main queue
+    #[inline]
+    fn park_task(&self, token: ParkToken, validate_flags: usize) -> TaskParkFuture {
+        let addr = self as *const _ as usize;
+        let validate = || {
+            let state = self.state.load(Ordering::Relaxed);
+            state & PARKED_BIT != 0 && (state & validate_flags != 0)
+        };
+        let before_sleep = || {};
+        // SAFETY:
+        // * `addr` is an address we control.
+        // * `validate`/`task_cancelled` does not panic or call into any function of `parking_lot`.
+        // * `before_sleep` does not call `park`, nor does it panic.
+        self.stats.park();
+        unsafe {
+            parking_lot_core::park_async(
+                addr,
+                validate,
+                before_sleep,
+                task_cancelled as fn(usize, bool),
+                token,
+                None,
+            )
+        }
+    }
+
+    // Wakes up parked threads after a task which was woken up or which owned
+    // WRITER_BIT abandoned its lock attempt, so that they get a chance to
+    // acquire the lock. This isn't necessary if WRITER_BIT or UPGRADABLE_BIT
+    // is held since those will wake up parked threads when released.
+    #[cold]
+    fn wake_parked_threads_after_cancel(&self) {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & PARKED_BIT == 0 || state & (WRITER_BIT | UPGRADABLE_BIT) != 0 {
+            return;
+        }
+        let callback = |_, result: UnparkResult| {
+            // Clear the parked bit if there no more parked threads
+            if !result.have_more_threads {
+                self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+            }
+            TOKEN_NORMAL
+        };
+        // SAFETY: `callback` does not panic or call into any function of `parking_lot`.
+        unsafe {
+            self.wake_parked_threads(0, callback);
+        }
+    }
+
+    // Checks the level of the lock against the locks held by the current
+    // thread. This must be done before attempting to acquire the lock, but
+    // not when reacquiring it after a bump, since panicking at that point
+    // would leave a guard for an unlocked lock.
+    #[inline]
+    fn check_level(&self) {
+        self.level.check(self as *const _ as usize);
+    }
+
+    #[inline]
+    fn lock_exclusive_unchecked(&self) {
+        if self
+            .state
+            .compare_exchange_weak(0, WRITER_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            let result = self.stats.slow_path(|| self.lock_exclusive_slow(None));
+            debug_assert!(result);
+        } else {
+            self.stats.fast_path();
+        }
+        self.stats.lock_exclusive();
+        self.deadlock_acquire();
+    }
+
+    #[inline]
+    fn lock_shared_unchecked(&self) {
+        if !self.try_lock_shared_fast(false) {
+            let result = self.stats.slow_path(|| self.lock_shared_slow(false, None));
+            debug_assert!(result);
+        } else {
+            self.stats.fast_path();
+        }
+        self.deadlock_acquire();
+    }
+
+    #[inline]
+    fn lock_upgradable_unchecked(&self) {
+        if !self.try_lock_upgradable_fast() {
+            let result = self.stats.slow_path(|| self.lock_upgradable_slow(None));
+            debug_assert!(result);
+        } else {
+            self.stats.fast_path();
+        }
+        self.deadlock_acquire();
+    }
+
     #[inline]
     fn deadlock_acquire(&self) {
         unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-        unsafe { deadlock::acquire_resource(self as *const _ as usize + 1) };
+        unsafe { deadlock::acquire_linked_resource(self as *const _ as usize + 1) };
+        unsafe { self.level.acquire(self as *const _ as usize) };
     }
 
     #[inline]
@@ -1135,3 +1700,15 @@ impl RawRwLock {
         unsafe { deadlock::release_resource(self as *const _ as usize + 1) };
     }
 }
+
+// Invoked while the queue is locked when an asynchronous lock operation is
+// cancelled while its task is still parked in the main queue.
+fn task_cancelled(key: usize, was_last_thread: bool) {
+    // Clear the parked bit if we were the last parked thread
+    if was_last_thread {
+        // SAFETY: The key is the address of the RawRwLock which the task was
+        // waiting for, which outlives the task's ParkFuture.
+        let rwlock = unsafe { &*(key as *const RawRwLock) };
+        rwlock.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::util;
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use lock_api::{GuardSend, RawSemaphore as RawSemaphore_};
+use parking_lot_core::{
+    self, FilterOp, ParkResult, ParkToken, SpinWait, UnparkResult, UnparkToken,
+};
+use std::time::Instant;
+
+// UnparkToken used to indicate that the permits requested by the target thread
+// have been handed off to it directly.
+const TOKEN_HANDOFF: UnparkToken = UnparkToken(1);
+
+/// This bit is set in the `state` of a `RawSemaphore` just before parking a
+/// thread. While it is set, newly arriving threads may not take permits from
+/// the semaphore and must queue up behind the parked threads instead.
+const PARKED_BIT: usize = 0b1;
+/// The permit count is stored in the remaining bits of the `state`.
+const ONE_PERMIT: usize = 0b10;
+/// Maximum number of permits that a semaphore can hold.
+const MAX_PERMITS: usize = !0 / ONE_PERMIT;
+
+/// Raw counting semaphore type backed by the parking lot.
+///
+/// Permits are granted in strict FIFO order: once a thread has started
+/// waiting, later threads will not be able to acquire permits until all the
+/// threads ahead of them have been served, even if they ask for fewer permits.
+pub struct RawSemaphore {
+    /// The lowest bit is `PARKED_BIT`, the rest holds the number of available
+    /// permits.
+    ///
+    /// While `PARKED_BIT` is set, permits are only ever taken out of the state
+    /// while the bucket lock is held (i.e. in a parking_lot_core callback),
+    /// which allows them to be handed off to the parked threads in order.
+    state: AtomicUsize,
+}
+
+// Converts a number of permits into the equivalent amount of state.
+#[inline]
+fn permits_to_state(n: usize) -> usize {
+    if n > MAX_PERMITS {
+        panic!("Semaphore permit count overflow");
+    }
+    n * ONE_PERMIT
+}
+
+unsafe impl lock_api::RawSemaphore for RawSemaphore {
+    type GuardMarker = GuardSend;
+
+    #[inline]
+    fn new(permits: usize) -> RawSemaphore {
+        RawSemaphore {
+            state: AtomicUsize::new(permits_to_state(permits)),
+        }
+    }
+
+    #[inline]
+    fn acquire(&self, n: usize) {
+        if !self.try_acquire(n) {
+            self.acquire_slow(n, None);
+        }
+    }
+
+    #[inline]
+    fn try_acquire(&self, n: usize) -> bool {
+        let delta = permits_to_state(n);
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & PARKED_BIT != 0 || state < delta {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state - delta,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    #[inline]
+    fn release(&self, n: usize) {
+        let delta = permits_to_state(n);
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            let new_state = match state.checked_add(delta) {
+                Some(x) => x,
+                None => panic!("Semaphore permit count overflow"),
+            };
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(x) => state = x,
+            }
+        }
+        if state & PARKED_BIT != 0 {
+            self.release_slow();
+        }
+    }
+
+    #[inline]
+    fn available_permits(&self) -> usize {
+        self.state.load(Ordering::Relaxed) / ONE_PERMIT
+    }
+}
+
+unsafe impl lock_api::RawSemaphoreTimed for RawSemaphore {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_acquire_until(&self, n: usize, timeout: Instant) -> bool {
+        self.try_acquire(n) || self.acquire_slow(n, Some(timeout))
+    }
+
+    #[inline]
+    fn try_acquire_for(&self, n: usize, timeout: Duration) -> bool {
+        self.try_acquire(n) || self.acquire_slow(n, util::to_deadline(timeout))
+    }
+}
+
+impl RawSemaphore {
+    #[cold]
+    fn acquire_slow(&self, n: usize, timeout: Option<Instant>) -> bool {
+        let delta = permits_to_state(n);
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Grab the permits if they are available and nobody is queued
+            // ahead of us.
+            if state & PARKED_BIT == 0 && state >= delta {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state - delta,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // If there is no queue, try spinning a few times
+            if state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Set the parked bit
+            if state & PARKED_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | PARKED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Park our thread until enough permits are handed off to us
+            let addr = self as *const _ as usize;
+            let validate = || self.state.load(Ordering::Relaxed) & PARKED_BIT != 0;
+            let before_sleep = || {};
+            let timed_out = |_, was_last_thread| {
+                // Clear the parked bit if we were the last parked thread
+                if was_last_thread {
+                    self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                }
+            };
+            // SAFETY:
+            //   * `addr` is an address we control.
+            //   * `validate`/`timed_out` does not panic or call into any function of `parking_lot`.
+            //   * `before_sleep` does not call `park`, nor does it panic.
+            match unsafe {
+                parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    ParkToken(n),
+                    timeout,
+                )
+            } {
+                // The thread that unparked us handed the permits to us
+                // directly.
+                ParkResult::Unparked(_) => return true,
+
+                // The validation function failed, try acquiring again
+                ParkResult::Invalid => (),
+
+                // Timeout expired. We may have been at the head of the queue
+                // and blocking threads behind us which can now be served.
+                ParkResult::TimedOut => {
+                    if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+                        self.release_slow();
+                    }
+                    return false;
+                }
+            }
+
+            // Loop back and try acquiring again
+            spinwait.reset();
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    #[cold]
+    fn release_slow(&self) {
+        // Wake up parked threads in order for as long as there are enough
+        // permits available for them. Since there are parked threads, permits
+        // can only be taken out of the state while the bucket lock is held, so
+        // anything we see here will still be there when the callback takes the
+        // permits out.
+        let taken = Cell::new(0usize);
+        let addr = self as *const _ as usize;
+        let filter = |ParkToken(n)| {
+            let available = self.state.load(Ordering::Relaxed) & !PARKED_BIT;
+            let delta = n * ONE_PERMIT;
+            if available - taken.get() >= delta {
+                taken.set(taken.get() + delta);
+                FilterOp::Unpark
+            } else {
+                FilterOp::Stop
+            }
+        };
+        let callback = |result: UnparkResult| {
+            // Take the permits for the threads we are waking up, and clear
+            // the parked bit if there are no more parked threads.
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                let mut new_state = state - taken.get();
+                if !result.have_more_threads {
+                    new_state &= !PARKED_BIT;
+                }
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return TOKEN_HANDOFF,
+                    Err(x) => state = x,
+                }
+            }
+        };
+        // SAFETY:
+        //   * `addr` is an address we control.
+        //   * `filter`/`callback` does not panic or call into any function of `parking_lot`.
+        unsafe {
+            parking_lot_core::unpark_filter(addr, filter, callback);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_robust_mutex::RawRobustMutex;
+
+/// A mutual exclusion primitive which detects that its owner died while
+/// holding it.
+///
+/// This behaves like `SharedMutex` and can likewise be placed in memory shared
+/// between processes, but if the thread or process holding the lock dies
+/// without unlocking it, other threads don't hang forever. Instead the next
+/// call to `lock_robust` acquires the lock and returns its guard inside
+/// `RobustLockError::OwnerDied`, giving the caller a chance to repair the
+/// protected data before calling `RobustMutexGuard::mark_consistent`. If the
+/// guard is dropped without marking the mutex consistent, the mutex becomes
+/// permanently unusable and `lock_robust` returns
+/// `RobustLockError::NotRecoverable` from then on.
+///
+/// The plain `lock` method can't report the death of the previous owner, so it
+/// acquires the lock but leaves the mutex inconsistent. The new owner can check
+/// this with `RobustMutexGuard::is_consistent`, and as with `lock_robust` the
+/// mutex becomes unusable if the guard is dropped without marking it
+/// consistent. `lock` panics if the mutex is not recoverable.
+///
+/// Owner death is detected by polling, so a waiting thread may take up to
+/// 100ms to notice it, and it goes unnoticed if the thread ID of the dead
+/// owner has already been reused by another thread. See `RawRobustMutex` for
+/// details.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::lock_api::RobustLockError;
+/// use parking_lot::{RobustMutex, RobustMutexGuard};
+///
+/// // Normally this would live in a shared memory mapping.
+/// let mutex = RobustMutex::new(0);
+/// match mutex.lock_robust() {
+///     Ok(mut guard) => *guard += 1,
+///     Err(RobustLockError::OwnerDied(mut guard)) => {
+///         // Repair the data, then allow others to use it again.
+///         *guard = 0;
+///         RobustMutexGuard::mark_consistent(&guard);
+///     }
+///     Err(RobustLockError::NotRecoverable) => panic!("data was lost"),
+/// };
+/// ```
+pub type RobustMutex<T> = lock_api::Mutex<RawRobustMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of a `RobustMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type RobustMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawRobustMutex, T>;
+
+/// An RAII mutex guard returned by `RobustMutexGuard::map`, which can point to
+/// a subfield of the protected data.
+pub type MappedRobustMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawRobustMutex, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{run_in_child, shared_alloc};
+    use crate::{RawRobustMutex, RobustMutex, RobustMutexGuard};
+    use lock_api::{RawMutex, RobustLockError};
+    use std::mem;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Locks the mutex on another thread, which then exits without unlocking it.
+    fn abandon(m: &Arc<RobustMutex<u32>>) {
+        let m = m.clone();
+        thread::spawn(move || mem::forget(m.lock())).join().unwrap();
+    }
+
+    #[test]
+    fn smoke() {
+        let m = RobustMutex::new(());
+        assert!(m.lock_robust().is_ok());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        assert!(m.try_lock_robust().is_none());
+        assert!(m.try_lock_for(Duration::from_millis(10)).is_none());
+        drop(guard);
+        assert!(m.try_lock_robust().unwrap().is_ok());
+    }
+
+    #[test]
+    fn zeroed_is_unlocked() {
+        let raw: RawRobustMutex = unsafe { mem::zeroed() };
+        assert_eq!(mem::size_of::<RawRobustMutex>(), 4);
+        assert!(raw.try_lock());
+        raw.unlock();
+    }
+
+    #[test]
+    fn owner_died() {
+        let m = Arc::new(RobustMutex::new(1));
+        abandon(&m);
+        match m.lock_robust() {
+            Err(RobustLockError::OwnerDied(mut guard)) => {
+                *guard = 0;
+                RobustMutexGuard::mark_consistent(&guard);
+            }
+            _ => panic!("owner death was not detected"),
+        }
+        assert_eq!(*m.lock_robust().unwrap(), 0);
+    }
+
+    #[test]
+    fn owner_died_while_waiting() {
+        let m = Arc::new(RobustMutex::new(0));
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || {
+            let guard = m2.lock();
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            mem::forget(guard);
+        });
+        rx.recv().unwrap();
+        assert!(m.lock_robust().unwrap_err().into_guard().is_some());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn not_recoverable() {
+        let m = Arc::new(RobustMutex::new(0));
+        abandon(&m);
+        drop(m.lock_robust().unwrap_err().into_guard().unwrap());
+        assert!(m.lock_robust().unwrap_err().into_guard().is_none());
+        let result = m.try_lock_robust().unwrap();
+        assert!(result.unwrap_err().into_guard().is_none());
+        assert!(m.try_lock().is_none());
+        assert!(m.try_lock_for(Duration::from_millis(10)).is_none());
+    }
+
+    #[test]
+    fn plain_lock_owner_died() {
+        let m = Arc::new(RobustMutex::new(0));
+        abandon(&m);
+        let guard = m.lock();
+        assert!(!RobustMutexGuard::is_consistent(&guard));
+        RobustMutexGuard::mark_consistent(&guard);
+        assert!(RobustMutexGuard::is_consistent(&guard));
+        drop(guard);
+        assert!(RobustMutexGuard::is_consistent(&m.lock_robust().unwrap()));
+
+        // Dropping the guard without repairing the data must not hide the
+        // death of the owner from others.
+        abandon(&m);
+        drop(m.lock());
+        assert!(m.lock_robust().unwrap_err().into_guard().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "mutex is not recoverable")]
+    fn plain_lock_not_recoverable() {
+        let m = Arc::new(RobustMutex::new(0));
+        abandon(&m);
+        drop(m.lock());
+        let _guard = m.lock();
+    }
+
+    #[test]
+    fn child_process() {
+        let m = shared_alloc(RobustMutex::new(0));
+        let child = run_in_child(concat!(module_path!(), "::child_process"), || {
+            let mut guard = m.lock();
+            *guard = 1;
+            mem::forget(guard);
+            true
+        });
+        while let Some(guard) = m.try_lock() {
+            drop(guard);
+            thread::yield_now();
+        }
+
+        // The child stays a zombie until it is reaped, which must still count
+        // as dead.
+        match m.lock_robust() {
+            Err(RobustLockError::OwnerDied(guard)) => {
+                assert_eq!(*guard, 1);
+                RobustMutexGuard::mark_consistent(&guard);
+            }
+            _ => panic!("owner death was not detected"),
+        }
+        assert!(child());
+        assert!(m.lock_robust().is_ok());
+    }
+}
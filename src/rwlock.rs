@@ -118,6 +118,32 @@ pub type MappedRwLockWriteGuard<'a, T> = lock_api::MappedRwLockWriteGuard<'a, Ra
 /// dropped.
 pub type RwLockUpgradableReadGuard<'a, T> = lock_api::RwLockUpgradableReadGuard<'a, RawRwLock, T>;
 
+/// RAII structure returned by `RwLock::read_arc`, which keeps the lock alive
+/// through an `Arc` and releases the shared read access when dropped.
+#[cfg(feature = "arc_lock")]
+pub type ArcRwLockReadGuard<T> = lock_api::ArcRwLockReadGuard<RawRwLock, T>;
+
+/// RAII structure returned by `RwLock::write_arc`, which keeps the lock alive
+/// through an `Arc` and releases the exclusive write access when dropped.
+#[cfg(feature = "arc_lock")]
+pub type ArcRwLockWriteGuard<T> = lock_api::ArcRwLockWriteGuard<RawRwLock, T>;
+
+/// RAII structure returned by `RwLock::upgradable_read_arc`, which keeps the
+/// lock alive through an `Arc` and releases the upgradable read access when
+/// dropped.
+#[cfg(feature = "arc_lock")]
+pub type ArcRwLockUpgradableReadGuard<T> = lock_api::ArcRwLockUpgradableReadGuard<RawRwLock, T>;
+
+/// An RAII read lock guard returned by `ArcRwLockReadGuard::map`, which can
+/// point to a subfield of the protected data.
+#[cfg(feature = "arc_lock")]
+pub type MappedArcRwLockReadGuard<T, U> = lock_api::MappedArcRwLockReadGuard<RawRwLock, T, U>;
+
+/// An RAII write lock guard returned by `ArcRwLockWriteGuard::map`, which can
+/// point to a subfield of the protected data.
+#[cfg(feature = "arc_lock")]
+pub type MappedArcRwLockWriteGuard<T, U> = lock_api::MappedArcRwLockWriteGuard<RawRwLock, T, U>;
+
 #[cfg(test)]
 mod tests {
     use crate::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
@@ -556,7 +582,10 @@ mod tests {
 
         assert_eq!(format!("{:?}", x), "RwLock { data: [0, 10] }");
         let _lock = x.write();
+        #[cfg(not(feature = "owner_tracking"))]
         assert_eq!(format!("{:?}", x), "RwLock { data: <locked> }");
+        #[cfg(feature = "owner_tracking")]
+        assert!(format!("{:?}", x).starts_with("RwLock { data: <locked>, owner: LockOwner {"));
     }
 
     #[test]
@@ -603,4 +632,54 @@ mod tests {
         .join()
         .unwrap();
     }
+
+    #[cfg(feature = "arc_lock")]
+    #[test]
+    fn test_arc_guards() {
+        use crate::{ArcRwLockReadGuard, ArcRwLockUpgradableReadGuard, ArcRwLockWriteGuard};
+
+        let lock = Arc::new(RwLock::new((1, 2)));
+
+        let read = lock.read_arc();
+        assert!(lock.try_write_arc().is_none());
+        let read2 = lock.try_read_arc().unwrap();
+        let mapped = ArcRwLockReadGuard::map(read2, |x| &x.1);
+        assert_eq!(*mapped, 2);
+        drop(read);
+        drop(mapped);
+
+        // Guards don't borrow the lock, so they can be stored as `'static` values.
+        let write: Box<dyn std::any::Any> = Box::new(lock.write_arc());
+        let write = write.downcast::<ArcRwLockWriteGuard<(i32, i32)>>().unwrap();
+        assert!(lock.try_read().is_none());
+        let mut mapped = ArcRwLockWriteGuard::map(*write, |x| &mut x.0);
+        *mapped = 5;
+        drop(mapped);
+        assert_eq!(*lock.read(), (5, 2));
+
+        let upgradable = lock.upgradable_read_arc();
+        assert!(lock.try_upgradable_read_arc().is_none());
+        let read = lock.read_arc();
+        let upgradable = ArcRwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap_err();
+        drop(read);
+        let mut write = ArcRwLockUpgradableReadGuard::upgrade(upgradable);
+        write.1 = 7;
+        let read = ArcRwLockWriteGuard::downgrade(write);
+        assert_eq!(
+            *lock.try_read_arc_for(Duration::from_millis(10)).unwrap(),
+            (5, 7)
+        );
+        drop(read);
+
+        let write = lock.write_arc();
+        let upgradable = ArcRwLockWriteGuard::downgrade_to_upgradable(write);
+        assert!(Arc::ptr_eq(
+            ArcRwLockUpgradableReadGuard::rwlock(&upgradable),
+            &lock
+        ));
+        let read = ArcRwLockUpgradableReadGuard::downgrade(upgradable);
+        assert!(lock.try_upgradable_read_arc().is_some());
+        drop(read);
+        assert!(lock.try_write_arc().is_some());
+    }
 }
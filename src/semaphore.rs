@@ -0,0 +1,187 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_semaphore::RawSemaphore;
+
+/// A counting semaphore, useful for limiting the number of threads which can
+/// access a shared resource at the same time.
+///
+/// A semaphore holds a number of permits. Threads acquire one or more permits
+/// with `acquire` or `acquire_many`, blocking until enough permits are
+/// available, and the permits are released back into the semaphore when the
+/// returned `SemaphorePermit` is dropped.
+///
+/// Permits are granted in FIFO order: a thread asking for many permits will not
+/// be starved by a stream of threads asking for fewer permits.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Semaphore;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// // Allow at most 2 threads to work at the same time.
+/// let semaphore = Arc::new(Semaphore::new(2));
+///
+/// let handles: Vec<_> = (0..10)
+///     .map(|_| {
+///         let semaphore = Arc::clone(&semaphore);
+///         thread::spawn(move || {
+///             let _permit = semaphore.acquire();
+///             // Do some work while holding the permit. It is released back
+///             // into the semaphore when `_permit` goes out of scope.
+///         })
+///     })
+///     .collect();
+///
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// assert_eq!(semaphore.available_permits(), 2);
+/// ```
+pub type Semaphore = lock_api::Semaphore<RawSemaphore>;
+
+/// An RAII implementation of one or more permits acquired from a semaphore.
+/// When this structure is dropped (falls out of scope), the permits are
+/// released back into the semaphore.
+pub type SemaphorePermit<'a> = lock_api::SemaphorePermit<'a, RawSemaphore>;
+
+#[cfg(test)]
+mod tests {
+    use crate::{Semaphore, SemaphorePermit};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn smoke() {
+        let s = Semaphore::new(1);
+        drop(s.acquire());
+        drop(s.acquire());
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn try_acquire() {
+        let s = Semaphore::new(3);
+        let a = s.try_acquire_many(2).unwrap();
+        assert_eq!(SemaphorePermit::num_permits(&a), 2);
+        assert!(s.try_acquire_many(2).is_none());
+        let b = s.try_acquire().unwrap();
+        assert!(s.try_acquire().is_none());
+        drop(a);
+        assert_eq!(s.available_permits(), 2);
+        drop(b);
+        assert_eq!(s.available_permits(), 3);
+    }
+
+    #[test]
+    fn add_and_forget() {
+        let s = Semaphore::new(0);
+        assert!(s.try_acquire().is_none());
+        s.add_permits(2);
+        SemaphorePermit::forget(s.acquire());
+        assert_eq!(s.available_permits(), 1);
+    }
+
+    #[test]
+    fn limits_concurrency() {
+        const N: usize = 3;
+        let s = Arc::new(Semaphore::new(N));
+        let active = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (s, active) = (s.clone(), active.clone());
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let _permit = s.acquire();
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        assert!(now <= N);
+                        thread::yield_now();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), N);
+    }
+
+    #[test]
+    fn wakes_blocked_thread() {
+        let s = Arc::new(Semaphore::new(0));
+        let s2 = s.clone();
+        let handle = thread::spawn(move || {
+            let permit = s2.acquire_many(3);
+            SemaphorePermit::forget(permit);
+        });
+        thread::sleep(Duration::from_millis(50));
+        s.add_permits(1);
+        s.add_permits(2);
+        handle.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn fifo_order() {
+        let s = Arc::new(Semaphore::new(0));
+        let s2 = s.clone();
+        let handle = thread::spawn(move || {
+            SemaphorePermit::forget(s2.acquire_many(2));
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // A waiter is queued, so newly arriving threads must wait their turn
+        // even though a permit is available.
+        s.add_permits(1);
+        assert!(s.try_acquire().is_none());
+        s.add_permits(1);
+        handle.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn timeout() {
+        let s = Arc::new(Semaphore::new(1));
+        let permit = s.acquire();
+        assert!(s.try_acquire_for(Duration::from_millis(10)).is_none());
+        drop(permit);
+        assert!(s.try_acquire_for(Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn timeout_unblocks_queue() {
+        let s = Arc::new(Semaphore::new(1));
+        let s2 = s.clone();
+        // This thread waits for more permits than will ever be available,
+        // which blocks the queue until it times out.
+        let big = thread::spawn(move || {
+            s2.try_acquire_many_for(2, Duration::from_millis(100))
+                .is_some()
+        });
+        thread::sleep(Duration::from_millis(20));
+        let s3 = s.clone();
+        let small = thread::spawn(move || {
+            SemaphorePermit::forget(s3.acquire());
+        });
+        assert!(!big.join().unwrap());
+        small.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_semaphore_debug() {
+        let s = Semaphore::new(2);
+        assert_eq!(format!("{:?}", s), "Semaphore { available_permits: 2 }");
+        let permit = s.acquire();
+        assert_eq!(format!("{:?}", permit), "SemaphorePermit { permits: 1 }");
+    }
+}
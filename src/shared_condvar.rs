@@ -0,0 +1,244 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::condvar::WaitTimeoutResult;
+use crate::futex::{futex_wait, futex_wake, futex_wake_all, Shared};
+use crate::raw_futex_mutex::RawSharedMutex;
+use crate::shared_mutex::SharedMutexGuard;
+use crate::util;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use lock_api::RawMutex as RawMutex_;
+use std::time::{Duration, Instant};
+
+/// A condition variable which can be placed in memory shared between
+/// processes, for use with `SharedMutex`.
+///
+/// Waiting threads sleep on a futex holding a notification counter, so
+/// threads of different processes which map the same memory can wait for and
+/// notify each other. A zero-filled memory region is a valid condition
+/// variable.
+///
+/// # Differences from `Condvar`
+///
+/// - Spurious wakeups are possible, so the predicate should always be checked
+///   in a loop.
+/// - It can be used with several mutexes at the same time.
+/// - `notify_all` wakes up all waiting threads at once instead of requeuing
+///   them onto the mutex, so they all contend for the mutex when they wake.
+///
+/// These differences come from the state layout. `Condvar` stores a pointer
+/// to the mutex it is used with and keeps its waiters in the process-local
+/// parking lot, which is what allows `notify_all` to requeue them. Neither
+/// works across processes: the same shared mutex is usually mapped at a
+/// different address in each process, and the parking lot of one process
+/// cannot wake threads of another. `SharedCondvar` therefore only holds a
+/// 32-bit counter which waiters sleep on directly. Without a mutex address
+/// that is valid in every process there is nothing to requeue the waiters
+/// onto, so a `notify_all` on a condition variable with many waiters causes a
+/// thundering herd on the mutex. Prefer `notify_one` where a single waiter can
+/// make progress.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::{SharedCondvar, SharedMutex};
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let pair = Arc::new((SharedMutex::new(false), SharedCondvar::new()));
+/// let pair2 = pair.clone();
+///
+/// thread::spawn(move || {
+///     let &(ref lock, ref cvar) = &*pair2;
+///     *lock.lock() = true;
+///     cvar.notify_one();
+/// });
+///
+/// let &(ref lock, ref cvar) = &*pair;
+/// let mut started = lock.lock();
+/// while !*started {
+///     cvar.wait(&mut started);
+/// }
+/// ```
+#[repr(C)]
+pub struct SharedCondvar {
+    seq: AtomicU32,
+}
+
+impl SharedCondvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    #[inline]
+    pub const fn new() -> SharedCondvar {
+        SharedCondvar {
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Wakes up one blocked thread on this condvar.
+    ///
+    /// Returns whether a thread was woken up.
+    #[inline]
+    pub fn notify_one(&self) -> bool {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        futex_wake::<Shared>(&self.seq, 1) != 0
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    ///
+    /// Returns the number of threads woken up.
+    #[inline]
+    pub fn notify_all(&self) -> usize {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        futex_wake_all::<Shared>(&self.seq)
+    }
+
+    /// Blocks the current thread until this condition variable receives a
+    /// notification.
+    ///
+    /// This function will atomically unlock the mutex specified (represented by
+    /// `mutex_guard`) and block the current thread. This means that any calls
+    /// to `notify_*()` which happen logically after the mutex is unlocked are
+    /// candidates to wake this thread up. When this function call returns, the
+    /// lock specified will have been re-acquired.
+    #[inline]
+    pub fn wait<T: ?Sized>(&self, mutex_guard: &mut SharedMutexGuard<'_, T>) {
+        self.wait_until_internal(unsafe { SharedMutexGuard::mutex(mutex_guard).raw() }, None);
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified time instant.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed. Like `wait`, the lock specified will be
+    /// re-acquired when this function returns, regardless of whether the
+    /// timeout elapsed or not.
+    #[inline]
+    pub fn wait_until<T: ?Sized>(
+        &self,
+        mutex_guard: &mut SharedMutexGuard<'_, T>,
+        timeout: Instant,
+    ) -> WaitTimeoutResult {
+        self.wait_until_internal(
+            unsafe { SharedMutexGuard::mutex(mutex_guard).raw() },
+            Some(timeout),
+        )
+    }
+
+    /// Waits on this condition variable for a notification, timing out after a
+    /// specified duration.
+    ///
+    /// The returned `WaitTimeoutResult` value indicates if the timeout is
+    /// known to have elapsed. Like `wait`, the lock specified will be
+    /// re-acquired when this function returns, regardless of whether the
+    /// timeout elapsed or not.
+    #[inline]
+    pub fn wait_for<T: ?Sized>(
+        &self,
+        mutex_guard: &mut SharedMutexGuard<'_, T>,
+        timeout: Duration,
+    ) -> WaitTimeoutResult {
+        let deadline = util::to_deadline(timeout);
+        self.wait_until_internal(
+            unsafe { SharedMutexGuard::mutex(mutex_guard).raw() },
+            deadline,
+        )
+    }
+
+    // This is a non-generic function to reduce the monomorphization cost of
+    // using `wait_until`.
+    fn wait_until_internal(
+        &self,
+        mutex: &RawSharedMutex,
+        timeout: Option<Instant>,
+    ) -> WaitTimeoutResult {
+        // Read the counter while still holding the mutex, so that any
+        // notification sent after the mutex is unlocked changes it and
+        // prevents us from going to sleep.
+        let seq = self.seq.load(Ordering::Relaxed);
+        mutex.unlock();
+        let woken = futex_wait::<Shared>(&self.seq, seq, timeout);
+        mutex.lock();
+        WaitTimeoutResult(!woken)
+    }
+}
+
+impl Default for SharedCondvar {
+    #[inline]
+    fn default() -> SharedCondvar {
+        SharedCondvar::new()
+    }
+}
+
+impl fmt::Debug for SharedCondvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("SharedCondvar { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{run_in_child, shared_alloc};
+    use crate::{SharedCondvar, SharedMutex};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn notify_one() {
+        let pair = Arc::new((SharedMutex::new(false), SharedCondvar::new()));
+        let pair2 = pair.clone();
+        let mut g = pair.0.lock();
+        let t = thread::spawn(move || {
+            *pair2.0.lock() = true;
+            pair2.1.notify_one();
+        });
+        while !*g {
+            pair.1.wait(&mut g);
+        }
+        drop(g);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn wait_for() {
+        let m = SharedMutex::new(());
+        let c = SharedCondvar::new();
+        let mut g = m.lock();
+        assert!(c.wait_for(&mut g, Duration::from_millis(10)).timed_out());
+        assert!(!c.notify_one());
+        assert_eq!(c.notify_all(), 0);
+    }
+
+    #[test]
+    fn child_process() {
+        let pair = shared_alloc((SharedMutex::new(0), SharedCondvar::new()));
+        let child = run_in_child(concat!(module_path!(), "::child_process"), || {
+            let mut g = pair.0.lock();
+            *g = 1;
+            pair.1.notify_all();
+            while *g != 2 {
+                pair.1.wait(&mut g);
+            }
+            true
+        });
+
+        let mut g = pair.0.lock();
+        while *g != 1 {
+            pair.1.wait(&mut g);
+        }
+        *g = 2;
+        pair.1.notify_all();
+        drop(g);
+        assert!(child());
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_futex_mutex::RawSharedMutex;
+
+/// A mutual exclusion primitive which can be placed in memory shared between
+/// processes.
+///
+/// This behaves like `Mutex`, except that waiting threads sleep on a futex in
+/// the mutex itself instead of in the parking lot, which is private to each
+/// process. It can therefore be placed in a region of memory which is
+/// `mmap`ed by several processes, whether they were `fork`ed from each other
+/// or started independently, and used with `SharedCondvar`.
+///
+/// The raw lock is a single 32-bit word which is zero when unlocked, so a
+/// zero-filled region of memory is a valid unlocked `RawSharedMutex`. Note
+/// that the layout of `SharedMutex<T>` itself is only guaranteed to match
+/// between processes built with the same compiler; independently built
+/// processes should embed `RawSharedMutex` in a `#[repr(C)]` structure and use
+/// `lock_api`'s raw locking methods instead.
+///
+/// If a process dies while holding the lock, it stays locked forever. Use
+/// `RobustMutex` if that needs to be handled.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::SharedMutex;
+///
+/// // Normally this would live in a shared memory mapping.
+/// let mutex = SharedMutex::new(0);
+/// *mutex.lock() += 1;
+/// assert_eq!(*mutex.lock(), 1);
+/// ```
+pub type SharedMutex<T> = lock_api::Mutex<RawSharedMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of a `SharedMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type SharedMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawSharedMutex, T>;
+
+/// An RAII mutex guard returned by `SharedMutexGuard::map`, which can point to
+/// a subfield of the protected data.
+pub type MappedSharedMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawSharedMutex, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{run_in_child, shared_alloc};
+    use crate::{RawSharedMutex, SharedMutex};
+    use lock_api::RawMutex;
+    use std::mem;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn smoke() {
+        let m = SharedMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        assert!(m.try_lock_for(Duration::from_millis(10)).is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn zeroed_is_unlocked() {
+        let raw: RawSharedMutex = unsafe { mem::zeroed() };
+        assert_eq!(mem::size_of::<RawSharedMutex>(), 4);
+        assert!(raw.try_lock());
+        raw.unlock();
+    }
+
+    #[test]
+    fn child_process() {
+        const N: u32 = 1000;
+
+        let m = shared_alloc(SharedMutex::new(0));
+        let child = run_in_child(concat!(module_path!(), "::child_process"), || {
+            for _ in 0..N {
+                *m.lock() += 1;
+            }
+            true
+        });
+        let guard = m.lock();
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        for _ in 0..N {
+            *m.lock() += 1;
+        }
+        assert!(child());
+        assert_eq!(*m.lock(), 2 * N);
+    }
+}
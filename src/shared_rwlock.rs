@@ -0,0 +1,96 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::raw_futex_rwlock::RawSharedRwLock;
+
+/// A reader-writer lock which can be placed in memory shared between
+/// processes.
+///
+/// This behaves like `FutexRwLock`, except that its futexes may be shared
+/// with other processes. It can therefore be placed in a region of memory
+/// which is `mmap`ed by several processes, whether they were `fork`ed from
+/// each other or started independently. Upgradable reads and downgrading are
+/// not supported, and writers are preferred over readers.
+///
+/// The raw lock consists of two 32-bit words which are zero when unlocked, so
+/// a zero-filled region of memory is a valid unlocked `RawSharedRwLock`. Note
+/// that the layout of `SharedRwLock<T>` itself is only guaranteed to match
+/// between processes built with the same compiler.
+///
+/// If a process dies while holding the lock, it stays locked forever.
+///
+/// This type is only available on Linux and Android.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::SharedRwLock;
+///
+/// // Normally this would live in a shared memory mapping.
+/// let lock = SharedRwLock::new(5);
+/// {
+///     let r1 = lock.read();
+///     let r2 = lock.read();
+///     assert_eq!(*r1 + *r2, 10);
+/// }
+/// *lock.write() += 1;
+/// assert_eq!(*lock.read(), 6);
+/// ```
+pub type SharedRwLock<T> = lock_api::RwLock<RawSharedRwLock, T>;
+
+/// RAII structure used to release the shared read access of a `SharedRwLock`
+/// when dropped.
+pub type SharedRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawSharedRwLock, T>;
+
+/// RAII structure used to release the exclusive write access of a
+/// `SharedRwLock` when dropped.
+pub type SharedRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawSharedRwLock, T>;
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{run_in_child, shared_alloc};
+    use crate::SharedRwLock;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn smoke() {
+        let l = SharedRwLock::new(());
+        drop(l.read());
+        drop(l.write());
+        drop((l.read(), l.read()));
+        let guard = l.write();
+        assert!(l.try_read_for(Duration::from_millis(10)).is_none());
+        drop(guard);
+        assert!(l.try_write().is_some());
+    }
+
+    #[test]
+    fn child_process() {
+        const N: u32 = 1000;
+
+        let l = shared_alloc(SharedRwLock::new(0));
+        let child = run_in_child(concat!(module_path!(), "::child_process"), || {
+            for _ in 0..N {
+                let value = *l.read();
+                *l.write() += 1;
+                if value > 2 * N {
+                    return false;
+                }
+            }
+            true
+        });
+        let reader = l.read();
+        thread::sleep(Duration::from_millis(10));
+        drop(reader);
+        for _ in 0..N {
+            *l.write() += 1;
+        }
+        assert!(child());
+        assert_eq!(*l.read(), 2 * N);
+    }
+}
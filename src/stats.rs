@@ -0,0 +1,305 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Per-lock contention statistics.
+//!
+//! This is enabled via the `stats` feature flag, which adds counters to every
+//! `Mutex`, `FairMutex`, `ReentrantMutex` and `RwLock`. The statistics of a
+//! lock can be read with its `stats` method and cleared with `reset_stats`.
+//! The `stats` feature requires 64-bit atomics.
+//!
+//! When the feature is disabled the counters take up no space and all of the
+//! bookkeeping compiles down to nothing.
+
+#[cfg(feature = "stats")]
+use crate::util::now_nanos;
+#[cfg(feature = "stats")]
+use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "stats")]
+use std::time::Duration;
+
+/// Contention statistics collected for a lock.
+///
+/// For an `RwLock`, acquisitions and waits are counted for all kinds of locks
+/// while `hold_time` only covers exclusive (write) locks, including upgraded
+/// upgradable locks.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Number of times the lock was acquired without entering the slow path.
+    pub fast_acquisitions: u64,
+
+    /// Number of times a thread entered the slow path to acquire the lock,
+    /// whether or not it then succeeded.
+    pub slow_path_entries: u64,
+
+    /// Number of times a thread spun while waiting for the lock.
+    pub spins: u64,
+
+    /// Number of times a thread was parked while waiting for the lock.
+    pub parks: u64,
+
+    /// Number of times the lock was handed off directly to a parked thread
+    /// by a fair unlock.
+    pub handoffs: u64,
+
+    /// Number of handoffs which were triggered by the eventual fairness
+    /// timeout rather than an explicit fair unlock.
+    pub fair_timeouts: u64,
+
+    /// Total time spent by threads in the slow path waiting for the lock.
+    pub wait_time: Duration,
+
+    /// Total time the lock was held exclusively.
+    pub hold_time: Duration,
+}
+
+// Counters embedded in every raw lock.
+#[cfg(feature = "stats")]
+pub(crate) struct LockCounters {
+    fast_acquisitions: AtomicU64,
+    slow_path_entries: AtomicU64,
+    spins: AtomicU64,
+    parks: AtomicU64,
+    handoffs: AtomicU64,
+    fair_timeouts: AtomicU64,
+    wait_nanos: AtomicU64,
+    hold_nanos: AtomicU64,
+
+    // Time at which the lock was last acquired exclusively, or 0 if it isn't
+    // currently held exclusively.
+    locked_at: AtomicU64,
+}
+
+#[cfg(not(feature = "stats"))]
+pub(crate) struct LockCounters;
+
+#[cfg(feature = "stats")]
+impl LockCounters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: LockCounters = LockCounters {
+        fast_acquisitions: AtomicU64::new(0),
+        slow_path_entries: AtomicU64::new(0),
+        spins: AtomicU64::new(0),
+        parks: AtomicU64::new(0),
+        handoffs: AtomicU64::new(0),
+        fair_timeouts: AtomicU64::new(0),
+        wait_nanos: AtomicU64::new(0),
+        hold_nanos: AtomicU64::new(0),
+        locked_at: AtomicU64::new(0),
+    };
+
+    #[inline]
+    pub(crate) fn fast_path(&self) {
+        self.fast_acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn slow_path(&self, f: impl FnOnce() -> bool) -> bool {
+        self.slow_path_entries.fetch_add(1, Ordering::Relaxed);
+        let start = now_nanos();
+        let result = f();
+        self.wait_nanos.fetch_add(now_nanos() - start, Ordering::Relaxed);
+        result
+    }
+
+    #[inline]
+    pub(crate) fn spin(&self) {
+        self.spins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn park(&self) {
+        self.parks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // This is called from parking_lot_core callbacks, so it must not panic or
+    // call into parking_lot.
+    #[inline]
+    pub(crate) fn handoff(&self, fair_timeout: bool) {
+        self.handoffs.fetch_add(1, Ordering::Relaxed);
+        if fair_timeout {
+            self.fair_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn lock_exclusive(&self) {
+        self.locked_at.store(now_nanos(), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn unlock_exclusive(&self) {
+        let locked_at = self.locked_at.swap(0, Ordering::Relaxed);
+        if locked_at != 0 {
+            self.hold_nanos
+                .fetch_add(now_nanos() - locked_at, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn get(&self) -> LockStats {
+        LockStats {
+            fast_acquisitions: self.fast_acquisitions.load(Ordering::Relaxed),
+            slow_path_entries: self.slow_path_entries.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+            parks: self.parks.load(Ordering::Relaxed),
+            handoffs: self.handoffs.load(Ordering::Relaxed),
+            fair_timeouts: self.fair_timeouts.load(Ordering::Relaxed),
+            wait_time: Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed)),
+            hold_time: Duration::from_nanos(self.hold_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.fast_acquisitions.store(0, Ordering::Relaxed);
+        self.slow_path_entries.store(0, Ordering::Relaxed);
+        self.spins.store(0, Ordering::Relaxed);
+        self.parks.store(0, Ordering::Relaxed);
+        self.handoffs.store(0, Ordering::Relaxed);
+        self.fair_timeouts.store(0, Ordering::Relaxed);
+        self.wait_nanos.store(0, Ordering::Relaxed);
+        self.hold_nanos.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+impl LockCounters {
+    pub(crate) const INIT: LockCounters = LockCounters;
+
+    #[inline(always)]
+    pub(crate) fn fast_path(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn slow_path(&self, f: impl FnOnce() -> bool) -> bool {
+        f()
+    }
+
+    #[inline(always)]
+    pub(crate) fn spin(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn park(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn handoff(&self, _fair_timeout: bool) {}
+
+    #[inline(always)]
+    pub(crate) fn lock_exclusive(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn unlock_exclusive(&self) {}
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+    use crate::{FairMutex, LockStats, Mutex, ReentrantMutex, RwLock};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_uncontended_mutex() {
+        let m = Mutex::new(0);
+        {
+            let _guard = m.lock();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(m.try_lock().is_some());
+        assert!(m.try_lock_for(Duration::from_millis(10)).is_some());
+
+        let stats = m.stats();
+        assert_eq!(stats.fast_acquisitions, 3);
+        assert_eq!(stats.slow_path_entries, 0);
+        assert_eq!(stats.parks, 0);
+        assert_eq!(stats.handoffs, 0);
+        assert!(stats.hold_time >= Duration::from_millis(10));
+
+        m.reset_stats();
+        assert_eq!(m.stats(), LockStats::default());
+    }
+
+    #[test]
+    fn test_contended_mutex() {
+        let m = Arc::new(Mutex::new(0));
+        let guard = m.lock();
+        let m2 = m.clone();
+        let t = thread::spawn(move || {
+            *m2.lock() += 1;
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        t.join().unwrap();
+
+        let stats = m.stats();
+        assert_eq!(stats.fast_acquisitions, 1);
+        assert_eq!(stats.slow_path_entries, 1);
+        assert!(stats.parks >= 1);
+        assert!(stats.wait_time >= Duration::from_millis(50));
+        assert!(stats.hold_time >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_fair_mutex_handoff() {
+        let m = Arc::new(FairMutex::new(0));
+        let guard = m.lock();
+        let m2 = m.clone();
+        let t = thread::spawn(move || {
+            *m2.lock() += 1;
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        t.join().unwrap();
+
+        let stats = m.stats();
+        assert_eq!(stats.handoffs, 1);
+        assert_eq!(stats.fair_timeouts, 0);
+    }
+
+    #[test]
+    fn test_rwlock() {
+        let l = Arc::new(RwLock::new(0));
+        {
+            let _a = l.read();
+            let _b = l.read();
+        }
+        {
+            let _guard = l.write();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = l.stats();
+        assert_eq!(stats.fast_acquisitions, 3);
+        assert_eq!(stats.slow_path_entries, 0);
+        assert!(stats.hold_time >= Duration::from_millis(10));
+
+        l.reset_stats();
+        let reader = l.read();
+        let l2 = l.clone();
+        let t = thread::spawn(move || {
+            *l2.write() += 1;
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(reader);
+        t.join().unwrap();
+
+        let stats = l.stats();
+        assert_eq!(stats.fast_acquisitions, 1);
+        assert_eq!(stats.slow_path_entries, 1);
+        assert!(stats.wait_time >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_reentrant_mutex() {
+        let m = ReentrantMutex::new(0);
+        {
+            let _a = m.lock();
+            let _b = m.lock();
+        }
+        let stats = m.stats();
+        assert_eq!(stats.fast_acquisitions, 1);
+        assert_eq!(stats.slow_path_entries, 0);
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Integration with `tracing`.
+//!
+//! This is enabled via the `tracing` feature flag, which emits `TRACE` level
+//! spans and events with the `parking_lot` target:
+//!
+//! - A `lock_slow` span around every acquisition of a `Mutex`, `FairMutex`,
+//!   `ReentrantMutex` or `RwLock` which has to take the slow path, recording
+//!   whether the lock was acquired.
+//! - A `condvar_requeue` event whenever `Condvar::notify_all` moves waiting
+//!   threads over to the queue of the mutex.
+//! - A `once_init` span around the initialization closure of a `Once`,
+//!   recording whether it completed.
+//!
+//! The feature also enables the `park` spans and `unpark` events emitted by
+//! `parking_lot_core`. Nothing is emitted by a thread which blocks on a lock
+//! while it is already calling into the subscriber for any of these, which
+//! avoids endless recursion if the subscriber uses `parking_lot` itself.
+//!
+//! When the feature is disabled all of this compiles down to nothing.
+
+#[cfg(feature = "tracing")]
+use parking_lot_core::trace_guard;
+use parking_lot_core::UnparkResult;
+use std::time::Instant;
+
+// Span covering the slow path of a lock acquisition.
+#[cfg(feature = "tracing")]
+pub(crate) struct LockSpan(Option<tracing::span::EnteredSpan>);
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct LockSpan;
+
+// Span covering the initialization closure of a `Once`.
+#[cfg(feature = "tracing")]
+pub(crate) struct OnceSpan(Option<tracing::span::EnteredSpan>);
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct OnceSpan;
+
+#[cfg(feature = "tracing")]
+impl LockSpan {
+    // `kind` describes which lock, and which mode of it, is being acquired.
+    #[inline]
+    pub(crate) fn enter(kind: &'static str, key: usize, timeout: Option<Instant>) -> LockSpan {
+        LockSpan(trace_guard(|| {
+            tracing::trace_span!(
+                target: "parking_lot",
+                "lock_slow",
+                kind,
+                key,
+                timed = timeout.is_some(),
+                acquired = tracing::field::Empty,
+            )
+            .entered()
+        }))
+    }
+
+    #[inline]
+    pub(crate) fn finish(self, acquired: bool) -> bool {
+        if let Some(span) = self.0 {
+            // The span is exited when it is dropped by the closure.
+            trace_guard(move || {
+                span.record("acquired", acquired);
+            });
+        }
+        acquired
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl LockSpan {
+    #[inline(always)]
+    pub(crate) fn enter(_kind: &'static str, _key: usize, _timeout: Option<Instant>) -> LockSpan {
+        LockSpan
+    }
+
+    #[inline(always)]
+    pub(crate) fn finish(self, acquired: bool) -> bool {
+        acquired
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl OnceSpan {
+    #[inline]
+    pub(crate) fn enter(key: usize, poisoned: bool) -> OnceSpan {
+        OnceSpan(trace_guard(|| {
+            tracing::trace_span!(
+                target: "parking_lot",
+                "once_init",
+                key,
+                poisoned,
+                done = tracing::field::Empty,
+            )
+            .entered()
+        }))
+    }
+
+    // Not called if the closure panics, in which case the span is exited
+    // without recording `done`.
+    #[inline]
+    pub(crate) fn finish(self, done: bool) {
+        if let Some(span) = self.0 {
+            trace_guard(move || {
+                span.record("done", done);
+            });
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl OnceSpan {
+    #[inline(always)]
+    pub(crate) fn enter(_key: usize, _poisoned: bool) -> OnceSpan {
+        OnceSpan
+    }
+
+    #[inline(always)]
+    pub(crate) fn finish(self, _done: bool) {}
+}
+
+// Emits an event for a `Condvar::notify_all` which requeued threads from the
+// condition variable at `condvar` to the mutex at `mutex`.
+#[cfg(feature = "tracing")]
+#[inline]
+pub(crate) fn condvar_requeue(condvar: usize, mutex: usize, result: UnparkResult) {
+    trace_guard(|| {
+        tracing::trace!(
+            target: "parking_lot",
+            condvar,
+            mutex,
+            unparked_threads = result.unparked_threads,
+            requeued_threads = result.requeued_threads,
+            "condvar_requeue",
+        )
+    });
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn condvar_requeue(_condvar: usize, _mutex: usize, _result: UnparkResult) {}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{Condvar, Mutex, Once};
+    use std::fmt::{self, Write};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex as StdMutex};
+    use std::thread;
+    use std::time::Duration;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    // Subscriber which records every span, recorded value and event as a line
+    // of text.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        lines: Arc<StdMutex<Vec<String>>>,
+        next_id: Arc<AtomicU64>,
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            write!(self.0, " {}={:?}", field.name(), value).unwrap();
+        }
+    }
+
+    impl Recorder {
+        fn push(&self, prefix: &str, name: &str, record: impl FnOnce(&mut Fields)) {
+            let mut fields = Fields(format!("{} {}", prefix, name));
+            record(&mut fields);
+            self.lines.lock().unwrap().push(fields.0);
+        }
+
+        fn lines(&self) -> Vec<String> {
+            self.lines.lock().unwrap().clone()
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            self.push("span", span.metadata().name(), |f| span.record(f));
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _span: &Id, values: &Record<'_>) {
+            self.push("record", "", |f| values.record(f));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            self.push("event", "", |f| event.record(f));
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    // Subscriber which locks a `Mutex` whenever a value is recorded, and
+    // records whether it was called again while doing so. Unlike the creation
+    // of spans, recording a value calls directly into the subscriber, which
+    // isn't protected against recursion by `tracing` itself.
+    #[derive(Clone, Default)]
+    struct Contending {
+        mutex: Arc<Mutex<()>>,
+        depth: Arc<AtomicUsize>,
+        reentered: Arc<AtomicBool>,
+    }
+
+    impl Contending {
+        fn call(&self, f: impl FnOnce()) {
+            if self.depth.fetch_add(1, Ordering::Relaxed) == 0 {
+                f();
+            } else {
+                self.reentered.store(true, Ordering::Relaxed);
+            }
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Subscriber for Contending {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            self.call(|| {});
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {
+            self.call(|| drop(self.mutex.lock()));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {
+            self.call(|| {});
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    fn contains(lines: &[String], parts: &[&str]) -> bool {
+        lines
+            .iter()
+            .any(|line| parts.iter().all(|part| line.contains(part)))
+    }
+
+    #[test]
+    fn test_mutex_slow_path() {
+        let recorder = Recorder::default();
+        let m = Arc::new(Mutex::new(()));
+        let guard = m.lock();
+
+        let m2 = m.clone();
+        let recorder2 = recorder.clone();
+        let thread = thread::spawn(move || {
+            tracing::subscriber::with_default(recorder2, || drop(m2.lock()));
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        thread.join().unwrap();
+
+        let lines = recorder.lines();
+        let key = format!("key={}", unsafe { m.raw() } as *const _ as usize);
+        assert!(contains(
+            &lines,
+            &["span lock_slow", "kind=\"mutex\"", &key]
+        ));
+        assert!(contains(&lines, &["span park", &key]));
+        assert!(contains(&lines, &["record", "acquired=true"]));
+    }
+
+    #[test]
+    fn test_condvar_requeue() {
+        let recorder = Recorder::default();
+        let pair = Arc::new((Mutex::new((0, false)), Condvar::new()));
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let pair = pair.clone();
+                thread::spawn(move || {
+                    let mut state = pair.0.lock();
+                    state.0 += 1;
+                    while !state.1 {
+                        pair.1.wait(&mut state);
+                    }
+                })
+            })
+            .collect();
+        loop {
+            let mut state = pair.0.lock();
+            if state.0 == 2 {
+                // Both waiters are parked on the condvar and the mutex is
+                // locked, so they are all requeued onto the mutex.
+                state.1 = true;
+                tracing::subscriber::with_default(recorder.clone(), || pair.1.notify_all());
+                break;
+            }
+            drop(state);
+            thread::yield_now();
+        }
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+
+        let lines = recorder.lines();
+        assert!(contains(
+            &lines,
+            &[
+                "event",
+                "condvar_requeue",
+                "unparked_threads=0",
+                "requeued_threads=2"
+            ]
+        ));
+        assert!(contains(
+            &lines,
+            &["event", "op=RequeueAll", "requeued_threads=2"]
+        ));
+    }
+
+    #[test]
+    fn test_once_init() {
+        let recorder = Recorder::default();
+        let once = Once::new();
+        tracing::subscriber::with_default(recorder.clone(), || once.call_once(|| {}));
+
+        let lines = recorder.lines();
+        assert!(contains(&lines, &["span once_init", "poisoned=false"]));
+        assert!(contains(&lines, &["record", "done=true"]));
+    }
+
+    #[test]
+    fn test_subscriber_contends() {
+        let subscriber = Contending::default();
+
+        // The subscriber has to park when `done` is recorded, since the mutex
+        // is still held by another thread.
+        let (tx, rx) = mpsc::channel();
+        let mutex = subscriber.mutex.clone();
+        let holder = thread::spawn(move || {
+            let _guard = mutex.lock();
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+        rx.recv().unwrap();
+
+        let once = Once::new();
+        tracing::subscriber::with_default(subscriber.clone(), || once.call_once(|| {}));
+        holder.join().unwrap();
+        assert!(!subscriber.reentered.load(Ordering::Relaxed));
+    }
+}
@@ -5,6 +5,8 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+#[cfg(any(feature = "stats", feature = "watchdog"))]
+use crate::once_cell::OnceCell;
 use std::time::{Duration, Instant};
 
 // Option::unchecked_unwrap
@@ -36,3 +38,189 @@ unsafe fn unreachable() -> ! {
 pub fn to_deadline(timeout: Duration) -> Option<Instant> {
     Instant::now().checked_add(timeout)
 }
+
+// Returns the number of nanoseconds elapsed since the first call. This is
+// never 0, so that 0 can be used to indicate that a lock isn't held.
+#[cfg(any(feature = "stats", feature = "watchdog"))]
+pub fn now_nanos() -> u64 {
+    static EPOCH: OnceCell<Instant> = OnceCell::new();
+    let epoch = EPOCH.get_or_init(Instant::now);
+    let elapsed = epoch.elapsed();
+    elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos()) + 1
+}
+
+// Runs a future to completion on the current thread. This is only used for
+// testing the asynchronous lock types.
+#[cfg(test)]
+pub fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use std::thread::{self, Thread};
+
+    unsafe fn clone(data: *const ()) -> RawWaker {
+        let thread = Arc::from_raw(data as *const Thread);
+        let cloned = thread.clone();
+        std::mem::forget(thread);
+        RawWaker::new(Arc::into_raw(cloned) as *const (), &VTABLE)
+    }
+    unsafe fn wake(data: *const ()) {
+        Arc::from_raw(data as *const Thread).unpark();
+    }
+    unsafe fn wake_by_ref(data: *const ()) {
+        (*(data as *const Thread)).unpark();
+    }
+    unsafe fn drop_waker(data: *const ()) {
+        drop(Arc::from_raw(data as *const Thread));
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+
+    let thread = Arc::new(thread::current());
+    let raw = RawWaker::new(Arc::into_raw(thread) as *const (), &VTABLE);
+    let waker = unsafe { Waker::from_raw(raw) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+            return result;
+        }
+        thread::park();
+    }
+}
+
+// Returns a waker which does nothing when woken up.
+#[cfg(test)]
+pub fn noop_waker() -> std::task::Waker {
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
+
+// Environment variables which tell a test binary started by `run_in_child`
+// that it is the child, and which inherited file descriptors hold the memory
+// that its parent allocated with `shared_alloc`.
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+const CHILD_ENV: &str = "PARKING_LOT_TEST_CHILD";
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+const SHARED_FDS_ENV: &str = "PARKING_LOT_TEST_SHARED_FDS";
+
+// Exit status of a child whose function succeeded. libtest itself never exits
+// with this status, so a test filter which doesn't match anything can't be
+// mistaken for success.
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+const CHILD_SUCCESS: i32 = 42;
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+thread_local! {
+    // In the parent, the file descriptors allocated by the current test which
+    // the next child needs to map. In the child, the ones it hasn't mapped yet.
+    // `const` initializers need Rust 1.59, which is newer than our MSRV.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static SHARED_FDS: std::cell::RefCell<Vec<libc::c_int>> = std::cell::RefCell::new(
+        std::env::var(SHARED_FDS_ENV)
+            .ok()
+            .filter(|_| std::env::var_os(CHILD_ENV).is_some())
+            .map(|fds| {
+                fds.split(',')
+                    .filter(|fd| !fd.is_empty())
+                    .rev()
+                    .map(|fd| fd.parse().unwrap())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    );
+}
+
+// Moves a value into a new shared memory mapping, backed by an unlinked file
+// whose descriptor is inherited by the child processes started afterwards by
+// `run_in_child`. In such a child, this instead maps the memory allocated by
+// the matching call in the parent and drops `value`. The mapping is never
+// freed. This is only used for testing the process-shared lock types.
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+pub fn shared_alloc<T>(value: T) -> &'static T {
+    use std::os::unix::io::IntoRawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let size = std::mem::size_of::<T>();
+    let child = std::env::var_os(CHILD_ENV).is_some();
+    let fd = if child {
+        SHARED_FDS
+            .with(|fds| fds.borrow_mut().pop())
+            .expect("the child allocated more shared memory than its parent")
+    } else {
+        let path = std::env::temp_dir().join(format!(
+            "parking_lot-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(size as u64).unwrap();
+        let fd = file.into_raw_fd();
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }, 0);
+        SHARED_FDS.with(|fds| fds.borrow_mut().push(fd));
+        fd
+    };
+    unsafe {
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        assert_ne!(ptr, libc::MAP_FAILED);
+        let ptr = ptr as *mut T;
+        if !child {
+            ptr.write(value);
+        }
+        &*ptr
+    }
+}
+
+// Runs the given function in a child process and returns a function which
+// waits for it to exit and returns whether it completed successfully.
+//
+// The child is a new instance of the test binary which only runs `test`, the
+// full path of the calling test as given by `module_path!`. It runs the test
+// from the start, so the test must not do anything before calling this that
+// the child can't repeat, such as locking a shared lock. When the child gets
+// here it runs `f` and exits instead of starting another child.
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+pub fn run_in_child(test: &str, f: impl FnOnce() -> bool) -> impl FnOnce() -> bool {
+    use std::process::{Command, Stdio};
+
+    if std::env::var_os(CHILD_ENV).is_some() {
+        std::process::exit(if f() { CHILD_SUCCESS } else { 1 });
+    }
+
+    // libtest names tests by their path without the crate name.
+    let test = &test[test.find("::").map_or(0, |i| i + 2)..];
+    let fds: Vec<String> = SHARED_FDS.with(|fds| {
+        let mut fds = fds.borrow_mut();
+        fds.drain(..).map(|fd| fd.to_string()).collect()
+    });
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .arg(test)
+        .arg("--exact")
+        .arg("--test-threads=1")
+        .arg("-q")
+        .env(CHILD_ENV, "1")
+        .env(SHARED_FDS_ENV, fds.join(","))
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    move || child.wait().unwrap().code() == Some(CHILD_SUCCESS)
+}
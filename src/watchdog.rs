@@ -0,0 +1,385 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reporting of locks which are held for too long.
+//!
+//! This is enabled via the `watchdog` feature flag. A `Watchdog` installed with
+//! `parking_lot_core::set_watchdog` is then told about every thread which stays
+//! parked for longer than its park threshold, and about every `Mutex`,
+//! `FairMutex`, `ReentrantMutex` and `RwLock` write lock which was held for
+//! longer than its hold threshold. Shared and upgradable read locks are not
+//! timed.
+//!
+//! A long hold is reported when the lock is released. Threads waiting for a
+//! lock also wake up once its current hold exceeds the threshold, and the
+//! first one to do so reports the hold while it is still ongoing, so that a
+//! lock which is never released is reported too.
+//!
+//! When the feature is disabled the timer takes up no space and all of the
+//! bookkeeping compiles down to nothing.
+
+#[cfg(feature = "watchdog")]
+use crate::util::now_nanos;
+#[cfg(feature = "watchdog")]
+use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "watchdog")]
+pub(crate) use parking_lot_core::LockKind;
+#[cfg(feature = "watchdog")]
+use std::time::Duration;
+use std::time::Instant;
+
+// Kinds of locks reported to the watchdog.
+#[cfg(not(feature = "watchdog"))]
+#[derive(Clone, Copy)]
+pub(crate) enum LockKind {
+    Mutex,
+    RwLockWrite,
+}
+
+// Time at which the current holder acquired a lock, as returned by
+// `now_nanos`, embedded in every raw lock. This is 0 if the hold isn't timed.
+// It is only modified by the thread holding the lock exclusively, except for
+// REPORTED_BIT which is set by the first waiting thread to report the hold.
+#[cfg(feature = "watchdog")]
+pub(crate) struct HoldTimer(AtomicU64);
+
+#[cfg(not(feature = "watchdog"))]
+pub(crate) struct HoldTimer;
+
+// A finished hold, which must be reported once the lock has been released.
+#[cfg(feature = "watchdog")]
+pub(crate) struct Hold(u64);
+
+#[cfg(not(feature = "watchdog"))]
+pub(crate) struct Hold;
+
+#[cfg(feature = "watchdog")]
+const REPORTED_BIT: u64 = 1 << 63;
+
+#[cfg(feature = "watchdog")]
+impl HoldTimer {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: HoldTimer = HoldTimer(AtomicU64::new(0));
+
+    // Must be called by the current thread after acquiring the lock.
+    #[inline]
+    pub(crate) fn start(&self) {
+        let start = match parking_lot_core::watchdog() {
+            Some(watchdog) if watchdog.hold_threshold().is_some() => now_nanos(),
+            _ => 0,
+        };
+        self.0.store(start, Ordering::Relaxed);
+    }
+
+    // Must be called by the current thread before releasing the lock.
+    #[inline]
+    pub(crate) fn stop(&self) -> Hold {
+        Hold(self.0.swap(0, Ordering::Relaxed))
+    }
+
+    // Must be called by the current thread after reacquiring a lock which it
+    // released after calling `stop`, to continue timing the original hold.
+    #[inline]
+    pub(crate) fn resume(&self, hold: Hold) {
+        self.0.store(hold.0, Ordering::Relaxed);
+    }
+
+    // Returns the deadline until which a thread waiting for the lock should
+    // park, given the deadline of the wait itself. This is earlier if the
+    // current hold may exceed the hold threshold before then, so that the
+    // waiting thread can report it with `timed_out` even if the lock is never
+    // released.
+    #[inline]
+    pub(crate) fn park_deadline(&self, timeout: Option<Instant>) -> Option<Instant> {
+        match parking_lot_core::watchdog() {
+            Some(watchdog) => match watchdog.hold_threshold() {
+                Some(threshold) => self.park_deadline_slow(threshold, timeout),
+                None => timeout,
+            },
+            None => timeout,
+        }
+    }
+
+    #[cold]
+    fn park_deadline_slow(&self, threshold: Duration, timeout: Option<Instant>) -> Option<Instant> {
+        let start = self.0.load(Ordering::Relaxed);
+        if start & REPORTED_BIT != 0 {
+            return timeout;
+        }
+
+        // If the hold isn't timed yet, the holder may be about to start the
+        // timer, so check again after a whole threshold.
+        let held = match start {
+            0 => Duration::from_secs(0),
+            start => Duration::from_nanos(now_nanos().saturating_sub(start)),
+        };
+        let remaining = threshold.checked_sub(held).unwrap_or_default();
+        match Instant::now().checked_add(remaining) {
+            Some(deadline) if timeout.map_or(true, |timeout| deadline < timeout) => Some(deadline),
+            _ => timeout,
+        }
+    }
+
+    // Must be called by a thread waiting for the lock identified by `key`
+    // when parking until the deadline returned by `park_deadline` timed out.
+    // This reports the current hold if it exceeded the threshold, and
+    // returns whether `timeout` has passed, in which case the thread should
+    // stop waiting.
+    #[inline]
+    pub(crate) fn timed_out(&self, key: usize, kind: LockKind, timeout: Option<Instant>) -> bool {
+        self.report_held(key, kind);
+        timeout.map_or(false, |timeout| Instant::now() >= timeout)
+    }
+
+    #[cold]
+    fn report_held(&self, key: usize, kind: LockKind) {
+        let watchdog = match parking_lot_core::watchdog() {
+            Some(watchdog) => watchdog,
+            None => return,
+        };
+        let threshold = match watchdog.hold_threshold() {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let start = self.0.load(Ordering::Relaxed);
+        if start == 0 || start & REPORTED_BIT != 0 {
+            return;
+        }
+        let elapsed = Duration::from_nanos(now_nanos().saturating_sub(start));
+        if elapsed >= threshold
+            && self
+                .0
+                .compare_exchange(
+                    start,
+                    start | REPORTED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            watchdog.report(key, kind, elapsed);
+        }
+    }
+}
+
+#[cfg(feature = "watchdog")]
+impl Hold {
+    // Reports the hold to the watchdog if it exceeded the threshold. This
+    // should be called after releasing the lock identified by `key`.
+    #[inline]
+    pub(crate) fn report(self, key: usize, kind: LockKind) {
+        let start = self.0 & !REPORTED_BIT;
+        if start != 0 {
+            report_slow(start, key, kind);
+        }
+    }
+}
+
+#[cfg(feature = "watchdog")]
+#[cold]
+fn report_slow(start: u64, key: usize, kind: LockKind) {
+    let watchdog = match parking_lot_core::watchdog() {
+        Some(watchdog) => watchdog,
+        None => return,
+    };
+    let elapsed = Duration::from_nanos(now_nanos().saturating_sub(start));
+    if let Some(threshold) = watchdog.hold_threshold() {
+        if elapsed >= threshold {
+            watchdog.report(key, kind, elapsed);
+        }
+    }
+}
+
+#[cfg(not(feature = "watchdog"))]
+impl HoldTimer {
+    pub(crate) const INIT: HoldTimer = HoldTimer;
+
+    #[inline(always)]
+    pub(crate) fn start(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn stop(&self) -> Hold {
+        Hold
+    }
+
+    #[inline(always)]
+    pub(crate) fn resume(&self, _hold: Hold) {}
+
+    #[inline(always)]
+    pub(crate) fn park_deadline(&self, timeout: Option<Instant>) -> Option<Instant> {
+        timeout
+    }
+
+    #[inline(always)]
+    pub(crate) fn timed_out(
+        &self,
+        _key: usize,
+        _kind: LockKind,
+        _timeout: Option<Instant>,
+    ) -> bool {
+        true
+    }
+}
+
+#[cfg(not(feature = "watchdog"))]
+impl Hold {
+    #[inline(always)]
+    pub(crate) fn report(self, _key: usize, _kind: LockKind) {}
+}
+
+#[cfg(all(test, feature = "watchdog"))]
+mod tests {
+    use crate::{Condvar, Mutex, RwLock, RwLockWriteGuard};
+    use parking_lot_core::{LockKind, Watchdog};
+    use std::sync::{Arc, Mutex as StdMutex, Once};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct TestWatchdog(StdMutex<Vec<(usize, LockKind, Duration)>>);
+
+    impl Watchdog for TestWatchdog {
+        fn park_threshold(&self) -> Option<Duration> {
+            None
+        }
+
+        fn hold_threshold(&self) -> Option<Duration> {
+            Some(Duration::from_millis(50))
+        }
+
+        fn report(&self, key: usize, kind: LockKind, elapsed: Duration) {
+            self.0.lock().unwrap().push((key, kind, elapsed));
+        }
+    }
+
+    static WATCHDOG: TestWatchdog = TestWatchdog(StdMutex::new(Vec::new()));
+
+    // Installs the watchdog and returns a function listing the holds of the
+    // lock with the given key which were reported so far. Reports left by
+    // an earlier lock at the same address are discarded.
+    fn install(key: usize) -> impl Fn() -> Vec<(LockKind, Duration)> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            parking_lot_core::set_watchdog(&WATCHDOG).ok().unwrap();
+        });
+        WATCHDOG.0.lock().unwrap().retain(|&(k, _, _)| k != key);
+        move || {
+            let reports = WATCHDOG.0.lock().unwrap();
+            reports
+                .iter()
+                .filter(|&&(k, _, _)| k == key)
+                .map(|&(_, kind, elapsed)| (kind, elapsed))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_mutex_hold() {
+        let m = Mutex::new(());
+        let key = unsafe { m.raw() } as *const _ as usize;
+        let reports = install(key);
+
+        drop(m.lock());
+        assert!(reports().is_empty());
+
+        let guard = m.lock();
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        let holds = reports();
+        assert_eq!(holds.len(), 1);
+        assert_eq!(holds[0].0, LockKind::Mutex);
+        assert!(holds[0].1 >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_rwlock_hold() {
+        let l = RwLock::new(());
+        let key = unsafe { l.raw() } as *const _ as usize;
+        let reports = install(key);
+
+        let read = l.read();
+        thread::sleep(Duration::from_millis(100));
+        drop(read);
+        assert!(reports().is_empty());
+
+        let write = l.write();
+        thread::sleep(Duration::from_millis(100));
+        let read = RwLockWriteGuard::downgrade(write);
+        thread::sleep(Duration::from_millis(100));
+        drop(read);
+        let holds = reports();
+        assert_eq!(holds.len(), 1);
+        assert_eq!(holds[0].0, LockKind::RwLockWrite);
+        assert!(holds[0].1 < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_hold_reported_by_waiter() {
+        let m = Arc::new(Mutex::new(()));
+        let key = unsafe { m.raw() } as *const _ as usize;
+        let reports = install(key);
+
+        let guard = m.lock();
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || drop(m.lock()))
+            })
+            .collect();
+        assert!(m.try_lock_for(Duration::from_millis(150)).is_none());
+
+        // The lock hasn't been released, but one of the waiters reported it.
+        let holds = reports();
+        assert_eq!(holds.len(), 1);
+        assert_eq!(holds[0].0, LockKind::Mutex);
+        assert!(holds[0].1 >= Duration::from_millis(50));
+
+        drop(guard);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        let holds = reports();
+        assert_eq!(holds.len(), 2);
+        assert!(holds[1].1 >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_write_hold_reported_by_reader() {
+        let l = Arc::new(RwLock::new(()));
+        let key = unsafe { l.raw() } as *const _ as usize;
+        let reports = install(key);
+
+        let write = l.write();
+        let l2 = l.clone();
+        let reader = thread::spawn(move || drop(l2.read()));
+        let start = Instant::now();
+        while reports().is_empty() && start.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let holds = reports();
+        assert_eq!(holds.len(), 1);
+        assert_eq!(holds[0].0, LockKind::RwLockWrite);
+
+        drop(write);
+        reader.join().unwrap();
+        assert_eq!(reports().len(), 2);
+    }
+
+    #[test]
+    fn test_condvar_wait_is_not_a_hold() {
+        let m = Mutex::new(());
+        let c = Condvar::new();
+        let key = unsafe { m.raw() } as *const _ as usize;
+        let reports = install(key);
+
+        let mut guard = m.lock();
+        assert!(c
+            .wait_for(&mut guard, Duration::from_millis(100))
+            .timed_out());
+        drop(guard);
+        assert!(reports().is_empty());
+    }
+}